@@ -0,0 +1,153 @@
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Number of bytes a backup copies at a time
+const BACKUP_CHUNK_BYTES: u64 = 64 * 1024;
+
+/// Maximum number of chunks copied by each step of a backup, see `HashStorage::continue_backup`
+pub(crate) const BACKUP_STEP_CHUNKS: usize = 16;
+
+/// Creates a file for a backup to be written to, a backup never overwrites an existing file
+pub(crate) async fn create_backup_file(path: &Path) -> Result<File, String> {
+    tokio::fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(path)
+        .await
+        .map_err(|e| format!("Failed to create the backup file {}: {}", path.display(), e))
+}
+
+/// A copy of a file as it was when the backup started, made while the file keeps changing
+///
+/// The file is copied in chunks of `BACKUP_CHUNK_BYTES`, a few at a time in order by
+/// `copy_chunks`. Before a range of the file is overwritten, `preserve` copies the chunks it
+/// touches which haven't been copied yet, so the copy only ever sees the contents from when the
+/// backup started. Anything written past the length the file had when the backup started is left
+/// out.
+pub(crate) struct FileBackup {
+    /// The file the copy is written to
+    destination: File,
+
+    /// The length of the file when the backup started
+    len: u64,
+
+    /// Whether each chunk has been copied
+    copied: Vec<bool>,
+
+    /// Every chunk before this one has been copied, `copy_chunks` carries on from here
+    cursor: usize,
+}
+
+impl FileBackup {
+    pub fn new(destination: File, len: u64) -> Self {
+        Self {
+            destination,
+            len,
+            copied: vec![false; len.div_ceil(BACKUP_CHUNK_BYTES) as usize],
+            cursor: 0,
+        }
+    }
+
+    /// Whether every chunk has been copied
+    pub fn is_done(&self) -> bool {
+        self.cursor >= self.copied.len()
+    }
+
+    /// Copies the chunks of the range which haven't been copied yet, called before the range of
+    /// the source is overwritten
+    pub async fn preserve(&mut self, source: &mut File, position: u64, len: u64) {
+        if len == 0 || position >= self.len {
+            return;
+        }
+        let first = (position / BACKUP_CHUNK_BYTES) as usize;
+        let last = ((position + len).min(self.len) - 1) / BACKUP_CHUNK_BYTES;
+        for chunk in first..=last as usize {
+            if !self.copied[chunk] {
+                self.copy_chunk(source, chunk).await;
+            }
+        }
+    }
+
+    /// Copies up to `max_chunks` of the chunks which haven't been copied yet
+    ///
+    /// # Returns
+    /// - The number of chunks copied
+    pub async fn copy_chunks(&mut self, source: &mut File, max_chunks: usize) -> usize {
+        let mut count = 0;
+        while count < max_chunks && !self.is_done() {
+            if !self.copied[self.cursor] {
+                self.copy_chunk(source, self.cursor).await;
+                count += 1;
+            }
+            self.cursor += 1;
+        }
+        count
+    }
+
+    /// Writes the copy to disk once every chunk has been copied
+    pub async fn finish(&mut self) {
+        self.destination.sync_all().await.unwrap();
+    }
+
+    async fn copy_chunk(&mut self, source: &mut File, chunk: usize) {
+        let position = chunk as u64 * BACKUP_CHUNK_BYTES;
+        let mut buf = vec![0; BACKUP_CHUNK_BYTES.min(self.len - position) as usize];
+        source.seek(SeekFrom::Start(position)).await.unwrap();
+        source.read_exact(&mut buf).await.unwrap();
+        self.destination
+            .seek(SeekFrom::Start(position))
+            .await
+            .unwrap();
+        self.destination.write_all(&buf).await.unwrap();
+        self.destination.flush().await.unwrap();
+        // Only marked once written, a copy which is interrupted is simply made again
+        self.copied[chunk] = true;
+    }
+}
+
+#[cfg(test)]
+mod test_backup {
+    use super::*;
+
+    #[tokio::test]
+    async fn copies_file_as_it_was() {
+        let source_path = "./test_data/test_backup_copies_file_as_it_was_source";
+        let backup_path = "./test_data/test_backup_copies_file_as_it_was_backup";
+        let original: Vec<u8> = (0..BACKUP_CHUNK_BYTES * 3 + 10)
+            .map(|x| (x % 251) as u8)
+            .collect();
+        std::fs::write(source_path, &original).unwrap();
+        let _ = std::fs::remove_file(backup_path);
+
+        let mut source: File = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(source_path)
+            .unwrap()
+            .into();
+        let destination = create_backup_file(Path::new(backup_path)).await.unwrap();
+        let mut backup = FileBackup::new(destination, original.len() as u64);
+        assert!(create_backup_file(Path::new(backup_path)).await.is_err());
+
+        assert_eq!(backup.copy_chunks(&mut source, 1).await, 1);
+
+        // Overwrite the end of the second chunk and the start of the third, and grow the file
+        let position = BACKUP_CHUNK_BYTES * 2 - 5;
+        backup.preserve(&mut source, position, 10).await;
+        source.seek(SeekFrom::Start(position)).await.unwrap();
+        source.write_all(&[0; 10]).await.unwrap();
+        let end = original.len() as u64;
+        backup.preserve(&mut source, end, 10).await;
+        source.seek(SeekFrom::Start(end)).await.unwrap();
+        source.write_all(&[0; 10]).await.unwrap();
+        source.flush().await.unwrap();
+
+        // Only the last chunk is left, the preserved ones are skipped
+        assert_eq!(backup.copy_chunks(&mut source, 10).await, 1);
+        assert!(backup.is_done());
+        backup.finish().await;
+        assert_eq!(std::fs::read(backup_path).unwrap(), original);
+    }
+}
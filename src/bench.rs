@@ -0,0 +1,182 @@
+use crate::command::*;
+use crate::hash_storage::{HashStorage, HashStorageOptions};
+use crate::linear_hash_storage::LinearHashStorage;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The number of keys written, read and deleted when `--bench` isn't given a count
+const DEFAULT_BENCH_KEYS: usize = 10_000;
+
+/// The directory the files of the benchmarked engine are created in, it is emptied first
+const BENCH_DIRECTORY: &str = "bench_data";
+
+/// The length in bytes of each value written by the benchmark
+const BENCH_VALUE_BYTES: usize = 100;
+
+/// A storage engine which can be benchmarked
+enum Engine {
+    Hash(Box<HashStorage>),
+    Linear(Box<LinearHashStorage>),
+}
+
+impl Engine {
+    /// Creates the named engine with empty files in the directory
+    async fn new(name: &str, directory: &Path) -> Result<Self, String> {
+        let path = |file: &str| directory.join(file).to_str().unwrap().to_string();
+        match name {
+            "hash" => HashStorage::new(
+                &path("hash_dir.db"),
+                &path("hash_data.db"),
+                HashStorageOptions::default(),
+            )
+            .await
+            .map(|storage| Engine::Hash(Box::new(storage))),
+            "linear" => Ok(Engine::Linear(Box::new(
+                LinearHashStorage::new(
+                    &path("linear_meta.db"),
+                    &path("linear_data.db"),
+                    &path("linear_overflow.db"),
+                )
+                .await,
+            ))),
+            _ => Err(format!("Unknown engine: {}", name)),
+        }
+    }
+
+    async fn handle_cmd(&mut self, cmd: StorageCommand) -> Result<CommandOutput, String> {
+        match self {
+            Engine::Hash(storage) => storage.handle_cmd(cmd).await,
+            Engine::Linear(storage) => storage.handle_cmd(cmd).await,
+        }
+    }
+}
+
+/// How long each phase of a benchmark took, with the stats of the engine once every key was put
+#[derive(Debug)]
+struct BenchResult {
+    put: Duration,
+
+    /// The time 99% of the puts took at most, which a split during a put shows up in
+    put_p99: Duration,
+
+    get: Duration,
+    delete: Duration,
+
+    /// The buckets, overflow pages and directory bytes the engine grew to, see `STATS`
+    stats: Vec<(String, String)>,
+}
+
+/// Puts, then gets, then deletes `count` keys with the named engine, timing each phase
+///
+/// Every get is checked to find the value which was put, so a broken engine fails the benchmark
+/// instead of looking fast
+async fn bench(name: &str, directory: &Path, count: usize) -> Result<BenchResult, String> {
+    let _ = std::fs::remove_dir_all(directory);
+    std::fs::create_dir_all(directory).map_err(|e| e.to_string())?;
+    let mut engine = Engine::new(name, directory).await?;
+    let key = |i: usize| -> Vec<u8> { format!("key{}", i).into() };
+    let value = |i: usize| -> Vec<u8> { format!("{:0>1$}", i, BENCH_VALUE_BYTES).into() };
+
+    let start = Instant::now();
+    let mut put_latencies = Vec::with_capacity(count);
+    for i in 0..count {
+        let cmd = PutCommand(key(i), value(i), None);
+        let put_start = Instant::now();
+        engine.handle_cmd(cmd.into()).await?;
+        put_latencies.push(put_start.elapsed());
+    }
+    let put = start.elapsed();
+    put_latencies.sort();
+    let put_p99 = put_latencies
+        .get(count * 99 / 100)
+        .copied()
+        .unwrap_or_default();
+    let CommandOutput::Stats(stats) = engine.handle_cmd(StorageCommand::Stats).await? else {
+        return Err("Expected the stats of the engine".into());
+    };
+
+    let start = Instant::now();
+    for i in 0..count {
+        let output = engine.handle_cmd(GetCommand(key(i)).into()).await?;
+        if output != CommandOutput::Found(value(i)) {
+            return Err(format!("Expected to find key{} but got {:?}", i, output));
+        }
+    }
+    let get = start.elapsed();
+
+    let start = Instant::now();
+    for i in 0..count {
+        engine.handle_cmd(DeleteCommand(key(i)).into()).await?;
+    }
+    let delete = start.elapsed();
+
+    engine.handle_cmd(StorageCommand::Flush).await?;
+    Ok(BenchResult {
+        put,
+        put_p99,
+        get,
+        delete,
+        stats,
+    })
+}
+
+/// Reads the arguments of `--bench <hash|linear> [count]`
+fn parse_args(args: &[String]) -> Result<(&str, usize), String> {
+    let usage = "Usage: --bench <hash|linear> [count]";
+    match args {
+        [engine] => Ok((engine.as_str(), DEFAULT_BENCH_KEYS)),
+        [engine, count] => Ok((
+            engine.as_str(),
+            count
+                .parse()
+                .map_err(|_| format!("Invalid count: {}", count))?,
+        )),
+        _ => Err(usage.into()),
+    }
+}
+
+/// Benchmarks a storage engine in `BENCH_DIRECTORY`, see `bench`
+pub async fn run_bench(args: &[String]) -> Result<(), String> {
+    let (engine, count) = parse_args(args)?;
+    let result = bench(engine, Path::new(BENCH_DIRECTORY), count).await?;
+    for (phase, duration) in [
+        ("put", result.put),
+        ("get", result.get),
+        ("delete", result.delete),
+    ] {
+        println!(
+            "{} {} {} keys in {:?}, {:.0} ops/s",
+            engine,
+            phase,
+            count,
+            duration,
+            count as f64 / duration.as_secs_f64()
+        );
+    }
+    println!("{} put p99 {:?}", engine, result.put_p99);
+    for (name, value) in result.stats {
+        println!("{} {} after put: {}", engine, name, value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_bench {
+    use super::*;
+
+    #[tokio::test]
+    async fn benches_both_engines() {
+        for engine in ["hash", "linear"] {
+            let directory = format!("./test_data/test_bench_{}", engine);
+            let result = bench(engine, Path::new(&directory), 500).await.unwrap();
+            let stat = |name: &str| result.stats.iter().find(|x| x.0 == name).map(|x| &x.1);
+            assert!(stat("buckets").is_some_and(|x| x != "1"));
+            assert!(stat("directory_bytes").is_some());
+        }
+        let directory = Path::new("./test_data/test_bench_unknown");
+        assert_eq!(
+            bench("btree", directory, 1).await.err(),
+            Some("Unknown engine: btree".to_string())
+        );
+    }
+}
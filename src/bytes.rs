@@ -11,3 +11,78 @@ pub trait ParseFromBytes<T>: Sized {
     type Metadata;
     fn from_bytes(bytes: T, metadata: Self::Metadata) -> Result<(Self, T), Self::Error>;
 }
+
+/// Writes the bytes as lowercase hex
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Parses bytes written as hex, in either case
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) {
+        return Err("Hex must have an even number of characters".into());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+                .ok_or_else(|| format!("Invalid hex: {}", hex))
+        })
+        .collect()
+}
+
+/// Appends `n` as a LEB128 varint, 7 bits per byte starting from the lowest, with the high bit
+/// set on every byte but the last
+pub fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/// Reads a varint written by `write_varint`
+pub fn read_varint<'a, T: Iterator<Item = &'a u8>>(bytes: &mut T) -> Result<u64, ()> {
+    let mut n = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.next().ok_or(())?;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(())
+}
+
+/// The number of bytes `n` takes up as a varint
+pub fn varint_len(n: u64) -> usize {
+    (64 - n.leading_zeros() as usize).max(1).div_ceil(7)
+}
+
+#[cfg(test)]
+mod test_bytes {
+    use super::*;
+
+    #[test]
+    fn varint_into_and_from_bytes() {
+        for n in [
+            0,
+            1,
+            127,
+            128,
+            300,
+            16_383,
+            16_384,
+            u32::MAX as u64,
+            u64::MAX,
+        ] {
+            let mut buf = vec![];
+            write_varint(&mut buf, n);
+            assert_eq!(buf.len(), varint_len(n));
+            assert_eq!(read_varint(&mut buf.iter()), Ok(n));
+        }
+        assert_eq!(read_varint(&mut [0x80].iter()), Err(()));
+        assert_eq!(read_varint(&mut [0xff; 11].iter()), Err(()));
+    }
+}
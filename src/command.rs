@@ -1,21 +1,167 @@
 use crate::bytes::*;
 use crate::parse::*;
+use crate::sorted_set::{Score, SortedSet};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::mem::size_of;
+use std::ops::Range;
 use std::fmt::Display;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// The longest key in bytes which can be stored
+///
+/// Every encoding of a key, the mutations, records and the value log, can hold a key of this
+/// length
+pub const MAX_KEY_BYTES: usize = 1024;
+
+/// The longest value in bytes which can be stored, values which don't fit in a page also need
+/// the value log
+pub const MAX_VALUE_BYTES: usize = 512 * 1024 * 1024;
+
+/// A point in time in milliseconds since the unix epoch
+pub type Timestamp = u64;
+
+/// The current time as a `Timestamp`
+pub fn now_millis() -> Timestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as Timestamp
+}
+
+/// Whether a key with the expiry has expired by `now`
+pub fn is_expired(expires_at: Option<Timestamp>, now: Timestamp) -> bool {
+    expires_at.is_some_and(|x| x <= now)
+}
+
+/// Reads a `Timestamp` in LE
+fn read_timestamp<'a, T: Iterator<Item = &'a u8>>(bytes: &mut T) -> Result<Timestamp, ()> {
+    let mut buf = [0; size_of::<Timestamp>()];
+    for byte in buf.iter_mut() {
+        *byte = *bytes.next().ok_or(())?;
+    }
+    Ok(Timestamp::from_le_bytes(buf))
+}
+
+/// Checks the key is within `MAX_KEY_BYTES`
+pub fn validate_key(key: &[u8]) -> Result<(), String> {
+    if key.len() > MAX_KEY_BYTES {
+        return Err(format!(
+            "Key is {} bytes, the limit is {} bytes",
+            key.len(),
+            MAX_KEY_BYTES
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the value is within `MAX_VALUE_BYTES`
+pub fn validate_value(value: &[u8]) -> Result<(), String> {
+    if value.len() > MAX_VALUE_BYTES {
+        return Err(format!(
+            "Value is {} bytes, the limit is {} bytes",
+            value.len(),
+            MAX_VALUE_BYTES
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the field of a hash or the member of a set is within `MAX_KEY_BYTES`, as it is stored
+/// in the key of its record
+pub fn validate_member(member: &[u8]) -> Result<(), String> {
+    if member.len() > MAX_KEY_BYTES {
+        return Err(format!(
+            "Member is {} bytes, the limit is {} bytes",
+            member.len(),
+            MAX_KEY_BYTES
+        ));
+    }
+    Ok(())
+}
+
+/// The error for a command used on a key holding a value of another type, such as `GET` on a list
+pub const WRONG_TYPE: &str = "Key holds a value of another type";
+
+/// A value stored under a key along with its type
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Value {
+    String(Vec<u8>),
+    /// The items of a list from the head to the tail, a list always has at least one item
+    List(VecDeque<Vec<u8>>),
+    /// The fields of a hash with their values, a hash always has at least one field
+    Hash(BTreeMap<Vec<u8>, Vec<u8>>),
+    /// The members of a sorted set, a sorted set always has at least one member
+    SortedSet(SortedSet),
+    /// The members of a set, a set always has at least one member
+    Set(BTreeSet<Vec<u8>>),
+}
+
+impl TryFrom<Value> for VecDeque<Vec<u8>> {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Value> {
+        match value {
+            Value::List(items) => Ok(items),
+            value => Err(value),
+        }
+    }
+}
+
+impl TryFrom<Value> for BTreeMap<Vec<u8>, Vec<u8>> {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Value> {
+        match value {
+            Value::Hash(fields) => Ok(fields),
+            value => Err(value),
+        }
+    }
+}
+
+impl TryFrom<Value> for SortedSet {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Value> {
+        match value {
+            Value::SortedSet(members) => Ok(members),
+            value => Err(value),
+        }
+    }
+}
+
+impl TryFrom<Value> for BTreeSet<Vec<u8>> {
+    type Error = Value;
+
+    fn try_from(value: Value) -> Result<Self, Value> {
+        match value {
+            Value::Set(members) => Ok(members),
+            value => Err(value),
+        }
+    }
+}
+
+/// # Binary layout:
+/// Header -> 2, or 4 when the key expires,
+/// Expiry -> `Timestamp` in LE, only when the header is 4,
+/// Key length -> varint,
+/// Value length -> varint,
+/// Key -> Bytes,
+/// Value -> Bytes
 impl IntoBytes for PutCommand {
     fn into_bytes(self) -> Vec<u8> {
         let mut bytes = Vec::new();
-        let key_bytes = self.0.into_bytes();
-        let value_bytes = self.1.into_bytes();
-        let key_len = key_bytes.len() as u8;
-        let value_len = value_bytes.len() as u16;
-        let header: u8 = 2;
-        let value_len_slice = value_len.to_le_bytes();
-
-        bytes.push(header);
-        bytes.push(key_len);
-        bytes.extend(value_len_slice);
+        let key_bytes = self.0;
+        let value_bytes = self.1;
+        match self.2 {
+            Some(expires_at) => {
+                bytes.push(4);
+                bytes.extend(expires_at.to_le_bytes());
+            }
+            None => bytes.push(2),
+        }
+        write_varint(&mut bytes, key_bytes.len() as u64);
+        write_varint(&mut bytes, value_bytes.len() as u64);
         bytes.extend(key_bytes);
         bytes.extend(value_bytes);
         bytes
@@ -23,10 +169,10 @@ impl IntoBytes for PutCommand {
 }
 
 /// # Binary layout:
-/// Key length -> u8,
-/// Value length -> u16,
-/// Key -> String,
-/// Value -> String
+/// Key length -> varint,
+/// Value length -> varint,
+/// Key -> Bytes,
+/// Value -> Bytes
 impl<'a, T> ParseFromBytes<T> for PutCommand
 where
     T: Iterator<Item = &'a u8>,
@@ -35,14 +181,17 @@ where
     type Metadata = ();
 
     fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), Self::Error> {
-        let key_len = bytes.next().ok_or(())?;
-        let value_len_bytes: Vec<u8> = bytes.by_ref().take(2).cloned().collect();
-        let value_len = u16::from_le_bytes([value_len_bytes[0], value_len_bytes[1]]);
-        let key_bytes: Vec<u8> = bytes.by_ref().take(*key_len as usize).cloned().collect();
-        let value_bytes: Vec<u8> = bytes.by_ref().take(value_len as usize).cloned().collect();
-        let key = String::from_utf8(key_bytes).map_err(|_| ())?;
-        let value = String::from_utf8(value_bytes).map_err(|_| ())?;
-        Ok((PutCommand(key, value), bytes))
+        let key_len = read_varint(&mut bytes)? as usize;
+        let value_len = read_varint(&mut bytes)? as usize;
+        if key_len > MAX_KEY_BYTES || value_len > MAX_VALUE_BYTES {
+            return Err(());
+        }
+        let key_bytes: Vec<u8> = bytes.by_ref().take(key_len).cloned().collect();
+        let value_bytes: Vec<u8> = bytes.by_ref().take(value_len).cloned().collect();
+        if key_bytes.len() != key_len || value_bytes.len() != value_len {
+            return Err(());
+        }
+        Ok((PutCommand(key_bytes, value_bytes, None), bytes))
     }
 }
 
@@ -54,10 +203,15 @@ where
     type Metadata = ();
 
     fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), ()> {
-        let key_len = bytes.next().ok_or(())?;
-        let key_bytes: Vec<u8> = bytes.by_ref().take(*key_len as usize).cloned().collect();
-        let key = String::from_utf8(key_bytes).map_err(|_| ())?;
-        Ok((DeleteCommand(key), bytes))
+        let key_len = read_varint(&mut bytes)? as usize;
+        if key_len > MAX_KEY_BYTES {
+            return Err(());
+        }
+        let key_bytes: Vec<u8> = bytes.by_ref().take(key_len).cloned().collect();
+        if key_bytes.len() != key_len {
+            return Err(());
+        }
+        Ok((DeleteCommand(key_bytes), bytes))
     }
 }
 
@@ -80,6 +234,75 @@ where
                 let (cmd, rest) = PutCommand::from_bytes(bytes, ())?;
                 Ok((Mutation::Put(cmd), rest))
             }
+            3 => {
+                let (cmd, rest) = ExpireCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::Expire(cmd), rest))
+            }
+            4 => {
+                let expires_at = read_timestamp(&mut bytes)?;
+                let (mut cmd, rest) = PutCommand::from_bytes(bytes, ())?;
+                cmd.2 = Some(expires_at);
+                Ok((Mutation::Put(cmd), rest))
+            }
+            5 => {
+                let (cmd, rest) = RenameCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::Rename(cmd), rest))
+            }
+            6 => Ok((Mutation::FlushDb, bytes)),
+            7 => {
+                let (cmd, rest) = ListPushCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::ListPush(cmd), rest))
+            }
+            8 => {
+                let (cmd, rest) = ListPopCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::ListPop(cmd), rest))
+            }
+            9 => {
+                let (RenameCommand(from, to), rest) = RenameCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::Copy(CopyCommand(from, to)), rest))
+            }
+            10 => {
+                let (cmd, rest) = HashSetCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::HashSet(cmd), rest))
+            }
+            11 => {
+                let (cmd, rest) = HashDeleteCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::HashDelete(cmd), rest))
+            }
+            12 => {
+                let (cmd, rest) = SortedSetAddCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::SortedSetAdd(cmd), rest))
+            }
+            13 => {
+                let (HashDeleteCommand(key, members), rest) =
+                    HashDeleteCommand::from_bytes(bytes, ())?;
+                Ok((
+                    Mutation::SortedSetRemove(SortedSetRemoveCommand(key, members)),
+                    rest,
+                ))
+            }
+            14 => {
+                let (HashDeleteCommand(key, members), rest) =
+                    HashDeleteCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::SetAdd(SetAddCommand(key, members)), rest))
+            }
+            15 => {
+                let (HashDeleteCommand(key, members), rest) =
+                    HashDeleteCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::SetRemove(SetRemoveCommand(key, members)), rest))
+            }
+            16 => {
+                let (cmd, rest) = IncrCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::Incr(cmd), rest))
+            }
+            17 => {
+                let (cmd, rest) = PutIfCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::PutIf(cmd), rest))
+            }
+            18 => {
+                let (cmd, rest) = CasCommand::from_bytes(bytes, ())?;
+                Ok((Mutation::Cas(cmd), rest))
+            }
             _ => Err(()),
         }
     }
@@ -87,23 +310,43 @@ where
 
 pub fn get_value_from_buffer<'a, T: Iterator<Item = &'a u8> + Clone>(
     bytes: T,
-    key: &str,
-) -> Result<Option<Option<String>>, ()> {
+    key: &[u8],
+) -> Result<Option<Option<Vec<u8>>>, ()> {
     let mut rest = bytes;
-    let mut value: Option<Option<String>> = None;
+    let mut value: Option<Option<Vec<u8>>> = None;
     while let Ok((mutation, new_rest)) = Mutation::from_bytes(rest, ()) {
         // println!("mutation: {:?}", mutation);
         match mutation {
-            Mutation::Put(PutCommand(k, v)) => {
+            Mutation::Put(PutCommand(k, v, _)) => {
                 if k == key {
                     value = Some(Some(v));
                 }
             }
-            Mutation::Delete(DeleteCommand(k)) => {
+            Mutation::Delete(DeleteCommand(k)) | Mutation::Rename(RenameCommand(k, _)) => {
+                if k == key {
+                    value = Some(None);
+                }
+            }
+            Mutation::ListPush(ListPushCommand(k, _, _))
+            | Mutation::Copy(CopyCommand(_, k))
+            | Mutation::HashSet(HashSetCommand(k, _))
+            | Mutation::SortedSetAdd(SortedSetAddCommand(k, _))
+            | Mutation::SetAdd(SetAddCommand(k, _)) => {
                 if k == key {
                     value = Some(None);
                 }
             }
+            Mutation::FlushDb => value = Some(None),
+            // Only transactions buffer increments and conditional puts, the storage logs the puts
+            // they make
+            Mutation::Expire(_)
+            | Mutation::ListPop(_)
+            | Mutation::HashDelete(_)
+            | Mutation::SortedSetRemove(_)
+            | Mutation::SetRemove(_)
+            | Mutation::Incr(_)
+            | Mutation::PutIf(_)
+            | Mutation::Cas(_) => {}
         }
         rest = new_rest;
     }
@@ -113,21 +356,55 @@ pub fn get_value_from_buffer<'a, T: Iterator<Item = &'a u8> + Clone>(
 
 pub fn get_value_from_mutations_ref<'a, T: Iterator<Item = &'a Mutation>>(
     muts: T,
-    key: &str,
-) -> Option<String> {
-    let mut value: Option<String> = None;
+    key: &[u8],
+) -> Option<Vec<u8>> {
+    let mut value: Option<Vec<u8>> = None;
     for m in muts {
         match m {
-            Mutation::Put(PutCommand(k, v)) => {
+            Mutation::Put(PutCommand(k, v, _)) => {
                 if k == key {
                     value = Some(v.clone());
                 }
             }
-            Mutation::Delete(DeleteCommand(k)) => {
+            Mutation::Delete(DeleteCommand(k)) | Mutation::Rename(RenameCommand(k, _)) => {
+                if k == key {
+                    value = None;
+                }
+            }
+            Mutation::ListPush(ListPushCommand(k, _, _))
+            | Mutation::Copy(CopyCommand(_, k))
+            | Mutation::HashSet(HashSetCommand(k, _))
+            | Mutation::SortedSetAdd(SortedSetAddCommand(k, _))
+            | Mutation::SetAdd(SetAddCommand(k, _)) => {
                 if k == key {
                     value = None;
                 }
             }
+            Mutation::FlushDb => value = None,
+            Mutation::Incr(IncrCommand(k, by)) => {
+                // An increment which fails is skipped on purpose like in `Wal::view`, the commit
+                // it belongs to fails before writing anything, see `Wal::check_increments`
+                if k == key {
+                    if let Ok(result) = increment(value.as_deref(), *by) {
+                        value = Some(result.to_string().into_bytes());
+                    }
+                }
+            }
+            Mutation::PutIf(PutIfCommand(PutCommand(k, v, _), condition)) => {
+                if k == key && condition.holds(value.is_some()) {
+                    value = Some(v.clone());
+                }
+            }
+            Mutation::Cas(CasCommand(k, expected, v)) => {
+                if k == key && value.as_ref() == Some(expected) {
+                    value = Some(v.clone());
+                }
+            }
+            Mutation::Expire(_)
+            | Mutation::ListPop(_)
+            | Mutation::HashDelete(_)
+            | Mutation::SortedSetRemove(_)
+            | Mutation::SetRemove(_) => {}
         }
     }
     value
@@ -135,21 +412,54 @@ pub fn get_value_from_mutations_ref<'a, T: Iterator<Item = &'a Mutation>>(
 
 pub fn get_value_from_mutations<T: Iterator<Item = Mutation>>(
     muts: &mut T,
-    key: &str,
-) -> Option<String> {
-    let mut value: Option<String> = None;
+    key: &[u8],
+) -> Option<Vec<u8>> {
+    let mut value: Option<Vec<u8>> = None;
     for m in muts {
         match m {
-            Mutation::Put(PutCommand(k, v)) => {
+            Mutation::Put(PutCommand(k, v, _)) => {
                 if k == key {
                     value = Some(v);
                 }
             }
-            Mutation::Delete(DeleteCommand(k)) => {
+            Mutation::Delete(DeleteCommand(k)) | Mutation::Rename(RenameCommand(k, _)) => {
+                if k == key {
+                    value = None;
+                }
+            }
+            Mutation::ListPush(ListPushCommand(k, _, _))
+            | Mutation::Copy(CopyCommand(_, k))
+            | Mutation::HashSet(HashSetCommand(k, _))
+            | Mutation::SortedSetAdd(SortedSetAddCommand(k, _))
+            | Mutation::SetAdd(SetAddCommand(k, _)) => {
                 if k == key {
                     value = None;
                 }
             }
+            Mutation::FlushDb => value = None,
+            Mutation::Incr(IncrCommand(k, by)) => {
+                // Skipped on purpose when it fails, see `get_value_from_mutations_ref`
+                if k == key {
+                    if let Ok(result) = increment(value.as_deref(), by) {
+                        value = Some(result.to_string().into_bytes());
+                    }
+                }
+            }
+            Mutation::PutIf(PutIfCommand(PutCommand(k, v, _), condition)) => {
+                if k == key && condition.holds(value.is_some()) {
+                    value = Some(v);
+                }
+            }
+            Mutation::Cas(CasCommand(k, expected, v)) => {
+                if k == key && value.as_ref() == Some(&expected) {
+                    value = Some(v);
+                }
+            }
+            Mutation::Expire(_)
+            | Mutation::ListPop(_)
+            | Mutation::HashDelete(_)
+            | Mutation::SortedSetRemove(_)
+            | Mutation::SetRemove(_) => {}
         }
     }
     value
@@ -169,146 +479,1673 @@ pub fn parse_buffer_to_mutations<'a, T: Iterator<Item = &'a u8> + Clone>(
 
 impl ByteLength for PutCommand {
     fn byte_len(&self) -> usize {
-        let key_len = self.0.as_bytes().len();
-        let value_len = self.1.as_bytes().len();
-        1 + 1 + 2 + key_len + value_len
+        let key_len = self.0.len();
+        let value_len = self.1.len();
+        let expiry_len = self.2.map_or(0, |_| size_of::<Timestamp>());
+        1 + expiry_len
+            + varint_len(key_len as u64)
+            + varint_len(value_len as u64)
+            + key_len
+            + value_len
     }
 }
 
-impl ByteLength for DeleteCommand {
+impl ByteLength for ExpireCommand {
     fn byte_len(&self) -> usize {
-        let key_len = self.0.as_bytes().len();
-        1 + 1 + key_len
+        let key_len = self.0.len();
+        let expiry_len = self.1.map_or(0, |_| size_of::<Timestamp>());
+        1 + varint_len(key_len as u64) + key_len + 1 + expiry_len
     }
 }
 
-impl IntoBytes for DeleteCommand {
+/// # Binary layout:
+/// Header -> 3,
+/// Key length -> varint,
+/// Key -> Bytes,
+/// Expires -> 1 when the key expires, 0 when it persists,
+/// Expiry -> `Timestamp` in LE, only when the key expires
+impl IntoBytes for ExpireCommand {
     fn into_bytes(self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        let key_bytes = self.0.into_bytes();
-        let key_len = key_bytes.len() as u8;
-        let header: u8 = 1;
-
-        bytes.push(header);
-        bytes.push(key_len);
-        bytes.extend(key_bytes);
+        let mut bytes = vec![3];
+        write_varint(&mut bytes, self.0.len() as u64);
+        bytes.extend(self.0);
+        match self.1 {
+            Some(expires_at) => {
+                bytes.push(1);
+                bytes.extend(expires_at.to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
         bytes
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct PutCommand(pub String, pub String);
+impl<'a, T> ParseFromBytes<T> for ExpireCommand
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct DeleteCommand(pub String);
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), ()> {
+        let key_len = read_varint(&mut bytes)? as usize;
+        if key_len > MAX_KEY_BYTES {
+            return Err(());
+        }
+        let key_bytes: Vec<u8> = bytes.by_ref().take(key_len).cloned().collect();
+        if key_bytes.len() != key_len {
+            return Err(());
+        }
+        let expires_at = match bytes.next() {
+            Some(0) => None,
+            Some(1) => Some(read_timestamp(&mut bytes)?),
+            _ => return Err(()),
+        };
+        Ok((ExpireCommand(key_bytes, expires_at), bytes))
+    }
+}
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct GetCommand(pub String);
+impl ByteLength for IncrCommand {
+    fn byte_len(&self) -> usize {
+        let key_len = self.0.len();
+        1 + varint_len(key_len as u64) + key_len + size_of::<i64>()
+    }
+}
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum Mutation {
-    Put(PutCommand),
-    Delete(DeleteCommand),
+/// # Binary layout:
+/// Header -> 16,
+/// Key length -> varint,
+/// Key -> Bytes,
+/// Amount -> i64 in LE
+impl IntoBytes for IncrCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![16];
+        write_varint(&mut bytes, self.0.len() as u64);
+        bytes.extend(self.0);
+        bytes.extend(self.1.to_le_bytes());
+        bytes
+    }
 }
 
-impl ByteLength for Mutation {
-    fn byte_len(&self) -> usize {
-        match self {
-            Mutation::Put(cmd) => cmd.byte_len(),
-            Mutation::Delete(cmd) => cmd.byte_len(),
+impl<'a, T> ParseFromBytes<T> for IncrCommand
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), ()> {
+        let key_len = read_varint(&mut bytes)? as usize;
+        if key_len > MAX_KEY_BYTES {
+            return Err(());
+        }
+        let key_bytes: Vec<u8> = bytes.by_ref().take(key_len).cloned().collect();
+        if key_bytes.len() != key_len {
+            return Err(());
         }
+        let mut buf = [0; size_of::<i64>()];
+        for byte in buf.iter_mut() {
+            *byte = *bytes.next().ok_or(())?;
+        }
+        Ok((IncrCommand(key_bytes, i64::from_le_bytes(buf)), bytes))
     }
 }
 
-impl IntoBytes for Mutation {
+impl ByteLength for PutIfCommand {
+    fn byte_len(&self) -> usize {
+        2 + self.0.byte_len()
+    }
+}
+
+/// # Binary layout:
+/// Header -> 17,
+/// Condition -> 0 when the key must be absent, 1 when it must be present,
+/// Put -> `PutCommand` with its header
+impl IntoBytes for PutIfCommand {
     fn into_bytes(self) -> Vec<u8> {
-        match self {
-            Mutation::Put(cmd) => cmd.into_bytes(),
-            Mutation::Delete(cmd) => cmd.into_bytes(),
+        let condition = match self.1 {
+            PutCondition::Absent => 0,
+            PutCondition::Present => 1,
+        };
+        let mut bytes = vec![17, condition];
+        bytes.extend(self.0.into_bytes());
+        bytes
+    }
+}
+
+impl<'a, T> ParseFromBytes<T> for PutIfCommand
+where
+    T: Iterator<Item = &'a u8> + Clone,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), ()> {
+        let condition = match bytes.next() {
+            Some(0) => PutCondition::Absent,
+            Some(1) => PutCondition::Present,
+            _ => return Err(()),
+        };
+        match Mutation::from_bytes(bytes, ())? {
+            (Mutation::Put(cmd), rest) => Ok((PutIfCommand(cmd, condition), rest)),
+            _ => Err(()),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum CommandOutput {
-    NotFound(String),
-    Found(String),
-    Put,
-    Delete,
-    Exit,
-    Commit,
-    Rollback,
-    Begin(String),
+impl ByteLength for CasCommand {
+    fn byte_len(&self) -> usize {
+        let lens = [self.0.len(), self.1.len(), self.2.len()];
+        1 + lens.iter().map(|x| varint_len(*x as u64) + x).sum::<usize>()
+    }
 }
 
-pub fn handle_command_output_for_transaction_id(
-    output: &CommandOutput,
-    existing: &mut Option<String>,
-) {
-    match output {
-        // Cheap clone?
-        CommandOutput::Begin(s) => *existing = Some(s.clone()),
-        CommandOutput::Rollback => *existing = None,
-        CommandOutput::Commit => *existing = None,
-        _ => {},
+/// # Binary layout:
+/// Header -> 18,
+/// Key length -> varint,
+/// Expected value length -> varint,
+/// New value length -> varint,
+/// Key -> Bytes,
+/// Expected value -> Bytes,
+/// New value -> Bytes
+impl IntoBytes for CasCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![18];
+        for part in [&self.0, &self.1, &self.2] {
+            write_varint(&mut bytes, part.len() as u64);
+        }
+        bytes.extend(self.0);
+        bytes.extend(self.1);
+        bytes.extend(self.2);
+        bytes
     }
 }
 
-impl Display for CommandOutput {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Exit => write!(f, "Bye"),
-            Self::Put => write!(f, "Put"),
-            Self::Delete => write!(f, "Delete"),
-            Self::Found(value) => write!(f, "{}", value),
-            Self::NotFound(_) => write!(f, "Key not found"),
-            Self::Commit => write!(f, "Commit"),
-            Self::Rollback => write!(f, "Rollback"),
-            Self::Begin(_) => write!(f, "Begin"),
+impl<'a, T> ParseFromBytes<T> for CasCommand
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), ()> {
+        let key_len = read_varint(&mut bytes)? as usize;
+        let expected_len = read_varint(&mut bytes)? as usize;
+        let value_len = read_varint(&mut bytes)? as usize;
+        if key_len > MAX_KEY_BYTES || expected_len > MAX_VALUE_BYTES || value_len > MAX_VALUE_BYTES
+        {
+            return Err(());
         }
+        let parts = [key_len, expected_len, value_len].map(|len| {
+            let part: Vec<u8> = bytes.by_ref().take(len).cloned().collect();
+            (part.len() == len).then_some(part)
+        });
+        let [Some(key), Some(expected), Some(value)] = parts else {
+            return Err(());
+        };
+        Ok((CasCommand(key, expected, value), bytes))
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum UserCommand {
-    Put(PutCommand),
-    Delete(DeleteCommand),
-    Get(GetCommand),
-    Exit,
-    Begin,
-    Commit,
-    Rollback,
+impl ByteLength for RenameCommand {
+    fn byte_len(&self) -> usize {
+        let (from_len, to_len) = (self.0.len(), self.1.len());
+        1 + varint_len(from_len as u64) + varint_len(to_len as u64) + from_len + to_len
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum StorageCommand {
-    Put(PutCommand),
-    Delete(DeleteCommand),
-    Get(GetCommand),
-    Flush,
+/// # Binary layout:
+/// Header -> 5,
+/// Key length -> varint,
+/// New key length -> varint,
+/// Key -> Bytes,
+/// New key -> Bytes
+impl IntoBytes for RenameCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![5];
+        write_varint(&mut bytes, self.0.len() as u64);
+        write_varint(&mut bytes, self.1.len() as u64);
+        bytes.extend(self.0);
+        bytes.extend(self.1);
+        bytes
+    }
 }
 
-impl From<PutCommand> for StorageCommand {
-    fn from(value: PutCommand) -> Self {
-        Self::Put(value)
+impl<'a, T> ParseFromBytes<T> for RenameCommand
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), ()> {
+        let from_len = read_varint(&mut bytes)? as usize;
+        let to_len = read_varint(&mut bytes)? as usize;
+        if from_len > MAX_KEY_BYTES || to_len > MAX_KEY_BYTES {
+            return Err(());
+        }
+        let from: Vec<u8> = bytes.by_ref().take(from_len).cloned().collect();
+        let to: Vec<u8> = bytes.by_ref().take(to_len).cloned().collect();
+        if from.len() != from_len || to.len() != to_len {
+            return Err(());
+        }
+        Ok((RenameCommand(from, to), bytes))
     }
 }
 
-impl From<GetCommand> for StorageCommand {
-    fn from(value: GetCommand) -> Self {
-        Self::Get(value)
+impl ByteLength for CopyCommand {
+    fn byte_len(&self) -> usize {
+        let (from_len, to_len) = (self.0.len(), self.1.len());
+        1 + varint_len(from_len as u64) + varint_len(to_len as u64) + from_len + to_len
     }
 }
 
-impl From<DeleteCommand> for StorageCommand {
-    fn from(value: DeleteCommand) -> Self {
-        Self::Delete(value)
+/// # Binary layout:
+/// Header -> 9,
+/// The rest is laid out like a `RenameCommand`
+impl IntoBytes for CopyCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![9];
+        write_varint(&mut bytes, self.0.len() as u64);
+        write_varint(&mut bytes, self.1.len() as u64);
+        bytes.extend(self.0);
+        bytes.extend(self.1);
+        bytes
     }
 }
 
-impl FromStr for UserCommand {
-    type Err = String;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        parse_command(s.to_string())
+/// Reads the side of a list written as 0 for the left and 1 for the right
+fn read_list_side<'a, T: Iterator<Item = &'a u8>>(bytes: &mut T) -> Result<ListSide, ()> {
+    match bytes.next() {
+        Some(0) => Ok(ListSide::Left),
+        Some(1) => Ok(ListSide::Right),
+        _ => Err(()),
+    }
+}
+
+/// Reads a key written as its length as a varint followed by the bytes
+fn read_key<'a, T: Iterator<Item = &'a u8>>(bytes: &mut T) -> Result<Vec<u8>, ()> {
+    let key_len = read_varint(bytes)? as usize;
+    if key_len > MAX_KEY_BYTES {
+        return Err(());
+    }
+    let key: Vec<u8> = bytes.by_ref().take(key_len).cloned().collect();
+    if key.len() != key_len {
+        return Err(());
+    }
+    Ok(key)
+}
+
+/// Reads a value written as its length as a varint followed by the bytes
+fn read_value<'a, T: Iterator<Item = &'a u8>>(bytes: &mut T) -> Result<Vec<u8>, ()> {
+    let value_len = read_varint(bytes)? as usize;
+    if value_len > MAX_VALUE_BYTES {
+        return Err(());
+    }
+    let value: Vec<u8> = bytes.by_ref().take(value_len).cloned().collect();
+    if value.len() != value_len {
+        return Err(());
+    }
+    Ok(value)
+}
+
+/// The length of bytes written as their length as a varint followed by the bytes
+fn prefixed_len(bytes: &[u8]) -> usize {
+    varint_len(bytes.len() as u64) + bytes.len()
+}
+
+/// Writes the bytes as their length as a varint followed by the bytes
+fn write_prefixed(buf: &mut Vec<u8>, bytes: Vec<u8>) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend(bytes);
+}
+
+impl ByteLength for ListPushCommand {
+    fn byte_len(&self) -> usize {
+        let key_len = self.0.len();
+        let values_len: usize = self
+            .2
+            .iter()
+            .map(|x| varint_len(x.len() as u64) + x.len())
+            .sum();
+        2 + varint_len(key_len as u64) + key_len + varint_len(self.2.len() as u64) + values_len
+    }
+}
+
+/// # Binary layout:
+/// Header -> 7,
+/// Side -> 0 for the left, 1 for the right,
+/// Key length -> varint,
+/// Key -> Bytes,
+/// Value count -> varint,
+/// Each value -> Length as a varint followed by the bytes
+impl IntoBytes for ListPushCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![7, self.1 as u8];
+        write_varint(&mut bytes, self.0.len() as u64);
+        bytes.extend(self.0);
+        write_varint(&mut bytes, self.2.len() as u64);
+        for value in self.2 {
+            write_varint(&mut bytes, value.len() as u64);
+            bytes.extend(value);
+        }
+        bytes
+    }
+}
+
+impl<'a, T> ParseFromBytes<T> for ListPushCommand
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), ()> {
+        let side = read_list_side(&mut bytes)?;
+        let key = read_key(&mut bytes)?;
+        let count = read_varint(&mut bytes)?;
+        let mut values = vec![];
+        for _ in 0..count {
+            values.push(read_value(&mut bytes)?);
+        }
+        Ok((ListPushCommand(key, side, values), bytes))
+    }
+}
+
+impl ByteLength for HashSetCommand {
+    fn byte_len(&self) -> usize {
+        let fields_len: usize = self
+            .1
+            .iter()
+            .map(|(field, value)| prefixed_len(field) + prefixed_len(value))
+            .sum();
+        1 + prefixed_len(&self.0) + varint_len(self.1.len() as u64) + fields_len
+    }
+}
+
+/// # Binary layout:
+/// Header -> 10,
+/// Key length -> varint,
+/// Key -> Bytes,
+/// Field count -> varint,
+/// Each field and then its value -> Length as a varint followed by the bytes
+impl IntoBytes for HashSetCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![10];
+        write_prefixed(&mut bytes, self.0);
+        write_varint(&mut bytes, self.1.len() as u64);
+        for (field, value) in self.1 {
+            write_prefixed(&mut bytes, field);
+            write_prefixed(&mut bytes, value);
+        }
+        bytes
+    }
+}
+
+impl<'a, T> ParseFromBytes<T> for HashSetCommand
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), ()> {
+        let key = read_key(&mut bytes)?;
+        let count = read_varint(&mut bytes)?;
+        let mut fields = vec![];
+        for _ in 0..count {
+            let field = read_key(&mut bytes)?;
+            fields.push((field, read_value(&mut bytes)?));
+        }
+        Ok((HashSetCommand(key, fields), bytes))
+    }
+}
+
+impl ByteLength for SortedSetAddCommand {
+    fn byte_len(&self) -> usize {
+        let members_len: usize = self
+            .1
+            .iter()
+            .map(|(_, member)| size_of::<f64>() + prefixed_len(member))
+            .sum();
+        1 + prefixed_len(&self.0) + varint_len(self.1.len() as u64) + members_len
+    }
+}
+
+/// # Binary layout:
+/// Header -> 12,
+/// Key length -> varint,
+/// Key -> Bytes,
+/// Member count -> varint,
+/// Each member -> Score as an f64 in LE, then the length of the member as a varint followed by
+/// the bytes
+impl IntoBytes for SortedSetAddCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![12];
+        write_prefixed(&mut bytes, self.0);
+        write_varint(&mut bytes, self.1.len() as u64);
+        for (score, member) in self.1 {
+            bytes.extend(score.value().to_le_bytes());
+            write_prefixed(&mut bytes, member);
+        }
+        bytes
+    }
+}
+
+impl<'a, T> ParseFromBytes<T> for SortedSetAddCommand
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), ()> {
+        let key = read_key(&mut bytes)?;
+        let count = read_varint(&mut bytes)?;
+        let mut members = vec![];
+        for _ in 0..count {
+            let mut buf = [0; size_of::<f64>()];
+            for byte in buf.iter_mut() {
+                *byte = *bytes.next().ok_or(())?;
+            }
+            let score = Score::new(f64::from_le_bytes(buf)).ok_or(())?;
+            members.push((score, read_value(&mut bytes)?));
+        }
+        Ok((SortedSetAddCommand(key, members), bytes))
+    }
+}
+
+impl ByteLength for SortedSetRemoveCommand {
+    fn byte_len(&self) -> usize {
+        let members_len: usize = self.1.iter().map(|x| prefixed_len(x)).sum();
+        1 + prefixed_len(&self.0) + varint_len(self.1.len() as u64) + members_len
+    }
+}
+
+/// # Binary layout:
+/// Header -> 13,
+/// The rest is laid out like a `HashDeleteCommand`, with the members in place of the fields
+impl IntoBytes for SortedSetRemoveCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = HashDeleteCommand(self.0, self.1).into_bytes();
+        bytes[0] = 13;
+        bytes
+    }
+}
+
+impl ByteLength for SetAddCommand {
+    fn byte_len(&self) -> usize {
+        let members_len: usize = self.1.iter().map(|x| prefixed_len(x)).sum();
+        1 + prefixed_len(&self.0) + varint_len(self.1.len() as u64) + members_len
+    }
+}
+
+/// # Binary layout:
+/// Header -> 14,
+/// The rest is laid out like a `HashDeleteCommand`, with the members in place of the fields
+impl IntoBytes for SetAddCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = HashDeleteCommand(self.0, self.1).into_bytes();
+        bytes[0] = 14;
+        bytes
+    }
+}
+
+impl ByteLength for SetRemoveCommand {
+    fn byte_len(&self) -> usize {
+        let members_len: usize = self.1.iter().map(|x| prefixed_len(x)).sum();
+        1 + prefixed_len(&self.0) + varint_len(self.1.len() as u64) + members_len
+    }
+}
+
+/// # Binary layout:
+/// Header -> 15,
+/// The rest is laid out like a `HashDeleteCommand`, with the members in place of the fields
+impl IntoBytes for SetRemoveCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = HashDeleteCommand(self.0, self.1).into_bytes();
+        bytes[0] = 15;
+        bytes
+    }
+}
+
+impl ByteLength for HashDeleteCommand {
+    fn byte_len(&self) -> usize {
+        let fields_len: usize = self.1.iter().map(|x| prefixed_len(x)).sum();
+        1 + prefixed_len(&self.0) + varint_len(self.1.len() as u64) + fields_len
+    }
+}
+
+/// # Binary layout:
+/// Header -> 11,
+/// Key length -> varint,
+/// Key -> Bytes,
+/// Field count -> varint,
+/// Each field -> Length as a varint followed by the bytes
+impl IntoBytes for HashDeleteCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![11];
+        write_prefixed(&mut bytes, self.0);
+        write_varint(&mut bytes, self.1.len() as u64);
+        for field in self.1 {
+            write_prefixed(&mut bytes, field);
+        }
+        bytes
+    }
+}
+
+impl<'a, T> ParseFromBytes<T> for HashDeleteCommand
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), ()> {
+        let key = read_key(&mut bytes)?;
+        let count = read_varint(&mut bytes)?;
+        let mut fields = vec![];
+        for _ in 0..count {
+            fields.push(read_key(&mut bytes)?);
+        }
+        Ok((HashDeleteCommand(key, fields), bytes))
+    }
+}
+
+impl ByteLength for ListPopCommand {
+    fn byte_len(&self) -> usize {
+        let key_len = self.0.len();
+        2 + varint_len(key_len as u64) + key_len
+    }
+}
+
+/// # Binary layout:
+/// Header -> 8,
+/// Side -> 0 for the left, 1 for the right,
+/// Key length -> varint,
+/// Key -> Bytes
+impl IntoBytes for ListPopCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![8, self.1 as u8];
+        write_varint(&mut bytes, self.0.len() as u64);
+        bytes.extend(self.0);
+        bytes
+    }
+}
+
+impl<'a, T> ParseFromBytes<T> for ListPopCommand
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), ()> {
+        let side = read_list_side(&mut bytes)?;
+        let key = read_key(&mut bytes)?;
+        Ok((ListPopCommand(key, side), bytes))
+    }
+}
+
+impl ByteLength for DeleteCommand {
+    fn byte_len(&self) -> usize {
+        let key_len = self.0.len();
+        1 + varint_len(key_len as u64) + key_len
+    }
+}
+
+impl IntoBytes for DeleteCommand {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let key_bytes = self.0;
+        let header: u8 = 1;
+
+        bytes.push(header);
+        write_varint(&mut bytes, key_bytes.len() as u64);
+        bytes.extend(key_bytes);
+        bytes
+    }
+}
+
+/// Puts the value under the key, which expires at the timestamp when there is one. A put without
+/// an expiry makes the key persist
+#[derive(Debug, Clone, PartialEq)]
+pub struct PutCommand(pub Vec<u8>, pub Vec<u8>, pub Option<Timestamp>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeleteCommand(pub Vec<u8>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GetCommand(pub Vec<u8>);
+
+/// Lists the keys matching the glob pattern, or every key without a pattern, see
+/// `glob::glob_match`
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeysCommand(pub Option<Vec<u8>>);
+
+/// The number of records a `ScanCommand` looks at when no count is given
+pub const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Lists a page of the keys, carrying on from the cursor returned by the previous page. A scan
+/// starts and ends with a cursor of 0
+///
+/// Every key which is present for the whole scan is listed, no matter how the hash table grows in
+/// between pages
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanCommand {
+    pub cursor: u64,
+
+    /// Only list the keys matching the glob pattern, see `glob::glob_match`
+    pub pattern: Option<Vec<u8>>,
+
+    /// Roughly how many records to look at before returning, the page can hold fewer keys when
+    /// the pattern filters some out
+    pub count: usize,
+}
+
+/// Sets the time the key expires at, or makes it persist with `None`
+///
+/// The expiry is a point in time rather than a duration so replaying the mutation expires the
+/// key at the same time
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpireCommand(pub Vec<u8>, pub Option<Timestamp>);
+
+/// Returns the time the key has left before it expires
+#[derive(Debug, Clone, PartialEq)]
+pub struct TtlCommand(pub Vec<u8>);
+
+/// Adds the amount to the integer stored under the key, a missing key counts as 0
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncrCommand(pub Vec<u8>, pub i64);
+
+/// When a `PutIfCommand` puts its value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PutCondition {
+    /// Only when the key is missing, `NX`
+    Absent,
+
+    /// Only when the key is present, `XX`
+    Present,
+}
+
+impl PutCondition {
+    /// Whether the condition holds for a key which is present or not
+    pub fn holds(&self, present: bool) -> bool {
+        match self {
+            Self::Absent => !present,
+            Self::Present => present,
+        }
+    }
+}
+
+/// Puts the value only when the condition holds, checked and put in one step
+#[derive(Debug, Clone, PartialEq)]
+pub struct PutIfCommand(pub PutCommand, pub PutCondition);
+
+/// Replaces the value of the key with the new value only when it currently holds the expected
+/// value, the expiry of the key is kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct CasCommand(pub Vec<u8>, pub Vec<u8>, pub Vec<u8>);
+
+/// Deletes the key and returns the value it had
+#[derive(Debug, Clone, PartialEq)]
+pub struct GetDelCommand(pub Vec<u8>);
+
+/// Puts the value under the key and returns the value it had, the key persists afterwards like
+/// after a put
+#[derive(Debug, Clone, PartialEq)]
+pub struct GetSetCommand(pub Vec<u8>, pub Vec<u8>);
+
+/// Gets the values of the keys
+#[derive(Debug, Clone, PartialEq)]
+pub struct MGetCommand(pub Vec<Vec<u8>>);
+
+/// Puts every key value pair at once, either all of them are put or none are
+#[derive(Debug, Clone, PartialEq)]
+pub struct MSetCommand(pub Vec<PutCommand>);
+
+/// Deletes the keys
+#[derive(Debug, Clone, PartialEq)]
+pub struct MDeleteCommand(pub Vec<Vec<u8>>);
+
+/// Whether the key is present
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExistsCommand(pub Vec<u8>);
+
+/// Moves the value and expiry of the first key to the second key, replacing its value
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenameCommand(pub Vec<u8>, pub Vec<u8>);
+
+/// Copies the value and expiry of the first key to the second key, replacing its value
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyCommand(pub Vec<u8>, pub Vec<u8>);
+
+/// The end of a list a push or pop works on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListSide {
+    /// The head of the list, `LPUSH` and `LPOP`
+    Left = 0,
+
+    /// The tail of the list, `RPUSH` and `RPOP`
+    Right = 1,
+}
+
+/// Pushes the values onto the side of the list under the key one after another, creating the
+/// list when the key is missing
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListPushCommand(pub Vec<u8>, pub ListSide, pub Vec<Vec<u8>>);
+
+/// Removes and returns the item at the side of the list under the key, the key is deleted along
+/// with the last item
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListPopCommand(pub Vec<u8>, pub ListSide);
+
+/// Returns the items of the list under the key from the start to the stop index inclusive, see
+/// `list_range`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListRangeCommand(pub Vec<u8>, pub i64, pub i64);
+
+/// Returns the number of items in the list under the key, 0 when the key is missing
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListLenCommand(pub Vec<u8>);
+
+/// Sets the fields of the hash under the key to the values one after another, creating the hash
+/// when the key is missing. The expiry of the key is kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashSetCommand(pub Vec<u8>, pub Vec<(Vec<u8>, Vec<u8>)>);
+
+/// Returns the value of the field of the hash under the key
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashGetCommand(pub Vec<u8>, pub Vec<u8>);
+
+/// Removes the fields from the hash under the key, the key is deleted along with the last field
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashDeleteCommand(pub Vec<u8>, pub Vec<Vec<u8>>);
+
+/// Returns every field of the hash under the key with its value, in the order of the fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashGetAllCommand(pub Vec<u8>);
+
+/// Returns the fields of the hash under the key in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashKeysCommand(pub Vec<u8>);
+
+/// Adds the members with the scores to the sorted set under the key one after another, creating
+/// the sorted set when the key is missing. A member which is already in the set moves to its new
+/// score. The expiry of the key is kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortedSetAddCommand(pub Vec<u8>, pub Vec<(Score, Vec<u8>)>);
+
+/// Returns the score of the member of the sorted set under the key
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortedSetScoreCommand(pub Vec<u8>, pub Vec<u8>);
+
+/// Returns the members of the sorted set under the key from the start to the stop position
+/// inclusive, see `SortedSet::range`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortedSetRangeCommand(pub Vec<u8>, pub i64, pub i64);
+
+/// Returns the members of the sorted set under the key with scores from the minimum to the
+/// maximum inclusive, see `SortedSet::range_by_score`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortedSetRangeByScoreCommand(pub Vec<u8>, pub f64, pub f64);
+
+/// Removes the members from the sorted set under the key, the key is deleted along with the last
+/// member
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortedSetRemoveCommand(pub Vec<u8>, pub Vec<Vec<u8>>);
+
+/// Adds the members to the set under the key, creating the set when the key is missing. The
+/// expiry of the key is kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetAddCommand(pub Vec<u8>, pub Vec<Vec<u8>>);
+
+/// Removes the members from the set under the key, the key is deleted along with the last member
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetRemoveCommand(pub Vec<u8>, pub Vec<Vec<u8>>);
+
+/// Returns whether the member is in the set under the key
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetIsMemberCommand(pub Vec<u8>, pub Vec<u8>);
+
+/// Returns the members of the set under the key in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetMembersCommand(pub Vec<u8>);
+
+/// Returns the number of members in the set under the key, 0 when the key is missing
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetCardCommand(pub Vec<u8>);
+
+/// Returns the members of the sets under the keys combined by the operation, in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetCombineCommand(pub SetOperation, pub Vec<Vec<u8>>);
+
+/// How a `SetCombineCommand` combines its sets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOperation {
+    /// The members which are in every set
+    Intersection,
+    /// The members which are in any set
+    Union,
+    /// The members of the first set which aren't in any of the others
+    Difference,
+}
+
+impl SetOperation {
+    /// Combines the sets in order, a missing key counts as an empty set
+    pub fn apply(self, sets: Vec<BTreeSet<Vec<u8>>>) -> BTreeSet<Vec<u8>> {
+        let mut sets = sets.into_iter();
+        let mut members = sets.next().unwrap_or_default();
+        for set in sets {
+            match self {
+                Self::Intersection => members.retain(|x| set.contains(x)),
+                Self::Union => members.extend(set),
+                Self::Difference => members.retain(|x| !set.contains(x)),
+            }
+        }
+        members
+    }
+}
+
+/// The positions of the items a `ListRangeCommand` returns from a list of `len` items
+///
+/// Negative indices count back from the tail, so -1 is the last item. Indices past either end
+/// are clamped to the list, and a start after the stop gives an empty range
+pub fn list_range(len: u64, start: i64, stop: i64) -> Range<u64> {
+    let len = len as i64;
+    let resolve = |index: i64| if index < 0 { index.saturating_add(len) } else { index };
+    let start = resolve(start).max(0);
+    let stop = resolve(stop).min(len - 1);
+    if start > stop {
+        return 0..0;
+    }
+    start as u64..stop as u64 + 1
+}
+
+/// Why an increment failed, see `increment`
+#[derive(Debug, Clone, PartialEq)]
+pub enum IncrError {
+    /// The stored value isn't a base 10 integer which fits in an i64
+    NotAnInteger,
+
+    /// The result doesn't fit in an i64
+    Overflow,
+
+    /// Reading the value or storing the result failed, such as when the key holds a list or
+    /// the database is out of space
+    Storage(String),
+}
+
+impl Display for IncrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotAnInteger => write!(f, "Value is not an integer"),
+            Self::Overflow => write!(f, "Increment would overflow"),
+            Self::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<IncrError> for String {
+    fn from(value: IncrError) -> Self {
+        value.to_string()
+    }
+}
+
+/// Adds `by` to the integer stored as a value, a missing value counts as 0
+pub fn increment(value: Option<&[u8]>, by: i64) -> Result<i64, IncrError> {
+    let current = match value {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|x| x.parse::<i64>().ok())
+            .ok_or(IncrError::NotAnInteger)?,
+        None => 0,
+    };
+    current.checked_add(by).ok_or(IncrError::Overflow)
+}
+
+/// Starts a backup of the database into the directory at the path, see
+/// `HashStorage::start_backup`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupCommand(pub String);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mutation {
+    Put(PutCommand),
+    Delete(DeleteCommand),
+    Expire(ExpireCommand),
+    Rename(RenameCommand),
+    /// Deletes every key, stored as the header 6 alone
+    FlushDb,
+    ListPush(ListPushCommand),
+    ListPop(ListPopCommand),
+    /// Copies a list or a hash, a copy of a string is logged as a put of the value instead
+    Copy(CopyCommand),
+    HashSet(HashSetCommand),
+    HashDelete(HashDeleteCommand),
+    SortedSetAdd(SortedSetAddCommand),
+    SortedSetRemove(SortedSetRemoveCommand),
+    SetAdd(SetAddCommand),
+    SetRemove(SetRemoveCommand),
+    /// Increments a counter inside a transaction, applied to the value the key holds when the
+    /// transaction commits. The storage logs an increment as a put of its result instead
+    Incr(IncrCommand),
+    /// Puts inside a transaction when the condition holds for the key as it is when the
+    /// transaction commits. The storage logs a put which happened instead
+    PutIf(PutIfCommand),
+    /// Swaps the value inside a transaction when the key holds the expected value as it is when
+    /// the transaction commits. The storage logs a put which happened instead
+    Cas(CasCommand),
+}
+
+impl ByteLength for Mutation {
+    fn byte_len(&self) -> usize {
+        match self {
+            Mutation::Put(cmd) => cmd.byte_len(),
+            Mutation::Delete(cmd) => cmd.byte_len(),
+            Mutation::Expire(cmd) => cmd.byte_len(),
+            Mutation::Rename(cmd) => cmd.byte_len(),
+            Mutation::FlushDb => 1,
+            Mutation::ListPush(cmd) => cmd.byte_len(),
+            Mutation::ListPop(cmd) => cmd.byte_len(),
+            Mutation::Copy(cmd) => cmd.byte_len(),
+            Mutation::HashSet(cmd) => cmd.byte_len(),
+            Mutation::HashDelete(cmd) => cmd.byte_len(),
+            Mutation::SortedSetAdd(cmd) => cmd.byte_len(),
+            Mutation::SortedSetRemove(cmd) => cmd.byte_len(),
+            Mutation::SetAdd(cmd) => cmd.byte_len(),
+            Mutation::SetRemove(cmd) => cmd.byte_len(),
+            Mutation::Incr(cmd) => cmd.byte_len(),
+            Mutation::PutIf(cmd) => cmd.byte_len(),
+            Mutation::Cas(cmd) => cmd.byte_len(),
+        }
+    }
+}
+
+impl IntoBytes for Mutation {
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Mutation::Put(cmd) => cmd.into_bytes(),
+            Mutation::Delete(cmd) => cmd.into_bytes(),
+            Mutation::Expire(cmd) => cmd.into_bytes(),
+            Mutation::Rename(cmd) => cmd.into_bytes(),
+            Mutation::FlushDb => vec![6],
+            Mutation::ListPush(cmd) => cmd.into_bytes(),
+            Mutation::ListPop(cmd) => cmd.into_bytes(),
+            Mutation::Copy(cmd) => cmd.into_bytes(),
+            Mutation::HashSet(cmd) => cmd.into_bytes(),
+            Mutation::HashDelete(cmd) => cmd.into_bytes(),
+            Mutation::SortedSetAdd(cmd) => cmd.into_bytes(),
+            Mutation::SortedSetRemove(cmd) => cmd.into_bytes(),
+            Mutation::SetAdd(cmd) => cmd.into_bytes(),
+            Mutation::SetRemove(cmd) => cmd.into_bytes(),
+            Mutation::Incr(cmd) => cmd.into_bytes(),
+            Mutation::PutIf(cmd) => cmd.into_bytes(),
+            Mutation::Cas(cmd) => cmd.into_bytes(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandOutput {
+    NotFound(Vec<u8>),
+    Found(Vec<u8>),
+    Keys(Vec<Vec<u8>>),
+    /// The cursor to carry on from and the keys of the page
+    Scan(u64, Vec<Vec<u8>>),
+    Backup,
+    Put,
+    /// The key of a `DeleteCommand` was present and is now deleted, a missing key gives
+    /// `NotFound`
+    Deleted,
+    /// The number of keys of a `MDeleteCommand` which were present and are now deleted
+    DeletedKeys(u64),
+    Expire,
+    Persist,
+    /// The seconds left before the key expires, `None` when it never expires
+    Ttl(Option<u64>),
+    /// The value of a counter after an increment
+    Integer(i64),
+    /// The condition of a `PutIfCommand` didn't hold so nothing was put
+    NotPut,
+    /// The key held the expected value of a `CasCommand` and now holds the new one
+    Swapped,
+    /// The key didn't hold the expected value of a `CasCommand`, or was missing
+    NotSwapped,
+    /// The value a `GetDelCommand` deleted
+    Taken(Vec<u8>),
+    /// The value a `GetSetCommand` replaced, `None` when the key was missing
+    Replaced(Option<Vec<u8>>),
+    /// The value of each key of a `MGetCommand` in order, `None` for the missing keys
+    Values(Vec<Option<Vec<u8>>>),
+    /// Whether the key of an `ExistsCommand` is present
+    Exists(bool),
+    Renamed,
+    Copied,
+    /// The number of keys stored
+    DbSize(u64),
+    FlushDb,
+    /// The number of items in a list after a push, or asked for by a `ListLenCommand` or
+    /// `SetCardCommand`
+    Length(u64),
+    /// The items of a list returned by a `ListRangeCommand`, the fields of a hash returned by a
+    /// `HashKeysCommand`, or the members of a set
+    Items(Vec<Vec<u8>>),
+    /// The number of fields or members a command added to or removed from a hash, sorted set or
+    /// set
+    Count(u64),
+    /// The hash of a `HashGetCommand` doesn't have the field, a missing key gives `NotFound`
+    FieldNotFound(Vec<u8>),
+    /// The fields of a hash with their values returned by a `HashGetAllCommand`
+    Fields(Vec<(Vec<u8>, Vec<u8>)>),
+    /// The score of the member of a `SortedSetScoreCommand`
+    Score(Score),
+    /// The sorted set of a `SortedSetScoreCommand` doesn't have the member, a missing key gives
+    /// `NotFound`
+    MemberNotFound(Vec<u8>),
+    /// The members of a sorted set with their scores in order
+    Members(Vec<(Vec<u8>, Score)>),
+    /// Whether the member of a `SetIsMemberCommand` is in the set
+    IsMember(bool),
+    /// Names and values describing the storage
+    Stats(Vec<(String, String)>),
+    Exit,
+    Commit,
+    Rollback,
+    Begin(String),
+}
+
+pub fn handle_command_output_for_transaction_id(
+    output: &CommandOutput,
+    existing: &mut Option<String>,
+) {
+    match output {
+        // Cheap clone?
+        CommandOutput::Begin(s) => *existing = Some(s.clone()),
+        CommandOutput::Rollback => *existing = None,
+        CommandOutput::Commit => *existing = None,
+        _ => {},
+    }
+}
+
+/// Formats bytes for the text protocol, UTF-8 is written as is and anything else is written as
+/// a hex literal which can be sent back in a command
+///
+/// Text holding control characters or reading like a hex or base64 literal is written as a hex
+/// literal as well, so the text `x"00"` can't be mistaken for the byte 0
+pub fn format_bytes(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !s.chars().any(char::is_control) && !is_literal_like(s) => s.to_string(),
+        _ => format!("x\"{}\"", encode_hex(bytes)),
+    }
+}
+
+/// Whether the text starts the way the lexer starts a hex or base64 literal
+fn is_literal_like(text: &str) -> bool {
+    ["x\"", "X\"", "b64\"", "B64\""]
+        .iter()
+        .any(|prefix| text.starts_with(prefix))
+}
+
+impl Display for CommandOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exit => write!(f, "Bye"),
+            Self::Put => write!(f, "Put"),
+            Self::Deleted => write!(f, "Deleted"),
+            Self::DeletedKeys(count) => write!(f, "{}", count),
+            Self::Expire => write!(f, "Expire"),
+            Self::Persist => write!(f, "Persist"),
+            Self::Ttl(Some(seconds)) => write!(f, "{}", seconds),
+            Self::Ttl(None) => write!(f, "No expiry"),
+            Self::Integer(value) => write!(f, "{}", value),
+            Self::NotPut => write!(f, "Not put"),
+            Self::Swapped => write!(f, "Swapped"),
+            Self::NotSwapped => write!(f, "Not swapped"),
+            Self::Taken(value) => write!(f, "{}", format_bytes(value)),
+            Self::Replaced(Some(value)) => write!(f, "{}", format_bytes(value)),
+            Self::Replaced(None) => write!(f, "No previous value"),
+            Self::Exists(true) => write!(f, "Exists"),
+            Self::Exists(false) => write!(f, "Does not exist"),
+            Self::Renamed => write!(f, "Renamed"),
+            Self::Copied => write!(f, "Copied"),
+            Self::DbSize(count) => write!(f, "{}", count),
+            Self::FlushDb => write!(f, "Flushed"),
+            Self::Length(len) => write!(f, "{}", len),
+            Self::Items(items) if items.is_empty() => write!(f, "(empty)"),
+            Self::Items(items) => {
+                let items: Vec<String> = items.iter().map(|x| format_bytes(x)).collect();
+                write!(f, "{}", items.join("\n"))
+            }
+            Self::Count(count) => write!(f, "{}", count),
+            Self::FieldNotFound(_) => write!(f, "Field not found"),
+            Self::Fields(fields) if fields.is_empty() => write!(f, "(empty)"),
+            Self::Fields(fields) => {
+                let lines: Vec<String> = fields
+                    .iter()
+                    .map(|(field, value)| {
+                        format!("{}: {}", format_bytes(field), format_bytes(value))
+                    })
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Self::Score(score) => write!(f, "{}", score),
+            Self::IsMember(true) => write!(f, "Is a member"),
+            Self::IsMember(false) => write!(f, "Is not a member"),
+            Self::MemberNotFound(_) => write!(f, "Member not found"),
+            Self::Members(members) if members.is_empty() => write!(f, "(empty)"),
+            Self::Members(members) => {
+                let lines: Vec<String> = members
+                    .iter()
+                    .map(|(member, score)| format!("{}: {}", format_bytes(member), score))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Self::Values(values) => {
+                let lines: Vec<String> = values
+                    .iter()
+                    .map(|x| x.as_ref().map_or("(not found)".into(), |x| format_bytes(x)))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Self::Stats(stats) => {
+                let lines: Vec<String> = stats
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}", name, value))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Self::Backup => write!(f, "Backup started"),
+            Self::Found(value) => write!(f, "{}", format_bytes(value)),
+            Self::NotFound(_) => write!(f, "Key not found"),
+            Self::Keys(keys) if keys.is_empty() => write!(f, "(empty)"),
+            Self::Keys(keys) => {
+                let keys: Vec<String> = keys.iter().map(|x| format_bytes(x)).collect();
+                write!(f, "{}", keys.join("\n"))
+            }
+            Self::Scan(cursor, keys) => {
+                write!(f, "{}", cursor)?;
+                for key in keys {
+                    write!(f, "\n{}", format_bytes(key))?;
+                }
+                Ok(())
+            }
+            Self::Commit => write!(f, "Commit"),
+            Self::Rollback => write!(f, "Rollback"),
+            Self::Begin(_) => write!(f, "Begin"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserCommand {
+    Put(PutCommand),
+    Delete(DeleteCommand),
+    Get(GetCommand),
+    Keys(KeysCommand),
+    Scan(ScanCommand),
+    Backup(BackupCommand),
+    Expire(ExpireCommand),
+    Ttl(TtlCommand),
+    Incr(IncrCommand),
+    PutIf(PutIfCommand),
+    Cas(CasCommand),
+    GetDel(GetDelCommand),
+    GetSet(GetSetCommand),
+    MGet(MGetCommand),
+    MSet(MSetCommand),
+    MDelete(MDeleteCommand),
+    Exists(ExistsCommand),
+    Rename(RenameCommand),
+    Copy(CopyCommand),
+    DbSize,
+    FlushDb,
+    ListPush(ListPushCommand),
+    ListPop(ListPopCommand),
+    ListRange(ListRangeCommand),
+    ListLen(ListLenCommand),
+    HashSet(HashSetCommand),
+    HashGet(HashGetCommand),
+    HashDelete(HashDeleteCommand),
+    HashGetAll(HashGetAllCommand),
+    HashKeys(HashKeysCommand),
+    SortedSetAdd(SortedSetAddCommand),
+    SortedSetScore(SortedSetScoreCommand),
+    SortedSetRange(SortedSetRangeCommand),
+    SortedSetRangeByScore(SortedSetRangeByScoreCommand),
+    SortedSetRemove(SortedSetRemoveCommand),
+    SetAdd(SetAddCommand),
+    SetRemove(SetRemoveCommand),
+    SetIsMember(SetIsMemberCommand),
+    SetMembers(SetMembersCommand),
+    SetCard(SetCardCommand),
+    SetCombine(SetCombineCommand),
+    Stats,
+    Exit,
+    Begin,
+    Commit,
+    Rollback,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageCommand {
+    Put(PutCommand),
+    Delete(DeleteCommand),
+    Get(GetCommand),
+    Keys(KeysCommand),
+    Scan(ScanCommand),
+    Backup(BackupCommand),
+    Expire(ExpireCommand),
+    Ttl(TtlCommand),
+    Incr(IncrCommand),
+    PutIf(PutIfCommand),
+    Cas(CasCommand),
+    GetDel(GetDelCommand),
+    GetSet(GetSetCommand),
+    MGet(MGetCommand),
+    MSet(MSetCommand),
+    MDelete(MDeleteCommand),
+    Exists(ExistsCommand),
+    Rename(RenameCommand),
+    Copy(CopyCommand),
+    DbSize,
+    /// Deletes every key, unlike `Flush` which writes everything to disk before exiting
+    FlushDb,
+    ListPush(ListPushCommand),
+    ListPop(ListPopCommand),
+    ListRange(ListRangeCommand),
+    ListLen(ListLenCommand),
+    HashSet(HashSetCommand),
+    HashGet(HashGetCommand),
+    HashDelete(HashDeleteCommand),
+    HashGetAll(HashGetAllCommand),
+    HashKeys(HashKeysCommand),
+    SortedSetAdd(SortedSetAddCommand),
+    SortedSetScore(SortedSetScoreCommand),
+    SortedSetRange(SortedSetRangeCommand),
+    SortedSetRangeByScore(SortedSetRangeByScoreCommand),
+    SortedSetRemove(SortedSetRemoveCommand),
+    SetAdd(SetAddCommand),
+    SetRemove(SetRemoveCommand),
+    SetIsMember(SetIsMemberCommand),
+    SetMembers(SetMembersCommand),
+    SetCard(SetCardCommand),
+    SetCombine(SetCombineCommand),
+    Stats,
+    Flush,
+}
+
+impl From<PutCommand> for StorageCommand {
+    fn from(value: PutCommand) -> Self {
+        Self::Put(value)
+    }
+}
+
+impl From<GetCommand> for StorageCommand {
+    fn from(value: GetCommand) -> Self {
+        Self::Get(value)
+    }
+}
+
+impl From<KeysCommand> for StorageCommand {
+    fn from(value: KeysCommand) -> Self {
+        Self::Keys(value)
+    }
+}
+
+impl From<ScanCommand> for StorageCommand {
+    fn from(value: ScanCommand) -> Self {
+        Self::Scan(value)
+    }
+}
+
+impl From<BackupCommand> for StorageCommand {
+    fn from(value: BackupCommand) -> Self {
+        Self::Backup(value)
+    }
+}
+
+impl From<DeleteCommand> for StorageCommand {
+    fn from(value: DeleteCommand) -> Self {
+        Self::Delete(value)
+    }
+}
+
+impl From<ExpireCommand> for StorageCommand {
+    fn from(value: ExpireCommand) -> Self {
+        Self::Expire(value)
+    }
+}
+
+impl From<TtlCommand> for StorageCommand {
+    fn from(value: TtlCommand) -> Self {
+        Self::Ttl(value)
+    }
+}
+
+impl From<IncrCommand> for StorageCommand {
+    fn from(value: IncrCommand) -> Self {
+        Self::Incr(value)
+    }
+}
+
+impl From<PutIfCommand> for StorageCommand {
+    fn from(value: PutIfCommand) -> Self {
+        Self::PutIf(value)
+    }
+}
+
+impl From<CasCommand> for StorageCommand {
+    fn from(value: CasCommand) -> Self {
+        Self::Cas(value)
+    }
+}
+
+impl From<GetDelCommand> for StorageCommand {
+    fn from(value: GetDelCommand) -> Self {
+        Self::GetDel(value)
+    }
+}
+
+impl From<GetSetCommand> for StorageCommand {
+    fn from(value: GetSetCommand) -> Self {
+        Self::GetSet(value)
+    }
+}
+
+impl From<MGetCommand> for StorageCommand {
+    fn from(value: MGetCommand) -> Self {
+        Self::MGet(value)
+    }
+}
+
+impl From<MSetCommand> for StorageCommand {
+    fn from(value: MSetCommand) -> Self {
+        Self::MSet(value)
+    }
+}
+
+impl From<MDeleteCommand> for StorageCommand {
+    fn from(value: MDeleteCommand) -> Self {
+        Self::MDelete(value)
+    }
+}
+
+impl From<ExistsCommand> for StorageCommand {
+    fn from(value: ExistsCommand) -> Self {
+        Self::Exists(value)
+    }
+}
+
+impl From<RenameCommand> for StorageCommand {
+    fn from(value: RenameCommand) -> Self {
+        Self::Rename(value)
+    }
+}
+
+impl From<CopyCommand> for StorageCommand {
+    fn from(value: CopyCommand) -> Self {
+        Self::Copy(value)
+    }
+}
+
+impl From<ListPushCommand> for StorageCommand {
+    fn from(value: ListPushCommand) -> Self {
+        Self::ListPush(value)
+    }
+}
+
+impl From<ListPopCommand> for StorageCommand {
+    fn from(value: ListPopCommand) -> Self {
+        Self::ListPop(value)
+    }
+}
+
+impl From<ListRangeCommand> for StorageCommand {
+    fn from(value: ListRangeCommand) -> Self {
+        Self::ListRange(value)
+    }
+}
+
+impl From<ListLenCommand> for StorageCommand {
+    fn from(value: ListLenCommand) -> Self {
+        Self::ListLen(value)
+    }
+}
+
+impl From<HashSetCommand> for StorageCommand {
+    fn from(value: HashSetCommand) -> Self {
+        Self::HashSet(value)
+    }
+}
+
+impl From<HashGetCommand> for StorageCommand {
+    fn from(value: HashGetCommand) -> Self {
+        Self::HashGet(value)
+    }
+}
+
+impl From<HashDeleteCommand> for StorageCommand {
+    fn from(value: HashDeleteCommand) -> Self {
+        Self::HashDelete(value)
+    }
+}
+
+impl From<HashGetAllCommand> for StorageCommand {
+    fn from(value: HashGetAllCommand) -> Self {
+        Self::HashGetAll(value)
+    }
+}
+
+impl From<HashKeysCommand> for StorageCommand {
+    fn from(value: HashKeysCommand) -> Self {
+        Self::HashKeys(value)
+    }
+}
+
+impl From<SortedSetAddCommand> for StorageCommand {
+    fn from(value: SortedSetAddCommand) -> Self {
+        Self::SortedSetAdd(value)
+    }
+}
+
+impl From<SortedSetScoreCommand> for StorageCommand {
+    fn from(value: SortedSetScoreCommand) -> Self {
+        Self::SortedSetScore(value)
+    }
+}
+
+impl From<SortedSetRangeCommand> for StorageCommand {
+    fn from(value: SortedSetRangeCommand) -> Self {
+        Self::SortedSetRange(value)
+    }
+}
+
+impl From<SortedSetRangeByScoreCommand> for StorageCommand {
+    fn from(value: SortedSetRangeByScoreCommand) -> Self {
+        Self::SortedSetRangeByScore(value)
+    }
+}
+
+impl From<SortedSetRemoveCommand> for StorageCommand {
+    fn from(value: SortedSetRemoveCommand) -> Self {
+        Self::SortedSetRemove(value)
+    }
+}
+
+impl From<SetAddCommand> for StorageCommand {
+    fn from(value: SetAddCommand) -> Self {
+        Self::SetAdd(value)
+    }
+}
+
+impl From<SetRemoveCommand> for StorageCommand {
+    fn from(value: SetRemoveCommand) -> Self {
+        Self::SetRemove(value)
+    }
+}
+
+impl From<SetIsMemberCommand> for StorageCommand {
+    fn from(value: SetIsMemberCommand) -> Self {
+        Self::SetIsMember(value)
+    }
+}
+
+impl From<SetMembersCommand> for StorageCommand {
+    fn from(value: SetMembersCommand) -> Self {
+        Self::SetMembers(value)
+    }
+}
+
+impl From<SetCardCommand> for StorageCommand {
+    fn from(value: SetCardCommand) -> Self {
+        Self::SetCard(value)
+    }
+}
+
+impl From<SetCombineCommand> for StorageCommand {
+    fn from(value: SetCombineCommand) -> Self {
+        Self::SetCombine(value)
+    }
+}
+
+impl FromStr for UserCommand {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_command(s.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test_command {
+    use super::*;
+
+    #[test]
+    fn mutations_into_and_from_bytes() {
+        let mutations = vec![
+            Mutation::Put(PutCommand(vec![1; 300], vec![2; 70_000], None)),
+            Mutation::Delete(DeleteCommand(vec![3; MAX_KEY_BYTES])),
+            Mutation::Put(PutCommand(b"key".to_vec(), vec![], Some(1_700_000_000_000))),
+            Mutation::Expire(ExpireCommand(b"key".to_vec(), Some(u64::MAX))),
+            Mutation::Expire(ExpireCommand(b"key".to_vec(), None)),
+            Mutation::Rename(RenameCommand(b"old".to_vec(), vec![4; 200])),
+            Mutation::FlushDb,
+            Mutation::ListPush(ListPushCommand(
+                b"list".to_vec(),
+                ListSide::Left,
+                vec![vec![5; 200], vec![]],
+            )),
+            Mutation::ListPop(ListPopCommand(b"list".to_vec(), ListSide::Right)),
+            Mutation::Copy(CopyCommand(b"list".to_vec(), b"copy".to_vec())),
+            Mutation::HashSet(HashSetCommand(
+                b"hash".to_vec(),
+                vec![(b"field".to_vec(), vec![6; 200]), (vec![], vec![])],
+            )),
+            Mutation::HashDelete(HashDeleteCommand(b"hash".to_vec(), vec![b"field".to_vec()])),
+            Mutation::SortedSetAdd(SortedSetAddCommand(
+                b"zset".to_vec(),
+                vec![
+                    (Score::new(-1.5).unwrap(), b"a".to_vec()),
+                    (Score::new(f64::MAX).unwrap(), vec![7; 200]),
+                ],
+            )),
+            Mutation::SortedSetRemove(SortedSetRemoveCommand(
+                b"zset".to_vec(),
+                vec![b"a".to_vec()],
+            )),
+            Mutation::SetAdd(SetAddCommand(b"set".to_vec(), vec![b"a".to_vec(), vec![8; 200]])),
+            Mutation::SetRemove(SetRemoveCommand(b"set".to_vec(), vec![vec![]])),
+            Mutation::Incr(IncrCommand(b"count".to_vec(), i64::MIN)),
+            Mutation::PutIf(PutIfCommand(
+                PutCommand(b"key".to_vec(), vec![9; 200], Some(1)),
+                PutCondition::Absent,
+            )),
+            Mutation::PutIf(PutIfCommand(
+                PutCommand(b"key".to_vec(), vec![], None),
+                PutCondition::Present,
+            )),
+            Mutation::Cas(CasCommand(b"key".to_vec(), vec![], vec![10; 200])),
+        ];
+        let mut bytes = vec![];
+        for mutation in mutations.iter().cloned() {
+            let len = mutation.byte_len();
+            let mutation_bytes = mutation.into_bytes();
+            assert_eq!(mutation_bytes.len(), len);
+            bytes.extend(mutation_bytes);
+        }
+        assert_eq!(parse_buffer_to_mutations(bytes.iter()), Ok(mutations));
+    }
+
+    #[test]
+    fn increments() {
+        assert_eq!(increment(None, 1), Ok(1));
+        assert_eq!(increment(Some(b"41"), 1), Ok(42));
+        assert_eq!(increment(Some(b"-5"), -10), Ok(-15));
+        assert_eq!(increment(Some(b"abc"), 1), Err(IncrError::NotAnInteger));
+        assert_eq!(increment(Some(b" 1"), 1), Err(IncrError::NotAnInteger));
+        assert_eq!(increment(Some(&[0xff]), 1), Err(IncrError::NotAnInteger));
+        assert_eq!(
+            increment(Some(i64::MAX.to_string().as_bytes()), 1),
+            Err(IncrError::Overflow)
+        );
+    }
+
+    #[test]
+    fn ranges_of_lists() {
+        assert_eq!(list_range(5, 0, -1), 0..5);
+        assert_eq!(list_range(5, 1, 2), 1..3);
+        assert_eq!(list_range(5, -2, -1), 3..5);
+        assert_eq!(list_range(5, -100, 100), 0..5);
+        assert_eq!(list_range(5, 3, 1), 0..0);
+        assert_eq!(list_range(5, 5, 10), 0..0);
+        assert_eq!(list_range(5, 0, -6), 0..0);
+        assert_eq!(list_range(0, 0, -1), 0..0);
+        assert_eq!(list_range(5, i64::MIN, i64::MAX), 0..5);
+    }
+
+    #[test]
+    fn set_operations() {
+        let set = |members: &[&str]| -> BTreeSet<Vec<u8>> {
+            members.iter().map(|x| x.as_bytes().to_vec()).collect()
+        };
+        let sets = vec![set(&["a", "b", "c"]), set(&["b", "c", "d"]), set(&["c", "e"])];
+        assert_eq!(SetOperation::Intersection.apply(sets.clone()), set(&["c"]));
+        assert_eq!(
+            SetOperation::Union.apply(sets.clone()),
+            set(&["a", "b", "c", "d", "e"])
+        );
+        assert_eq!(SetOperation::Difference.apply(sets), set(&["a"]));
+        assert_eq!(SetOperation::Union.apply(vec![]), set(&[]));
+        assert_eq!(
+            SetOperation::Intersection.apply(vec![set(&["a"]), set(&[])]),
+            set(&[])
+        );
+        assert_eq!(
+            SetOperation::Difference.apply(vec![set(&[]), set(&["a"])]),
+            set(&[])
+        );
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(format_bytes(b"value"), "value");
+        assert_eq!(format_bytes(&[0]), "x\"00\"");
+        assert_eq!(format_bytes(&[0xff]), "x\"ff\"");
+        assert_eq!(format_bytes(b"a\nb"), "x\"610a62\"");
+        assert_eq!(format_bytes(b"x\"00\""), "x\"7822303022\"");
+        assert_eq!(format_bytes(b"b64\"AA==\""), "x\"6236342241413d3d22\"");
+        assert_eq!(format_bytes(b"x"), "x");
+    }
+
+    #[test]
+    fn rejects_long_keys_and_values() {
+        assert!(validate_key(&[0; MAX_KEY_BYTES]).is_ok());
+        assert!(validate_key(&[0; MAX_KEY_BYTES + 1]).is_err());
+        assert!(validate_value(&[0; 1024]).is_ok());
+
+        let mut bytes = vec![1];
+        write_varint(&mut bytes, MAX_KEY_BYTES as u64 + 1);
+        bytes.extend(vec![0; MAX_KEY_BYTES + 1]);
+        assert_eq!(Mutation::from_bytes(bytes.iter(), ()).err(), Some(()));
     }
 }
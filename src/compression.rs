@@ -0,0 +1,64 @@
+/// Values shorter than this many bytes are never compressed, they rarely shrink enough to be
+/// worth it
+const MIN_COMPRESSED_VALUE_BYTES: usize = 64;
+
+/// Compresses the value if compression is compiled in and it makes the value smaller
+///
+/// # Returns
+/// - `None` if the value should be stored as is
+#[cfg(feature = "lz4")]
+pub(crate) fn compress(value: &[u8]) -> Option<Vec<u8>> {
+    if value.len() < MIN_COMPRESSED_VALUE_BYTES {
+        return None;
+    }
+    let compressed = lz4_flex::compress_prepend_size(value);
+    if compressed.len() < value.len() {
+        Some(compressed)
+    } else {
+        None
+    }
+}
+
+/// Compresses the value if compression is compiled in and it makes the value smaller
+///
+/// # Returns
+/// - `None` if the value should be stored as is
+#[cfg(not(feature = "lz4"))]
+pub(crate) fn compress(value: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+/// Decompresses a value which was compressed by `compress`
+#[cfg(feature = "lz4")]
+pub(crate) fn decompress(value: &[u8]) -> Result<Vec<u8>, String> {
+    lz4_flex::decompress_size_prepended(value)
+        .map_err(|e| format!("Failed to decompress value: {}", e))
+}
+
+/// Decompresses a value which was compressed by `compress`
+#[cfg(not(feature = "lz4"))]
+pub(crate) fn decompress(value: &[u8]) -> Result<Vec<u8>, String> {
+    Err("Value is compressed but the lz4 feature is not enabled".into())
+}
+
+#[cfg(all(test, feature = "lz4"))]
+mod test_compression {
+    use super::*;
+
+    #[test]
+    fn compress_and_decompress() {
+        let value = "{\"name\": \"value\"}".repeat(20).into_bytes();
+        let compressed = compress(&value).unwrap();
+        assert!(compressed.len() < value.len());
+        assert_eq!(decompress(&compressed).unwrap(), value);
+    }
+
+    #[test]
+    fn skips_values_which_do_not_shrink() {
+        assert_eq!(compress(b"short"), None);
+        let random: Vec<u8> = (0..256_u32)
+            .map(|x| (x.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        assert_eq!(compress(&random), None);
+    }
+}
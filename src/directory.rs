@@ -0,0 +1,654 @@
+use crate::encryption::BlockFile;
+use crate::hash_storage::{
+    BucketIndexType, BucketLevel, BUCKET_INDEX_TYPE_BYTES, BUCKET_LEVEL_BYTES, DEFAULT_PAGE_BYTES,
+};
+use std::collections::HashMap;
+use std::mem::size_of;
+use tokio::fs::File;
+
+/// Number of bytes in a directory page, the directory is paged on its own so it doesn't follow
+/// the page size of the buckets
+const DIRECTORY_PAGE_BYTES: usize = DEFAULT_PAGE_BYTES;
+
+/// Number of directory entries in a directory page
+const DIRECTORY_PAGE_ENTRIES: usize = DIRECTORY_PAGE_BYTES / BUCKET_INDEX_TYPE_BYTES;
+
+/// Maximum number of directory pages kept in memory before the cache is written back and
+/// emptied
+const DIRECTORY_CACHE_PAGES: usize = 1024;
+
+/// Maximum number of pages copied into the upper half of a doubled directory by each call to
+/// `Directory::grow`
+pub(crate) const DIRECTORY_GROWTH_PAGES: usize = 8;
+
+/// Set in the global level stored in the directory file when it is followed by the growth
+/// cursor, the global level itself never gets near this bit
+const GROWTH_CURSOR_FLAG: BucketLevel = 0x80;
+
+/// The length in bytes of the growth cursor in the directory file
+const GROWTH_CURSOR_BYTES: usize = size_of::<u64>();
+
+/// Index of a page in the directory file, page n holds the entries from
+/// `n * DIRECTORY_PAGE_ENTRIES` up to but not including `(n + 1) * DIRECTORY_PAGE_ENTRIES`
+type DirectoryPageIndex = usize;
+
+/// A page of directory entries held in memory
+#[derive(Debug, Clone, PartialEq)]
+struct DirectoryPage {
+    /// The entries of the page, the last page of a directory with less than
+    /// `DIRECTORY_PAGE_ENTRIES` entries is shorter than a full page
+    entries: Vec<BucketIndexType>,
+
+    /// Whether the page has changed since it was last written to the directory file
+    dirty: bool,
+}
+
+/// The directory of the hash table, stored in pages which are only read and written as they
+/// are touched
+///
+/// # File layout
+/// - First `BUCKET_LEVEL_BYTES` is the global level in LE, with `GROWTH_CURSOR_FLAG` set when it
+///   is followed by the growth cursor
+/// - When `GROWTH_CURSOR_FLAG` is set, the growth cursor in `GROWTH_CURSOR_BYTES` LE. Files
+///   written before the cursor was stored don't have it
+/// - Next is followed by the list of `BucketIndexTypes`s stored in LE as the index lookup for
+///   where the buckets are stored
+///   The length of this list is 2^global_level
+///
+/// Every `DIRECTORY_PAGE_ENTRIES` entries of the list make up a page. When the file is
+/// encrypted the global level and every page are stored as separate blocks, see `BlockFile`.
+///
+/// ## Growth
+///
+/// Doubling a directory which spans more than one page doesn't copy anything straight away.
+/// Pages of the new upper half are "unmaterialized" until they are copied from their source page
+/// in the lower half, either a few at a time by `grow` or as soon as they are touched. Every page
+/// before the growth cursor is materialized. A page at or past the cursor is materialized once
+/// it has been written, an unmaterialized page reads as zeros or is past the end of the file.
+///
+/// A written page of zeros past the cursor is taken for unmaterialized, which gives the same
+/// entries: bucket 0 only shows up in the upper half where its lower half entry is bucket 0 as
+/// well, as splitting it moves the upper half entries to the new bucket.
+///
+/// Reading through an unmaterialized page is still correct because every split which changes
+/// an entry in the lower half changes the matching entry in the upper half as well, and the
+/// entries only found in the upper half are always written through `set`.
+pub(crate) struct Directory {
+    /// The directory file
+    file: BlockFile,
+
+    /// The global level of the index
+    global_level: BucketLevel,
+
+    /// Whether the global level or growth cursor have changed since they were last written to the
+    /// directory file
+    header_dirty: bool,
+
+    /// Whether the growth cursor is stored after the global level, which files written before
+    /// the cursor was stored don't do
+    has_growth_cursor: bool,
+
+    /// The pages of the directory which have been touched since they were last evicted
+    pages: HashMap<DirectoryPageIndex, DirectoryPage>,
+
+    /// Every page before this one is known to be materialized, `grow` carries on from here
+    growth_cursor: DirectoryPageIndex,
+}
+
+impl Directory {
+    /// Loads the directory from the directory file, only the global level is read
+    ///
+    /// An empty file gives a directory with a global level of 0 pointing to the first bucket
+    ///
+    /// # Returns
+    /// - An error if the global level can't be read, which is how a wrong encryption key shows
+    pub async fn load(mut file: BlockFile) -> Result<Self, String> {
+        if file.len().await == 0 {
+            let mut pages = HashMap::new();
+            pages.insert(
+                0,
+                DirectoryPage {
+                    entries: vec![0],
+                    dirty: true,
+                },
+            );
+            return Ok(Self {
+                file,
+                global_level: 0,
+                header_dirty: true,
+                has_growth_cursor: true,
+                pages,
+                growth_cursor: 1,
+            });
+        }
+
+        let global_level_buf = file
+            .read_block(0, BUCKET_LEVEL_BYTES)
+            .await
+            .map_err(|e| format!("Failed to load the directory: {}", e))?
+            .ok_or("Failed to load the directory: the global level is missing")?;
+        let global_level = BucketLevel::from_le_bytes(global_level_buf.try_into().unwrap());
+
+        let mut directory = Self {
+            file,
+            global_level: global_level & !GROWTH_CURSOR_FLAG,
+            header_dirty: false,
+            has_growth_cursor: global_level & GROWTH_CURSOR_FLAG != 0,
+            pages: HashMap::new(),
+            growth_cursor: 0,
+        };
+
+        directory.growth_cursor = if directory.has_growth_cursor {
+            let buf = directory
+                .file
+                .read_block(directory.growth_cursor_position(), GROWTH_CURSOR_BYTES)
+                .await
+                .map_err(|e| format!("Failed to load the directory: {}", e))?
+                .ok_or("Failed to load the directory: the growth cursor is missing")?;
+            u64::from_le_bytes(buf.try_into().unwrap())
+                .try_into()
+                .map_err(|_| "Failed to load the directory: the growth cursor is invalid")?
+        } else {
+            // A doubling always finishes growing the previous upper half before it starts, so
+            // only the current upper half can hold unmaterialized pages
+            directory.page_count().div_ceil(2)
+        };
+        Ok(directory)
+    }
+
+    pub fn global_level(&self) -> BucketLevel {
+        self.global_level
+    }
+
+    /// The number of entries in the directory
+    pub fn len(&self) -> usize {
+        2_usize.pow(self.global_level.into())
+    }
+
+    /// The number of pages the directory spans
+    fn page_count(&self) -> usize {
+        self.len().div_ceil(DIRECTORY_PAGE_ENTRIES)
+    }
+
+    /// The number of entries in a page
+    fn page_len(&self, page: DirectoryPageIndex) -> usize {
+        (self.len() - page * DIRECTORY_PAGE_ENTRIES).min(DIRECTORY_PAGE_ENTRIES)
+    }
+
+    /// The position of the growth cursor in the directory file
+    fn growth_cursor_position(&self) -> u64 {
+        self.file.block_len(BUCKET_LEVEL_BYTES) as u64
+    }
+
+    /// The position of the page in the directory file
+    fn page_position(&self, page: DirectoryPageIndex) -> u64 {
+        let mut header_len = self.file.block_len(BUCKET_LEVEL_BYTES);
+        if self.has_growth_cursor {
+            header_len += self.file.block_len(GROWTH_CURSOR_BYTES);
+        }
+        let page_len = self
+            .file
+            .block_len(DIRECTORY_PAGE_ENTRIES * BUCKET_INDEX_TYPE_BYTES);
+        (header_len + page * page_len) as u64
+    }
+
+    /// The number of directory pages currently held in memory
+    pub fn cached_page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Reads the page from the directory file
+    ///
+    /// # Returns
+    /// - `None` if the page is unmaterialized
+    async fn read_page(&mut self, page: DirectoryPageIndex) -> Option<Vec<BucketIndexType>> {
+        let buf = self
+            .file
+            .read_block(
+                self.page_position(page),
+                self.page_len(page) * BUCKET_INDEX_TYPE_BYTES,
+            )
+            .await
+            .unwrap()?;
+
+        if page >= self.growth_cursor && buf.iter().all(|x| *x == 0) {
+            return None;
+        }
+
+        Some(
+            buf.chunks_exact(BUCKET_INDEX_TYPE_BYTES)
+                .map(|chunk| BucketIndexType::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    /// Returns the entries of an unmaterialized page by following it back to the lower half
+    /// it was doubled from
+    ///
+    /// Every step clears the top bit of the page index, so it stops at a page before the growth
+    /// cursor at the latest, which is always materialized
+    async fn derive_page(&mut self, page: DirectoryPageIndex) -> Vec<BucketIndexType> {
+        let mut source = page;
+        let mut half = self.page_count() / 2;
+        while half > 0 {
+            if source >= half {
+                source -= half;
+                if let Some(cached) = self.pages.get(&source) {
+                    return cached.entries.clone();
+                }
+                if let Some(entries) = self.read_page(source).await {
+                    return entries;
+                }
+            }
+            half /= 2;
+        }
+        panic!("Directory page {} has no materialized source page", page);
+    }
+
+    async fn write_page(&mut self, page: DirectoryPageIndex, entries: &[BucketIndexType]) {
+        let mut buf = Vec::with_capacity(entries.len() * BUCKET_INDEX_TYPE_BYTES);
+        for entry in entries {
+            buf.extend(entry.to_le_bytes());
+        }
+        self.file.write_block(self.page_position(page), &buf).await;
+    }
+
+    /// Returns the cached page, reading it from the directory file if it isn't cached
+    async fn page(&mut self, page: DirectoryPageIndex) -> &mut DirectoryPage {
+        if !self.pages.contains_key(&page) {
+            if self.pages.len() >= DIRECTORY_CACHE_PAGES {
+                self.flush().await;
+                self.pages.clear();
+            }
+            // Unmaterialized pages are materialized the next time the cache is flushed
+            let page_ = match self.read_page(page).await {
+                Some(entries) => DirectoryPage {
+                    entries,
+                    dirty: false,
+                },
+                None => DirectoryPage {
+                    entries: self.derive_page(page).await,
+                    dirty: true,
+                },
+            };
+            self.pages.insert(page, page_);
+        }
+        self.pages.get_mut(&page).unwrap()
+    }
+
+    /// Returns the bucket index stored at the directory entry
+    pub async fn get(&mut self, index: usize) -> BucketIndexType {
+        let page = self.page(index / DIRECTORY_PAGE_ENTRIES).await;
+        page.entries[index % DIRECTORY_PAGE_ENTRIES]
+    }
+
+    /// Points the directory entry at the bucket index
+    pub async fn set(&mut self, index: usize, bucket_index: BucketIndexType) {
+        let page = self.page(index / DIRECTORY_PAGE_ENTRIES).await;
+        page.entries[index % DIRECTORY_PAGE_ENTRIES] = bucket_index;
+        page.dirty = true;
+    }
+
+    /// Doubles the directory for a global split, the new upper half is a copy of the lower
+    /// half
+    ///
+    /// When the directory spans more than one page the upper half is left unmaterialized and is
+    /// copied by `grow`. If the previous upper half hasn't finished growing, it is finished here
+    /// first.
+    pub async fn double(&mut self) {
+        let old_page_count = self.page_count();
+        if self.len() < DIRECTORY_PAGE_ENTRIES {
+            let page = self.page(0).await;
+            page.entries.extend(page.entries.clone());
+            page.dirty = true;
+            self.growth_cursor = 1;
+        } else {
+            self.grow(old_page_count).await;
+        }
+        self.global_level += 1;
+        self.header_dirty = true;
+    }
+
+    /// Materializes up to `max_pages` pages of the upper half of the directory, carrying on
+    /// from where the last call left off
+    ///
+    /// Copied pages are written straight to the directory file rather than being cached
+    pub async fn grow(&mut self, max_pages: usize) {
+        let page_count = self.page_count();
+        let mut grown = 0;
+        while grown < max_pages && self.growth_cursor < page_count {
+            let page = self.growth_cursor;
+            // Cached pages are materialized when they are flushed
+            if !self.pages.contains_key(&page) && self.read_page(page).await.is_none() {
+                let entries = self.derive_page(page).await;
+                self.write_page(page, &entries).await;
+            }
+            self.growth_cursor += 1;
+            self.header_dirty = true;
+            grown += 1;
+        }
+    }
+
+    /// Whether every page of the directory is materialized
+    pub fn is_grown(&self) -> bool {
+        self.growth_cursor >= self.page_count()
+    }
+
+    /// Writes every changed page, then the global level and growth cursor to the directory file
+    ///
+    /// The pages go first as the growth cursor can have moved past cached pages which are only
+    /// materialized by being written here
+    pub async fn flush(&mut self) {
+        let mut dirty: Vec<DirectoryPageIndex> = self
+            .pages
+            .iter()
+            .filter(|(_, page)| page.dirty)
+            .map(|(index, _)| *index)
+            .collect();
+        dirty.sort();
+        for index in dirty {
+            let entries = self.pages[&index].entries.clone();
+            self.write_page(index, &entries).await;
+            self.pages.get_mut(&index).unwrap().dirty = false;
+        }
+
+        if self.header_dirty {
+            let mut global_level = self.global_level;
+            if self.has_growth_cursor {
+                global_level |= GROWTH_CURSOR_FLAG;
+                let cursor = self.growth_cursor as u64;
+                let position = self.growth_cursor_position();
+                self.file.write_block(position, &cursor.to_le_bytes()).await;
+            }
+            self.file.write_block(0, &global_level.to_le_bytes()).await;
+            self.header_dirty = false;
+        }
+    }
+
+    pub async fn sync_all(&mut self) {
+        self.file.sync_all().await;
+    }
+
+    /// Starts a backup of the directory file, see `BlockFile::start_backup`. Changed pages have
+    /// to be flushed first for them to be part of it
+    pub async fn start_backup(&mut self, destination: File) {
+        self.file.start_backup(destination).await;
+    }
+
+    /// See `BlockFile::continue_backup`
+    pub async fn continue_backup(&mut self, max_chunks: usize) -> usize {
+        self.file.continue_backup(max_chunks).await
+    }
+
+    pub fn is_backing_up(&self) -> bool {
+        self.file.is_backing_up()
+    }
+
+    /// Reads every entry of the directory
+    pub async fn entries(&mut self) -> Vec<BucketIndexType> {
+        let mut result = Vec::with_capacity(self.len());
+        for index in 0..self.len() {
+            result.push(self.get(index).await);
+        }
+        result
+    }
+
+    /// Replaces the whole directory with the entries, the length of the entries must be a power
+    /// of 2
+    #[cfg(test)]
+    pub async fn set_entries(&mut self, entries: Vec<BucketIndexType>) {
+        assert!(entries.len().is_power_of_two());
+        self.global_level = entries.len().trailing_zeros() as BucketLevel;
+        self.header_dirty = true;
+        self.pages = entries
+            .chunks(DIRECTORY_PAGE_ENTRIES)
+            .enumerate()
+            .map(|(index, chunk)| {
+                (
+                    index,
+                    DirectoryPage {
+                        entries: chunk.to_vec(),
+                        dirty: true,
+                    },
+                )
+            })
+            .collect();
+        self.growth_cursor = self.page_count();
+    }
+}
+
+#[cfg(test)]
+mod test_directory {
+    use super::*;
+    use crate::encryption::{EncryptionKey, ENCRYPTION_KEY_BYTES};
+    use crate::test::*;
+
+    async fn get_directory(name: &str) -> Directory {
+        let path = format!("./test_data/{}", name);
+        Directory::load(BlockFile::new(reset_or_create_file(&path).into(), None))
+            .await
+            .unwrap()
+    }
+
+    async fn reload_directory_with_key(
+        name: &str,
+        key: Option<&EncryptionKey>,
+    ) -> Result<Directory, String> {
+        let path = format!("./test_data/{}", name);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        Directory::load(BlockFile::new(file.into(), key)).await
+    }
+
+    async fn reload_directory(name: &str) -> Directory {
+        reload_directory_with_key(name, None).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn empty_file() {
+        let mut directory = get_directory("test_directory_empty_file").await;
+        assert_eq!(directory.global_level(), 0);
+        assert_eq!(directory.entries().await, vec![0]);
+    }
+
+    #[tokio::test]
+    async fn double_within_a_page() {
+        let mut directory = get_directory("test_directory_double_within_a_page").await;
+        directory.set_entries(vec![1, 2]).await;
+        directory.double().await;
+        directory.set(3, 4).await;
+        assert_eq!(directory.global_level(), 2);
+        assert_eq!(directory.entries().await, vec![1, 2, 1, 4]);
+    }
+
+    #[tokio::test]
+    async fn double_across_pages() {
+        let name = "test_directory_double_across_pages";
+        let mut directory = get_directory(name).await;
+        let entries: Vec<BucketIndexType> = (0..DIRECTORY_PAGE_ENTRIES * 2).collect();
+        directory.set_entries(entries.clone()).await;
+        directory.flush().await;
+
+        directory.double().await;
+        assert!(!directory.is_grown());
+        directory.set(DIRECTORY_PAGE_ENTRIES * 3 + 1, 9999).await;
+
+        // Only the page which was changed after doubling is dirty
+        let dirty: Vec<_> = directory
+            .pages
+            .iter()
+            .filter(|(_, page)| page.dirty)
+            .map(|(index, _)| *index)
+            .collect();
+        assert_eq!(dirty, vec![3]);
+
+        directory.flush().await;
+
+        let mut expected = entries.clone();
+        expected.extend(entries);
+        expected[DIRECTORY_PAGE_ENTRIES * 3 + 1] = 9999;
+
+        let mut reloaded = reload_directory(name).await;
+        assert_eq!(reloaded.global_level(), 11);
+        assert_eq!(reloaded.cached_page_count(), 0);
+        assert_eq!(reloaded.read_page(2).await, None);
+        assert_eq!(reloaded.entries().await, expected);
+    }
+
+    #[tokio::test]
+    async fn grow_a_few_pages_at_a_time() {
+        let name = "test_directory_grow_a_few_pages_at_a_time";
+        let mut directory = get_directory(name).await;
+        let entries: Vec<BucketIndexType> = (1..=DIRECTORY_PAGE_ENTRIES * 4).collect();
+        directory.set_entries(entries.clone()).await;
+        directory.flush().await;
+
+        directory.double().await;
+        for page in 4..8 {
+            assert_eq!(directory.read_page(page).await, None);
+        }
+
+        directory.grow(3).await;
+        assert!(!directory.is_grown());
+        for page in 4..7 {
+            assert_eq!(
+                directory.read_page(page).await.unwrap(),
+                directory.read_page(page - 4).await.unwrap()
+            );
+        }
+        assert_eq!(directory.read_page(7).await, None);
+        assert!((4..8).all(|page| !directory.pages.contains_key(&page)));
+
+        directory.grow(3).await;
+        assert!(directory.is_grown());
+        assert_eq!(
+            directory.read_page(7).await.unwrap(),
+            directory.read_page(3).await.unwrap()
+        );
+    }
+
+    /// Doubling twice without growing in between should materialize the first upper half
+    /// before the second doubling, so every page can be followed back to a materialized page
+    #[tokio::test]
+    async fn double_twice_before_growing() {
+        let name = "test_directory_double_twice_before_growing";
+        let mut directory = get_directory(name).await;
+        let entries: Vec<BucketIndexType> = (1..=DIRECTORY_PAGE_ENTRIES * 2).collect();
+        directory.set_entries(entries.clone()).await;
+        directory.flush().await;
+
+        directory.double().await;
+        directory.set(DIRECTORY_PAGE_ENTRIES * 2, 9999).await;
+        directory.double().await;
+        directory.flush().await;
+
+        assert!(directory.read_page(3).await.is_some());
+        assert_eq!(directory.read_page(4).await, None);
+
+        let mut expected = entries.clone();
+        expected.extend(entries);
+        expected[DIRECTORY_PAGE_ENTRIES * 2] = 9999;
+        expected.extend(expected.clone());
+
+        let mut reloaded = reload_directory(name).await;
+        assert_eq!(reloaded.growth_cursor, 4);
+        assert_eq!(reloaded.entries().await, expected);
+    }
+
+    #[tokio::test]
+    async fn stores_growth_cursor() {
+        let name = "test_directory_stores_growth_cursor";
+        let mut directory = get_directory(name).await;
+        let mut entries: Vec<BucketIndexType> = (1..=DIRECTORY_PAGE_ENTRIES * 4).collect();
+        // A page of zeros before the cursor is materialized like any other
+        entries[DIRECTORY_PAGE_ENTRIES * 3..].fill(0);
+        directory.set_entries(entries.clone()).await;
+        directory.flush().await;
+        directory.double().await;
+        directory.grow(2).await;
+        directory.flush().await;
+
+        let mut reloaded = reload_directory(name).await;
+        assert_eq!(reloaded.growth_cursor, 6);
+        assert_eq!(
+            reloaded.read_page(3).await,
+            Some(vec![0; DIRECTORY_PAGE_ENTRIES])
+        );
+        let mut expected = entries.clone();
+        expected.extend(entries);
+        assert_eq!(reloaded.entries().await, expected);
+    }
+
+    #[tokio::test]
+    async fn reads_directory_without_growth_cursor() {
+        let name = "test_directory_reads_directory_without_growth_cursor";
+        let mut directory = get_directory(name).await;
+        let entries: Vec<BucketIndexType> = (1..=DIRECTORY_PAGE_ENTRIES * 2).collect();
+        directory.set_entries(entries.clone()).await;
+        directory.flush().await;
+        directory.double().await;
+        directory.flush().await;
+
+        // Files written before the growth cursor was stored only hold the global level
+        let path = format!("./test_data/{}", name);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.drain(BUCKET_LEVEL_BYTES..BUCKET_LEVEL_BYTES + GROWTH_CURSOR_BYTES);
+        bytes[0] &= !GROWTH_CURSOR_FLAG;
+        std::fs::write(&path, bytes).unwrap();
+
+        let mut reloaded = reload_directory(name).await;
+        assert!(!reloaded.has_growth_cursor);
+        assert_eq!(reloaded.global_level(), 11);
+        assert_eq!(reloaded.growth_cursor, 2);
+        let mut expected = entries.clone();
+        expected.extend(entries);
+        assert_eq!(reloaded.entries().await, expected);
+    }
+
+    #[tokio::test]
+    async fn only_touched_pages_are_cached() {
+        let name = "test_directory_only_touched_pages_are_cached";
+        let mut directory = get_directory(name).await;
+        let entries: Vec<BucketIndexType> = (0..DIRECTORY_PAGE_ENTRIES * 4).collect();
+        directory.set_entries(entries).await;
+        directory.flush().await;
+
+        let mut reloaded = reload_directory(name).await;
+        assert_eq!(
+            reloaded.get(DIRECTORY_PAGE_ENTRIES * 2 + 5).await,
+            DIRECTORY_PAGE_ENTRIES * 2 + 5
+        );
+        assert_eq!(reloaded.cached_page_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn encrypted_pages() {
+        let name = "test_directory_encrypted_pages";
+        let key = EncryptionKey::from_bytes(&[7; ENCRYPTION_KEY_BYTES]).unwrap();
+        let path = format!("./test_data/{}", name);
+        let file = BlockFile::new(reset_or_create_file(&path).into(), Some(&key));
+        let mut directory = Directory::load(file).await.unwrap();
+        let entries: Vec<BucketIndexType> = (0..DIRECTORY_PAGE_ENTRIES * 2).collect();
+        directory.set_entries(entries.clone()).await;
+        directory.flush().await;
+        directory.double().await;
+        directory.set(DIRECTORY_PAGE_ENTRIES * 3, 9999).await;
+        directory.flush().await;
+
+        let mut expected = entries.clone();
+        expected.extend(entries);
+        expected[DIRECTORY_PAGE_ENTRIES * 3] = 9999;
+
+        let mut reloaded = reload_directory_with_key(name, Some(&key)).await.unwrap();
+        assert_eq!(reloaded.read_page(2).await, None);
+        assert_eq!(reloaded.entries().await, expected);
+
+        let wrong_key = EncryptionKey::from_bytes(&[8; ENCRYPTION_KEY_BYTES]).unwrap();
+        assert!(reload_directory_with_key(name, Some(&wrong_key))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,613 @@
+use crate::command::*;
+use crate::hash_storage::HashStorage;
+use crate::setup::setup_db;
+use crate::sorted_set::{Score, SortedSet};
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::fs::File;
+use tokio::io::{
+    stdin, stdout, AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+};
+
+/// A key value pair written as a line of a dump
+///
+/// Keys and values which are valid UTF-8 are written as JSON strings in `key` and `value`, any
+/// other bytes are written as base64 in `key_base64` and `value_base64` instead. Keys which
+/// expire have the time they expire at in `expires_at`, in milliseconds since the unix epoch
+///
+/// A list has its items from head to tail in `list` instead of a value, or in `list_base64` as
+/// base64 when any item isn't valid UTF-8. Likewise a hash has its fields and their values as an
+/// object in `hash`, or in `hash_base64` with both written as base64. A sorted set has its
+/// members and their scores as an object in `sorted_set`, or in `sorted_set_base64` with the
+/// members written as base64. A set has its members in order in `set`, or in `set_base64` as
+/// base64 when any member isn't valid UTF-8
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct DumpEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_base64: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value_base64: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    list: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    list_base64: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<BTreeMap<String, String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash_base64: Option<BTreeMap<String, String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sorted_set: Option<BTreeMap<String, f64>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sorted_set_base64: Option<BTreeMap<String, f64>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    set: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    set_base64: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<Timestamp>,
+}
+
+/// Splits bytes into the text and base64 fields of a `DumpEntry`, only one of which is set
+fn encode_field(bytes: Vec<u8>) -> (Option<String>, Option<String>) {
+    match String::from_utf8(bytes) {
+        Ok(text) => (Some(text), None),
+        Err(e) => (None, Some(BASE64_STANDARD.encode(e.into_bytes()))),
+    }
+}
+
+/// Reads the bytes back from the text and base64 fields of a `DumpEntry`
+fn decode_field(
+    name: &str,
+    text: Option<String>,
+    base64: Option<String>,
+) -> Result<Vec<u8>, String> {
+    match (text, base64) {
+        (Some(text), None) => Ok(text.into_bytes()),
+        (None, Some(base64)) => BASE64_STANDARD
+            .decode(base64)
+            .map_err(|e| format!("Invalid base64 in {}_base64: {}", name, e)),
+        (None, None) => Err(format!("Missing {} or {}_base64", name, name)),
+        (Some(_), Some(_)) => Err(format!(
+            "Only one of {} and {}_base64 can be set",
+            name, name
+        )),
+    }
+}
+
+/// Splits the items of a list into the text and base64 fields of a `DumpEntry`, only one of
+/// which is set
+fn encode_items(items: Vec<Vec<u8>>) -> (Option<Vec<String>>, Option<Vec<String>>) {
+    if items.iter().all(|x| std::str::from_utf8(x).is_ok()) {
+        let items = items.into_iter().map(|x| String::from_utf8(x).unwrap());
+        return (Some(items.collect()), None);
+    }
+    let items = items.into_iter().map(|x| BASE64_STANDARD.encode(x));
+    (None, Some(items.collect()))
+}
+
+/// Reads the items of a list back from the text or base64 field of a `DumpEntry`
+fn decode_items(
+    text: Option<Vec<String>>,
+    base64: Option<Vec<String>>,
+) -> Result<Vec<Vec<u8>>, String> {
+    let items: Vec<Vec<u8>> = match (text, base64) {
+        (Some(text), None) => text.into_iter().map(|x| x.into_bytes()).collect(),
+        (None, Some(base64)) => base64
+            .into_iter()
+            .map(|x| BASE64_STANDARD.decode(x))
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("Invalid base64 in list_base64: {}", e))?,
+        _ => return Err("Only one of list and list_base64 can be set".into()),
+    };
+    if items.is_empty() {
+        return Err("A list needs at least one item".into());
+    }
+    Ok(items)
+}
+
+/// The fields of a hash as the text and base64 fields of a `DumpEntry`, only one of which is set
+type EncodedFields = (
+    Option<BTreeMap<String, String>>,
+    Option<BTreeMap<String, String>>,
+);
+
+/// Splits the fields of a hash into the text and base64 fields of a `DumpEntry`, only one of
+/// which is set
+fn encode_fields(fields: BTreeMap<Vec<u8>, Vec<u8>>) -> EncodedFields {
+    let is_text = |x: &[u8]| std::str::from_utf8(x).is_ok();
+    if fields
+        .iter()
+        .all(|(field, value)| is_text(field) && is_text(value))
+    {
+        let text = |x| String::from_utf8(x).unwrap();
+        let fields = fields.into_iter().map(|(f, v)| (text(f), text(v)));
+        return (Some(fields.collect()), None);
+    }
+    let fields = fields
+        .into_iter()
+        .map(|(f, v)| (BASE64_STANDARD.encode(f), BASE64_STANDARD.encode(v)));
+    (None, Some(fields.collect()))
+}
+
+/// Reads the fields of a hash back from the text or base64 field of a `DumpEntry`
+fn decode_fields(
+    text: Option<BTreeMap<String, String>>,
+    base64: Option<BTreeMap<String, String>>,
+) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, String> {
+    let fields: BTreeMap<Vec<u8>, Vec<u8>> = match (text, base64) {
+        (Some(text), None) => text
+            .into_iter()
+            .map(|(f, v)| (f.into_bytes(), v.into_bytes()))
+            .collect(),
+        (None, Some(base64)) => base64
+            .into_iter()
+            .map(|(f, v)| Ok((BASE64_STANDARD.decode(f)?, BASE64_STANDARD.decode(v)?)))
+            .collect::<Result<_, base64::DecodeError>>()
+            .map_err(|e| format!("Invalid base64 in hash_base64: {}", e))?,
+        _ => return Err("Only one of hash and hash_base64 can be set".into()),
+    };
+    if fields.is_empty() {
+        return Err("A hash needs at least one field".into());
+    }
+    Ok(fields)
+}
+
+/// The members of a sorted set as the text and base64 fields of a `DumpEntry`, only one of which
+/// is set
+type EncodedMembers = (Option<BTreeMap<String, f64>>, Option<BTreeMap<String, f64>>);
+
+/// Splits the members of a sorted set into the text and base64 fields of a `DumpEntry`, only one
+/// of which is set
+fn encode_members(members: SortedSet) -> EncodedMembers {
+    let members = members.iter().map(|(m, score)| (m.clone(), score.value()));
+    let members: Vec<_> = members.collect();
+    if members.iter().all(|x| std::str::from_utf8(&x.0).is_ok()) {
+        let members = members
+            .into_iter()
+            .map(|(m, score)| (String::from_utf8(m).unwrap(), score));
+        return (Some(members.collect()), None);
+    }
+    let members = members
+        .into_iter()
+        .map(|(m, score)| (BASE64_STANDARD.encode(m), score));
+    (None, Some(members.collect()))
+}
+
+/// Reads the members of a sorted set back from the text or base64 field of a `DumpEntry`
+fn decode_members(
+    text: Option<BTreeMap<String, f64>>,
+    base64: Option<BTreeMap<String, f64>>,
+) -> Result<Vec<(Score, Vec<u8>)>, String> {
+    let members: Vec<(f64, Vec<u8>)> = match (text, base64) {
+        (Some(text), None) => text.into_iter().map(|(m, s)| (s, m.into_bytes())).collect(),
+        (None, Some(base64)) => base64
+            .into_iter()
+            .map(|(m, s)| Ok((s, BASE64_STANDARD.decode(m)?)))
+            .collect::<Result<_, base64::DecodeError>>()
+            .map_err(|e| format!("Invalid base64 in sorted_set_base64: {}", e))?,
+        _ => return Err("Only one of sorted_set and sorted_set_base64 can be set".into()),
+    };
+    if members.is_empty() {
+        return Err("A sorted set needs at least one member".into());
+    }
+    members
+        .into_iter()
+        .map(|(score, m)| Ok((Score::new(score).ok_or("Scores must be finite")?, m)))
+        .collect()
+}
+
+/// Reads the members of a set back from the text or base64 field of a `DumpEntry`
+fn decode_set(
+    text: Option<Vec<String>>,
+    base64: Option<Vec<String>>,
+) -> Result<Vec<Vec<u8>>, String> {
+    let members: Vec<Vec<u8>> = match (text, base64) {
+        (Some(text), None) => text.into_iter().map(|x| x.into_bytes()).collect(),
+        (None, Some(base64)) => base64
+            .into_iter()
+            .map(|x| BASE64_STANDARD.decode(x))
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("Invalid base64 in set_base64: {}", e))?,
+        _ => return Err("Only one of set and set_base64 can be set".into()),
+    };
+    if members.is_empty() {
+        return Err("A set needs at least one member".into());
+    }
+    Ok(members)
+}
+
+impl DumpEntry {
+    fn new(key: Vec<u8>, value: Value, expires_at: Option<Timestamp>) -> Self {
+        let (key, key_base64) = encode_field(key);
+        let mut entry = Self {
+            key,
+            key_base64,
+            value: None,
+            value_base64: None,
+            list: None,
+            list_base64: None,
+            hash: None,
+            hash_base64: None,
+            sorted_set: None,
+            sorted_set_base64: None,
+            set: None,
+            set_base64: None,
+            expires_at,
+        };
+        match value {
+            Value::String(value) => (entry.value, entry.value_base64) = encode_field(value),
+            Value::List(items) => (entry.list, entry.list_base64) = encode_items(items.into()),
+            Value::Hash(fields) => (entry.hash, entry.hash_base64) = encode_fields(fields),
+            Value::SortedSet(members) => {
+                (entry.sorted_set, entry.sorted_set_base64) = encode_members(members)
+            }
+            Value::Set(members) => {
+                (entry.set, entry.set_base64) = encode_items(members.into_iter().collect())
+            }
+        }
+        entry
+    }
+
+    /// The commands which store the entry, a list, hash, sorted set or set is written and then
+    /// given its expiry
+    fn into_commands(self) -> Result<Vec<StorageCommand>, String> {
+        let key = decode_field("key", self.key, self.key_base64)?;
+        let is_value = self.value.is_some() || self.value_base64.is_some();
+        let is_list = self.list.is_some() || self.list_base64.is_some();
+        let is_hash = self.hash.is_some() || self.hash_base64.is_some();
+        let is_sorted_set = self.sorted_set.is_some() || self.sorted_set_base64.is_some();
+        let is_set = self.set.is_some() || self.set_base64.is_some();
+        let kinds = [is_value, is_list, is_hash, is_sorted_set, is_set];
+        if kinds.iter().filter(|x| **x).count() > 1 {
+            return Err(
+                "Only one of a value, a list, a hash, a sorted set and a set can be set".into(),
+            );
+        }
+        let cmd = if is_list {
+            let items = decode_items(self.list, self.list_base64)?;
+            ListPushCommand(key.clone(), ListSide::Right, items).into()
+        } else if is_hash {
+            let fields = decode_fields(self.hash, self.hash_base64)?;
+            HashSetCommand(key.clone(), fields.into_iter().collect()).into()
+        } else if is_sorted_set {
+            let members = decode_members(self.sorted_set, self.sorted_set_base64)?;
+            SortedSetAddCommand(key.clone(), members).into()
+        } else if is_set {
+            let members = decode_set(self.set, self.set_base64)?;
+            SetAddCommand(key.clone(), members).into()
+        } else {
+            let value = decode_field("value", self.value, self.value_base64)?;
+            return Ok(vec![PutCommand(key, value, self.expires_at).into()]);
+        };
+        let mut commands = vec![cmd];
+        if self.expires_at.is_some() {
+            commands.push(ExpireCommand(key, self.expires_at).into());
+        }
+        Ok(commands)
+    }
+}
+
+/// Writes every key value pair of the storage as JSON Lines, see `DumpEntry`
+///
+/// # Returns
+/// - The number of key value pairs written
+pub(crate) async fn dump(
+    storage: &mut HashStorage,
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<usize, String> {
+    let mut iter = storage.keyspace();
+    let mut count = 0;
+    while let Some((key, value, expires_at)) = iter.next(storage).await? {
+        let entry = DumpEntry::new(key, value, expires_at);
+        let mut line = serde_json::to_vec(&entry).unwrap();
+        line.push(b'\n');
+        writer
+            .write_all(&line)
+            .await
+            .map_err(|e| format!("Failed to write the dump: {}", e))?;
+        count += 1;
+    }
+    writer
+        .flush()
+        .await
+        .map_err(|e| format!("Failed to write the dump: {}", e))?;
+    Ok(count)
+}
+
+/// Puts every key value pair of a dump written by `dump` into the storage, which must be empty
+///
+/// # Returns
+/// - The number of key value pairs stored
+/// - An error naming the line of the dump which can't be read or stored
+pub(crate) async fn restore(
+    storage: &mut HashStorage,
+    reader: impl AsyncBufRead + Unpin,
+) -> Result<usize, String> {
+    if !storage.is_empty().await {
+        return Err("Refusing to restore into a database which already holds keys".into());
+    }
+
+    let mut lines = reader.lines();
+    let mut line_number = 0;
+    let mut count = 0;
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .map_err(|e| format!("Failed to read the dump: {}", e))?
+    {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let commands = serde_json::from_str::<DumpEntry>(&line)
+            .map_err(|e| e.to_string())
+            .and_then(DumpEntry::into_commands)
+            .map_err(|e| format!("Line {}: {}", line_number, e))?;
+        for cmd in commands {
+            storage
+                .handle_cmd(cmd)
+                .await
+                .map_err(|e| format!("Line {}: {}", line_number, e))?;
+        }
+        count += 1;
+    }
+    storage.handle_cmd(StorageCommand::Flush).await?;
+    Ok(count)
+}
+
+/// Dumps the database to the file at the path, or to stdout without a path
+pub async fn run_dump(path: Option<&str>) -> Result<(), String> {
+    let (mut storage, _) = setup_db().await;
+    let count = match path {
+        Some(path) => {
+            let file = File::create(path)
+                .await
+                .map_err(|e| format!("Failed to create {}: {}", path, e))?;
+            dump(&mut storage, &mut BufWriter::new(file)).await?
+        }
+        None => dump(&mut storage, &mut BufWriter::new(stdout())).await?,
+    };
+    // stdout may be holding the dump
+    eprintln!("Dumped {} keys", count);
+    Ok(())
+}
+
+/// Restores a dump from the file at the path, or from stdin without a path, into an empty
+/// database
+pub async fn run_restore(path: Option<&str>) -> Result<(), String> {
+    let (mut storage, _) = setup_db().await;
+    let count = match path {
+        Some(path) => {
+            let file = File::open(path)
+                .await
+                .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+            restore(&mut storage, BufReader::new(file)).await?
+        }
+        None => restore(&mut storage, BufReader::new(stdin())).await?,
+    };
+    println!("Restored {} keys", count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_dump {
+    use super::*;
+    use std::collections::BTreeSet;
+    use crate::hash_storage::HashStorageOptions;
+    use crate::test::*;
+
+    async fn get_engine(test_prefix: &str) -> HashStorage {
+        let data_path = format!("./test_data/{}_data.db", test_prefix);
+        let dir_path = format!("./test_data/{}_dir.db", test_prefix);
+        reset_or_create_file(&data_path);
+        reset_or_create_file(&dir_path);
+        HashStorage::new(&dir_path, &data_path, HashStorageOptions::default())
+            .await
+            .unwrap()
+    }
+
+    async fn sorted_entries(storage: &mut HashStorage) -> Vec<(Vec<u8>, Value, Option<Timestamp>)> {
+        let mut iter = storage.keyspace();
+        let mut entries = vec![];
+        while let Some(entry) = iter.next(storage).await.unwrap() {
+            entries.push(entry);
+        }
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn entries_into_json() {
+        let entry = DumpEntry::new(b"key \"1\"".to_vec(), Value::String(vec![0, 255]), None);
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(json, r#"{"key":"key \"1\"","value_base64":"AP8="}"#);
+        let entry: DumpEntry = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            entry.into_commands(),
+            Ok(vec![
+                PutCommand(b"key \"1\"".to_vec(), vec![0, 255], None).into()
+            ])
+        );
+
+        let value = Value::String(b"1".to_vec());
+        let entry = DumpEntry::new(b"a".to_vec(), value, Some(1_700_000_000_000));
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(
+            json,
+            r#"{"key":"a","value":"1","expires_at":1700000000000}"#
+        );
+
+        let items = vec![b"x".to_vec(), vec![255]];
+        let entry = DumpEntry::new(b"l".to_vec(), Value::List(items.clone().into()), Some(1));
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(
+            json,
+            r#"{"key":"l","list_base64":["eA==","/w=="],"expires_at":1}"#
+        );
+        let entry: DumpEntry = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            entry.into_commands(),
+            Ok(vec![
+                ListPushCommand(b"l".to_vec(), ListSide::Right, items).into(),
+                ExpireCommand(b"l".to_vec(), Some(1)).into(),
+            ])
+        );
+        let entry = DumpEntry::new(b"l".to_vec(), Value::List(vec![b"x".to_vec()].into()), None);
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(json, r#"{"key":"l","list":["x"]}"#);
+
+        let fields = BTreeMap::from([(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), vec![255])]);
+        let entry = DumpEntry::new(b"h".to_vec(), Value::Hash(fields.clone()), None);
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(
+            json,
+            r#"{"key":"h","hash_base64":{"YQ==":"MQ==","Yg==":"/w=="}}"#
+        );
+        let entry: DumpEntry = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            entry.into_commands(),
+            Ok(vec![HashSetCommand(
+                b"h".to_vec(),
+                fields.into_iter().collect()
+            )
+            .into()])
+        );
+        let fields = BTreeMap::from([(b"a".to_vec(), b"1".to_vec())]);
+        let entry = DumpEntry::new(b"h".to_vec(), Value::Hash(fields), Some(1));
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(json, r#"{"key":"h","hash":{"a":"1"},"expires_at":1}"#);
+
+        let score = |x| Score::new(x).unwrap();
+        let members = vec![(score(0.1), b"a".to_vec()), (score(-2.0), vec![255])];
+        let set = members.iter().map(|(s, m)| (m.clone(), *s)).collect();
+        let entry = DumpEntry::new(b"z".to_vec(), Value::SortedSet(set), Some(1));
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(
+            json,
+            r#"{"key":"z","sorted_set_base64":{"/w==":-2.0,"YQ==":0.1},"expires_at":1}"#
+        );
+        let entry: DumpEntry = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            entry.into_commands(),
+            Ok(vec![
+                SortedSetAddCommand(b"z".to_vec(), members.into_iter().rev().collect()).into(),
+                ExpireCommand(b"z".to_vec(), Some(1)).into(),
+            ])
+        );
+        let set = [(b"a".to_vec(), score(1.5))].into_iter().collect();
+        let entry = DumpEntry::new(b"z".to_vec(), Value::SortedSet(set), None);
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(json, r#"{"key":"z","sorted_set":{"a":1.5}}"#);
+
+        let members = BTreeSet::from([b"b".to_vec(), vec![255]]);
+        let entry = DumpEntry::new(b"s".to_vec(), Value::Set(members.clone()), Some(1));
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(
+            json,
+            r#"{"key":"s","set_base64":["Yg==","/w=="],"expires_at":1}"#
+        );
+        let entry: DumpEntry = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            entry.into_commands(),
+            Ok(vec![
+                SetAddCommand(b"s".to_vec(), members.into_iter().collect()).into(),
+                ExpireCommand(b"s".to_vec(), Some(1)).into(),
+            ])
+        );
+        let members = BTreeSet::from([b"b".to_vec(), b"a".to_vec()]);
+        let entry = DumpEntry::new(b"s".to_vec(), Value::Set(members), None);
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(json, r#"{"key":"s","set":["a","b"]}"#);
+
+        for json in [
+            r#"{"key":"l","list":[]}"#,
+            r#"{"key":"l","list":["x"],"value":"1"}"#,
+            r#"{"key":"l","list":["x"],"list_base64":["eA=="]}"#,
+            r#"{"key":"h","hash":{}}"#,
+            r#"{"key":"h","hash":{"a":"1"},"list":["x"]}"#,
+            r#"{"key":"h","hash_base64":{"a":"!"}}"#,
+            r#"{"key":"z","sorted_set":{}}"#,
+            r#"{"key":"z","sorted_set":{"a":1},"hash":{"a":"1"}}"#,
+            r#"{"key":"s","set":[]}"#,
+            r#"{"key":"s","set":["a"],"list":["a"]}"#,
+            r#"{"key":"s","set_base64":["!"]}"#,
+        ] {
+            let entry: DumpEntry = serde_json::from_str(json).unwrap();
+            assert!(entry.into_commands().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn dump_and_restore() {
+        let mut source = get_engine("dump_dump_and_restore_source").await;
+        for i in 0..500 {
+            let cmd = PutCommand(
+                format!("key{}", i).into(),
+                format!("value\n{}", i).into(),
+                None,
+            );
+            source.handle_cmd(cmd.into()).await.unwrap();
+        }
+        let cmd = PutCommand(vec![0, 159, 146, 150], vec![255; 10], Some(u64::MAX));
+        source.handle_cmd(cmd.into()).await.unwrap();
+        let items = vec![b"a".to_vec(), vec![0, 255]];
+        let cmd = ListPushCommand(b"list".to_vec(), ListSide::Left, items);
+        source.handle_cmd(cmd.into()).await.unwrap();
+        let cmd = ExpireCommand(b"list".to_vec(), Some(u64::MAX));
+        source.handle_cmd(cmd.into()).await.unwrap();
+        let fields = vec![(b"name".to_vec(), b"Ann".to_vec()), (vec![0], vec![255])];
+        let cmd = HashSetCommand(b"hash".to_vec(), fields);
+        source.handle_cmd(cmd.into()).await.unwrap();
+        let score = |x| Score::new(x).unwrap();
+        let members = vec![(score(0.1), b"ann".to_vec()), (score(-3e10), vec![0])];
+        let cmd = SortedSetAddCommand(b"sorted".to_vec(), members);
+        source.handle_cmd(cmd.into()).await.unwrap();
+        let cmd = SetAddCommand(b"set".to_vec(), vec![b"a".to_vec(), vec![0, 255]]);
+        source.handle_cmd(cmd.into()).await.unwrap();
+
+        let mut buf = vec![];
+        assert_eq!(dump(&mut source, &mut buf).await, Ok(505));
+        assert_eq!(buf.iter().filter(|x| **x == b'\n').count(), 505);
+
+        let mut target = get_engine("dump_dump_and_restore_target").await;
+        assert_eq!(restore(&mut target, buf.as_slice()).await, Ok(505));
+        assert_eq!(
+            sorted_entries(&mut target).await,
+            sorted_entries(&mut source).await
+        );
+
+        // The target isn't empty anymore
+        assert!(restore(&mut target, buf.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn restore_reports_bad_lines() {
+        let mut engine = get_engine("dump_restore_reports_bad_lines").await;
+        let dump = "{\"key\":\"a\",\"value\":\"1\"}\n\n{\"key\":\"b\"}\n";
+        assert_eq!(
+            restore(&mut engine, dump.as_bytes()).await,
+            Err("Line 3: Missing value or value_base64".to_string())
+        );
+
+        let mut engine = get_engine("dump_restore_reports_bad_lines").await;
+        let error = restore(&mut engine, "not json".as_bytes()).await;
+        assert!(error.unwrap_err().starts_with("Line 1: "));
+    }
+}
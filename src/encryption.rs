@@ -0,0 +1,422 @@
+use crate::backup::FileBackup;
+use crate::bytes::decode_hex;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::io::SeekFrom;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// The length in bytes of an encryption key
+pub const ENCRYPTION_KEY_BYTES: usize = 32;
+
+/// The length in bytes of the random nonce stored in front of every encrypted block
+const NONCE_BYTES: usize = 24;
+
+/// The length in bytes of the authentication tag stored after every encrypted block
+const TAG_BYTES: usize = 16;
+
+/// Written in plaintext at the start of an encrypted file, so opening it without a key fails
+const ENCRYPTED_FILE_MAGIC: &[u8; 8] = b"SRKVENC1";
+
+/// Encrypted after `ENCRYPTED_FILE_MAGIC`, so opening an encrypted file with the wrong key fails
+/// before anything else is read
+const KEY_CHECK: &[u8; 16] = b"silly-rusty-kv!!";
+
+/// A 256 bit key used to encrypt the database files
+#[derive(Clone, PartialEq)]
+pub struct EncryptionKey([u8; ENCRYPTION_KEY_BYTES]);
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keep the key out of logs
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let key = bytes.try_into().map_err(|_| {
+            format!(
+                "Encryption key must be {} bytes, got {}",
+                ENCRYPTION_KEY_BYTES,
+                bytes.len()
+            )
+        })?;
+        Ok(Self(key))
+    }
+
+    /// Parses a key written as hex, surrounding whitespace is ignored
+    pub fn from_hex(hex: &str) -> Result<Self, String> {
+        let hex = hex.trim();
+        if hex.len() != ENCRYPTION_KEY_BYTES * 2 {
+            return Err(format!(
+                "Encryption key must be {} hex characters, got {}",
+                ENCRYPTION_KEY_BYTES * 2,
+                hex.len()
+            ));
+        }
+        let bytes = decode_hex(hex)
+            .map_err(|_| "Encryption key must only contain hex characters".to_string())?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Reads a key file, which holds either the raw key or the key written as hex
+    pub fn read_key_file(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read(path)
+            .map_err(|e| format!("Failed to read encryption key file {}: {}", path, e))?;
+        if contents.len() == ENCRYPTION_KEY_BYTES {
+            return Self::from_bytes(&contents);
+        }
+        Self::from_hex(&String::from_utf8_lossy(&contents))
+    }
+}
+
+/// A file read and written in blocks, which are encrypted when the file is opened with a key
+///
+/// Each block is encrypted on its own with XChaCha20-Poly1305 so a single page can be read or
+/// written without touching the rest of the file. The position of the block in the file is
+/// authenticated along with its contents, so a block copied to a different position fails to
+/// decrypt just like a block which was tampered with.
+///
+/// # Encrypted block layout
+/// - A random nonce of `NONCE_BYTES`
+/// - The encrypted contents, the same length as the plaintext
+/// - The authentication tag of `TAG_BYTES`
+///
+/// Without a key blocks are stored as plaintext, so the layout of the file is the same as
+/// before encryption existed.
+///
+/// # Encrypted file layout
+/// A file opened with `BlockFile::open` and a key starts with a key check, which positions are
+/// relative to:
+/// - `ENCRYPTED_FILE_MAGIC` in plaintext
+/// - `KEY_CHECK` as an encrypted block
+///
+/// Encrypted files written before the key check existed start straight away with their blocks.
+pub(crate) struct BlockFile {
+    file: File,
+    cipher: Option<XChaCha20Poly1305>,
+
+    /// The length in bytes of the key check at the start of the file, which every position is
+    /// offset by
+    offset: u64,
+
+    /// The backup being made of the file, blocks are copied as stored so an encrypted file stays
+    /// encrypted in the backup
+    backup: Option<FileBackup>,
+}
+
+impl BlockFile {
+    pub fn new(file: File, key: Option<&EncryptionKey>) -> Self {
+        Self {
+            file,
+            cipher: key.map(|key| XChaCha20Poly1305::new(&key.0.into())),
+            offset: 0,
+            backup: None,
+        }
+    }
+
+    /// Opens the file, checking the key against the key check at the start of the file
+    ///
+    /// An empty file opened with a key gets a key check written to it
+    ///
+    /// # Returns
+    /// - An error if the file is encrypted and no key or the wrong key is given
+    pub async fn open(file: File, key: Option<&EncryptionKey>) -> Result<Self, String> {
+        let mut block_file = Self::new(file, key);
+        let magic = block_file
+            .read_physical_block(0, ENCRYPTED_FILE_MAGIC.len(), false)
+            .await?;
+        let offset = (ENCRYPTED_FILE_MAGIC.len() + block_file.block_len(KEY_CHECK.len())) as u64;
+        match (magic.as_deref() == Some(ENCRYPTED_FILE_MAGIC), key) {
+            (true, None) => {
+                return Err("The file is encrypted, but no encryption key was given".into())
+            }
+            (true, Some(_)) => {
+                let position = ENCRYPTED_FILE_MAGIC.len() as u64;
+                let key_check = block_file
+                    .read_physical_block(position, KEY_CHECK.len(), true)
+                    .await
+                    .map_err(|_| "The encryption key is wrong")?;
+                if key_check.as_deref() != Some(KEY_CHECK) {
+                    return Err("The encryption key is wrong".into());
+                }
+                block_file.offset = offset;
+            }
+            (false, Some(_)) if block_file.len().await == 0 => {
+                block_file
+                    .write_physical_block(0, ENCRYPTED_FILE_MAGIC, false)
+                    .await;
+                let position = ENCRYPTED_FILE_MAGIC.len() as u64;
+                block_file
+                    .write_physical_block(position, KEY_CHECK, true)
+                    .await;
+                block_file.offset = offset;
+            }
+            (false, _) => {}
+        }
+        Ok(block_file)
+    }
+
+    /// The number of bytes a block holding `len` bytes takes up in the file
+    pub fn block_len(&self, len: usize) -> usize {
+        match self.cipher {
+            Some(_) => NONCE_BYTES + len + TAG_BYTES,
+            None => len,
+        }
+    }
+
+    /// The length of the file in bytes, leaving out the key check
+    pub async fn len(&self) -> u64 {
+        let len = self.file.metadata().await.unwrap().len();
+        len.saturating_sub(self.offset)
+    }
+
+    /// Reads the block holding `len` bytes which starts at `position`
+    ///
+    /// # Returns
+    /// - `None` if the block has never been written, either because it is past the end of the
+    ///   file or because it is an encrypted block which is still all zeros
+    /// - An error if the block fails to decrypt
+    pub async fn read_block(
+        &mut self,
+        position: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.read_physical_block(self.offset + position, len, true)
+            .await
+    }
+
+    /// Reads the block holding `len` bytes which starts at `position` from the start of the
+    /// file, decrypting it when `encrypted` is set and there is a key
+    async fn read_physical_block(
+        &mut self,
+        position: u64,
+        len: usize,
+        encrypted: bool,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let block_len = match encrypted {
+            true => self.block_len(len),
+            false => len,
+        };
+        let file_len = self.file.metadata().await.unwrap().len();
+        if file_len < position + block_len as u64 {
+            return Ok(None);
+        }
+
+        self.file.seek(SeekFrom::Start(position)).await.unwrap();
+        let mut buf = vec![0; block_len];
+        self.file.read_exact(&mut buf).await.unwrap();
+
+        let Some(cipher) = self.cipher.as_ref().filter(|_| encrypted) else {
+            return Ok(Some(buf));
+        };
+        if buf.iter().all(|x| *x == 0) {
+            return Ok(None);
+        }
+        let (nonce, ciphertext) = buf.split_at(NONCE_BYTES);
+        let plaintext = cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &position.to_le_bytes(),
+                },
+            )
+            .map_err(|_| {
+                format!(
+                    "Failed to decrypt the block at byte {}, the encryption key is wrong or the file is corrupted",
+                    position
+                )
+            })?;
+        Ok(Some(plaintext))
+    }
+
+    /// Writes the block starting at `position`
+    pub async fn write_block(&mut self, position: u64, bytes: &[u8]) {
+        self.write_physical_block(self.offset + position, bytes, true)
+            .await;
+    }
+
+    /// Writes the block starting at `position` from the start of the file, encrypting it when
+    /// `encrypted` is set and there is a key
+    async fn write_physical_block(&mut self, position: u64, bytes: &[u8], encrypted: bool) {
+        let buf = match self.cipher.as_ref().filter(|_| encrypted) {
+            Some(cipher) => {
+                let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let ciphertext = cipher
+                    .encrypt(
+                        &nonce,
+                        Payload {
+                            msg: bytes,
+                            aad: &position.to_le_bytes(),
+                        },
+                    )
+                    .unwrap();
+                let mut buf = Vec::with_capacity(NONCE_BYTES + bytes.len() + TAG_BYTES);
+                buf.extend(nonce);
+                buf.extend(ciphertext);
+                buf
+            }
+            None => bytes.to_vec(),
+        };
+        if let Some(backup) = &mut self.backup {
+            backup
+                .preserve(&mut self.file, position, buf.len() as u64)
+                .await;
+        }
+        self.file.seek(SeekFrom::Start(position)).await.unwrap();
+        self.file.write_all(&buf).await.unwrap();
+        // Wait for the write to land so `len` sees it
+        self.file.flush().await.unwrap();
+    }
+
+    /// Starts copying the file as it is now into the destination, see `FileBackup`
+    pub async fn start_backup(&mut self, destination: File) {
+        let len = self.file.metadata().await.unwrap().len();
+        self.backup = Some(FileBackup::new(destination, len));
+    }
+
+    /// Copies up to `max_chunks` chunks of the running backup, finishing it once the whole file
+    /// is copied
+    ///
+    /// # Returns
+    /// - The number of chunks copied
+    pub async fn continue_backup(&mut self, max_chunks: usize) -> usize {
+        let Some(backup) = &mut self.backup else {
+            return 0;
+        };
+        let copied = backup.copy_chunks(&mut self.file, max_chunks).await;
+        if backup.is_done() {
+            backup.finish().await;
+            self.backup = None;
+        }
+        copied
+    }
+
+    /// Whether a backup of the file is running
+    pub fn is_backing_up(&self) -> bool {
+        self.backup.is_some()
+    }
+
+    pub async fn sync_all(&mut self) {
+        self.file.sync_all().await.unwrap();
+    }
+
+    /// Cuts the file down to `len` bytes, leaving the key check in place
+    pub async fn set_len(&mut self, len: u64) {
+        self.file.set_len(self.offset + len).await.unwrap();
+    }
+
+    /// Whether blocks are encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test_encryption {
+    use super::*;
+    use crate::test::*;
+
+    fn key(byte: u8) -> EncryptionKey {
+        EncryptionKey([byte; ENCRYPTION_KEY_BYTES])
+    }
+
+    fn get_file(name: &str, key: Option<&EncryptionKey>) -> BlockFile {
+        let path = format!("./test_data/{}", name);
+        BlockFile::new(reset_or_create_file(&path).into(), key)
+    }
+
+    fn reopen_file(name: &str, key: Option<&EncryptionKey>) -> BlockFile {
+        let path = format!("./test_data/{}", name);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        BlockFile::new(file.into(), key)
+    }
+
+    #[test]
+    fn key_from_hex() {
+        let hex = "00ff".repeat(ENCRYPTION_KEY_BYTES / 2);
+        let key = EncryptionKey::from_hex(&format!("{}\n", hex)).unwrap();
+        assert_eq!(key.0[..2], [0x00, 0xff]);
+        assert!(EncryptionKey::from_hex("00ff").is_err());
+        assert!(EncryptionKey::from_hex(&"zz".repeat(ENCRYPTION_KEY_BYTES)).is_err());
+    }
+
+    #[tokio::test]
+    async fn encrypted_blocks() {
+        let name = "test_encryption_encrypted_blocks";
+        let mut file = get_file(name, Some(&key(1)));
+        let block_len = file.block_len(10) as u64;
+        file.write_block(0, b"first page").await;
+        file.write_block(block_len * 2, b"third page").await;
+
+        assert_eq!(
+            file.read_block(0, 10).await,
+            Ok(Some(b"first page".to_vec()))
+        );
+        // The skipped block is still zeros and the one after it doesn't exist
+        assert_eq!(file.read_block(block_len, 10).await, Ok(None));
+        assert_eq!(file.read_block(block_len * 3, 10).await, Ok(None));
+
+        let raw = std::fs::read(format!("./test_data/{}", name)).unwrap();
+        assert!(!raw.windows(10).any(|x| x == b"first page"));
+
+        let mut reopened = reopen_file(name, Some(&key(1)));
+        assert_eq!(
+            reopened.read_block(block_len * 2, 10).await,
+            Ok(Some(b"third page".to_vec()))
+        );
+
+        let mut wrong_key = reopen_file(name, Some(&key(2)));
+        assert!(wrong_key.read_block(0, 10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn moved_block_fails_to_decrypt() {
+        let name = "test_encryption_moved_block_fails_to_decrypt";
+        let mut file = get_file(name, Some(&key(1)));
+        let block_len = file.block_len(4) as u64;
+        file.write_block(0, b"page").await;
+
+        let raw = std::fs::read(format!("./test_data/{}", name)).unwrap();
+        file.file.seek(SeekFrom::Start(block_len)).await.unwrap();
+        file.file.write_all(&raw).await.unwrap();
+
+        assert!(file.read_block(block_len, 4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn checks_the_key_on_open() {
+        let name = "test_encryption_checks_the_key_on_open";
+        let path = format!("./test_data/{}", name);
+        let file = reset_or_create_file(&path).into();
+        let mut file = BlockFile::open(file, Some(&key(1))).await.unwrap();
+        file.write_block(0, b"page").await;
+        assert_eq!(file.len().await, file.block_len(4) as u64);
+
+        let raw = std::fs::read(&path).unwrap();
+        assert!(raw.starts_with(ENCRYPTED_FILE_MAGIC));
+
+        let file = reopen_file(name, None).file;
+        let mut reopened = BlockFile::open(file, Some(&key(1))).await.unwrap();
+        assert_eq!(reopened.read_block(0, 4).await, Ok(Some(b"page".to_vec())));
+        let file = reopen_file(name, None).file;
+        assert!(BlockFile::open(file, Some(&key(2))).await.is_err());
+        let file = reopen_file(name, None).file;
+        assert!(BlockFile::open(file, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn plaintext_blocks() {
+        let mut file = get_file("test_encryption_plaintext_blocks", None);
+        assert_eq!(file.block_len(10), 10);
+        file.write_block(4, &[0; 4]).await;
+        assert_eq!(file.len().await, 8);
+        assert_eq!(file.read_block(4, 4).await, Ok(Some(vec![0; 4])));
+    }
+}
@@ -1,6 +1,7 @@
-use crate::{hash_storage::HashStorage, wal::Wal};
+use crate::{hash_storage::HashStorage, sorted_set::SortedSet, wal::Wal};
 
 use super::command::*;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
 
 pub async fn execute_user_input(
     storage: &mut HashStorage,
@@ -21,22 +22,253 @@ pub async fn execute_command(
 ) -> Result<CommandOutput, String> {
     if let Some(id) = transaction_id {
         match cmd {
-            UserCommand::Get(ref cmd) => {
-                let output = wal.get(id, &cmd);
-                if let Some(output) = output {
-                    if let Some(value) = output {
-                        return Ok(CommandOutput::Found(value));
-                    }
-                    return Ok(CommandOutput::NotFound(cmd.0.clone()));
-                }
+            UserCommand::Get(cmd) => {
+                return match view_string(storage, wal, id, &cmd.0).await? {
+                    Some((value, _)) => Ok(CommandOutput::Found(value)),
+                    None => Ok(CommandOutput::NotFound(cmd.0)),
+                };
             }
             UserCommand::Put(cmd) => {
                 wal.mutate(id, Mutation::Put(cmd)).unwrap();
                 return Ok(CommandOutput::Put);
             }
             UserCommand::Delete(cmd) => {
-                wal.mutate(id, Mutation::Delete(cmd)).unwrap();
-                return Ok(CommandOutput::Delete);
+                let present = view(storage, wal, id, &cmd.0).await?.is_some();
+                wal.mutate(id, Mutation::Delete(cmd.clone())).unwrap();
+                if present {
+                    return Ok(CommandOutput::Deleted);
+                }
+                return Ok(CommandOutput::NotFound(cmd.0));
+            }
+            UserCommand::Expire(cmd) => {
+                if view(storage, wal, id, &cmd.0).await?.is_none() {
+                    return Ok(CommandOutput::NotFound(cmd.0));
+                }
+                let output = match cmd.1 {
+                    Some(_) => CommandOutput::Expire,
+                    None => CommandOutput::Persist,
+                };
+                wal.mutate(id, Mutation::Expire(cmd)).unwrap();
+                return Ok(output);
+            }
+            UserCommand::Incr(cmd) => {
+                let value = execute_incr(storage, wal, cmd, transaction_id).await?;
+                return Ok(CommandOutput::Integer(value));
+            }
+            // Conditional puts are checked against the view for their output, and checked again
+            // when the transaction commits in case another client changed the key in between
+            UserCommand::PutIf(cmd) => {
+                let PutIfCommand(put, condition) = &cmd;
+                let present = view(storage, wal, id, &put.0).await?.is_some();
+                if !condition.holds(present) {
+                    return Ok(CommandOutput::NotPut);
+                }
+                wal.mutate(id, Mutation::PutIf(cmd)).unwrap();
+                return Ok(CommandOutput::Put);
+            }
+            UserCommand::Cas(cmd) => {
+                return match view_string(storage, wal, id, &cmd.0).await? {
+                    Some((current, _)) if current == cmd.1 => {
+                        wal.mutate(id, Mutation::Cas(cmd)).unwrap();
+                        Ok(CommandOutput::Swapped)
+                    }
+                    _ => Ok(CommandOutput::NotSwapped),
+                };
+            }
+            UserCommand::GetDel(cmd) => {
+                return match view_string(storage, wal, id, &cmd.0).await? {
+                    Some((value, _)) => {
+                        wal.mutate(id, Mutation::Delete(DeleteCommand(cmd.0)))
+                            .unwrap();
+                        Ok(CommandOutput::Taken(value))
+                    }
+                    None => Ok(CommandOutput::NotFound(cmd.0)),
+                };
+            }
+            UserCommand::GetSet(cmd) => {
+                let previous = view_string(storage, wal, id, &cmd.0).await?.map(|x| x.0);
+                let put = PutCommand(cmd.0, cmd.1, None);
+                wal.mutate(id, Mutation::Put(put)).unwrap();
+                return Ok(CommandOutput::Replaced(previous));
+            }
+            UserCommand::MGet(cmd) => {
+                let mut values = vec![];
+                for key in cmd.0 {
+                    values.push(view_string(storage, wal, id, &key).await?.map(|x| x.0));
+                }
+                return Ok(CommandOutput::Values(values));
+            }
+            UserCommand::MSet(cmd) => {
+                for put in cmd.0 {
+                    wal.mutate(id, Mutation::Put(put)).unwrap();
+                }
+                return Ok(CommandOutput::Put);
+            }
+            UserCommand::MDelete(cmd) => {
+                let mut deleted = 0;
+                for key in cmd.0 {
+                    if view(storage, wal, id, &key).await?.is_some() {
+                        deleted += 1;
+                    }
+                    wal.mutate(id, Mutation::Delete(DeleteCommand(key)))
+                        .unwrap();
+                }
+                return Ok(CommandOutput::DeletedKeys(deleted));
+            }
+            UserCommand::Exists(cmd) => {
+                let exists = view(storage, wal, id, &cmd.0).await?.is_some();
+                return Ok(CommandOutput::Exists(exists));
+            }
+            // Buffered as writes of the new key and a delete, the value is known from the view
+            // already
+            UserCommand::Rename(RenameCommand(from, to)) => {
+                let Some((value, expires_at)) = view(storage, wal, id, &from).await? else {
+                    return Ok(CommandOutput::NotFound(from));
+                };
+                if from != to {
+                    buffer_value(wal, id, to, value, expires_at);
+                    wal.mutate(id, Mutation::Delete(DeleteCommand(from)))
+                        .unwrap();
+                }
+                return Ok(CommandOutput::Renamed);
+            }
+            UserCommand::Copy(CopyCommand(from, to)) => {
+                let Some((value, expires_at)) = view(storage, wal, id, &from).await? else {
+                    return Ok(CommandOutput::NotFound(from));
+                };
+                buffer_value(wal, id, to, value, expires_at);
+                return Ok(CommandOutput::Copied);
+            }
+            UserCommand::ListPush(cmd) => {
+                let items: VecDeque<_> = view_typed(storage, wal, id, &cmd.0).await?;
+                let len = items.len() + cmd.2.len();
+                wal.mutate(id, Mutation::ListPush(cmd)).unwrap();
+                return Ok(CommandOutput::Length(len as u64));
+            }
+            UserCommand::ListPop(cmd) => {
+                let mut items: VecDeque<_> = view_typed(storage, wal, id, &cmd.0).await?;
+                let item = match cmd.1 {
+                    ListSide::Left => items.pop_front(),
+                    ListSide::Right => items.pop_back(),
+                };
+                let Some(item) = item else {
+                    return Ok(CommandOutput::NotFound(cmd.0));
+                };
+                wal.mutate(id, Mutation::ListPop(cmd)).unwrap();
+                return Ok(CommandOutput::Found(item));
+            }
+            UserCommand::ListRange(cmd) => {
+                let items: VecDeque<_> = view_typed(storage, wal, id, &cmd.0).await?;
+                let range = list_range(items.len() as u64, cmd.1, cmd.2);
+                let range = range.start as usize..range.end as usize;
+                return Ok(CommandOutput::Items(items.range(range).cloned().collect()));
+            }
+            UserCommand::ListLen(cmd) => {
+                let items: VecDeque<_> = view_typed(storage, wal, id, &cmd.0).await?;
+                return Ok(CommandOutput::Length(items.len() as u64));
+            }
+            UserCommand::HashSet(cmd) => {
+                let fields: BTreeMap<_, _> = view_typed(storage, wal, id, &cmd.0).await?;
+                let added = count_members(cmd.1.iter().map(|x| &x.0), |x| !fields.contains_key(x));
+                wal.mutate(id, Mutation::HashSet(cmd)).unwrap();
+                return Ok(CommandOutput::Count(added));
+            }
+            UserCommand::HashGet(cmd) => {
+                let Some((value, _)) = view(storage, wal, id, &cmd.0).await? else {
+                    return Ok(CommandOutput::NotFound(cmd.0));
+                };
+                let Value::Hash(mut fields) = value else {
+                    return Err(WRONG_TYPE.into());
+                };
+                return match fields.remove(&cmd.1) {
+                    Some(value) => Ok(CommandOutput::Found(value)),
+                    None => Ok(CommandOutput::FieldNotFound(cmd.1)),
+                };
+            }
+            UserCommand::HashDelete(cmd) => {
+                let fields: BTreeMap<_, _> = view_typed(storage, wal, id, &cmd.0).await?;
+                let removed = count_members(cmd.1.iter(), |x| fields.contains_key(x));
+                wal.mutate(id, Mutation::HashDelete(cmd)).unwrap();
+                return Ok(CommandOutput::Count(removed));
+            }
+            UserCommand::HashGetAll(cmd) => {
+                let fields: BTreeMap<_, _> = view_typed(storage, wal, id, &cmd.0).await?;
+                return Ok(CommandOutput::Fields(fields.into_iter().collect()));
+            }
+            UserCommand::HashKeys(cmd) => {
+                let fields: BTreeMap<_, _> = view_typed(storage, wal, id, &cmd.0).await?;
+                return Ok(CommandOutput::Items(fields.into_keys().collect()));
+            }
+            UserCommand::SortedSetAdd(cmd) => {
+                let members: SortedSet = view_typed(storage, wal, id, &cmd.0).await?;
+                let added =
+                    count_members(cmd.1.iter().map(|x| &x.1), |x| members.score(x).is_none());
+                wal.mutate(id, Mutation::SortedSetAdd(cmd)).unwrap();
+                return Ok(CommandOutput::Count(added));
+            }
+            UserCommand::SortedSetScore(cmd) => {
+                let Some((value, _)) = view(storage, wal, id, &cmd.0).await? else {
+                    return Ok(CommandOutput::NotFound(cmd.0));
+                };
+                let Value::SortedSet(members) = value else {
+                    return Err(WRONG_TYPE.into());
+                };
+                return match members.score(&cmd.1) {
+                    Some(score) => Ok(CommandOutput::Score(score)),
+                    None => Ok(CommandOutput::MemberNotFound(cmd.1)),
+                };
+            }
+            UserCommand::SortedSetRange(cmd) => {
+                let members: SortedSet = view_typed(storage, wal, id, &cmd.0).await?;
+                return Ok(CommandOutput::Members(members.range(cmd.1, cmd.2)));
+            }
+            UserCommand::SortedSetRangeByScore(cmd) => {
+                let members: SortedSet = view_typed(storage, wal, id, &cmd.0).await?;
+                let members = members.range_by_score(cmd.1, cmd.2);
+                return Ok(CommandOutput::Members(members));
+            }
+            UserCommand::SortedSetRemove(cmd) => {
+                let members: SortedSet = view_typed(storage, wal, id, &cmd.0).await?;
+                let removed = count_members(cmd.1.iter(), |x| members.score(x).is_some());
+                wal.mutate(id, Mutation::SortedSetRemove(cmd)).unwrap();
+                return Ok(CommandOutput::Count(removed));
+            }
+            UserCommand::SetAdd(cmd) => {
+                let members: BTreeSet<_> = view_typed(storage, wal, id, &cmd.0).await?;
+                let added = count_members(cmd.1.iter(), |x| !members.contains(x));
+                wal.mutate(id, Mutation::SetAdd(cmd)).unwrap();
+                return Ok(CommandOutput::Count(added));
+            }
+            UserCommand::SetRemove(cmd) => {
+                let members: BTreeSet<_> = view_typed(storage, wal, id, &cmd.0).await?;
+                let removed = count_members(cmd.1.iter(), |x| members.contains(x));
+                wal.mutate(id, Mutation::SetRemove(cmd)).unwrap();
+                return Ok(CommandOutput::Count(removed));
+            }
+            UserCommand::SetIsMember(cmd) => {
+                let members: BTreeSet<_> = view_typed(storage, wal, id, &cmd.0).await?;
+                return Ok(CommandOutput::IsMember(members.contains(&cmd.1)));
+            }
+            UserCommand::SetMembers(cmd) => {
+                let members: BTreeSet<_> = view_typed(storage, wal, id, &cmd.0).await?;
+                return Ok(CommandOutput::Items(members.into_iter().collect()));
+            }
+            UserCommand::SetCard(cmd) => {
+                let members: BTreeSet<_> = view_typed(storage, wal, id, &cmd.0).await?;
+                return Ok(CommandOutput::Length(members.len() as u64));
+            }
+            UserCommand::SetCombine(cmd) => {
+                let mut sets = vec![];
+                for key in &cmd.1 {
+                    let members: BTreeSet<_> = view_typed(storage, wal, id, key).await?;
+                    sets.push(members);
+                }
+                let members = cmd.0.apply(sets);
+                return Ok(CommandOutput::Items(members.into_iter().collect()));
+            }
+            UserCommand::FlushDb => {
+                wal.mutate(id, Mutation::FlushDb).unwrap();
+                return Ok(CommandOutput::FlushDb);
             }
             _ => {}
         };
@@ -46,16 +278,109 @@ pub async fn execute_command(
         UserCommand::Get(cmd) => storage.handle_cmd(StorageCommand::Get(cmd)).await,
         UserCommand::Put(cmd) => storage.handle_cmd(StorageCommand::Put(cmd)).await,
         UserCommand::Delete(cmd) => storage.handle_cmd(StorageCommand::Delete(cmd)).await,
+        UserCommand::Keys(cmd) => storage.handle_cmd(StorageCommand::Keys(cmd)).await,
+        UserCommand::Scan(cmd) => storage.handle_cmd(StorageCommand::Scan(cmd)).await,
+        UserCommand::Backup(cmd) => storage.handle_cmd(StorageCommand::Backup(cmd)).await,
+        UserCommand::Expire(cmd) => storage.handle_cmd(StorageCommand::Expire(cmd)).await,
+        UserCommand::Ttl(cmd) => storage.handle_cmd(StorageCommand::Ttl(cmd)).await,
+        UserCommand::Incr(cmd) => {
+            let value = execute_incr(storage, wal, cmd, transaction_id).await?;
+            Ok(CommandOutput::Integer(value))
+        }
+        UserCommand::PutIf(cmd) => storage.handle_cmd(StorageCommand::PutIf(cmd)).await,
+        UserCommand::Cas(cmd) => storage.handle_cmd(StorageCommand::Cas(cmd)).await,
+        UserCommand::GetDel(cmd) => storage.handle_cmd(StorageCommand::GetDel(cmd)).await,
+        UserCommand::GetSet(cmd) => storage.handle_cmd(StorageCommand::GetSet(cmd)).await,
+        UserCommand::MGet(cmd) => storage.handle_cmd(StorageCommand::MGet(cmd)).await,
+        UserCommand::MSet(cmd) => storage.handle_cmd(StorageCommand::MSet(cmd)).await,
+        UserCommand::MDelete(cmd) => storage.handle_cmd(StorageCommand::MDelete(cmd)).await,
+        UserCommand::Exists(cmd) => storage.handle_cmd(StorageCommand::Exists(cmd)).await,
+        UserCommand::Rename(cmd) => storage.handle_cmd(StorageCommand::Rename(cmd)).await,
+        UserCommand::Copy(cmd) => storage.handle_cmd(StorageCommand::Copy(cmd)).await,
+        UserCommand::DbSize => storage.handle_cmd(StorageCommand::DbSize).await,
+        UserCommand::FlushDb => storage.handle_cmd(StorageCommand::FlushDb).await,
+        UserCommand::ListPush(cmd) => storage.handle_cmd(StorageCommand::ListPush(cmd)).await,
+        UserCommand::ListPop(cmd) => storage.handle_cmd(StorageCommand::ListPop(cmd)).await,
+        UserCommand::ListRange(cmd) => storage.handle_cmd(StorageCommand::ListRange(cmd)).await,
+        UserCommand::ListLen(cmd) => storage.handle_cmd(StorageCommand::ListLen(cmd)).await,
+        UserCommand::HashSet(cmd) => storage.handle_cmd(StorageCommand::HashSet(cmd)).await,
+        UserCommand::HashGet(cmd) => storage.handle_cmd(StorageCommand::HashGet(cmd)).await,
+        UserCommand::HashDelete(cmd) => storage.handle_cmd(StorageCommand::HashDelete(cmd)).await,
+        UserCommand::HashGetAll(cmd) => storage.handle_cmd(StorageCommand::HashGetAll(cmd)).await,
+        UserCommand::HashKeys(cmd) => storage.handle_cmd(StorageCommand::HashKeys(cmd)).await,
+        UserCommand::SortedSetAdd(cmd) => {
+            storage.handle_cmd(StorageCommand::SortedSetAdd(cmd)).await
+        }
+        UserCommand::SortedSetScore(cmd) => {
+            storage
+                .handle_cmd(StorageCommand::SortedSetScore(cmd))
+                .await
+        }
+        UserCommand::SortedSetRange(cmd) => {
+            storage
+                .handle_cmd(StorageCommand::SortedSetRange(cmd))
+                .await
+        }
+        UserCommand::SortedSetRangeByScore(cmd) => {
+            storage
+                .handle_cmd(StorageCommand::SortedSetRangeByScore(cmd))
+                .await
+        }
+        UserCommand::SortedSetRemove(cmd) => {
+            storage
+                .handle_cmd(StorageCommand::SortedSetRemove(cmd))
+                .await
+        }
+        UserCommand::SetAdd(cmd) => storage.handle_cmd(StorageCommand::SetAdd(cmd)).await,
+        UserCommand::SetRemove(cmd) => storage.handle_cmd(StorageCommand::SetRemove(cmd)).await,
+        UserCommand::SetIsMember(cmd) => storage.handle_cmd(StorageCommand::SetIsMember(cmd)).await,
+        UserCommand::SetMembers(cmd) => storage.handle_cmd(StorageCommand::SetMembers(cmd)).await,
+        UserCommand::SetCard(cmd) => storage.handle_cmd(StorageCommand::SetCard(cmd)).await,
+        UserCommand::SetCombine(cmd) => storage.handle_cmd(StorageCommand::SetCombine(cmd)).await,
+        UserCommand::Stats => storage.handle_cmd(StorageCommand::Stats).await,
         UserCommand::Exit => storage.handle_cmd(StorageCommand::Flush).await,
         UserCommand::Begin => Ok(CommandOutput::Begin(wal.begin())),
         UserCommand::Commit => {
-            let muts = wal
-                .retrieve_mutations(transaction_id.unwrap_or(""))
-                .ok_or("Not in a transaction")?;
+            let id = transaction_id.unwrap_or("");
+            // An increment fails when another client changed its key since it was buffered, so
+            // every increment is checked first and a commit which would fail writes nothing
+            let keys = wal.incremented_keys(id).ok_or("Not in a transaction")?;
+            for key in keys {
+                let committed = storage.get_value(&key).await?;
+                wal.check_increments(id, &key, committed)?;
+            }
+            let muts = wal.retrieve_mutations(id).unwrap();
             for m in muts {
                 match m {
                     Mutation::Put(c) => storage.handle_cmd(StorageCommand::Put(c)).await?,
                     Mutation::Delete(c) => storage.handle_cmd(StorageCommand::Delete(c)).await?,
+                    Mutation::Expire(c) => storage.handle_cmd(StorageCommand::Expire(c)).await?,
+                    Mutation::Rename(c) => storage.handle_cmd(StorageCommand::Rename(c)).await?,
+                    Mutation::FlushDb => storage.handle_cmd(StorageCommand::FlushDb).await?,
+                    Mutation::ListPush(c) => {
+                        storage.handle_cmd(StorageCommand::ListPush(c)).await?
+                    }
+                    Mutation::ListPop(c) => storage.handle_cmd(StorageCommand::ListPop(c)).await?,
+                    Mutation::Copy(c) => storage.handle_cmd(StorageCommand::Copy(c)).await?,
+                    Mutation::HashSet(c) => storage.handle_cmd(StorageCommand::HashSet(c)).await?,
+                    Mutation::HashDelete(c) => {
+                        storage.handle_cmd(StorageCommand::HashDelete(c)).await?
+                    }
+                    Mutation::SortedSetAdd(c) => {
+                        storage.handle_cmd(StorageCommand::SortedSetAdd(c)).await?
+                    }
+                    Mutation::SortedSetRemove(c) => {
+                        storage
+                            .handle_cmd(StorageCommand::SortedSetRemove(c))
+                            .await?
+                    }
+                    Mutation::SetAdd(c) => storage.handle_cmd(StorageCommand::SetAdd(c)).await?,
+                    Mutation::SetRemove(c) => {
+                        storage.handle_cmd(StorageCommand::SetRemove(c)).await?
+                    }
+                    Mutation::Incr(c) => storage.handle_cmd(StorageCommand::Incr(c)).await?,
+                    Mutation::PutIf(c) => storage.handle_cmd(StorageCommand::PutIf(c)).await?,
+                    Mutation::Cas(c) => storage.handle_cmd(StorageCommand::Cas(c)).await?,
                 };
             }
             Ok(CommandOutput::Commit)
@@ -67,3 +392,545 @@ pub async fn execute_command(
         }
     }
 }
+
+/// The value of the key as seen from inside the transaction, see `Wal::view`
+async fn view(
+    storage: &mut HashStorage,
+    wal: &Wal,
+    id: &str,
+    key: &[u8],
+) -> Result<Option<(Value, Option<Timestamp>)>, String> {
+    let committed = storage.get_value(key).await?;
+    Ok(wal.view(id, key, committed))
+}
+
+/// Adds the amount to the counter under the key, returning its new value
+///
+/// Inside a transaction the result is worked out from the view, but the increment itself is
+/// buffered and applied to the value the key holds when the transaction commits, so increments
+/// from other clients in between aren't lost.
+pub async fn execute_incr(
+    storage: &mut HashStorage,
+    wal: &mut Wal,
+    cmd: IncrCommand,
+    transaction_id: Option<&str>,
+) -> Result<i64, IncrError> {
+    let Some(id) = transaction_id else {
+        return storage.incr(cmd).await;
+    };
+    let entry = view_string(storage, wal, id, &cmd.0)
+        .await
+        .map_err(IncrError::Storage)?;
+    let value = increment(entry.as_ref().map(|x| x.0.as_slice()), cmd.1)?;
+    wal.mutate(id, Mutation::Incr(cmd)).unwrap();
+    Ok(value)
+}
+
+/// The string value of the key as seen from inside the transaction, a key holding another type
+/// is an error
+async fn view_string(
+    storage: &mut HashStorage,
+    wal: &Wal,
+    id: &str,
+    key: &[u8],
+) -> Result<Option<(Vec<u8>, Option<Timestamp>)>, String> {
+    match view(storage, wal, id, key).await? {
+        Some((Value::String(value), expires_at)) => Ok(Some((value, expires_at))),
+        Some(_) => Err(WRONG_TYPE.into()),
+        None => Ok(None),
+    }
+}
+
+/// The list, hash, sorted set or set under the key as seen from inside the transaction, empty
+/// when the key is missing and an error when it holds another type
+async fn view_typed<T: TryFrom<Value> + Default>(
+    storage: &mut HashStorage,
+    wal: &Wal,
+    id: &str,
+    key: &[u8],
+) -> Result<T, String> {
+    match view(storage, wal, id, key).await? {
+        Some((value, _)) => T::try_from(value).map_err(|_| WRONG_TYPE.into()),
+        None => Ok(T::default()),
+    }
+}
+
+/// The number of members named by a command which pass the check, a member named more than once
+/// is counted once
+fn count_members<'a>(
+    members: impl Iterator<Item = &'a Vec<u8>>,
+    check: impl Fn(&Vec<u8>) -> bool,
+) -> u64 {
+    let members: HashSet<_> = members.filter(|x| check(x)).collect();
+    members.len() as u64
+}
+
+/// Buffers the mutations which store the value under the key, replacing the value it held
+///
+/// A list, hash, sorted set or set is rebuilt from its items, fields or members rather than buffered as a
+/// `Mutation::Copy`, so the view of the key doesn't depend on another key
+fn buffer_value(
+    wal: &mut Wal,
+    id: &str,
+    key: Vec<u8>,
+    value: Value,
+    expires_at: Option<Timestamp>,
+) {
+    let rebuild = match value {
+        Value::String(value) => {
+            let put = PutCommand(key, value, expires_at);
+            wal.mutate(id, Mutation::Put(put)).unwrap();
+            return;
+        }
+        Value::List(items) => {
+            let push = ListPushCommand(key.clone(), ListSide::Right, items.into());
+            Mutation::ListPush(push)
+        }
+        Value::Hash(fields) => {
+            let set = HashSetCommand(key.clone(), fields.into_iter().collect());
+            Mutation::HashSet(set)
+        }
+        Value::SortedSet(members) => {
+            let members = members.iter().map(|(m, score)| (score, m.clone()));
+            let add = SortedSetAddCommand(key.clone(), members.collect());
+            Mutation::SortedSetAdd(add)
+        }
+        Value::Set(members) => {
+            let add = SetAddCommand(key.clone(), members.into_iter().collect());
+            Mutation::SetAdd(add)
+        }
+    };
+    wal.mutate(id, Mutation::Delete(DeleteCommand(key.clone())))
+        .unwrap();
+    wal.mutate(id, rebuild).unwrap();
+    if expires_at.is_some() {
+        let expire = ExpireCommand(key, expires_at);
+        wal.mutate(id, Mutation::Expire(expire)).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_execute {
+    use super::*;
+    use crate::hash_storage::HashStorageOptions;
+    use crate::sorted_set::Score;
+    use crate::test::*;
+
+    async fn get_storage(test_prefix: &str) -> HashStorage {
+        let data_path = format!("./test_data/{}_data.db", test_prefix);
+        let dir_path = format!("./test_data/{}_dir.db", test_prefix);
+        reset_or_create_file(&data_path);
+        reset_or_create_file(&dir_path);
+        HashStorage::new(&dir_path, &data_path, HashStorageOptions::default())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn deletes_in_transactions() {
+        let mut storage = get_storage("execute_deletes_in_transactions").await;
+        let mut wal = Wal::new();
+        for input in ["PUT a \"1\"", "PUT b \"2\""] {
+            execute_user_input(&mut storage, &mut wal, input, None)
+                .await
+                .unwrap();
+        }
+
+        let id = wal.begin();
+        let id = Some(id.as_str());
+        let outputs = [
+            ("DELETE a", CommandOutput::Deleted),
+            ("DELETE a", CommandOutput::NotFound(b"a".to_vec())),
+            (
+                "DELETE missing",
+                CommandOutput::NotFound(b"missing".to_vec()),
+            ),
+            ("PUT c \"3\"", CommandOutput::Put),
+            ("EXPIRE c 60", CommandOutput::Expire),
+            ("PERSIST c", CommandOutput::Persist),
+            ("EXPIRE a 60", CommandOutput::NotFound(b"a".to_vec())),
+            (
+                "PERSIST missing",
+                CommandOutput::NotFound(b"missing".to_vec()),
+            ),
+            ("MDELETE a b c missing", CommandOutput::DeletedKeys(2)),
+        ];
+        for (input, output) in outputs {
+            assert_eq!(
+                execute_user_input(&mut storage, &mut wal, input, id).await,
+                Ok(output)
+            );
+        }
+        // Nothing is deleted before the commit
+        assert_eq!(
+            execute_user_input(&mut storage, &mut wal, "GET b", None).await,
+            Ok(CommandOutput::Found(b"2".to_vec()))
+        );
+
+        execute_user_input(&mut storage, &mut wal, "COMMIT", id)
+            .await
+            .unwrap();
+        for key in ["a", "b", "c"] {
+            assert_eq!(
+                execute_command(
+                    &mut storage,
+                    &mut wal,
+                    UserCommand::Exists(ExistsCommand(key.into())),
+                    None
+                )
+                .await,
+                Ok(CommandOutput::Exists(false))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn increments_in_transactions() {
+        let mut storage = get_storage("execute_increments_in_transactions").await;
+        let mut wal = Wal::new();
+        for input in ["PUT count \"10\"", "PUT name \"bob\""] {
+            execute_user_input(&mut storage, &mut wal, input, None)
+                .await
+                .unwrap();
+        }
+
+        let id = wal.begin();
+        let id = Some(id.as_str());
+        assert_eq!(
+            execute_user_input(&mut storage, &mut wal, "INCR count", id).await,
+            Ok(CommandOutput::Integer(11))
+        );
+        assert_eq!(
+            execute_user_input(&mut storage, &mut wal, "INCRBY count 5", id).await,
+            Ok(CommandOutput::Integer(16))
+        );
+        let incr = |key: &str| IncrCommand(key.into(), 1);
+        assert_eq!(
+            execute_incr(&mut storage, &mut wal, incr("name"), id).await,
+            Err(IncrError::NotAnInteger)
+        );
+
+        // Another client increments the counter before the commit, neither increment is lost
+        assert_eq!(
+            execute_incr(&mut storage, &mut wal, incr("count"), None).await,
+            Ok(11)
+        );
+        execute_user_input(&mut storage, &mut wal, "COMMIT", id)
+            .await
+            .unwrap();
+        assert_eq!(
+            execute_user_input(&mut storage, &mut wal, "GET count", None).await,
+            Ok(CommandOutput::Found(b"17".to_vec()))
+        );
+    }
+
+    /// Another client stores a value which isn't an integer under the counter before the
+    /// commit, so the commit fails and none of the writes of the transaction land
+    #[tokio::test]
+    async fn failed_increments_fail_the_commit() {
+        let mut storage = get_storage("execute_failed_increments_fail_the_commit").await;
+        let mut wal = Wal::new();
+        execute_user_input(&mut storage, &mut wal, "PUT count \"1\"", None)
+            .await
+            .unwrap();
+
+        let id = wal.begin();
+        let id = Some(id.as_str());
+        for input in ["PUT name \"bob\"", "INCR count", "RPUSH list \"a\""] {
+            execute_user_input(&mut storage, &mut wal, input, id)
+                .await
+                .unwrap();
+        }
+        execute_user_input(&mut storage, &mut wal, "PUT count \"x\"", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            execute_user_input(&mut storage, &mut wal, "COMMIT", id).await,
+            Err(IncrError::NotAnInteger.to_string())
+        );
+        let outputs = [
+            ("GET name", CommandOutput::NotFound(b"name".to_vec())),
+            ("LLEN list", CommandOutput::Length(0)),
+            ("GET count", CommandOutput::Found(b"x".to_vec())),
+        ];
+        for (input, output) in outputs {
+            assert_eq!(
+                execute_user_input(&mut storage, &mut wal, input, None).await,
+                Ok(output)
+            );
+        }
+        // The transaction is still open, so it can be rolled back
+        assert_eq!(
+            execute_user_input(&mut storage, &mut wal, "ROLLBACK", id).await,
+            Ok(CommandOutput::Rollback)
+        );
+    }
+
+    #[tokio::test]
+    async fn conditional_updates_in_transactions() {
+        let mut storage = get_storage("execute_conditional_updates_in_transactions").await;
+        let mut wal = Wal::new();
+        for input in ["PUT a \"1\"", "PUT b \"1\"", "PUT c \"1\""] {
+            execute_user_input(&mut storage, &mut wal, input, None)
+                .await
+                .unwrap();
+        }
+
+        let id = wal.begin();
+        let id = Some(id.as_str());
+        let outputs = [
+            ("PUT new \"mine\" NX", CommandOutput::Put),
+            ("PUT new \"again\" NX", CommandOutput::NotPut),
+            ("PUT a \"mine\" XX", CommandOutput::Put),
+            ("CAS b \"1\" \"mine\"", CommandOutput::Swapped),
+            ("CAS c \"1\" \"mine\"", CommandOutput::Swapped),
+            ("CAS c \"1\" \"again\"", CommandOutput::NotSwapped),
+        ];
+        for (input, output) in outputs {
+            assert_eq!(
+                execute_user_input(&mut storage, &mut wal, input, id).await,
+                Ok(output)
+            );
+        }
+        assert_eq!(
+            execute_user_input(&mut storage, &mut wal, "GET c", id).await,
+            Ok(CommandOutput::Found(b"mine".to_vec()))
+        );
+
+        // Another client writes before the commit, so only the CAS of c still holds
+        for input in ["PUT new \"theirs\"", "DELETE a", "PUT b \"theirs\""] {
+            execute_user_input(&mut storage, &mut wal, input, None)
+                .await
+                .unwrap();
+        }
+        execute_user_input(&mut storage, &mut wal, "COMMIT", id)
+            .await
+            .unwrap();
+        let outputs = [
+            ("GET new", CommandOutput::Found(b"theirs".to_vec())),
+            ("GET a", CommandOutput::NotFound(b"a".to_vec())),
+            ("GET b", CommandOutput::Found(b"theirs".to_vec())),
+            ("GET c", CommandOutput::Found(b"mine".to_vec())),
+        ];
+        for (input, output) in outputs {
+            assert_eq!(
+                execute_user_input(&mut storage, &mut wal, input, None).await,
+                Ok(output)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_in_transactions() {
+        let mut storage = get_storage("execute_lists_in_transactions").await;
+        let mut wal = Wal::new();
+        for input in ["RPUSH list \"a\" \"b\"", "PUT string \"1\""] {
+            execute_user_input(&mut storage, &mut wal, input, None)
+                .await
+                .unwrap();
+        }
+        let items = |items: &[&str]| {
+            CommandOutput::Items(items.iter().map(|x| x.as_bytes().to_vec()).collect())
+        };
+
+        let id = wal.begin();
+        let id = Some(id.as_str());
+        let outputs = [
+            ("LPUSH list \"c\"", Ok(CommandOutput::Length(3))),
+            ("RPUSH list \"d\" \"e\"", Ok(CommandOutput::Length(5))),
+            ("LRANGE list 1 -2", Ok(items(&["a", "b", "d"]))),
+            ("RPOP list", Ok(CommandOutput::Found(b"e".to_vec()))),
+            ("LPOP list", Ok(CommandOutput::Found(b"c".to_vec()))),
+            ("LLEN list", Ok(CommandOutput::Length(3))),
+            (
+                "LPOP missing",
+                Ok(CommandOutput::NotFound(b"missing".to_vec())),
+            ),
+            ("LPUSH string \"x\"", Err(WRONG_TYPE.to_string())),
+        ];
+        for (input, output) in outputs {
+            assert_eq!(
+                execute_user_input(&mut storage, &mut wal, input, id).await,
+                output
+            );
+        }
+
+        // Another client pushes before the commit, the pushes and pops of the transaction are
+        // applied to the list as it is then
+        execute_user_input(&mut storage, &mut wal, "RPUSH list \"f\"", None)
+            .await
+            .unwrap();
+        execute_user_input(&mut storage, &mut wal, "COMMIT", id)
+            .await
+            .unwrap();
+        assert_eq!(
+            execute_user_input(&mut storage, &mut wal, "LRANGE list 0 -1", None).await,
+            Ok(items(&["a", "b", "f", "d"]))
+        );
+    }
+
+    #[tokio::test]
+    async fn hashes_in_transactions() {
+        let mut storage = get_storage("execute_hashes_in_transactions").await;
+        let mut wal = Wal::new();
+        for input in ["HSET user name \"Ann\" age \"41\"", "PUT string \"1\""] {
+            execute_user_input(&mut storage, &mut wal, input, None)
+                .await
+                .unwrap();
+        }
+
+        let id = wal.begin();
+        let id = Some(id.as_str());
+        let outputs = [
+            ("HSET user age \"42\"", Ok(CommandOutput::Count(0))),
+            ("HDEL user name", Ok(CommandOutput::Count(1))),
+            ("HSET user name \"Bo\"", Ok(CommandOutput::Count(1))),
+            (
+                "HSET user nick \"a\" nick \"b\"",
+                Ok(CommandOutput::Count(1)),
+            ),
+            ("HGET user nick", Ok(CommandOutput::Found(b"b".to_vec()))),
+            ("HGET string nick", Err(WRONG_TYPE.to_string())),
+        ];
+        for (input, output) in outputs {
+            assert_eq!(
+                execute_user_input(&mut storage, &mut wal, input, id).await,
+                output
+            );
+        }
+
+        // Another client sets a field before the commit, it is kept along with the fields of
+        // the transaction
+        execute_user_input(&mut storage, &mut wal, "HSET user phone \"555\"", None)
+            .await
+            .unwrap();
+        execute_user_input(&mut storage, &mut wal, "COMMIT", id)
+            .await
+            .unwrap();
+        let fields = [
+            ("age", "42"),
+            ("name", "Bo"),
+            ("nick", "b"),
+            ("phone", "555"),
+        ];
+        let fields = fields
+            .iter()
+            .map(|(f, v)| (f.as_bytes().to_vec(), v.as_bytes().to_vec()));
+        assert_eq!(
+            execute_user_input(&mut storage, &mut wal, "HGETALL user", None).await,
+            Ok(CommandOutput::Fields(fields.collect()))
+        );
+    }
+
+    #[tokio::test]
+    async fn sorted_sets_in_transactions() {
+        let mut storage = get_storage("execute_sorted_sets_in_transactions").await;
+        let mut wal = Wal::new();
+        for input in ["ZADD board 3 ann 1 bob", "PUT string \"1\""] {
+            execute_user_input(&mut storage, &mut wal, input, None)
+                .await
+                .unwrap();
+        }
+        let members = |members: &[(&str, f64)]| {
+            let members = members
+                .iter()
+                .map(|(m, s)| (m.as_bytes().to_vec(), Score::new(*s).unwrap()));
+            CommandOutput::Members(members.collect())
+        };
+
+        let id = wal.begin();
+        let id = Some(id.as_str());
+        let outputs = [
+            // Moving a member to a lower score moves it ahead of the others
+            ("ZADD board 0 ann", Ok(CommandOutput::Count(0))),
+            (
+                "ZRANGE board 0 -1",
+                Ok(members(&[("ann", 0.0), ("bob", 1.0)])),
+            ),
+            ("ZADD board 2 cid", Ok(CommandOutput::Count(1))),
+            (
+                "ZRANGE board -2 -1",
+                Ok(members(&[("bob", 1.0), ("cid", 2.0)])),
+            ),
+            (
+                "ZRANGEBYSCORE board -inf 1",
+                Ok(members(&[("ann", 0.0), ("bob", 1.0)])),
+            ),
+            ("ZREM board bob bob", Ok(CommandOutput::Count(1))),
+            ("ZSCORE string ann", Err(WRONG_TYPE.to_string())),
+        ];
+        for (input, output) in outputs {
+            assert_eq!(
+                execute_user_input(&mut storage, &mut wal, input, id).await,
+                output
+            );
+        }
+
+        // Another client adds a member before the commit, it is ranked among the members of the
+        // transaction
+        execute_user_input(&mut storage, &mut wal, "ZADD board 1.5 dan", None)
+            .await
+            .unwrap();
+        execute_user_input(&mut storage, &mut wal, "COMMIT", id)
+            .await
+            .unwrap();
+        assert_eq!(
+            execute_user_input(&mut storage, &mut wal, "ZRANGE board 0 -1", None).await,
+            Ok(members(&[("ann", 0.0), ("dan", 1.5), ("cid", 2.0)]))
+        );
+    }
+
+    #[tokio::test]
+    async fn sets_in_transactions() {
+        let mut storage = get_storage("execute_sets_in_transactions").await;
+        let mut wal = Wal::new();
+        for input in ["SADD tags red blue", "SADD other blue", "PUT string \"1\""] {
+            execute_user_input(&mut storage, &mut wal, input, None)
+                .await
+                .unwrap();
+        }
+        let items = |members: &[&str]| {
+            CommandOutput::Items(members.iter().map(|x| x.as_bytes().to_vec()).collect())
+        };
+
+        let id = wal.begin();
+        let id = Some(id.as_str());
+        let outputs = [
+            ("SADD tags green", Ok(CommandOutput::Count(1))),
+            // Removing the last member deletes the set
+            ("SREM other blue", Ok(CommandOutput::Count(1))),
+            ("EXISTS other", Ok(CommandOutput::Exists(false))),
+            ("SINTER tags other", Ok(items(&[]))),
+            ("SDIFF tags other", Ok(items(&["blue", "green", "red"]))),
+            ("RENAME tags colors", Ok(CommandOutput::Renamed)),
+            ("SISMEMBER tags green", Ok(CommandOutput::IsMember(false))),
+            ("SCARD colors", Ok(CommandOutput::Length(3))),
+            ("SUNION colors string", Err(WRONG_TYPE.to_string())),
+        ];
+        for (input, output) in outputs {
+            assert_eq!(
+                execute_user_input(&mut storage, &mut wal, input, id).await,
+                output
+            );
+        }
+        // The sets are unchanged before the commit
+        assert_eq!(
+            execute_user_input(&mut storage, &mut wal, "SMEMBERS tags", None).await,
+            Ok(items(&["blue", "red"]))
+        );
+
+        execute_user_input(&mut storage, &mut wal, "COMMIT", id)
+            .await
+            .unwrap();
+        for (input, output) in [
+            ("SMEMBERS colors", items(&["blue", "green", "red"])),
+            ("EXISTS tags", CommandOutput::Exists(false)),
+            ("EXISTS other", CommandOutput::Exists(false)),
+        ] {
+            assert_eq!(
+                execute_user_input(&mut storage, &mut wal, input, None).await,
+                Ok(output)
+            );
+        }
+    }
+}
@@ -0,0 +1,142 @@
+/// Whether the text matches the glob pattern, used to filter keys
+///
+/// - `*` matches any number of bytes
+/// - `?` matches a single byte
+/// - `[abc]` matches one of the listed bytes, `[a-z]` a range of bytes and `[^abc]` or `[!abc]`
+///   any byte which isn't listed
+/// - `\` matches the byte after it literally
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+    // The position of the last `*` and of the text it has matched up to, used to retry with the
+    // `*` matching one more byte when the rest of the pattern stops matching
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() {
+            if pattern[p] == b'*' {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            if let Some(len) = match_byte(&pattern[p..], text[t]) {
+                p += len;
+                t += 1;
+                continue;
+            }
+        }
+        match backtrack {
+            Some((star, matched)) => {
+                backtrack = Some((star, matched + 1));
+                p = star + 1;
+                t = matched + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|x| *x == b'*')
+}
+
+/// Matches the byte against the element at the start of the pattern
+///
+/// # Returns
+/// - The length of the element in the pattern if the byte matches it
+fn match_byte(pattern: &[u8], byte: u8) -> Option<usize> {
+    match pattern[0] {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == byte).then_some(2),
+        b'[' => match_class(pattern, byte),
+        x => (x == byte).then_some(1),
+    }
+}
+
+/// Matches the byte against the `[...]` class at the start of the pattern, a class which is
+/// never closed is matched as a literal `[`
+fn match_class(pattern: &[u8], byte: u8) -> Option<usize> {
+    let negated = matches!(pattern.get(1), Some(b'^' | b'!'));
+    let start = if negated { 2 } else { 1 };
+    let mut matched = false;
+    let mut i = start;
+
+    while let Some(x) = pattern.get(i) {
+        // A `]` straight after the opening bracket is part of the class
+        if *x == b']' && i > start {
+            return (matched != negated).then_some(i + 1);
+        }
+        let (low, next) = escaped_byte(pattern, i);
+        let is_range =
+            pattern.get(next) == Some(&b'-') && pattern.get(next + 1).is_some_and(|x| *x != b']');
+        if is_range {
+            let (high, after) = escaped_byte(pattern, next + 1);
+            matched |= (low.min(high)..=low.max(high)).contains(&byte);
+            i = after;
+        } else {
+            matched |= low == byte;
+            i = next;
+        }
+    }
+    (byte == b'[').then_some(1)
+}
+
+/// Reads the byte at `i` in a class, which may be escaped with `\`
+///
+/// # Returns
+/// - The byte and the position after it
+fn escaped_byte(pattern: &[u8], i: usize) -> (u8, usize) {
+    match pattern.get(i + 1) {
+        Some(x) if pattern[i] == b'\\' => (*x, i + 2),
+        _ => (pattern[i], i + 1),
+    }
+}
+
+#[cfg(test)]
+mod test_glob {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:1"));
+        assert!(!matches("user:*", "users:1"));
+        assert!(matches("*:name", "user:1:name"));
+        assert!(matches("u*r*e", "user:one"));
+        assert!(!matches("u*r*e", "user:one:x"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("key", "key"));
+        assert!(!matches("key", "keys"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(matches("h[c-a]llo", "hbllo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[!e]llo", "hello"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        // Never closed so it is a literal
+        assert!(matches("[ab", "[ab"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("a\\", "a\\"));
+    }
+
+    #[test]
+    fn binary_text() {
+        assert!(glob_match(b"\x00*\xff", &[0, 1, 2, 255]));
+        assert!(!glob_match(b"\x00?", &[0, 1, 2]));
+    }
+}
@@ -1,197 +1,492 @@
-use crate::bytes::{ByteLength, IntoBytes, ParseFromBytes};
+use crate::backup::{create_backup_file, FileBackup, BACKUP_STEP_CHUNKS};
+use crate::bytes::{read_varint, varint_len, write_varint, ByteLength, IntoBytes, ParseFromBytes};
 use crate::command::*;
-use std::hash::{Hash as _, Hasher};
-use std::io::SeekFrom;
+use crate::compression::{compress, decompress};
+use crate::directory::{Directory, DIRECTORY_GROWTH_PAGES};
+use crate::encryption::{BlockFile, EncryptionKey};
+use crate::glob::glob_match;
+use crate::mutation_log::{MutationLog, MutationLogOptions, BACKUP_LSN_FILE_NAME};
+use crate::quota::{EvictionPolicy, Quota, QuotaOptions, EVICTION_SAMPLE_BUCKETS};
+use crate::sorted_set::{Score, SortedSet};
+use crate::value_log::{SegmentId, ValueLog, ValueLogEntry, ValuePointer};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::hash::Hasher;
 use std::mem::size_of;
+use std::path::{Path, PathBuf};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use twox_hash::XxHash64;
 
-fn take_bytes_from_iterator<'a, T: Iterator<Item = &'a u8>, const N: usize>(
+pub(crate) fn take_bytes_from_iterator<'a, T: Iterator<Item = &'a u8>, const N: usize>(
     bytes: &mut T,
 ) -> [u8; N] {
     let mut buf = [0; N];
-    for i in 0..N {
-        buf[i] = *bytes.next().unwrap();
+    for byte in buf.iter_mut() {
+        *byte = *bytes.next().unwrap();
     }
     buf
 }
 
-/// Number of bytes in a page
-const PAGE_BYTES: usize = 4096;
+/// Number of bytes in a page unless another page size is chosen when the database is created,
+/// see `HashStorageOptions::page_bytes`
+pub(crate) const DEFAULT_PAGE_BYTES: usize = 4096;
+
+/// The smallest page size a database can be created with
+pub const MIN_PAGE_BYTES: usize = 4096;
+
+/// The largest page size a database can be created with
+pub const MAX_PAGE_BYTES: usize = 64 * 1024;
 
 /// Type representing a hash in the hash table
-type Hash = u64;
+pub(crate) type Hash = u64;
 
 /// The length in bytes of a hash
-const HASH_BYTES: usize = size_of::<Hash>();
+pub(crate) const HASH_BYTES: usize = size_of::<Hash>();
 
 /// The type used to represent the level of a bucket in the hash table
-type BucketLevel = u8;
+pub(crate) type BucketLevel = u8;
 
 /// The length in bytes of `BucketLevel`
-const BUCKET_LEVEL_BYTES: usize = size_of::<BucketLevel>();
+pub(crate) const BUCKET_LEVEL_BYTES: usize = size_of::<BucketLevel>();
 
 /// Type used for indexing and counting the number of buckets in the hash table
 ///
 /// The reason this is usize is because we store the bucket index in a vec which is indexed by
 /// usize so the largest bucket count we can have is the largest usize
-type BucketIndexType = usize;
+pub(crate) type BucketIndexType = usize;
 
 /// The length in bytes of `BucketIndexType`
-const BUCKET_INDEX_TYPE_BYTES: usize = size_of::<BucketIndexType>();
+pub(crate) const BUCKET_INDEX_TYPE_BYTES: usize = size_of::<BucketIndexType>();
 
-/// Returns a hash from a string key
-fn hash_string_key(key: &str) -> Hash {
+/// Returns the hash of a key
+///
+/// Keys are hashed the same way as the `str` keys this used to take, followed by `0xff`, so
+/// existing files keep their layout
+pub(crate) fn hash_key(key: &[u8]) -> Hash {
     let mut hasher = XxHash64::with_seed(0);
-    key.hash(&mut hasher);
+    hasher.write(key);
+    hasher.write_u8(0xff);
     hasher.finish()
 }
 
-/// Given an length of the directory addresses derive the
-/// global level
+/// The last component of the path, used to name the copies of the files in a backup
+fn file_name(path: &Path) -> &std::ffi::OsStr {
+    path.file_name().expect("Database files are named")
+}
+
+/// The path the empty copy of a file is written to by a `FLUSHDB` before it replaces the file
+fn flush_temp_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".flush");
+    path.into()
+}
+
+/// The path of the file holding a `FLUSHDB` or `RENAME` which was started but may not have
+/// finished, see `HashStorage::finish_pending`
+fn pending_path(buckets_path: &Path) -> PathBuf {
+    let mut path = buckets_path.as_os_str().to_owned();
+    path.push(".pending");
+    path.into()
+}
+
+/// Writes the mutation to the pending file and syncs it, encrypted when there is a key
+///
+/// # File layout
+/// - The length of the mutation as a block holding a `u32` in LE
+/// - The mutation as a block, see `Mutation`
+async fn write_pending(path: &Path, mutation: Mutation, key: Option<&EncryptionKey>) {
+    let mut file = BlockFile::open(create_empty_file(path), key).await.unwrap();
+    let bytes = mutation.into_bytes();
+    let len = u32::try_from(bytes.len()).expect("Pending mutations are small");
+    file.write_block(0, &len.to_le_bytes()).await;
+    let position = file.block_len(size_of::<u32>()) as u64;
+    file.write_block(position, &bytes).await;
+    file.sync_all().await;
+}
+
+/// Reads the mutation from the pending file, see `write_pending`
 ///
-/// This should just be log base 2 of the global level or
-/// the position of most significant bit
-fn addr_count_to_global_level(mut length: usize) -> BucketLevel {
-    let mut result: BucketLevel = 0;
-    while length > 1 {
-        length = length >> 1;
-        result += 1;
+/// # Returns
+/// - `None` if there is no pending file, or it was cut short by a crash while it was written in
+///   which case the mutation hadn't started
+/// - An error if the file is encrypted and the key is wrong or missing
+async fn read_pending(
+    path: &Path,
+    key: Option<&EncryptionKey>,
+) -> Result<Option<Mutation>, String> {
+    let Ok(file) = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+    else {
+        return Ok(None);
+    };
+    let mut file = BlockFile::open(file.into(), key)
+        .await
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let Ok(Some(len)) = file.read_block(0, size_of::<u32>()).await else {
+        return Ok(None);
+    };
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    let position = file.block_len(size_of::<u32>()) as u64;
+    let Ok(Some(bytes)) = file.read_block(position, len).await else {
+        return Ok(None);
+    };
+    Ok(Mutation::from_bytes(bytes.iter(), ()).ok().map(|x| x.0))
+}
+
+/// Creates an empty file, truncating it if it exists
+fn create_empty_file(path: &Path) -> File {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap()
+        .into()
+}
+
+/// The length in bytes of the header at the start of the buckets file, the same on every target
+const HEADER_BYTES: usize = size_of::<u64>();
+
+/// The number of low bits of the buckets file header which hold the bucket count, the top byte
+/// holds the flags
+const BUCKET_COUNT_BITS: u32 = 56;
+
+/// Set in the buckets file header when it is followed by the key count, see the `buckets_file`
+/// field of `HashStorage`
+const KEY_COUNT_FLAG: u64 = 1 << 63;
+
+/// Set in the buckets file header when the key count is followed by the page size
+const PAGE_BYTES_FLAG: u64 = 1 << 62;
+
+/// The bits of the top byte of the buckets file header which held log2 of the page size before
+/// it got a field of its own
+const LEGACY_PAGE_BITS_MASK: u64 = 0x3f << BUCKET_COUNT_BITS;
+
+/// The length in bytes of the key count in the buckets file
+const KEY_COUNT_BYTES: usize = size_of::<u64>();
+
+/// The type used for the page size in the buckets file
+type PageBytesField = u32;
+
+/// The length in bytes of the page size in the buckets file
+const PAGE_BYTES_FIELD_BYTES: usize = size_of::<PageBytesField>();
+
+// Every page size a database can be created with fits the field
+const _: () = assert!(MAX_PAGE_BYTES <= PageBytesField::MAX as usize);
+
+/// Checks that a database can be created with the page size
+fn validate_page_bytes(page_bytes: usize) -> Result<(), String> {
+    if !page_bytes.is_power_of_two() || !(MIN_PAGE_BYTES..=MAX_PAGE_BYTES).contains(&page_bytes) {
+        return Err(format!(
+            "Page size must be a power of 2 between {} and {} bytes, got {}",
+            MIN_PAGE_BYTES, MAX_PAGE_BYTES, page_bytes
+        ));
+    }
+    Ok(())
+}
+
+/// The file containing the buckets, see the `buckets_file` field of `HashStorage` for the layout
+struct BucketsFile {
+    file: BlockFile,
+
+    /// The number of bytes in every bucket, recorded in the header of the file
+    page_bytes: usize,
+
+    /// Whether the header is followed by the key count, which files written before the key
+    /// count was recorded aren't
+    has_key_count: bool,
+
+    /// Whether the key count is followed by the page size, files written before the page size
+    /// had a field of its own keep it in the top byte of the header
+    has_page_bytes: bool,
+}
+
+impl BucketsFile {
+    /// The largest record in bytes which can be stored, one which fits in any page of a bucket,
+    /// see `Bucket`
+    fn max_record_bytes(&self) -> usize {
+        self.page_bytes - BUCKET_HEADER_BYTES - BUCKET_LINK_BYTES
+    }
+
+    /// The position of the key count block in the buckets file
+    fn key_count_position(&self) -> u64 {
+        self.file.block_len(HEADER_BYTES) as u64
+    }
+
+    /// The position of the page size block in the buckets file
+    fn page_bytes_position(&self) -> u64 {
+        self.key_count_position() + self.file.block_len(KEY_COUNT_BYTES) as u64
+    }
+
+    /// The position of the bucket in the buckets file
+    fn bucket_position(&self, bucket_index: BucketIndexType) -> u64 {
+        let mut header_len = self.file.block_len(HEADER_BYTES);
+        if self.has_key_count {
+            header_len += self.file.block_len(KEY_COUNT_BYTES);
+        }
+        if self.has_page_bytes {
+            header_len += self.file.block_len(PAGE_BYTES_FIELD_BYTES);
+        }
+        let page_len = self.file.block_len(self.page_bytes);
+        header_len as u64 + bucket_index as u64 * page_len as u64
     }
-    return result;
 }
 
-/// Reads the bucket count from the "buckets file" of the hash table, see the `buckets_file` field of `HashStorage`
-/// for more details
-async fn load_buckets_file(buckets_file: &mut File) -> BucketIndexType {
-    if buckets_file.metadata().await.unwrap().len() == 0 {
+/// Reads the bucket count, key count and page size from the "buckets file" of the hash table, see
+/// the `buckets_file` field of `HashStorage` for more details
+///
+/// An empty file is set up with a single empty bucket of `page_bytes`
+///
+/// # Returns
+/// - The key count is `None` for files written before it was recorded
+/// - An error if the bucket count can't be read, which is how a wrong encryption key shows
+async fn load_buckets_file(
+    file: BlockFile,
+    page_bytes: usize,
+) -> Result<(BucketsFile, BucketIndexType, Option<u64>), String> {
+    if file.len().await == 0 {
+        validate_page_bytes(page_bytes)?;
+        let mut buckets_file = BucketsFile {
+            file,
+            page_bytes,
+            has_key_count: true,
+            has_page_bytes: true,
+        };
+        let position = buckets_file.page_bytes_position();
+        buckets_file
+            .file
+            .write_block(position, &(page_bytes as PageBytesField).to_le_bytes())
+            .await;
         // setup the file by pushing an empty bucket to it
         let bucket = Bucket {
             records: vec![],
             level: 0,
             bucket_index: 0,
-            // Doesn't matter
-            remaining_byte_space: 0,
+            page_bytes,
+            overflow: vec![],
         };
-        bucket.save_to_file(buckets_file).await;
-        return 1;
+        bucket.save_to_file(&mut buckets_file).await;
+        save_buckets_file(1, 0, &mut buckets_file).await;
+        return Ok((buckets_file, 1, Some(0)));
     }
-    buckets_file.seek(SeekFrom::Start(0)).await.unwrap();
-    let mut buf = [0; BUCKET_INDEX_TYPE_BYTES];
-    buckets_file.read_exact(&mut buf).await.unwrap();
-    return BucketIndexType::from_le_bytes(buf);
-}
 
-/// Saves the bucket count into the "buckets file" of the hash table, see the `buckets_file` field of `HashStorage`
-/// for more details
-async fn save_buckets_file(bucket_count: BucketIndexType, buckets_file: &mut File) {
-    buckets_file.seek(SeekFrom::Start(0)).await.unwrap();
-    let buf = bucket_count.to_le_bytes();
-    return buckets_file.write_all(&buf).await.unwrap();
+    let mut file = file;
+    let buf = file
+        .read_block(0, HEADER_BYTES)
+        .await
+        .map_err(|e| format!("Failed to load the buckets file: {}", e))?
+        .ok_or("Failed to load the buckets file: the bucket count is missing")?;
+    let header = u64::from_le_bytes(buf.try_into().unwrap());
+    let bucket_count = (header & ((1 << BUCKET_COUNT_BITS) - 1))
+        .try_into()
+        .map_err(|_| "Failed to load the buckets file: too many buckets for this target")?;
+    let mut buckets_file = BucketsFile {
+        file,
+        page_bytes: DEFAULT_PAGE_BYTES,
+        has_key_count: header & KEY_COUNT_FLAG != 0,
+        has_page_bytes: header & PAGE_BYTES_FLAG != 0,
+    };
+    if buckets_file.has_page_bytes && !buckets_file.has_key_count {
+        return Err("Failed to load the buckets file: the header is invalid".into());
+    }
+
+    buckets_file.page_bytes = if buckets_file.has_page_bytes {
+        let position = buckets_file.page_bytes_position();
+        let buf = buckets_file
+            .file
+            .read_block(position, PAGE_BYTES_FIELD_BYTES)
+            .await
+            .map_err(|e| format!("Failed to load the buckets file: {}", e))?
+            .ok_or("Failed to load the buckets file: the page size is missing")?;
+        PageBytesField::from_le_bytes(buf.try_into().unwrap()) as usize
+    } else {
+        match (header & LEGACY_PAGE_BITS_MASK) >> BUCKET_COUNT_BITS {
+            // Written before the page size was recorded
+            0 => DEFAULT_PAGE_BYTES,
+            page_bits => 1_usize
+                .checked_shl(page_bits as u32)
+                .ok_or("Failed to load the buckets file: the page size is invalid")?,
+        }
+    };
+    validate_page_bytes(buckets_file.page_bytes)
+        .map_err(|e| format!("Failed to load the buckets file: {}", e))?;
+
+    if !buckets_file.has_key_count {
+        return Ok((buckets_file, bucket_count, None));
+    }
+    let position = buckets_file.key_count_position();
+    let buf = buckets_file
+        .file
+        .read_block(position, KEY_COUNT_BYTES)
+        .await
+        .map_err(|e| format!("Failed to load the buckets file: {}", e))?
+        .ok_or("Failed to load the buckets file: the key count is missing")?;
+    let key_count = u64::from_le_bytes(buf.try_into().unwrap());
+    Ok((buckets_file, bucket_count, Some(key_count)))
 }
 
-/// Loads the directory file of the hash table
+/// Saves the bucket count, key count and page size into the "buckets file" of the hash table,
+/// see the `buckets_file` field of `HashStorage` for more details
 ///
-/// # Returns
-/// - A tuple containing the directory and the global level of the hash table
-async fn load_directory(directory_file: &mut File) -> (Vec<BucketIndexType>, BucketLevel) {
-    // Return if the file is empty
-    if directory_file.metadata().await.unwrap().len() == 0 {
-        return (vec![0], 0);
+/// The key count isn't saved in files written before it was recorded
+async fn save_buckets_file(
+    bucket_count: BucketIndexType,
+    key_count: u64,
+    buckets_file: &mut BucketsFile,
+) {
+    let mut header = bucket_count as u64;
+    if buckets_file.has_page_bytes {
+        header |= PAGE_BYTES_FLAG;
+    } else {
+        let page_bits = buckets_file.page_bytes.trailing_zeros() as u64;
+        header |= page_bits << BUCKET_COUNT_BITS;
     }
+    if buckets_file.has_key_count {
+        header |= KEY_COUNT_FLAG;
+        let position = buckets_file.key_count_position();
+        buckets_file
+            .file
+            .write_block(position, &key_count.to_le_bytes())
+            .await;
+    }
+    buckets_file
+        .file
+        .write_block(0, &header.to_le_bytes())
+        .await
+}
 
-    directory_file.seek(SeekFrom::Start(0)).await.unwrap();
+/// When a closed value log segment has less than this fraction of its bytes still referenced it
+/// is garbage collected
+const VALUE_LOG_GC_LIVE_RATIO: f64 = 0.5;
 
-    let mut global_level_buf = [0; BUCKET_LEVEL_BYTES];
-    directory_file
-        .read_exact(&mut global_level_buf)
-        .await
-        .unwrap();
-    let global_level = BucketLevel::from_le_bytes(global_level_buf);
+/// Options for opening a `HashStorage`
+#[derive(Debug, Clone, Default)]
+pub struct HashStorageOptions {
+    /// Store large values in a value log, see `ValueLogOptions`
+    ///
+    /// When `None` every value is stored inline in its bucket
+    pub value_log: Option<ValueLogOptions>,
 
-    let addr_count = 2_usize.pow(global_level.into());
+    /// Encrypt the directory and buckets files with the key, see `BlockFile`
+    ///
+    /// The value log is not encrypted, so it can't be turned on along with the key
+    pub encryption_key: Option<EncryptionKey>,
 
-    let mut buf = vec![0; addr_count * BUCKET_INDEX_TYPE_BYTES];
-    directory_file.read_exact(&mut buf).await.unwrap();
+    /// The number of bytes in a bucket, a power of 2 between `MIN_PAGE_BYTES` and
+    /// `MAX_PAGE_BYTES`. Larger pages fit larger records
+    ///
+    /// Only used when the database is created, an existing database keeps the page size
+    /// recorded in its buckets file. When `None` the page size is `DEFAULT_PAGE_BYTES`
+    pub page_bytes: Option<usize>,
 
-    let mut result = vec![0; addr_count];
-    for i in 0..addr_count {
-        let start = BUCKET_INDEX_TYPE_BYTES * i;
-        result[i] = BucketIndexType::from_le_bytes(
-            buf[start..start + BUCKET_INDEX_TYPE_BYTES]
-                .try_into()
-                .unwrap(),
-        );
-    }
-    return (result, global_level);
+    /// Write every mutation to a durable mutation log before applying it, see `MutationLog`
+    ///
+    /// When `None` mutations aren't logged
+    pub mutation_log: Option<MutationLogOptions>,
+
+    /// Keep the size of the keys and values under a maximum, see `Quota`
+    ///
+    /// When `None` the data can grow without bound
+    pub quota: Option<QuotaOptions>,
 }
 
-/// Saves the directory of the hash table into the directory file
-async fn save_directory(vec: &Vec<BucketIndexType>, directory_file: &mut File) {
-    let addr_count = vec.len();
-    let global_level = addr_count_to_global_level(addr_count);
+/// Options for key/value separation, values larger than the threshold are appended to a value
+/// log and the record in the bucket only holds a pointer to it
+#[derive(Debug, Clone)]
+pub struct ValueLogOptions {
+    /// The directory containing the value log segments
+    pub directory: String,
 
-    directory_file.seek(SeekFrom::Start(0)).await.unwrap();
-    let global_level_buf = global_level.to_le_bytes();
-    directory_file.write_all(&global_level_buf).await.unwrap();
+    /// Values larger than this many bytes are stored in the value log
+    pub threshold: usize,
 
-    let mut buf = vec![0; vec.len() * BUCKET_INDEX_TYPE_BYTES];
-    for i in 0..addr_count {
-        let bytes = vec[i].to_le_bytes();
-        let start = BUCKET_INDEX_TYPE_BYTES * i;
-        for k in 0..BUCKET_INDEX_TYPE_BYTES {
-            buf[start + k] = bytes[k]
-        }
-    }
-    directory_file.write_all(&buf).await.unwrap();
+    /// Size in bytes after which the value log moves on to a new segment
+    pub segment_bytes: u64,
 }
 
 /// Hash storage engine utilising extensible hashing
 pub struct HashStorage {
-    /// The file containing the look up table and the global level
-    ///
-    /// # File layout
-    /// - First `BUCKET_LEVEL_BYTES` is the global level in LE
-    /// - Next is followed by the list of `BucketIndexTypes`s stored in LE as the index lookup for
-    /// where the buckets are stored
-    /// The length of this list is 2^global_level
+    /// The directory of the index, see `Directory` for the layout of the directory file
     ///
-    /// There are no pages in this file, the entire file is loaded and saved all at once
-    directory_file: File,
+    /// Only the pages of the directory which are touched are loaded, and only the changed pages
+    /// are saved
+    directory: Directory,
 
     /// The file containing the buckets
     ///
     /// # File layout
-    /// - First `BUCKET_HEADER_BYTES` is the number of current buckets
-    /// - Followed by pages of PAGE_SIZE, with each page being a bucket
-    buckets_file: File,
+    /// - First `HEADER_BYTES` is a header in LE
+    ///     - The low `BUCKET_COUNT_BITS` are the number of current buckets
+    ///     - The top bits are `KEY_COUNT_FLAG` and `PAGE_BYTES_FLAG`
+    ///     - Files written before `PAGE_BYTES_FLAG` keep log2 of the page size in the rest of the
+    ///       top byte, 0 in files written before the page size was recorded, which use
+    ///       `DEFAULT_PAGE_BYTES`
+    /// - When `KEY_COUNT_FLAG` is set, the number of keys in `KEY_COUNT_BYTES` LE. Files written
+    ///   before the key count was recorded don't have it and count their keys when they open
+    /// - When `PAGE_BYTES_FLAG` is set, the page size in `PAGE_BYTES_FIELD_BYTES` LE
+    /// - Followed by pages of the page size, with each page being a bucket
+    ///
+    /// When the file is encrypted the header and every bucket are stored as separate blocks, see
+    /// `BlockFile`
+    buckets_file: BucketsFile,
 
     /// The current number of buckets, we need this to know
     /// where to create new buckets, loaded from the buckets file
+    ///
+    /// Overflow pages are counted as buckets, they take pages of the buckets file the same way
     bucket_count: BucketIndexType,
 
-    /// The global level of the index
+    /// The buckets which hold more records than fit in their page, split one at a time by
+    /// `split_overflowing`
     ///
-    /// Saved and loaded from the directory file
-    global_level: BucketLevel,
+    /// Not saved, a bucket left with overflow pages when the database closes is added again by
+    /// the next put into it
+    overflowing: BTreeSet<BucketIndexType>,
 
-    /// Pointers to the bucket number
-    ///
-    /// Together with the global_level, this is the directory of the index.
-    /// The vector is sorted by the hash codes of the buckets.
-    ///
-    /// For example if the global level is 3, the directory will look like this:
-    /// 000 -> element 0
-    /// 001 -> element 1
-    /// 010 -> element 2
-    /// 011 -> element 3
-    /// 100 -> element 4
-    /// 101 -> element 5
-    /// ... and so on
+    /// The number of keys stored, saved in the buckets file along with the bucket count
     ///
-    /// This is loaded and saved from the directory file
-    bucket_lookup: Vec<BucketIndexType>,
+    /// Expired keys are counted until their records are removed
+    key_count: u64,
+
+    /// The key the directory and buckets files are encrypted with, kept to create the empty
+    /// files of a `FLUSHDB`
+    encryption_key: Option<EncryptionKey>,
+
+    /// The value log holding the values too large to be stored inline, along with the
+    /// threshold in bytes for a value to be stored there
+    value_log: Option<(ValueLog, usize)>,
+
+    /// The log every mutation is written to before it is applied
+    mutation_log: Option<MutationLog>,
+
+    /// The quota on the size of the data
+    quota: Option<Quota>,
+
+    /// The paths the directory and buckets files were opened from, a backup names its copies
+    /// after them
+    paths: (PathBuf, PathBuf),
+
+    /// The backup being written, see `HashStorage::start_backup`
+    backup: Option<Backup>,
+
+    /// The directory the last finished backup was written to, shown in the stats
+    finished_backup: Option<PathBuf>,
+}
+
+/// A running backup of a `HashStorage`
+///
+/// The directory and buckets files are copied by their `BlockFile`s, which copy a block before it
+/// is overwritten. The value log segments are only ever appended to, so they are copied up to
+/// the length they had when the backup started.
+struct Backup {
+    /// The directory the backup is written to
+    path: PathBuf,
+
+    /// The value log segments along with their copies
+    segments: Vec<(File, FileBackup)>,
 }
 
 impl HashStorage {
@@ -202,66 +497,492 @@ impl HashStorage {
     /// # Arguments
     /// * `directory_file` - The file containing the directory
     /// * `buckets_file` - The file containing the buckets
+    /// * `options` - See `HashStorageOptions`
     ///
     /// # Returns
-    /// A new instance of the index
-    pub async fn new(directory_file: &str, buckets_file: &str) -> Self {
-        let mut directory_file = std::fs::OpenOptions::new()
+    /// A new instance of the index, or an error if the files can't be loaded, for example when
+    /// the encryption key is wrong or missing, or if the options can't be used together
+    pub async fn new(
+        directory_file: &str,
+        buckets_file: &str,
+        options: HashStorageOptions,
+    ) -> Result<Self, String> {
+        if options.encryption_key.is_some() && options.value_log.is_some() {
+            return Err("The value log can't be used with encryption as it isn't encrypted".into());
+        }
+        let key = options.encryption_key.as_ref();
+        let (directory_path, buckets_path) = (directory_file, buckets_file);
+
+        // A FLUSHDB which got as far as recording itself replaces both files, otherwise its empty
+        // files are left over from before it started replacing anything
+        let pending = read_pending(&pending_path(Path::new(buckets_path)), key).await?;
+        for path in [directory_path, buckets_path] {
+            let temp = flush_temp_path(Path::new(path));
+            if !temp.exists() {
+                continue;
+            }
+            match pending {
+                Some(Mutation::FlushDb) => tokio::fs::rename(&temp, path).await.unwrap(),
+                _ => tokio::fs::remove_file(&temp).await.unwrap(),
+            }
+        }
+
+        let directory_file = std::fs::OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
             .open(directory_file)
-            .unwrap()
-            .into();
+            .unwrap();
 
-        let mut buckets_file = std::fs::OpenOptions::new()
+        let buckets_file = std::fs::OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
             .open(buckets_file)
-            .unwrap()
-            .into();
+            .unwrap();
+        let buckets_file = BlockFile::open(buckets_file.into(), key)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", buckets_path, e))?;
+
+        let directory_file = BlockFile::open(directory_file.into(), key)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", directory_path, e))?;
+        let directory = Directory::load(directory_file).await?;
 
-        let (bucket_addresses, global_level) = load_directory(&mut directory_file).await;
+        let (buckets_file, bucket_count, key_count) = load_buckets_file(
+            buckets_file,
+            options.page_bytes.unwrap_or(DEFAULT_PAGE_BYTES),
+        )
+        .await?;
+
+        let value_log = match options.value_log {
+            Some(options) => Some((
+                ValueLog::open(&options.directory, options.segment_bytes).await?,
+                options.threshold,
+            )),
+            None => None,
+        };
 
-        let bucket_count = load_buckets_file(&mut buckets_file).await;
+        let mutation_log = match &options.mutation_log {
+            Some(options) => Some(MutationLog::open(options, key).await?),
+            None => None,
+        };
 
-        Self {
-            directory_file,
+        let mut storage = Self {
+            directory,
             bucket_count,
+            overflowing: BTreeSet::new(),
+            key_count: key_count.unwrap_or(0),
+            encryption_key: options.encryption_key.clone(),
             buckets_file,
-            bucket_lookup: bucket_addresses,
-            global_level,
+            value_log,
+            mutation_log,
+            quota: None,
+            paths: (directory_path.into(), buckets_path.into()),
+            backup: None,
+            finished_backup: None,
+        };
+        if key_count.is_none() {
+            storage.key_count = storage.count_keys().await;
+        }
+        storage.finish_pending(pending).await?;
+        if let Some(options) = options.quota {
+            let data_bytes = storage.count_data_bytes().await;
+            storage.quota = Some(Quota::new(options, data_bytes));
+        }
+        Ok(storage)
+    }
+
+    /// Counts the bytes of the keys and stored values of every record, see `Quota`
+    async fn count_data_bytes(&mut self) -> u64 {
+        let mut data_bytes = 0;
+        for bucket_index in 0..self.bucket_count {
+            let bucket = Bucket::read_page(&mut self.buckets_file, bucket_index).await;
+            data_bytes += data_len(&bucket.records);
+        }
+        data_bytes
+    }
+
+    /// Counts the records of every bucket which are keys, for files which don't record the key
+    /// count
+    async fn count_keys(&mut self) -> u64 {
+        let mut key_count = 0;
+        for bucket_index in 0..self.bucket_count {
+            let bucket = Bucket::read_page(&mut self.buckets_file, bucket_index).await;
+            key_count += bucket.records.iter().filter(|x| !x.5.is_item()).count() as u64;
         }
+        key_count
     }
 
     pub async fn handle_cmd(&mut self, cmd: StorageCommand) -> Result<CommandOutput, String> {
-        match cmd {
-            StorageCommand::Put(cmd) => {
-                self.put(Record(
-                    hash_string_key(&cmd.0),
-                    cmd.0.into_bytes(),
-                    cmd.1.into_bytes(),
-                ))
+        let output = self.handle_cmd_inner(cmd).await;
+        // Spread the copying of a doubled directory across commands instead of doing it all in
+        // the put which caused the global split
+        self.directory.grow(DIRECTORY_GROWTH_PAGES).await;
+        // Likewise a bucket left with overflow pages by a put is split a step at a time
+        self.split_overflowing().await;
+        // Likewise a running backup is copied a few chunks at a time
+        self.continue_backup(BACKUP_STEP_CHUNKS).await;
+        output
+    }
+
+    /// Starts a backup of the database into the directory at the path, which is copied a few
+    /// chunks at a time by `continue_backup` while commands keep being handled
+    ///
+    /// The backup holds the database as it is when this is called. It is a directory holding
+    /// the directory file, the buckets file and the value log under the same names, so the
+    /// database can be opened from it like any other. With the mutation log turned on it also
+    /// holds the LSN of the first mutation left out of the backup, see `recovery::recover`.
+    ///
+    /// # Returns
+    /// - An error if a backup is already running or the backup files can't be created, a backup
+    ///   never overwrites existing files
+    pub async fn start_backup(&mut self, path: &str) -> Result<(), String> {
+        if self.backup.is_some() {
+            return Err("A backup is already running".into());
+        }
+        let path = PathBuf::from(path);
+        tokio::fs::create_dir_all(&path)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let directory_backup = create_backup_file(&path.join(file_name(&self.paths.0))).await?;
+        let buckets_backup = create_backup_file(&path.join(file_name(&self.paths.1))).await?;
+
+        // Everything the backup holds has to be in the files before it starts
+        self.save_index().await;
+
+        // Recovery replays the archived mutations from here on top of the backup
+        if let Some(mutation_log) = &self.mutation_log {
+            let lsn_path = path.join(BACKUP_LSN_FILE_NAME);
+            let mut lsn_file = create_backup_file(&lsn_path).await?;
+            lsn_file
+                .write_all(mutation_log.next_lsn().to_string().as_bytes())
                 .await
                 .unwrap();
+            lsn_file.sync_all().await.unwrap();
+        }
+
+        let mut segments = vec![];
+        if let Some((value_log, _)) = &mut self.value_log {
+            value_log.sync_all().await;
+            let directory = path.join(file_name(value_log.directory()));
+            tokio::fs::create_dir_all(&directory)
+                .await
+                .map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+            for (segment_path, len) in value_log.segment_files().await {
+                let destination =
+                    create_backup_file(&directory.join(file_name(&segment_path))).await?;
+                let source = File::open(&segment_path).await.unwrap();
+                segments.push((source, FileBackup::new(destination, len)));
+            }
+        }
+
+        self.directory.start_backup(directory_backup).await;
+        self.buckets_file.file.start_backup(buckets_backup).await;
+        self.backup = Some(Backup { path, segments });
+        Ok(())
+    }
+
+    /// Copies up to `max_chunks` chunks of the running backup, finishing it once everything has
+    /// been copied
+    pub async fn continue_backup(&mut self, max_chunks: usize) {
+        let Some(backup) = &mut self.backup else {
+            return;
+        };
+        let mut remaining = max_chunks;
+        remaining -= self.directory.continue_backup(remaining).await;
+        remaining -= self.buckets_file.file.continue_backup(remaining).await;
+        for (source, segment) in backup.segments.iter_mut() {
+            remaining -= segment.copy_chunks(source, remaining).await;
+        }
+
+        if self.directory.is_backing_up()
+            || self.buckets_file.file.is_backing_up()
+            || backup.segments.iter().any(|(_, x)| !x.is_done())
+        {
+            return;
+        }
+        for (_, segment) in backup.segments.iter_mut() {
+            segment.finish().await;
+        }
+        self.finished_backup = self.backup.take().map(|x| x.path);
+    }
+
+    /// Whether a backup is running, see `start_backup`
+    pub fn is_backing_up(&self) -> bool {
+        self.backup.is_some()
+    }
+
+    async fn handle_cmd_inner(&mut self, cmd: StorageCommand) -> Result<CommandOutput, String> {
+        match cmd {
+            StorageCommand::Put(cmd) => {
+                self.put_value(cmd).await?;
                 Ok(CommandOutput::Put)
             }
             StorageCommand::Delete(cmd) => {
-                self.delete(cmd).await.unwrap();
-                Ok(CommandOutput::Delete)
+                if self.delete_value(cmd.clone()).await {
+                    Ok(CommandOutput::Deleted)
+                } else {
+                    Ok(CommandOutput::NotFound(cmd.0))
+                }
             }
             StorageCommand::Get(cmd) => {
-                if let Some(value) = self
-                    .get(hash_string_key(&cmd.0), cmd.0.as_bytes())
+                if let Some((value, _)) = self.get_entry(&cmd.0).await? {
+                    Ok(CommandOutput::Found(value))
+                } else {
+                    Ok(CommandOutput::NotFound(cmd.0))
+                }
+            }
+            StorageCommand::Expire(cmd) => {
+                let Some(mut record) = self.find_record(hash_key(&cmd.0), &cmd.0).await else {
+                    return Ok(CommandOutput::NotFound(cmd.0));
+                };
+                self.log_mutation(Mutation::Expire(cmd.clone())).await;
+                record.4 = cmd.1;
+                self.put(record.clone())
                     .await
-                    .unwrap()
-                    .map(|x| String::from_utf8(x).unwrap())
+                    .map_err(|_| "Key and value are too large to store".to_string())?;
+                self.expire_items(&record).await?;
+                match cmd.1 {
+                    Some(_) => Ok(CommandOutput::Expire),
+                    None => Ok(CommandOutput::Persist),
+                }
+            }
+            StorageCommand::Ttl(cmd) => match self.find_record(hash_key(&cmd.0), &cmd.0).await {
+                Some(record) => {
+                    let now = now_millis();
+                    let seconds = record.4.map(|x| x.saturating_sub(now).div_ceil(1000));
+                    Ok(CommandOutput::Ttl(seconds))
+                }
+                None => Ok(CommandOutput::NotFound(cmd.0)),
+            },
+            StorageCommand::Incr(cmd) => Ok(CommandOutput::Integer(self.incr(cmd).await?)),
+            StorageCommand::PutIf(PutIfCommand(cmd, condition)) => {
+                let present = self.find_record(hash_key(&cmd.0), &cmd.0).await.is_some();
+                if !condition.holds(present) {
+                    return Ok(CommandOutput::NotPut);
+                }
+                self.put_value(cmd).await?;
+                Ok(CommandOutput::Put)
+            }
+            StorageCommand::Cas(CasCommand(key, expected, value)) => {
+                match self.get_entry(&key).await? {
+                    Some((current, expires_at)) if current == expected => {
+                        self.put_value(PutCommand(key, value, expires_at)).await?;
+                        Ok(CommandOutput::Swapped)
+                    }
+                    _ => Ok(CommandOutput::NotSwapped),
+                }
+            }
+            StorageCommand::GetDel(cmd) => match self.get_entry(&cmd.0).await? {
+                Some((value, _)) => {
+                    self.delete_value(DeleteCommand(cmd.0)).await;
+                    Ok(CommandOutput::Taken(value))
+                }
+                None => Ok(CommandOutput::NotFound(cmd.0)),
+            },
+            StorageCommand::GetSet(cmd) => {
+                let previous = self.get_entry(&cmd.0).await?.map(|x| x.0);
+                self.put_value(PutCommand(cmd.0, cmd.1, None)).await?;
+                Ok(CommandOutput::Replaced(previous))
+            }
+            StorageCommand::MGet(cmd) => {
+                let mut values = vec![];
+                for key in cmd.0 {
+                    values.push(self.get_entry(&key).await?.map(|x| x.0));
+                }
+                Ok(CommandOutput::Values(values))
+            }
+            StorageCommand::MSet(cmd) => {
+                self.put_values(cmd.0).await?;
+                Ok(CommandOutput::Put)
+            }
+            StorageCommand::MDelete(cmd) => {
+                let mut deleted = 0;
+                for key in cmd.0 {
+                    if self.delete_value(DeleteCommand(key)).await {
+                        deleted += 1;
+                    }
+                }
+                Ok(CommandOutput::DeletedKeys(deleted))
+            }
+            StorageCommand::Exists(cmd) => {
+                let record = self.find_record(hash_key(&cmd.0), &cmd.0).await;
+                Ok(CommandOutput::Exists(record.is_some()))
+            }
+            StorageCommand::Rename(cmd) => {
+                let Some((value, expires_at)) = self.get_value(&cmd.0).await? else {
+                    return Ok(CommandOutput::NotFound(cmd.0));
+                };
+                if cmd.0 != cmd.1 {
+                    self.rename(cmd, value, expires_at).await?;
+                }
+                Ok(CommandOutput::Renamed)
+            }
+            StorageCommand::Copy(cmd) => {
+                let Some((value, expires_at)) = self.get_value(&cmd.0).await? else {
+                    return Ok(CommandOutput::NotFound(cmd.0));
+                };
+                match value {
+                    Value::String(value) => {
+                        self.put_value(PutCommand(cmd.1, value, expires_at)).await?
+                    }
+                    value => self.copy_typed(cmd, value, expires_at).await?,
+                }
+                Ok(CommandOutput::Copied)
+            }
+            StorageCommand::DbSize => Ok(CommandOutput::DbSize(self.key_count)),
+            StorageCommand::FlushDb => {
+                self.flush_db().await;
+                Ok(CommandOutput::FlushDb)
+            }
+            StorageCommand::ListPush(cmd) => Ok(CommandOutput::Length(self.push(cmd).await?)),
+            StorageCommand::ListPop(cmd) => match self.pop(cmd.clone()).await? {
+                Some(value) => Ok(CommandOutput::Found(value)),
+                None => Ok(CommandOutput::NotFound(cmd.0)),
+            },
+            StorageCommand::ListRange(cmd) => {
+                let Some((_, list)) = self.find_list(&cmd.0).await? else {
+                    return Ok(CommandOutput::Items(vec![]));
+                };
+                let range = list_range(list.len(), cmd.1, cmd.2);
+                let indices = range.map(|x| list.head + x as i64);
+                let items = self.load_items(&cmd.0, indices).await?;
+                Ok(CommandOutput::Items(items))
+            }
+            StorageCommand::ListLen(cmd) => {
+                let list = self.find_list(&cmd.0).await?;
+                Ok(CommandOutput::Length(
+                    list.map_or(0, |(_, list)| list.len()),
+                ))
+            }
+            StorageCommand::HashSet(cmd) => {
+                let mutation = Mutation::HashSet(cmd.clone());
+                let added = self
+                    .add_members(cmd.0, ValueType::Hash, cmd.1, mutation)
+                    .await?;
+                Ok(CommandOutput::Count(added))
+            }
+            StorageCommand::HashGet(cmd) => {
+                if self.find_typed(&cmd.0, ValueType::Hash).await?.is_none() {
+                    return Ok(CommandOutput::NotFound(cmd.0));
+                }
+                match self.find_member(&cmd.0, &cmd.1).await? {
+                    Some((_, _, value)) => Ok(CommandOutput::Found(value)),
+                    None => Ok(CommandOutput::FieldNotFound(cmd.1)),
+                }
+            }
+            StorageCommand::HashDelete(cmd) => {
+                let mutation = Mutation::HashDelete(cmd.clone());
+                let removed = self
+                    .remove_members(cmd.0, ValueType::Hash, cmd.1, mutation)
+                    .await?;
+                Ok(CommandOutput::Count(removed))
+            }
+            StorageCommand::HashGetAll(cmd) => {
+                let fields: Option<BTreeMap<_, _>> = self.find_members(&cmd.0).await?;
+                Ok(CommandOutput::Fields(
+                    fields.unwrap_or_default().into_iter().collect(),
+                ))
+            }
+            StorageCommand::HashKeys(cmd) => {
+                let fields: Option<BTreeMap<_, _>> = self.find_members(&cmd.0).await?;
+                Ok(CommandOutput::Items(
+                    fields.unwrap_or_default().into_keys().collect(),
+                ))
+            }
+            StorageCommand::SortedSetAdd(cmd) => {
+                let mutation = Mutation::SortedSetAdd(cmd.clone());
+                let members = cmd
+                    .1
+                    .into_iter()
+                    .map(|(score, member)| (member, score_payload(score)));
+                let added = self
+                    .add_members(cmd.0, ValueType::SortedSet, members.collect(), mutation)
+                    .await?;
+                Ok(CommandOutput::Count(added))
+            }
+            StorageCommand::SortedSetScore(cmd) => {
+                if self
+                    .find_typed(&cmd.0, ValueType::SortedSet)
+                    .await?
+                    .is_none()
                 {
-                    return Ok(CommandOutput::Found(value));
-                } else {
                     return Ok(CommandOutput::NotFound(cmd.0));
                 }
+                match self.find_member(&cmd.0, &cmd.1).await? {
+                    Some((_, _, payload)) => Ok(CommandOutput::Score(score_of_payload(&payload)?)),
+                    None => Ok(CommandOutput::MemberNotFound(cmd.1)),
+                }
+            }
+            StorageCommand::SortedSetRange(cmd) => {
+                let members: Option<SortedSet> = self.find_members(&cmd.0).await?;
+                let members = members.unwrap_or_default().range(cmd.1, cmd.2);
+                Ok(CommandOutput::Members(members))
+            }
+            StorageCommand::SortedSetRangeByScore(cmd) => {
+                let members: Option<SortedSet> = self.find_members(&cmd.0).await?;
+                let members = members.unwrap_or_default().range_by_score(cmd.1, cmd.2);
+                Ok(CommandOutput::Members(members))
+            }
+            StorageCommand::SortedSetRemove(cmd) => {
+                let mutation = Mutation::SortedSetRemove(cmd.clone());
+                let removed = self
+                    .remove_members(cmd.0, ValueType::SortedSet, cmd.1, mutation)
+                    .await?;
+                Ok(CommandOutput::Count(removed))
+            }
+            StorageCommand::SetAdd(cmd) => {
+                let mutation = Mutation::SetAdd(cmd.clone());
+                let members = cmd.1.into_iter().map(|member| (member, vec![]));
+                let added = self
+                    .add_members(cmd.0, ValueType::Set, members.collect(), mutation)
+                    .await?;
+                Ok(CommandOutput::Count(added))
+            }
+            StorageCommand::SetRemove(cmd) => {
+                let mutation = Mutation::SetRemove(cmd.clone());
+                let removed = self
+                    .remove_members(cmd.0, ValueType::Set, cmd.1, mutation)
+                    .await?;
+                Ok(CommandOutput::Count(removed))
+            }
+            StorageCommand::SetIsMember(cmd) => {
+                let is_member = self.find_typed(&cmd.0, ValueType::Set).await?.is_some()
+                    && self.find_member(&cmd.0, &cmd.1).await?.is_some();
+                Ok(CommandOutput::IsMember(is_member))
+            }
+            StorageCommand::SetMembers(cmd) => {
+                let members: Option<BTreeSet<_>> = self.find_members(&cmd.0).await?;
+                Ok(CommandOutput::Items(
+                    members.unwrap_or_default().into_iter().collect(),
+                ))
+            }
+            StorageCommand::SetCard(cmd) => {
+                let record = self.find_typed(&cmd.0, ValueType::Set).await?;
+                let len = record.map_or(Ok(0), |x| member_count(&x))?;
+                Ok(CommandOutput::Length(len))
+            }
+            StorageCommand::SetCombine(cmd) => {
+                let mut sets = vec![];
+                for key in &cmd.1 {
+                    let members: Option<BTreeSet<_>> = self.find_members(key).await?;
+                    sets.push(members.unwrap_or_default());
+                }
+                let members = cmd.0.apply(sets);
+                Ok(CommandOutput::Items(members.into_iter().collect()))
+            }
+            StorageCommand::Keys(cmd) => Ok(CommandOutput::Keys(self.keys(cmd.0).await)),
+            StorageCommand::Stats => Ok(CommandOutput::Stats(self.stats())),
+            StorageCommand::Backup(cmd) => {
+                self.start_backup(&cmd.0).await?;
+                Ok(CommandOutput::Backup)
+            }
+            StorageCommand::Scan(cmd) => {
+                let (cursor, keys) = self.scan(cmd).await;
+                Ok(CommandOutput::Scan(cursor, keys))
             }
             StorageCommand::Flush => {
                 self.exit().await;
@@ -270,677 +991,4965 @@ impl HashStorage {
         }
     }
 
-    async fn exit(&mut self) {
-        save_directory(&self.bucket_lookup, &mut self.directory_file).await;
-        save_buckets_file(self.bucket_count, &mut self.buckets_file).await;
-        self.directory_file.sync_all().await.unwrap();
-        self.buckets_file.sync_all().await.unwrap();
-    }
-
-    fn hash_key_to_remainder(&self, key: &str) -> (Hash, usize) {
-        let hash = hash_string_key(key);
-        let remainder = hash % 2_u64.pow(self.global_level.into());
-        (hash, remainder.try_into().unwrap())
+    /// Returns an iterator over every key value pair in the hash table, see `KeyspaceIter`
+    pub fn keyspace(&self) -> KeyspaceIter {
+        KeyspaceIter {
+            directory_index: 0,
+            visited: HashSet::new(),
+            records: vec![].into_iter(),
+        }
     }
 
-    fn hash_to_remainder(&self, hash: Hash) -> usize {
-        (hash % 2_u64.pow(self.global_level.into()))
-            .try_into()
-            .unwrap()
+    /// Whether no keys are stored, every bucket is read to find out
+    pub async fn is_empty(&mut self) -> bool {
+        self.keyspace().next_record(self).await.is_none()
     }
 
-    fn debug(&self) {
-        println!(
-            "Bucket count: {}, Bucket_lookup: {:?}, Global lvl: {}",
-            self.bucket_count, self.bucket_lookup, self.global_level
-        );
+    /// Returns the keys matching the glob pattern, or every key without a pattern
+    async fn keys(&mut self, pattern: Option<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut iter = self.keyspace();
+        let mut keys = vec![];
+        while let Some(record) = iter.next_record(self).await {
+            if pattern.as_ref().is_none_or(|x| glob_match(x, &record.1)) {
+                keys.push(record.1);
+            }
+        }
+        keys
     }
 
-    async fn put(&mut self, record: Record) -> Result<(), ()> {
-        // Look up the address of the bucket
-        let bucket_index = self.bucket_lookup[self.hash_to_remainder(record.0)];
-
-        // Load the bucket
-        let mut bucket = Bucket::read_from_file(&mut self.buckets_file, bucket_index).await;
-
-        // Put command in or split the bucket
-
-        // self.debug();
-
+    /// Returns a page of keys starting from the cursor, along with the cursor of the next page
+    /// which is 0 once every bucket has been visited
+    ///
+    /// # Cursor
+    ///
+    /// The cursor is a hash with its bits reversed. Walking the hashes in reversed bit order
+    /// visits the hashes sharing their low bits one after another, so every bucket covers one
+    /// contiguous range of cursors no matter its level, and splitting a bucket or doubling the
+    /// directory only cuts a range into smaller ranges. The keys whose reversed hash is below the
+    /// cursor have been returned, so a page only returns the keys of a bucket from the cursor
+    /// onwards and then moves the cursor to the start of the range after the bucket.
+    async fn scan(&mut self, cmd: ScanCommand) -> (u64, Vec<Vec<u8>>) {
+        let mut cursor = cmd.cursor;
+        let mut keys = vec![];
+        let mut visited = 0;
+        let now = now_millis();
         loop {
-            // Easy cases, they fit
-            if let Some(existing_record) = bucket
-                .records
-                .iter_mut()
-                .find(|x| x.0 == record.0 && x.1 == record.1)
-            {
-                // Check the difference between the existing record and the new record to see if
-                // the bucket can accomodate to the difference in size
-                if existing_record.2.len() >= record.2.len()
-                    || record.2.len() - existing_record.2.len() <= bucket.remaining_byte_space
-                {
-                    existing_record.2 = record.2;
-                    bucket.update_remaining_byte_count();
-                    bucket.save_to_file(&mut self.buckets_file).await;
-                    return Ok(());
+            let bucket_index = self.directory.get(self.hash_to_remainder(cursor)).await;
+            let bucket = Bucket::read_from_file(&mut self.buckets_file, bucket_index).await;
+            for record in bucket.records {
+                if record.0.reverse_bits() < cursor.reverse_bits() {
+                    continue;
                 }
-            } else if bucket.remaining_byte_space >= record.byte_len() {
-                bucket.records.push(record);
-                bucket.update_remaining_byte_count();
-                bucket.save_to_file(&mut self.buckets_file).await;
-                return Ok(());
-            }
-
-            // Bucket split
-
-            // This is the original remainder for the bucket before the split, we have to call
-            // self.hash_to_remainder again as the global level may have changed between the
-            // iterations of the loop due to a global split
-            let og_bucket_remainder =
-                self.hash_to_remainder(record.0) % 2_usize.pow(bucket.level.into());
-
-            bucket.level += 1;
-
-            // Split the bucket in half into 2 Vec<Records> one with the new bucket and one with the original bucket
-            let (original, new) = bucket.records.drain(..bucket.records.len()).fold(
-                (vec![], vec![]),
-                |(mut og_, mut new_), x| {
-                    let remainder = x.0 % 2_u64.pow(bucket.level.into());
-                    if remainder > og_bucket_remainder as u64 {
-                        new_.push(x)
-                    } else {
-                        og_.push(x)
-                    }
-                    (og_, new_)
-                },
-            );
-
-            // Original bucket
-            bucket.records = original;
-
-            let new_bucket_index = self.bucket_count;
-
-            // New bucket
-            let mut new_bucket = Bucket {
-                level: bucket.level,
-                records: new,
-                bucket_index: new_bucket_index,
-                remaining_byte_space: 0,
-            };
-
-            // Save both buckets
-            bucket.update_remaining_byte_count();
-            new_bucket.update_remaining_byte_count();
-            bucket.save_to_file(&mut self.buckets_file).await;
-            new_bucket.save_to_file(&mut self.buckets_file).await;
-
-            self.bucket_count += 1;
-
-            // Local split
-            if bucket.level <= self.global_level {
-                // Grab all the lookup entries which point to the existing bucket
-                let mut indices = vec![];
-                for i in 0..self.bucket_lookup.len() {
-                    if self.bucket_lookup[i] == bucket.bucket_index {
-                        indices.push(i);
-                    }
+                visited += 1;
+                if record.is_expired(now) || record.5.is_item() {
+                    continue;
                 }
-
-                // Re-adjust the lookup
-                for index in &indices {
-                    let remainder = index % 2_usize.pow(bucket.level.into());
-                    if remainder > og_bucket_remainder {
-                        self.bucket_lookup[*index] = new_bucket_index;
-                    }
+                if cmd
+                    .pattern
+                    .as_ref()
+                    .is_none_or(|x| glob_match(x, &record.1))
+                {
+                    keys.push(record.1);
                 }
-            } else {
-                // Global split
-
-                // Readjust the indices
-                let old_len = self.bucket_lookup.len();
-                self.bucket_lookup.extend(self.bucket_lookup.clone());
-                self.bucket_lookup[old_len + og_bucket_remainder] = new_bucket_index;
-
-                self.global_level += 1;
             }
 
-            // Re-assign "bucket" to the new bucket which the record matches against hash of the
-            // record. This is because it might need to split again
-            let new_remainder = record.0 % 2_u64.pow(bucket.level.into());
-            if new_remainder > og_bucket_remainder as u64 {
-                bucket = new_bucket;
+            cursor = next_scan_cursor(cursor, bucket.level);
+            if cursor == 0 || visited >= cmd.count {
+                return (cursor, keys);
             }
         }
     }
 
-    async fn get(&mut self, hash: Hash, key: &[u8]) -> Result<Option<Vec<u8>>, ()> {
-        let remainder = self.hash_to_remainder(hash);
-        let bucket =
-            Bucket::read_from_file(&mut self.buckets_file, self.bucket_lookup[remainder]).await;
-
-        Ok(bucket
-            .records
-            .into_iter()
-            .find(|x| x.0 == hash && x.1 == key)
-            .map(|r| r.2))
+    /// The stats of the storage as name value pairs
+    fn stats(&self) -> Vec<(String, String)> {
+        let directory_bytes = self.directory.len() * BUCKET_INDEX_TYPE_BYTES;
+        let mut stats = vec![
+            ("buckets".to_string(), self.bucket_count.to_string()),
+            ("directory_bytes".to_string(), directory_bytes.to_string()),
+        ];
+        if let Some(backup) = &self.backup {
+            stats.push(("backup_running".into(), backup.path.display().to_string()));
+        }
+        if let Some(path) = &self.finished_backup {
+            stats.push(("backup_finished".into(), path.display().to_string()));
+        }
+        if let Some(quota) = &self.quota {
+            stats.extend(quota.stats());
+        }
+        stats
     }
 
-    pub async fn delete(&mut self, cmd: DeleteCommand) -> Result<(), ()> {
-        let (hash, remainder) = self.hash_key_to_remainder(&cmd.0);
-
-        let mut bucket =
-            Bucket::read_from_file(&mut self.buckets_file, self.bucket_lookup[remainder]).await;
+    /// Makes room under the quota for putting a value of `value_len` bytes under the key,
+    /// evicting other keys or refusing the put depending on the eviction policy
+    ///
+    /// The value is counted before it is compressed, so a put may be refused or evict keys
+    /// when its compressed value would have fit
+    async fn make_room(&mut self, puts: &[PutCommand], keep: &[Hash]) -> Result<(), String> {
+        if self.quota.is_none() {
+            return Ok(());
+        }
+        let mut hashes = keep.to_vec();
+        let mut existing = 0;
+        let mut incoming = 0;
+        for cmd in puts {
+            let hash = hash_key(&cmd.0);
+            existing += self
+                .find_record(hash, &cmd.0)
+                .await
+                .map_or(0, |x| x.data_len());
+            incoming += (cmd.0.len() + cmd.1.len()) as u64;
+            hashes.push(hash);
+        }
+        self.free_space(existing, incoming, &hashes).await
+    }
 
-        bucket.records = bucket
-            .records
-            .into_iter()
-            .filter(|x| x.0 != hash && x.1 != cmd.0.as_bytes())
-            .collect();
-        bucket.update_remaining_byte_count();
-        bucket.save_to_file(&mut self.buckets_file).await;
-        Ok(())
+    /// Makes room under the quota for `incoming` bytes replacing `existing` bytes, evicting keys
+    /// other than the ones with the hashes or refusing the write depending on the eviction policy
+    async fn free_space(
+        &mut self,
+        existing: u64,
+        incoming: u64,
+        hashes: &[Hash],
+    ) -> Result<(), String> {
+        if self.quota.is_none() {
+            return Ok(());
+        }
+        loop {
+            let quota = self.quota.as_ref().unwrap();
+            let (used, max) = (quota.data_bytes, quota.options.max_data_bytes);
+            if used.saturating_sub(existing) + incoming <= max {
+                return Ok(());
+            }
+            let can_evict = incoming <= max && quota.options.policy != EvictionPolicy::Reject;
+            if !can_evict || !self.evict_one(hashes).await {
+                self.quota.as_mut().unwrap().rejected_writes += 1;
+                return Err(format!(
+                    "Out of space, the keys and values need {} bytes with {} of {} bytes used",
+                    incoming, used, max
+                ));
+            }
+        }
     }
-}
 
-/// Rust representation of a bucket
-///
-/// ## Binary layout
-///
-/// - First `BUCKET_HEADER_BYTES` indicate the local level of the bucket
-/// - Rest are records
-///
-#[derive(PartialEq, Debug, Clone)]
-pub struct Bucket {
-    /// The nth bucket in the bucket file, 0 indexed
-    bucket_index: BucketIndexType,
+    /// Evicts a key other than the ones with the hashes, picked from the records of a few buckets
+    /// sampled at random by the eviction policy
+    ///
+    /// The eviction is written to the mutation log as a delete, so a recovery replays it
+    ///
+    /// # Returns
+    /// - Whether a key was evicted, there is nothing to evict once the keys with the hashes are the
+    ///   only ones left
+    async fn evict_one(&mut self, skip: &[Hash]) -> bool {
+        let now = now_millis();
+        let mut candidates = vec![];
+        for _ in 0..EVICTION_SAMPLE_BUCKETS {
+            let quota = self.quota.as_mut().unwrap();
+            let index = quota.random() as usize % self.directory.len();
+            let bucket_index = self.directory.get(index).await;
+            let bucket = Bucket::read_from_file(&mut self.buckets_file, bucket_index).await;
+            candidates.extend(
+                bucket
+                    .records
+                    .into_iter()
+                    .filter(|x| !skip.contains(&x.0) && !x.is_expired(now) && !x.5.is_item()),
+            );
+        }
+        // The sampled buckets can all be empty while others aren't
+        if candidates.is_empty() {
+            let mut iter = self.keyspace();
+            while let Some(record) = iter.next_record(self).await {
+                if !skip.contains(&record.0) {
+                    candidates.push(record);
+                    break;
+                }
+            }
+        }
+        if candidates.is_empty() {
+            return false;
+        }
 
-    /// The local level of the bucket
-    level: BucketLevel,
+        let quota = self.quota.as_mut().unwrap();
+        let victim = match quota.options.policy {
+            EvictionPolicy::Random => quota.random() as usize % candidates.len(),
+            _ => (0..candidates.len())
+                .min_by_key(|x| quota.last_used(candidates[*x].0))
+                .unwrap(),
+        };
+        let key = candidates.swap_remove(victim).1;
+        self.log_mutation(Mutation::Delete(DeleteCommand(key.clone())))
+            .await;
+        self.delete(DeleteCommand(key)).await.unwrap();
+        self.quota.as_mut().unwrap().evicted_keys += 1;
+        true
+    }
 
-    /// The number of bytes remaining available in the bucket
-    remaining_byte_space: usize,
+    /// Writes the mutation to the mutation log when it is turned on, called before the mutation
+    /// is applied
+    async fn log_mutation(&mut self, mutation: Mutation) {
+        if let Some(mutation_log) = &mut self.mutation_log {
+            mutation_log.append(&mutation).await;
+        }
+    }
 
-    /// The records contained in the bucket
-    records: Vec<Record>,
-}
+    async fn exit(&mut self) {
+        // Finish the backup, it can't carry on once the files are closed
+        self.continue_backup(usize::MAX).await;
+        self.save_index().await;
+        self.directory.sync_all().await;
+        self.buckets_file.file.sync_all().await;
+        if let Some((value_log, _)) = &mut self.value_log {
+            value_log.sync_all().await;
+        }
+        // The archive holds every mutation up to now, so it can recover to the latest state
+        if let Some(mutation_log) = &mut self.mutation_log {
+            mutation_log.archive_head().await;
+        }
+    }
 
-/// The length of the bucket header in bytes
-const BUCKET_HEADER_BYTES: usize = BUCKET_LEVEL_BYTES;
+    /// Creates the record for a key value pair, compressing the value if it is worth it and
+    /// appending the value to the value log if it is larger than the value log threshold
+    async fn new_record(
+        &mut self,
+        hash: Hash,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<Timestamp>,
+    ) -> Record {
+        let (value, header) = match compress(&value) {
+            Some(compressed) => (compressed, RECORD_HEADER | RECORD_COMPRESSED),
+            None => (value, RECORD_HEADER),
+        };
 
-impl<'a, T> ParseFromBytes<T> for Bucket
-where
-    T: Iterator<Item = &'a u8>,
-{
-    type Error = ();
+        let Some((value_log, threshold)) = &mut self.value_log else {
+            return Record(hash, key, value, header, expires_at, ValueType::String);
+        };
+        if value.len() <= *threshold {
+            return Record(hash, key, value, header, expires_at, ValueType::String);
+        }
 
-    type Metadata = BucketIndexType;
+        let head = value_log.head();
+        let pointer = value_log.append(hash, &key, &value).await;
+        let rotated = value_log.head() != head;
+        let record = Record(
+            hash,
+            key,
+            pointer.into_bytes(),
+            header | RECORD_VALUE_POINTER,
+            expires_at,
+            ValueType::String,
+        );
 
-    fn from_bytes(mut bytes: T, bucket_index: Self::Metadata) -> Result<(Self, T), Self::Error> {
-        let page: [u8; PAGE_BYTES] = take_bytes_from_iterator(&mut bytes);
-        let mut page = page.iter().peekable();
+        // A new head segment was started, so there is a newly closed segment which could be
+        // cleaned up
+        if rotated {
+            self.collect_value_log_garbage().await;
+        }
+        record
+    }
 
-        let level_bytes: [u8; BUCKET_LEVEL_BYTES] = take_bytes_from_iterator(&mut page);
-        let level = BucketLevel::from_le_bytes(level_bytes.try_into().unwrap());
-        let mut records = vec![];
+    /// Returns the value of a record, reading it from the value log and decompressing it if
+    /// needed
+    async fn load_value(&mut self, record: Record) -> Result<Vec<u8>, String> {
+        let value = match (record.value_pointer(), &mut self.value_log) {
+            (Some(pointer), Some((value_log, _))) => value_log.read(pointer).await,
+            (Some(_), None) => {
+                return Err("Record points into the value log which is not enabled".into())
+            }
+            (None, _) => record.2,
+        };
+        if record.3 & RECORD_COMPRESSED != 0 {
+            decompress(&value)
+        } else {
+            Ok(value)
+        }
+    }
 
-        while let Some(x) = page.peek() {
-            if **x == 0 {
-                page.next();
+    /// Rewrites the closed value log segments which are mostly garbage
+    ///
+    /// The live bytes of a segment are kept up to date as records are written and removed, so
+    /// only segments written before the value log was opened are scanned to count them, once.
+    ///
+    /// The values still referenced by a record are appended to the head of the value log and the
+    /// records are pointed at the new copies before the old segment is deleted, so a crash part
+    /// way through leaves every record pointing at a segment which still exists.
+    async fn collect_value_log_garbage(&mut self) {
+        let Some((value_log, _)) = &mut self.value_log else {
+            return;
+        };
+        // The segments can't be removed before a running backup has copied them
+        if self.backup.is_some() {
+            return;
+        }
+        for segment in value_log.closed_segments().await {
+            let (value_log, _) = self.value_log.as_mut().unwrap();
+            let segment_len = value_log.segment_len(segment).await;
+            let live_len = match value_log.live_bytes(segment) {
+                Some(live_len) => live_len,
+                None => {
+                    let live = self.live_value_log_entries(segment).await;
+                    let live_len = live.iter().map(|x| x.byte_len() as u64).sum();
+                    let (value_log, _) = self.value_log.as_mut().unwrap();
+                    value_log.set_live_bytes(segment, live_len);
+                    live_len
+                }
+            };
+            if live_len as f64 >= segment_len as f64 * VALUE_LOG_GC_LIVE_RATIO {
                 continue;
             }
-            let (record, rest_page) = Record::from_bytes(page, ()).unwrap();
-            records.push(record);
-            page = rest_page;
+
+            for entry in self.live_value_log_entries(segment).await {
+                let (value_log, _) = self.value_log.as_mut().unwrap();
+                let value = value_log.read(entry.pointer).await;
+                let pointer = value_log.append(entry.hash, &entry.key, &value).await;
+
+                let bucket_index = self.directory.get(self.hash_to_remainder(entry.hash)).await;
+                let mut bucket = Bucket::read_from_file(&mut self.buckets_file, bucket_index).await;
+                if let Some(record) = bucket.records.iter_mut().find(|x| {
+                    x.0 == entry.hash
+                        && x.1 == entry.key
+                        && x.value_pointer() == Some(entry.pointer)
+                }) {
+                    record.2 = pointer.into_bytes();
+                }
+                bucket.save_to_file(&mut self.buckets_file).await;
+            }
+
+            let (value_log, _) = self.value_log.as_mut().unwrap();
+            value_log.sync_all().await;
+            self.buckets_file.file.sync_all().await;
+            value_log.remove_segment(segment).await;
         }
+    }
 
-        let mut bucket = Bucket {
-            bucket_index,
-            level,
-            records,
-            remaining_byte_space: 0,
-        };
+    /// Returns the entries of the value log segment which a record still points at
+    async fn live_value_log_entries(&mut self, segment: SegmentId) -> Vec<ValueLogEntry> {
+        let (value_log, _) = self.value_log.as_mut().unwrap();
+        let (entries, _) = value_log.scan(segment).await;
+
+        let mut live = vec![];
+        for entry in entries {
+            let bucket_index = self.directory.get(self.hash_to_remainder(entry.hash)).await;
+            let bucket = Bucket::read_from_file(&mut self.buckets_file, bucket_index).await;
+            let is_live = bucket.records.iter().any(|x| {
+                x.0 == entry.hash && x.1 == entry.key && x.value_pointer() == Some(entry.pointer)
+            });
+            if is_live {
+                live.push(entry);
+            }
+        }
+        live
+    }
 
-        bucket.update_remaining_byte_count();
+    /// Writes the changed directory pages and the bucket count
+    async fn save_index(&mut self) {
+        self.directory.flush().await;
+        save_buckets_file(self.bucket_count, self.key_count, &mut self.buckets_file).await;
+    }
 
-        Ok((bucket, bytes))
+    fn hash_key_to_remainder(&self, key: &[u8]) -> (Hash, usize) {
+        let hash = hash_key(key);
+        let remainder = hash % 2_u64.pow(self.directory.global_level().into());
+        (hash, remainder.try_into().unwrap())
     }
-}
 
-impl Bucket {
-    fn update_remaining_byte_count(&mut self) {
-        let records_byte_len: usize = self.records.iter().map(|r| r.byte_len()).sum();
-        self.remaining_byte_space = PAGE_BYTES - BUCKET_HEADER_BYTES - records_byte_len
+    fn hash_to_remainder(&self, hash: Hash) -> usize {
+        (hash % 2_u64.pow(self.directory.global_level().into()))
+            .try_into()
+            .unwrap()
     }
 
-    async fn read_from_file(file: &mut File, bucket_index: BucketIndexType) -> Self {
-        file.seek(SeekFrom::Start(
-            (BUCKET_INDEX_TYPE_BYTES + (bucket_index as usize) * PAGE_BYTES) as u64,
-        ))
-        .await
-        .unwrap();
-        let mut buf = [0; PAGE_BYTES];
-        file.read_exact(&mut buf).await.unwrap();
-        let (bucket, _) = Self::from_bytes(buf.iter(), bucket_index).unwrap();
-        bucket
+    fn debug(&self) {
+        println!(
+            "Bucket count: {}, Cached directory pages: {}, Global lvl: {}",
+            self.bucket_count,
+            self.directory.cached_page_count(),
+            self.directory.global_level()
+        );
     }
 
-    async fn save_to_file(&self, file: &mut File) {
-        file.seek(SeekFrom::Start(
-            (BUCKET_INDEX_TYPE_BYTES + self.bucket_index as usize * PAGE_BYTES) as u64,
-        ))
-        .await
-        .unwrap();
-        let mut buf = [0_u8; PAGE_BYTES];
-        buf[0] = self.level;
-        let mut ptr = 1_usize;
-        // TODO: Decide what to do with this clone
-        for record in self.records.clone().into_iter() {
-            let bytes = record.into_bytes();
-            let length = bytes.len();
-            for i in ptr..ptr + length {
-                buf[i] = bytes[i - ptr];
-            }
-            ptr = ptr + length;
+    /// Stores the record in its bucket, replacing the record of the same key or item
+    ///
+    /// A bucket without room for the record is split once. When the half the record belongs to
+    /// still doesn't have room it takes overflow pages, and is split further by
+    /// `split_overflowing` as commands come in, so a put never waits on more than one split
+    ///
+    /// # Returns
+    /// - The record which was replaced, or an error when the record is too large for a page
+    async fn put(&mut self, record: Record) -> Result<Option<Record>, ()> {
+        if record.byte_len() > self.buckets_file.max_record_bytes() {
+            return Err(());
         }
 
-        file.write_all(&buf).await.unwrap();
-    }
-}
+        // Look up the address of the bucket
+        let bucket_index = self.directory.get(self.hash_to_remainder(record.0)).await;
 
-#[cfg(test)]
-mod test_bucket {
-    use super::*;
-    use crate::test::*;
+        // Load the bucket, dropping the expired records to make room
+        let mut bucket = Bucket::read_from_file(&mut self.buckets_file, bucket_index).await;
+        let removed = bucket.remove_expired(now_millis());
+        self.records_removed(&removed);
+        self.update_data_bytes(record.data_len(), 0);
 
-    #[tokio::test]
-    async fn to_and_from_file() {
-        let mut bucket = Bucket {
-            bucket_index: 0,
-            level: 1,
-            remaining_byte_space: 0,
-            records: vec![
-                Record(0b_1110, vec![1], vec![25, 236, 36, 46]),
-                Record(0b_0010, vec![2], vec![26, 236, 36, 46]),
-                Record(0b_0110, vec![3], vec![27, 236, 36, 46]),
-            ],
+        let existing = bucket
+            .records
+            .iter_mut()
+            .find(|x| x.is(record.0, &record.1, record.item_type()));
+        let replaced = match existing {
+            Some(existing) => {
+                let replaced = std::mem::replace(existing, record);
+                self.update_data_bytes(0, replaced.data_len());
+                if replaced.value_pointer() != existing.value_pointer() {
+                    self.release_values(std::slice::from_ref(&replaced));
+                }
+                Some(replaced)
+            }
+            None => {
+                if !record.5.is_item() {
+                    self.key_count += 1;
+                }
+                bucket.records.push(record);
+                None
+            }
         };
-        bucket.update_remaining_byte_count();
 
-        let mut file = reset_or_create_file("./test_data/test_bucket_to_and_from_file").into();
-        bucket.save_to_file(&mut file).await;
+        let bucket_count = self.bucket_count;
+        let split = !bucket.fits() && bucket.can_split();
+        let buckets = if split {
+            let (bucket, new_bucket) = self.split(bucket).await;
+            vec![bucket, new_bucket]
+        } else {
+            vec![bucket]
+        };
+        for mut bucket in buckets {
+            self.add_overflow_pages(&mut bucket, &mut vec![]);
+            if !bucket.fits_page() {
+                self.overflowing.insert(bucket.bucket_index);
+            }
+            bucket.save_to_file(&mut self.buckets_file).await;
+        }
 
-        let bucket_ = Bucket::read_from_file(&mut file, 0).await;
-        assert_eq!(bucket_, bucket);
+        // Persist the directory entries and bucket count changed by a split or overflow pages
+        if split || self.bucket_count != bucket_count {
+            self.save_index().await;
+        }
+        Ok(replaced)
     }
-}
-
-/// The record stored in the database
-///
-/// Made of the hash of type `Hash` and the associated binary data
-///
-/// ## Binary representation
-///
-/// - A record header of `RECORD_HEADER_BYTES` in length
-///     - This has a value of 1, indicating that it is not empty space
-/// - The hash containing `HASH_BYTES` in length
-/// - Record key value header indicating the length of the key, has a length of `RECORD_KEY_HEADER_BYTES`
-/// - The bytes containing the key with the length indicated by the record's key header
-/// - Record value header indicating the length of the value, has a length of `RECORD_VALUE_HEADER_BYTES`
-/// - The bytes containing the value with the length indicated by the record's value header
-///
-#[derive(Clone, Debug, PartialEq)]
-struct Record(Hash, Vec<u8>, Vec<u8>);
-
-/// The type used to indicate the header of a record, see `Record` for the full layout
-type RecordHeader = u8;
 
-/// The header to indicate that a record is present and not empty space, see `Record` for the full
-/// layout
-const RECORD_HEADER: RecordHeader = 1;
+    /// Splits one of the buckets which hold more records than fit in their page, see `put`
+    async fn split_overflowing(&mut self) {
+        let Some(bucket_index) = self.overflowing.pop_first() else {
+            return;
+        };
+        let bucket = Bucket::read_from_file(&mut self.buckets_file, bucket_index).await;
+        if bucket.fits_page() || !bucket.can_split() {
+            return;
+        }
+        let (bucket, new_bucket) = self.split(bucket).await;
+        for bucket in [bucket, new_bucket] {
+            if !bucket.fits_page() {
+                self.overflowing.insert(bucket.bucket_index);
+            }
+            bucket.save_to_file(&mut self.buckets_file).await;
+        }
+        self.save_index().await;
+    }
 
-/// The length of the record header in bytes
-const RECORD_HEADER_BYTES: usize = size_of::<RecordHeader>();
+    /// Splits the bucket in two by the next bit of the hashes of its records, pointing the
+    /// directory entries of the upper half at the new bucket and doubling the directory when the
+    /// bucket was already at the global level
+    ///
+    /// The pages of the bucket are handed out again, with the new bucket taking a page the
+    /// bucket no longer needs before a new one. Neither bucket is saved, both fit in their pages
+    async fn split(&mut self, mut bucket: Bucket) -> (Bucket, Bucket) {
+        // Every record of the bucket shares the original remainder in the low bits of its hash
+        let og_bucket_remainder = (bucket.records[0].0 % 2_u64.pow(bucket.level.into())) as usize;
 
-/// The type used to indicate the len of the key component in a record, see `Record` for the full layout
-type RecordKeyLength = u16;
+        bucket.level += 1;
 
-/// The len in bytes for the key header in a record, see `Record` for the full layout
-const RECORD_KEY_HEADER_BYTES: usize = size_of::<RecordKeyLength>();
+        // Split the bucket in half into 2 Vec<Records> one with the new bucket and one with the original bucket
+        let (original, new): (Vec<_>, Vec<_>) = std::mem::take(&mut bucket.records)
+            .into_iter()
+            .partition(|x| x.0 % 2_u64.pow(bucket.level.into()) == og_bucket_remainder as u64);
+        bucket.records = original;
+
+        let mut spare = std::mem::take(&mut bucket.overflow);
+        spare.reverse();
+        self.add_overflow_pages(&mut bucket, &mut spare);
+        let new_bucket_index = spare.pop().unwrap_or_else(|| self.new_page());
+        let mut new_bucket = Bucket {
+            bucket_index: new_bucket_index,
+            overflow: vec![],
+            level: bucket.level,
+            page_bytes: bucket.page_bytes,
+            records: new,
+        };
+        self.add_overflow_pages(&mut new_bucket, &mut spare);
+        // Pages which neither bucket needs stay chained to the bucket to be used again
+        bucket.overflow.extend(spare.into_iter().rev());
+
+        // Local split
+        if bucket.level <= self.directory.global_level() {
+            // The lookup entries which point to the existing bucket are the ones ending with
+            // the original remainder, re-adjust the ones which now belong to the new bucket.
+            // These are computed rather than searched for so only the directory pages
+            // holding them are touched
+            let bucket_addr_count = 2_usize.pow(bucket.level.into());
+            let first_index = og_bucket_remainder + bucket_addr_count / 2;
+            for index in (first_index..self.directory.len()).step_by(bucket_addr_count) {
+                self.directory.set(index, new_bucket_index).await;
+            }
+        } else {
+            // Global split
+
+            // Readjust the indices
+            let old_len = self.directory.len();
+            self.directory.double().await;
+            self.directory
+                .set(old_len + og_bucket_remainder, new_bucket_index)
+                .await;
+        }
+        (bucket, new_bucket)
+    }
 
-/// The type used to indicate the len of the value component in a record, see `Record` for the full layout
-type RecordValueLength = u16;
+    /// Chains overflow pages to the bucket until its records fit, taking them from the end of
+    /// `spare` before making new ones
+    fn add_overflow_pages(&mut self, bucket: &mut Bucket, spare: &mut Vec<BucketIndexType>) {
+        while !bucket.fits() {
+            let page = spare.pop().unwrap_or_else(|| self.new_page());
+            bucket.overflow.push(page);
+        }
+    }
 
-/// The len in bytes for the value header in a record, see `Record` for the full layout
-const RECORD_VALUE_HEADER_BYTES: usize = size_of::<RecordValueLength>();
+    /// Takes the next page of the buckets file for a bucket or overflow page
+    fn new_page(&mut self) -> BucketIndexType {
+        self.bucket_count += 1;
+        self.bucket_count - 1
+    }
 
-/// The maximum length in bytes in which a key and value pair can be in a record
-const MAX_RECORD_KEY_VALUE_BYTES: usize = PAGE_BYTES
-    - BUCKET_HEADER_BYTES
-    - RECORD_HEADER_BYTES
-    - HASH_BYTES
-    - RECORD_KEY_HEADER_BYTES
-    - RECORD_VALUE_HEADER_BYTES;
+    /// Stores the value of a put, making room for it under the quota and logging it first
+    async fn put_value(&mut self, cmd: PutCommand) -> Result<(), String> {
+        validate_key(&cmd.0)?;
+        validate_value(&cmd.1)?;
+        self.make_room(std::slice::from_ref(&cmd), &[]).await?;
+        self.store_value(cmd).await
+    }
 
-impl IntoBytes for Record {
-    fn into_bytes(self) -> Vec<u8> {
-        let mut result = vec![];
-        result.push(RECORD_HEADER);
-        result.extend(self.0.to_le_bytes());
-        result.extend((self.1.len() as RecordKeyLength).to_le_bytes());
-        result.extend(self.1);
-        result.extend((self.2.len() as RecordValueLength).to_le_bytes());
-        result.extend(self.2);
-        return result;
+    /// Stores every put or none of them, checking every key and value and making room for all of
+    /// them before the first is stored
+    ///
+    /// When a key is put more than once the last value wins
+    async fn put_values(&mut self, puts: Vec<PutCommand>) -> Result<(), String> {
+        let mut keys = HashSet::new();
+        let mut puts: Vec<PutCommand> = puts
+            .into_iter()
+            .rev()
+            .filter(|x| keys.insert(x.0.clone()))
+            .collect();
+        puts.reverse();
+        for cmd in &puts {
+            validate_key(&cmd.0)?;
+            validate_value(&cmd.1)?;
+        }
+        self.make_room(&puts, &[]).await?;
+        for cmd in puts {
+            self.store_value(cmd).await?;
+        }
+        Ok(())
     }
-}
 
-impl ByteLength for Record {
-    fn byte_len(&self) -> usize {
-        RECORD_HEADER_BYTES
-            + HASH_BYTES
-            + RECORD_KEY_HEADER_BYTES
-            + self.1.len()
-            + RECORD_VALUE_HEADER_BYTES
-            + self.2.len()
+    /// Logs and stores the value of a put once it is known to fit
+    async fn store_value(&mut self, cmd: PutCommand) -> Result<(), String> {
+        self.log_mutation(Mutation::Put(cmd.clone())).await;
+        self.write_value(cmd).await
     }
-}
 
-impl<'a, T> ParseFromBytes<T> for Record
-where
-    T: Iterator<Item = &'a u8>,
-{
-    type Error = ();
-    type Metadata = ();
+    /// Stores the value of a put without logging it, the items of a list it replaces are removed
+    async fn write_value(&mut self, cmd: PutCommand) -> Result<(), String> {
+        self.write_record(cmd.0, cmd.1, cmd.2, ValueType::String)
+            .await
+    }
 
-    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), Self::Error> {
-        let header_bytes: [u8; RECORD_HEADER_BYTES] = take_bytes_from_iterator(&mut bytes);
-        let header = RecordHeader::from_le_bytes(header_bytes.try_into().unwrap());
-        if header != RECORD_HEADER {
-            return Err(());
+    /// Stores the value of the type under the key without logging it, see `write_value`
+    async fn write_record(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<Timestamp>,
+        value_type: ValueType,
+    ) -> Result<(), String> {
+        let hash = hash_key(&key);
+        let mut record = self.new_record(hash, key, value, expires_at).await;
+        record.5 = value_type;
+        let replaced = self
+            .put(record)
+            .await
+            .map_err(|_| "Key and value are too large to store".to_string())?;
+        if let Some(replaced) = replaced {
+            self.remove_items(&replaced).await;
         }
+        if let Some(quota) = &mut self.quota {
+            quota.touch(hash);
+        }
+        Ok(())
+    }
 
-        let hash_bytes: [u8; HASH_BYTES] = take_bytes_from_iterator(&mut bytes);
-        let hash = Hash::from_le_bytes(hash_bytes.try_into().unwrap());
-
-        let key_header_bytes: [u8; RECORD_KEY_HEADER_BYTES] = take_bytes_from_iterator(&mut bytes);
-        let key_len = RecordValueLength::from_le_bytes(key_header_bytes.try_into().unwrap());
-        let key: Vec<u8> = bytes.by_ref().take(key_len as usize).cloned().collect();
+    /// Moves the value and expiry of the first key of the rename to the second key
+    ///
+    /// The rename is logged as one mutation. The value is written under the new key before the
+    /// old key is deleted, so the value is never missing from both, and nothing is deleted when
+    /// the new key can't be written. Values are written again rather than moving the record, as
+    /// the value log entries and list items are tied to their key
+    async fn rename(
+        &mut self,
+        cmd: RenameCommand,
+        value: Value,
+        expires_at: Option<Timestamp>,
+    ) -> Result<(), String> {
+        validate_key(&cmd.1)?;
+        // The old key mustn't be evicted, the logged rename needs it to replay
+        let keep = [hash_key(&cmd.0)];
+        match &value {
+            Value::String(value) => {
+                let put = PutCommand(cmd.1.clone(), value.clone(), None);
+                self.make_room(std::slice::from_ref(&put), &keep).await?
+            }
+            value => {
+                let incoming = value_data_len(&cmd.1, value);
+                self.free_space(0, incoming, &keep).await?
+            }
+        }
+        self.log_mutation(Mutation::Rename(cmd.clone())).await;
+        self.start_pending(Mutation::Rename(cmd.clone())).await;
+        self.write_typed(cmd.1, value, expires_at).await?;
+        self.delete(DeleteCommand(cmd.0)).await.unwrap();
+        self.end_pending().await;
+        Ok(())
+    }
 
-        let value_header_bytes: [u8; RECORD_VALUE_HEADER_BYTES] =
-            take_bytes_from_iterator(&mut bytes);
-        let value_len = RecordValueLength::from_le_bytes(value_header_bytes.try_into().unwrap());
-        let value: Vec<u8> = bytes.by_ref().take(value_len as usize).cloned().collect();
+    /// Records the `FLUSHDB` or `RENAME` as started, so a crash before `end_pending` is finished
+    /// when the database opens, see `finish_pending`
+    async fn start_pending(&mut self, mutation: Mutation) {
+        let path = pending_path(&self.paths.1);
+        write_pending(&path, mutation, self.encryption_key.as_ref()).await;
+    }
 
-        return Ok((Record(hash, key, value), bytes));
+    /// Records the mutation passed to `start_pending` as finished, once everything it changed is
+    /// in the files
+    async fn end_pending(&mut self) {
+        self.save_index().await;
+        self.directory.sync_all().await;
+        self.buckets_file.file.sync_all().await;
+        tokio::fs::remove_file(pending_path(&self.paths.1))
+            .await
+            .unwrap();
     }
-}
 
-#[cfg(test)]
-mod test_record {
-    use super::*;
+    /// Finishes the mutation which was pending when the database was last closed, called when
+    /// it opens
+    ///
+    /// A rename is finished by renaming the old key again if it is still there, which moves it
+    /// over whatever part of the new key was written. A `FLUSHDB` is finished before the files
+    /// are opened, by `new`.
+    async fn finish_pending(&mut self, pending: Option<Mutation>) -> Result<(), String> {
+        match pending {
+            Some(Mutation::Rename(cmd)) => {
+                if let Some((value, expires_at)) = self.get_value(&cmd.0).await? {
+                    self.write_typed(cmd.1, value, expires_at).await?;
+                    self.delete(DeleteCommand(cmd.0)).await.unwrap();
+                }
+            }
+            Some(_) => {}
+            None => return Ok(()),
+        }
+        self.end_pending().await;
+        Ok(())
+    }
 
-    #[test]
-    fn into_and_from_bytes() {
-        let r = Record(0b_1110, vec![24, 21, 56, 0], vec![25, 236, 36, 46]);
-        let bytes = r.clone().into_bytes();
-        let (r_, bs) = Record::from_bytes(bytes.iter(), ()).unwrap();
-        assert_eq!(r_, r);
-        assert_eq!(bs.len(), 0);
+    /// Copies the list or hash under the first key along with its expiry to the second key,
+    /// replacing its value
+    ///
+    /// Logged as one mutation rather than the writes which rebuild the value
+    async fn copy_typed(
+        &mut self,
+        cmd: CopyCommand,
+        value: Value,
+        expires_at: Option<Timestamp>,
+    ) -> Result<(), String> {
+        validate_key(&cmd.1)?;
+        let incoming = value_data_len(&cmd.1, &value);
+        self.free_space(0, incoming, &[hash_key(&cmd.0)]).await?;
+        self.log_mutation(Mutation::Copy(cmd.clone())).await;
+        self.write_typed(cmd.1, value, expires_at).await
     }
-}
+
+    /// Stores the value of any type under the key without logging it, replacing the value of
+    /// the key
+    async fn write_typed(
+        &mut self,
+        key: Vec<u8>,
+        value: Value,
+        expires_at: Option<Timestamp>,
+    ) -> Result<(), String> {
+        match value {
+            Value::String(value) => self.write_value(PutCommand(key, value, expires_at)).await,
+            Value::List(items) => self.write_list(key, items, expires_at).await,
+            Value::Hash(fields) => self.write_members(key, &fields, expires_at).await,
+            Value::SortedSet(members) => self.write_members(key, &members, expires_at).await,
+            Value::Set(members) => self.write_members(key, &members, expires_at).await,
+        }
+    }
+
+    /// Stores the items as a new list under the key without logging it, replacing the value of
+    /// the key
+    async fn write_list(
+        &mut self,
+        key: Vec<u8>,
+        items: VecDeque<Vec<u8>>,
+        expires_at: Option<Timestamp>,
+    ) -> Result<(), String> {
+        self.delete_record(hash_key(&key), &key).await;
+        let mut list = ListMeta::default();
+        for value in items {
+            self.write_item(&key, list.tail, value, expires_at).await?;
+            list.tail += 1;
+        }
+        self.write_meta(key, list.into_bytes(), expires_at, ValueType::List)
+            .await
+    }
+
+    /// Returns the record of the key when it holds a value of the type, unless the key is
+    /// missing. The key counts as used for the eviction policy
+    ///
+    /// # Returns
+    /// - An error when the key holds another type
+    async fn find_typed(
+        &mut self,
+        key: &[u8],
+        value_type: ValueType,
+    ) -> Result<Option<Record>, String> {
+        let hash = hash_key(key);
+        match self.find_record(hash, key).await {
+            Some(record) if record.5 == value_type => {
+                if let Some(quota) = &mut self.quota {
+                    quota.touch(hash);
+                }
+                Ok(Some(record))
+            }
+            Some(_) => Err(WRONG_TYPE.into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the record of the list under the key, see `find_typed`
+    async fn find_list(&mut self, key: &[u8]) -> Result<Option<(Record, ListMeta)>, String> {
+        let Some(record) = self.find_typed(key, ValueType::List).await? else {
+            return Ok(None);
+        };
+        let list = ListMeta::of(&record)?;
+        Ok(Some((record, list)))
+    }
+
+    /// Pushes the values onto the list, creating it when the key is missing, see
+    /// `ListPushCommand`
+    ///
+    /// The items are written before the list record which counts them, so the list record never
+    /// counts an item which isn't stored. New items expire along with the list
+    ///
+    /// # Returns
+    /// - The number of items in the list afterwards
+    async fn push(&mut self, cmd: ListPushCommand) -> Result<u64, String> {
+        validate_key(&cmd.0)?;
+        for value in &cmd.2 {
+            validate_value(value)?;
+        }
+        let (mut list, expires_at, existing) = match self.find_list(&cmd.0).await? {
+            Some((record, list)) => (list, record.4, record.data_len()),
+            None => (ListMeta::default(), None, 0),
+        };
+        if cmd.2.is_empty() {
+            return Ok(list.len());
+        }
+        let hash = hash_key(&cmd.0);
+        let incoming = list_data_len(&cmd.0, &cmd.2);
+        self.free_space(existing, incoming, &[hash]).await?;
+
+        self.log_mutation(Mutation::ListPush(cmd.clone())).await;
+        let ListPushCommand(key, side, values) = cmd;
+        for value in values {
+            let index = match side {
+                ListSide::Left => {
+                    list.head -= 1;
+                    list.head
+                }
+                ListSide::Right => {
+                    list.tail += 1;
+                    list.tail - 1
+                }
+            };
+            self.write_item(&key, index, value, expires_at).await?;
+        }
+        self.write_meta(key, list.into_bytes(), expires_at, ValueType::List)
+            .await?;
+        if let Some(quota) = &mut self.quota {
+            quota.touch(hash);
+        }
+        Ok(list.len())
+    }
+
+    /// Removes and returns the item at the side of the list, deleting the key along with the last
+    /// item, see `ListPopCommand`
+    ///
+    /// # Returns
+    /// - The item, or `None` when the key is missing
+    async fn pop(&mut self, cmd: ListPopCommand) -> Result<Option<Vec<u8>>, String> {
+        let Some((record, mut list)) = self.find_list(&cmd.0).await? else {
+            return Ok(None);
+        };
+        self.log_mutation(Mutation::ListPop(cmd.clone())).await;
+        let index = match cmd.1 {
+            ListSide::Left => {
+                list.head += 1;
+                list.head - 1
+            }
+            ListSide::Right => {
+                list.tail -= 1;
+                list.tail
+            }
+        };
+        let item_key = list_item_key(&cmd.0, index);
+        let item = self
+            .remove_record(hash_key(&item_key), &item_key, Some(ValueType::ListItem))
+            .await
+            .ok_or("List item is missing")?;
+        let value = self.load_value(item).await?;
+        if list.len() == 0 {
+            self.remove_record(record.0, &cmd.0, None).await;
+        } else {
+            self.write_meta(cmd.0, list.into_bytes(), record.4, ValueType::List)
+                .await?;
+        }
+        Ok(Some(value))
+    }
+
+    /// Returns every member of the hash, sorted set or set under the key, see `find_typed`
+    async fn find_members<T: Members>(&mut self, key: &[u8]) -> Result<Option<T>, String> {
+        let Some(record) = self.find_typed(key, T::VALUE_TYPE).await? else {
+            return Ok(None);
+        };
+        let members = self.load_members(&record).await?;
+        T::from_members(members).map(Some)
+    }
+
+    /// Returns the record of the member of the hash, sorted set or set under the key with its
+    /// slot and payload, unless it isn't a member
+    async fn find_member(
+        &mut self,
+        key: &[u8],
+        member: &[u8],
+    ) -> Result<Option<(Record, u64, Vec<u8>)>, String> {
+        let member_key = member_key(key, member);
+        let hash = hash_key(&member_key);
+        let Some(record) = self.find(hash, &member_key, Some(ValueType::Member)).await else {
+            return Ok(None);
+        };
+        let value = self.load_value(record.clone()).await?;
+        let mut bytes = value.iter();
+        let slot = read_varint(&mut bytes).map_err(|_| "Member record is corrupt".to_string())?;
+        let payload = bytes.cloned().collect();
+        Ok(Some((record, slot, payload)))
+    }
+
+    /// Returns every member of the record of a hash, sorted set or set with its payload, in the
+    /// order of their slots
+    async fn load_members(&mut self, record: &Record) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+        let mut members = vec![];
+        for slot in 0..member_count(record)? {
+            let slot_key = slot_key(&record.1, slot);
+            let member = self
+                .find(hash_key(&slot_key), &slot_key, Some(ValueType::Slot))
+                .await
+                .ok_or("Member slot is missing")?
+                .2;
+            let (_, _, payload) = self
+                .find_member(&record.1, &member)
+                .await?
+                .ok_or("Member record is missing")?;
+            members.push((member, payload));
+        }
+        Ok(members)
+    }
+
+    /// Adds the members with their payloads to the hash, sorted set or set of the type under
+    /// the key, creating it when the key is missing, and logs the mutation first
+    ///
+    /// Only the records of the members are written, so the cost of an add doesn't grow with the
+    /// value. The member records are written before the record which counts them, like the
+    /// items of a list, see `push`. New members expire along with the key, and when a member is
+    /// given more than once the last payload wins
+    ///
+    /// # Returns
+    /// - The number of members which weren't there before
+    async fn add_members(
+        &mut self,
+        key: Vec<u8>,
+        value_type: ValueType,
+        members: Vec<(Vec<u8>, Vec<u8>)>,
+        mutation: Mutation,
+    ) -> Result<u64, String> {
+        validate_key(&key)?;
+        for (member, payload) in &members {
+            validate_member(member)?;
+            validate_value(payload)?;
+        }
+        let (len, expires_at) = match self.find_typed(&key, value_type).await? {
+            Some(record) => (member_count(&record)?, record.4),
+            None => (0, None),
+        };
+        let mut seen = HashSet::new();
+        let mut members: Vec<_> = members
+            .into_iter()
+            .rev()
+            .filter(|x| seen.insert(x.0.clone()))
+            .collect();
+        members.reverse();
+        if members.is_empty() {
+            return Ok(0);
+        }
+
+        // Members which are already there keep their slot, new ones take the slots after the last
+        let mut slots = vec![];
+        let mut added = 0;
+        let mut existing = 0;
+        let mut incoming = 0;
+        if len == 0 {
+            incoming += (key.len() + MEMBER_COUNT_BYTES) as u64;
+        }
+        for (member, payload) in &members {
+            let slot = match self.find_member(&key, member).await? {
+                Some((record, slot, _)) => {
+                    existing +=
+                        record.data_len() + (slot_key(&key, slot).len() + member.len()) as u64;
+                    slots.push((slot, false));
+                    slot
+                }
+                None => {
+                    added += 1;
+                    slots.push((len + added - 1, true));
+                    len + added - 1
+                }
+            };
+            incoming += member_data_len(&key, slot, member, payload);
+        }
+        let hash = hash_key(&key);
+        self.free_space(existing, incoming, &[hash]).await?;
+
+        // Every record is made before the first is stored, so a member which doesn't fit in a
+        // page is refused before anything is written
+        let mut records = vec![];
+        for ((member, payload), (slot, new)) in members.into_iter().zip(slots) {
+            if new {
+                records.push(slot_record(&key, slot, member.clone(), expires_at));
+            }
+            records.push(
+                self.member_record(&key, member, slot, payload, expires_at)
+                    .await,
+            );
+        }
+        let max_record_bytes = self.buckets_file.max_record_bytes();
+        if records.iter().any(|x| x.byte_len() > max_record_bytes) {
+            self.release_values(&records);
+            return Err("Member is too large to store".into());
+        }
+
+        self.log_mutation(mutation).await;
+        self.put_members(records).await?;
+        let count = (len + added).to_le_bytes().to_vec();
+        self.write_meta(key, count, expires_at, value_type).await?;
+        if let Some(quota) = &mut self.quota {
+            quota.touch(hash);
+        }
+        Ok(added)
+    }
+
+    /// Removes the members from the hash, sorted set or set of the type under the key, deleting
+    /// the key along with the last member, and logs the mutation first
+    ///
+    /// The member in the last slot is moved into the slot of each removed member, so the slots
+    /// stay numbered from 0 without gaps, see `ValueType`
+    ///
+    /// # Returns
+    /// - The number of members which were there
+    async fn remove_members(
+        &mut self,
+        key: Vec<u8>,
+        value_type: ValueType,
+        members: Vec<Vec<u8>>,
+        mutation: Mutation,
+    ) -> Result<u64, String> {
+        let Some(record) = self.find_typed(&key, value_type).await? else {
+            return Ok(0);
+        };
+        let mut removed = vec![];
+        for member in members {
+            if !removed.contains(&member) && self.find_member(&key, &member).await?.is_some() {
+                removed.push(member);
+            }
+        }
+        if removed.is_empty() {
+            return Ok(0);
+        }
+
+        self.log_mutation(mutation).await;
+        let mut len = member_count(&record)?;
+        for member in &removed {
+            let (member_record, slot, _) = self
+                .find_member(&key, member)
+                .await?
+                .ok_or("Member record is missing")?;
+            self.remove_record(member_record.0, &member_record.1, Some(ValueType::Member))
+                .await;
+            len -= 1;
+            self.move_member(&key, len, slot).await?;
+        }
+        if len == 0 {
+            self.remove_record(record.0, &key, None).await;
+        } else {
+            let count = len.to_le_bytes().to_vec();
+            self.write_meta(key, count, record.4, value_type).await?;
+        }
+        Ok(removed.len() as u64)
+    }
+
+    /// Moves the member in the last slot of the hash, sorted set or set under the key into the
+    /// slot, which a member was removed from, and drops the last slot
+    async fn move_member(&mut self, key: &[u8], last: u64, slot: u64) -> Result<(), String> {
+        let last_key = slot_key(key, last);
+        let last_record = self
+            .remove_record(hash_key(&last_key), &last_key, Some(ValueType::Slot))
+            .await
+            .ok_or("Member slot is missing")?;
+        if last == slot {
+            return Ok(());
+        }
+        let (member, expires_at) = (last_record.2, last_record.4);
+        let (_, _, payload) = self
+            .find_member(key, &member)
+            .await?
+            .ok_or("Member record is missing")?;
+        let slot_record = slot_record(key, slot, member.clone(), expires_at);
+        let member_record = self
+            .member_record(key, member, slot, payload, expires_at)
+            .await;
+        self.put_members(vec![slot_record, member_record]).await
+    }
+
+    /// Stores the members as a new hash, sorted set or set under the key without logging it,
+    /// replacing the value of the key
+    async fn write_members<T: Members>(
+        &mut self,
+        key: Vec<u8>,
+        value: &T,
+        expires_at: Option<Timestamp>,
+    ) -> Result<(), String> {
+        self.delete_record(hash_key(&key), &key).await;
+        let mut len: u64 = 0;
+        for (member, payload) in value.to_members() {
+            let slot_record = slot_record(&key, len, member.clone(), expires_at);
+            let member_record = self
+                .member_record(&key, member, len, payload, expires_at)
+                .await;
+            self.put_members(vec![slot_record, member_record]).await?;
+            len += 1;
+        }
+        let count = len.to_le_bytes().to_vec();
+        self.write_meta(key, count, expires_at, T::VALUE_TYPE).await
+    }
+
+    /// Creates the record of the member of a hash, sorted set or set holding its slot and
+    /// payload, see `new_record`
+    async fn member_record(
+        &mut self,
+        key: &[u8],
+        member: Vec<u8>,
+        slot: u64,
+        payload: Vec<u8>,
+        expires_at: Option<Timestamp>,
+    ) -> Record {
+        let member_key = member_key(key, &member);
+        let mut value = vec![];
+        write_varint(&mut value, slot);
+        value.extend(payload);
+        let hash = hash_key(&member_key);
+        let mut record = self.new_record(hash, member_key, value, expires_at).await;
+        record.5 = ValueType::Member;
+        record
+    }
+
+    /// Stores the member and slot records of a hash, sorted set or set without logging them
+    async fn put_members(&mut self, records: Vec<Record>) -> Result<(), String> {
+        for record in records {
+            self.put(record)
+                .await
+                .map_err(|_| "Member is too large to store".to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Stores the item of the list with the number without logging it
+    async fn write_item(
+        &mut self,
+        key: &[u8],
+        index: i64,
+        value: Vec<u8>,
+        expires_at: Option<Timestamp>,
+    ) -> Result<(), String> {
+        let item_key = list_item_key(key, index);
+        let hash = hash_key(&item_key);
+        let mut record = self.new_record(hash, item_key, value, expires_at).await;
+        record.5 = ValueType::ListItem;
+        self.put(record)
+            .await
+            .map_err(|_| "List item is too large to store".to_string())?;
+        Ok(())
+    }
+
+    /// Stores the record under the key of a value with items, holding the `ListMeta` of a list
+    /// or the `member_count` of a hash, sorted set or set, without logging it
+    async fn write_meta(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<Timestamp>,
+        value_type: ValueType,
+    ) -> Result<(), String> {
+        let hash = hash_key(&key);
+        let record = Record(hash, key, value, RECORD_HEADER, expires_at, value_type);
+        self.put(record)
+            .await
+            .map_err(|_| "Key is too large to store".to_string())?;
+        Ok(())
+    }
+
+    /// Returns the items of the list under the key with the numbers, see `ListMeta`
+    async fn load_items(
+        &mut self,
+        key: &[u8],
+        indices: impl Iterator<Item = i64>,
+    ) -> Result<Vec<Vec<u8>>, String> {
+        let mut items = vec![];
+        for index in indices {
+            let item_key = list_item_key(key, index);
+            let item = self
+                .find(hash_key(&item_key), &item_key, Some(ValueType::ListItem))
+                .await
+                .ok_or("List item is missing")?;
+            items.push(self.load_value(item).await?);
+        }
+        Ok(items)
+    }
+
+    /// Returns the key and type of every item of the record, the items of a list or the member
+    /// and slot records of a hash, sorted set or set. Other records have no items
+    async fn item_keys(&mut self, record: &Record) -> Result<Vec<(Vec<u8>, ValueType)>, String> {
+        let mut keys = vec![];
+        match record.5 {
+            ValueType::List => {
+                let list = ListMeta::of(record)?;
+                for index in list.head..list.tail {
+                    keys.push((list_item_key(&record.1, index), ValueType::ListItem));
+                }
+            }
+            ValueType::Hash | ValueType::SortedSet | ValueType::Set => {
+                for slot in 0..member_count(record)? {
+                    let slot_key = slot_key(&record.1, slot);
+                    let hash = hash_key(&slot_key);
+                    let slot_record = self.find(hash, &slot_key, Some(ValueType::Slot)).await;
+                    if let Some(slot_record) = slot_record {
+                        keys.push((member_key(&record.1, &slot_record.2), ValueType::Member));
+                    }
+                    keys.push((slot_key, ValueType::Slot));
+                }
+            }
+            _ => {}
+        }
+        Ok(keys)
+    }
+
+    /// Gives the items of the record the expiry of the record, see `item_keys`
+    async fn expire_items(&mut self, record: &Record) -> Result<(), String> {
+        for (item_key, item_type) in self.item_keys(record).await? {
+            let hash = hash_key(&item_key);
+            if let Some(mut item) = self.find(hash, &item_key, Some(item_type)).await {
+                item.4 = record.4;
+                self.put(item)
+                    .await
+                    .map_err(|_| "Item is too large to store".to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the items of the record, see `item_keys`
+    async fn remove_items(&mut self, record: &Record) {
+        let Ok(item_keys) = self.item_keys(record).await else {
+            return;
+        };
+        for (item_key, item_type) in item_keys {
+            self.remove_record(hash_key(&item_key), &item_key, Some(item_type))
+                .await;
+        }
+    }
+
+    /// Deletes every key by replacing the directory and buckets files with empty ones
+    ///
+    /// A running backup is finished first as it copies from the files being replaced. The empty
+    /// files are written and synced next to the old ones before being renamed over them, so each
+    /// file is replaced in one step. The flush is recorded as pending before the first rename, so
+    /// a crash between the two renames is finished when the database opens, see `new`
+    async fn flush_db(&mut self) {
+        self.continue_backup(usize::MAX).await;
+        self.log_mutation(Mutation::FlushDb).await;
+        let key = self.encryption_key.clone();
+        let (directory_path, buckets_path) = self.paths.clone();
+
+        let directory_temp = flush_temp_path(&directory_path);
+        let file = create_empty_file(&directory_temp);
+        let file = BlockFile::open(file, key.as_ref()).await.unwrap();
+        let mut directory = Directory::load(file).await.unwrap();
+        directory.flush().await;
+        directory.sync_all().await;
+
+        let buckets_temp = flush_temp_path(&buckets_path);
+        let file = create_empty_file(&buckets_temp);
+        let page_bytes = self.buckets_file.page_bytes;
+        let (mut buckets_file, bucket_count, _) = load_buckets_file(
+            BlockFile::open(file, key.as_ref()).await.unwrap(),
+            page_bytes,
+        )
+        .await
+        .unwrap();
+        buckets_file.file.sync_all().await;
+
+        self.start_pending(Mutation::FlushDb).await;
+        tokio::fs::rename(&directory_temp, &directory_path)
+            .await
+            .unwrap();
+        tokio::fs::rename(&buckets_temp, &buckets_path)
+            .await
+            .unwrap();
+        self.directory = directory;
+        self.buckets_file = buckets_file;
+        self.bucket_count = bucket_count;
+        self.overflowing.clear();
+        self.key_count = 0;
+        self.end_pending().await;
+        if let Some(quota) = &mut self.quota {
+            quota.clear();
+        }
+        // Every value in the closed segments is garbage now
+        if let Some((value_log, _)) = &mut self.value_log {
+            value_log.release_all().await;
+        }
+        self.collect_value_log_garbage().await;
+    }
+
+    /// Deletes the key, logging the delete first
+    ///
+    /// # Returns
+    /// - Whether the key was present
+    async fn delete_value(&mut self, cmd: DeleteCommand) -> bool {
+        self.log_mutation(Mutation::Delete(cmd.clone())).await;
+        self.delete(cmd).await.unwrap()
+    }
+
+    /// Adds the amount to the counter under the key keeping its expiry, and returns its new
+    /// value
+    ///
+    /// Logged as a put of the result, so replaying the log gives the same value even once the
+    /// key has expired
+    pub(crate) async fn incr(&mut self, cmd: IncrCommand) -> Result<i64, IncrError> {
+        let entry = self.get_entry(&cmd.0).await.map_err(IncrError::Storage)?;
+        let (value, expires_at) = match &entry {
+            Some((value, expires_at)) => (Some(value.as_slice()), *expires_at),
+            None => (None, None),
+        };
+        let value = increment(value, cmd.1)?;
+        let put = PutCommand(cmd.0, value.to_string().into_bytes(), expires_at);
+        self.put_value(put).await.map_err(IncrError::Storage)?;
+        Ok(value)
+    }
+
+    /// Returns the string value of the key with the time it expires at, unless it is missing or
+    /// has expired
+    ///
+    /// # Returns
+    /// - An error when the key holds another type
+    pub(crate) async fn get_entry(
+        &mut self,
+        key: &[u8],
+    ) -> Result<Option<(Vec<u8>, Option<Timestamp>)>, String> {
+        let Some(record) = self.find_typed(key, ValueType::String).await? else {
+            return Ok(None);
+        };
+        let expires_at = record.4;
+        Ok(Some((self.load_value(record).await?, expires_at)))
+    }
+
+    /// Returns the value of the key of any type with the time it expires at, unless it is
+    /// missing or has expired. Every item of a list is loaded
+    pub(crate) async fn get_value(
+        &mut self,
+        key: &[u8],
+    ) -> Result<Option<(Value, Option<Timestamp>)>, String> {
+        let hash = hash_key(key);
+        let Some(record) = self.find_record(hash, key).await else {
+            return Ok(None);
+        };
+        if let Some(quota) = &mut self.quota {
+            quota.touch(hash);
+        }
+        let expires_at = record.4;
+        Ok(Some((self.load_typed_value(record).await?, expires_at)))
+    }
+
+    /// Returns the value of the record of a key along with its type
+    async fn load_typed_value(&mut self, record: Record) -> Result<Value, String> {
+        match record.5 {
+            ValueType::List => {
+                let list = ListMeta::of(&record)?;
+                let items = self.load_items(&record.1, list.head..list.tail).await?;
+                Ok(Value::List(items.into()))
+            }
+            ValueType::Hash => {
+                let members = self.load_members(&record).await?;
+                Ok(Value::Hash(Members::from_members(members)?))
+            }
+            ValueType::SortedSet => {
+                let members = self.load_members(&record).await?;
+                Ok(Value::SortedSet(Members::from_members(members)?))
+            }
+            ValueType::Set => {
+                let members = self.load_members(&record).await?;
+                Ok(Value::Set(Members::from_members(members)?))
+            }
+            _ => Ok(Value::String(self.load_value(record).await?)),
+        }
+    }
+
+    /// Returns the record of the key unless it is missing or has expired, see `find`
+    async fn find_record(&mut self, hash: Hash, key: &[u8]) -> Option<Record> {
+        self.find(hash, key, None).await
+    }
+
+    /// Returns the record of the key, or of the item of the type when `item` is set, unless it
+    /// is missing or has expired
+    ///
+    /// The expired records of the bucket are removed on the way
+    async fn find(&mut self, hash: Hash, key: &[u8], item: Option<ValueType>) -> Option<Record> {
+        let remainder = self.hash_to_remainder(hash);
+        let bucket_index = self.directory.get(remainder).await;
+        let mut bucket = Bucket::read_from_file(&mut self.buckets_file, bucket_index).await;
+        let removed = bucket.remove_expired(now_millis());
+        if !removed.is_empty() {
+            self.records_removed(&removed);
+            bucket.save_to_file(&mut self.buckets_file).await;
+        }
+
+        bucket.records.into_iter().find(|x| x.is(hash, key, item))
+    }
+
+    /// Deletes the key, see `delete_record`
+    pub async fn delete(&mut self, cmd: DeleteCommand) -> Result<bool, ()> {
+        let hash = hash_key(&cmd.0);
+        Ok(self.delete_record(hash, &cmd.0).await)
+    }
+
+    /// Deletes the record matching both the hash and the key, every other record of the bucket
+    /// is kept, including records which only share the hash. The items of a list are deleted
+    /// along with it
+    ///
+    /// # Returns
+    /// - Whether the key was present, a key which has expired counts as missing
+    async fn delete_record(&mut self, hash: Hash, key: &[u8]) -> bool {
+        let Some(record) = self.remove_record(hash, key, None).await else {
+            return false;
+        };
+        self.remove_items(&record).await;
+        true
+    }
+
+    /// Removes the record of the key, or of the item of the type when `item` is set, from its
+    /// bucket
+    ///
+    /// # Returns
+    /// - The removed record, `None` when it was missing or had expired
+    async fn remove_record(
+        &mut self,
+        hash: Hash,
+        key: &[u8],
+        item: Option<ValueType>,
+    ) -> Option<Record> {
+        let bucket_index = self.directory.get(self.hash_to_remainder(hash)).await;
+        let mut bucket = Bucket::read_from_file(&mut self.buckets_file, bucket_index).await;
+        let expired = bucket.remove_expired(now_millis());
+        self.records_removed(&expired);
+
+        let position = bucket.records.iter().position(|x| x.is(hash, key, item));
+        let Some(position) = position else {
+            if !expired.is_empty() {
+                bucket.save_to_file(&mut self.buckets_file).await;
+            }
+            return None;
+        };
+        let removed = bucket.records.remove(position);
+        self.records_removed(std::slice::from_ref(&removed));
+        if let Some(quota) = &mut self.quota {
+            quota.forget(hash);
+        }
+        bucket.save_to_file(&mut self.buckets_file).await;
+        Some(removed)
+    }
+
+    /// Updates the key count, data size and value log once the records are removed from their
+    /// bucket
+    fn records_removed(&mut self, removed: &[Record]) {
+        let keys = removed.iter().filter(|x| !x.5.is_item()).count();
+        self.key_count = self.key_count.saturating_sub(keys as u64);
+        self.update_data_bytes(0, data_len(removed));
+        self.release_values(removed);
+    }
+
+    /// Marks the values the records point at in the value log as garbage
+    fn release_values(&mut self, records: &[Record]) {
+        let Some((value_log, _)) = &mut self.value_log else {
+            return;
+        };
+        for record in records {
+            if let Some(pointer) = record.value_pointer() {
+                value_log.release(pointer, record.1.len());
+            }
+        }
+    }
+
+    /// Updates the data size counted by the quota, see `Quota::update`
+    fn update_data_bytes(&mut self, added: u64, removed: u64) {
+        if let Some(quota) = &mut self.quota {
+            quota.update(added, removed);
+        }
+    }
+}
+
+/// Moves the cursor of a scan past the range of the bucket with the level, see
+/// `HashStorage::scan`
+///
+/// # Returns
+/// - 0 once the cursor wraps around, which is when every bucket has been visited
+fn next_scan_cursor(cursor: Hash, level: BucketLevel) -> Hash {
+    let level_mask = (1 as Hash)
+        .checked_shl(level.into())
+        .map_or(Hash::MAX, |x| x - 1);
+    (cursor | !level_mask)
+        .reverse_bits()
+        .wrapping_add(1)
+        .reverse_bits()
+}
+
+/// Iterator over every key value pair in the hash table, created by `HashStorage::keyspace`
+///
+/// The directory is walked in order and each bucket is read the first time an entry points at
+/// it, the entries which alias a bucket that was already visited are skipped, so every bucket
+/// page is read once. The storage is passed to every call rather than borrowed by the iterator
+/// so the storage can be used in between, but putting or deleting keys part way through may
+/// skip or repeat keys.
+pub struct KeyspaceIter {
+    /// The next directory entry to visit
+    directory_index: usize,
+
+    /// The buckets which have been visited
+    visited: HashSet<BucketIndexType>,
+
+    /// The records of the current bucket which haven't been returned yet
+    records: std::vec::IntoIter<Record>,
+}
+
+impl KeyspaceIter {
+    /// Returns the next key value pair along with the time the key expires, or `None` once
+    /// every bucket has been visited
+    pub async fn next(
+        &mut self,
+        storage: &mut HashStorage,
+    ) -> Result<Option<(Vec<u8>, Value, Option<Timestamp>)>, String> {
+        let Some(record) = self.next_record(storage).await else {
+            return Ok(None);
+        };
+        let (key, expires_at) = (record.1.clone(), record.4);
+        Ok(Some((
+            key,
+            storage.load_typed_value(record).await?,
+            expires_at,
+        )))
+    }
+
+    /// Returns the record of the next key which hasn't expired without loading its value, the
+    /// records of list items are skipped
+    async fn next_record(&mut self, storage: &mut HashStorage) -> Option<Record> {
+        let now = now_millis();
+        loop {
+            if let Some(record) = self.records.next() {
+                if record.is_expired(now) || record.5.is_item() {
+                    continue;
+                }
+                return Some(record);
+            }
+            if self.directory_index >= storage.directory.len() {
+                return None;
+            }
+            let bucket_index = storage.directory.get(self.directory_index).await;
+            self.directory_index += 1;
+            if self.visited.insert(bucket_index) {
+                let bucket = Bucket::read_from_file(&mut storage.buckets_file, bucket_index).await;
+                self.records = bucket.records.into_iter();
+            }
+        }
+    }
+}
+
+/// Rust representation of a bucket
+///
+/// ## Binary layout
+///
+/// - First `BUCKET_HEADER_BYTES` indicate the local level of the bucket, with `OVERFLOW_FLAG`
+///   set when the page is followed by an overflow page
+/// - When `OVERFLOW_FLAG` is set, the index of the overflow page in `BUCKET_LINK_BYTES` LE
+/// - Rest are records
+///
+/// A bucket holding more records than fit in its page keeps the rest in overflow pages, which
+/// are pages of the buckets file chained one after the other and not pointed at by the
+/// directory. They only last until the bucket is split, see `HashStorage::split_overflowing`.
+/// Every record fits in a page with a link, so any page of the chain can hold it.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Bucket {
+    /// The nth bucket in the bucket file, 0 indexed
+    bucket_index: BucketIndexType,
+
+    /// The overflow pages chained to the page of the bucket, in order
+    overflow: Vec<BucketIndexType>,
+
+    /// The local level of the bucket
+    level: BucketLevel,
+
+    /// The number of bytes in the page holding the bucket
+    page_bytes: usize,
+
+    /// The records contained in the bucket, across its page and overflow pages
+    records: Vec<Record>,
+}
+
+/// The length of the bucket header in bytes
+const BUCKET_HEADER_BYTES: usize = BUCKET_LEVEL_BYTES;
+
+/// Set in the level stored in a page of a bucket when it is followed by the index of an
+/// overflow page, the level itself never gets near this bit
+const OVERFLOW_FLAG: BucketLevel = 0x80;
+
+/// The length in bytes of the index of the overflow page following a page
+const BUCKET_LINK_BYTES: usize = size_of::<u64>();
+
+impl<'a, T> ParseFromBytes<T> for Bucket
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+
+    /// The index of the bucket and the page size
+    type Metadata = (BucketIndexType, usize);
+
+    fn from_bytes(mut bytes: T, metadata: Self::Metadata) -> Result<(Self, T), Self::Error> {
+        let (bucket_index, page_bytes) = metadata;
+        let page: Vec<u8> = bytes.by_ref().take(page_bytes).cloned().collect();
+        if page.len() != page_bytes {
+            return Err(());
+        }
+        let mut page = page.iter().peekable();
+
+        let level_bytes: [u8; BUCKET_LEVEL_BYTES] = take_bytes_from_iterator(&mut page);
+        let level = BucketLevel::from_le_bytes(level_bytes);
+        let mut overflow = vec![];
+        if level & OVERFLOW_FLAG != 0 {
+            let link: [u8; BUCKET_LINK_BYTES] = take_bytes_from_iterator(&mut page);
+            overflow.push(u64::from_le_bytes(link) as BucketIndexType);
+        }
+        let mut records = vec![];
+
+        while let Some(x) = page.peek() {
+            if **x == 0 {
+                page.next();
+                continue;
+            }
+            let (record, rest_page) = Record::from_bytes(page, ()).unwrap();
+            records.push(record);
+            page = rest_page;
+        }
+
+        let bucket = Bucket {
+            bucket_index,
+            overflow,
+            level: level & !OVERFLOW_FLAG,
+            page_bytes,
+            records,
+        };
+        Ok((bucket, bytes))
+    }
+}
+
+impl Bucket {
+    /// Spreads the records across the page and overflow pages of the bucket, each record going
+    /// into the first page with room for it
+    ///
+    /// # Returns
+    /// - The records of each page, `None` when they don't fit
+    fn pack(&self) -> Option<Vec<Vec<&Record>>> {
+        let page_count = self.overflow.len() + 1;
+        let mut pages: Vec<(usize, Vec<&Record>)> = (0..page_count)
+            .map(|x| {
+                let linked = x + 1 < page_count;
+                let space = self.page_bytes - BUCKET_HEADER_BYTES;
+                (space - if linked { BUCKET_LINK_BYTES } else { 0 }, vec![])
+            })
+            .collect();
+        for record in &self.records {
+            let len = record.byte_len();
+            let (space, records) = pages.iter_mut().find(|x| x.0 >= len)?;
+            *space -= len;
+            records.push(record);
+        }
+        Some(pages.into_iter().map(|x| x.1).collect())
+    }
+
+    /// Whether the records fit in the pages of the bucket
+    fn fits(&self) -> bool {
+        self.pack().is_some()
+    }
+
+    /// Whether the records fit in the page of the bucket without any overflow pages
+    fn fits_page(&self) -> bool {
+        let len: usize = self.records.iter().map(|x| x.byte_len()).sum();
+        len <= self.page_bytes - BUCKET_HEADER_BYTES
+    }
+
+    /// Whether splitting the bucket can move any of its records, which it can't when they all
+    /// have the same hash
+    fn can_split(&self) -> bool {
+        self.records.iter().any(|x| x.0 != self.records[0].0)
+    }
+
+    /// Removes the records which have expired by `now`
+    ///
+    /// # Returns
+    /// - The removed records, the bucket needs saving when there are any
+    fn remove_expired(&mut self, now: Timestamp) -> Vec<Record> {
+        let (expired, records) = std::mem::take(&mut self.records)
+            .into_iter()
+            .partition(|x| x.is_expired(now));
+        self.records = records;
+        expired
+    }
+
+    /// Reads the bucket from its page and the overflow pages chained to it
+    async fn read_from_file(file: &mut BucketsFile, bucket_index: BucketIndexType) -> Self {
+        let mut bucket = Self::read_page(file, bucket_index).await;
+        let mut next = bucket.overflow.first().copied();
+        while let Some(index) = next {
+            let page = Self::read_page(file, index).await;
+            next = page.overflow.first().copied();
+            bucket.records.extend(page.records);
+            bucket.overflow.extend(page.overflow);
+        }
+        bucket
+    }
+
+    /// Reads a single page of a bucket, with the overflow page following it in `overflow`
+    async fn read_page(file: &mut BucketsFile, bucket_index: BucketIndexType) -> Self {
+        let position = file.bucket_position(bucket_index);
+        let buf = file
+            .file
+            .read_block(position, file.page_bytes)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to read bucket {}: {}", bucket_index, e))
+            .expect("Bucket is missing from the buckets file");
+        let (bucket, _) = Self::from_bytes(buf.iter(), (bucket_index, file.page_bytes)).unwrap();
+        bucket
+    }
+
+    /// Writes the page and overflow pages of the bucket, see `pack`
+    async fn save_to_file(&self, file: &mut BucketsFile) {
+        let pages = self
+            .pack()
+            .expect("Records don't fit in the pages of the bucket");
+        let indices = std::iter::once(&self.bucket_index).chain(&self.overflow);
+        for (i, (index, records)) in indices.zip(pages).enumerate() {
+            let mut buf = vec![0_u8; self.page_bytes];
+            buf[0] = self.level;
+            let mut ptr = BUCKET_HEADER_BYTES;
+            if let Some(next) = self.overflow.get(i) {
+                buf[0] |= OVERFLOW_FLAG;
+                buf[ptr..ptr + BUCKET_LINK_BYTES].copy_from_slice(&(*next as u64).to_le_bytes());
+                ptr += BUCKET_LINK_BYTES;
+            }
+            for record in records {
+                let bytes = record.clone().into_bytes();
+                let length = bytes.len();
+                buf[ptr..ptr + length].copy_from_slice(&bytes);
+                ptr += length;
+            }
+
+            let position = file.bucket_position(*index);
+            file.file.write_block(position, &buf).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_bucket {
+    use super::*;
+    use crate::test::*;
+
+    #[tokio::test]
+    async fn to_and_from_file() {
+        let bucket = Bucket {
+            bucket_index: 0,
+            level: 1,
+            overflow: vec![],
+            page_bytes: DEFAULT_PAGE_BYTES,
+            records: vec![
+                Record::new(0b_1110, vec![1], vec![25, 236, 36, 46]),
+                Record::new(0b_0010, vec![2], vec![26, 236, 36, 46]),
+                Record::new(0b_0110, vec![3], vec![27, 236, 36, 46]),
+            ],
+        };
+
+        let file = reset_or_create_file("./test_data/test_bucket_to_and_from_file");
+        let mut file = BucketsFile {
+            file: BlockFile::new(file.into(), None),
+            page_bytes: DEFAULT_PAGE_BYTES,
+            has_key_count: true,
+            has_page_bytes: true,
+        };
+        bucket.save_to_file(&mut file).await;
+
+        let bucket_ = Bucket::read_from_file(&mut file, 0).await;
+        assert_eq!(bucket_, bucket);
+
+        // Records which don't fit in the page of the bucket go into its overflow pages
+        let bucket = Bucket {
+            bucket_index: 1,
+            overflow: vec![3, 2],
+            level: 1,
+            page_bytes: DEFAULT_PAGE_BYTES,
+            records: (1..=5)
+                .map(|x| Record::new(x, vec![x as u8], vec![7; 1500]))
+                .collect(),
+        };
+        bucket.save_to_file(&mut file).await;
+        assert_eq!(Bucket::read_from_file(&mut file, 1).await, bucket);
+        let page = Bucket::read_page(&mut file, 3).await;
+        assert_eq!(page.overflow, vec![2]);
+        assert_eq!(page.records, bucket.records[2..4]);
+    }
+}
+
+/// The record stored in the database
+///
+/// Made of the hash of type `Hash` and the associated binary data
+///
+/// ## Binary representation
+///
+/// - A record header of `RECORD_HEADER_BYTES` in length, this is a set of flags
+///     - `RECORD_HEADER` is always set, indicating that it is not empty space
+///     - `RECORD_VALUE_POINTER` is set when the value is a `ValuePointer` into the value log
+///       rather than the value itself
+///     - `RECORD_COMPRESSED` is set when the value is compressed, see `compression::compress`.
+///       Together with `RECORD_VALUE_POINTER` this means the value in the value log is compressed
+///     - `RECORD_VARINT_LENGTHS` is set when the lengths below are varints, see
+///       `bytes::write_varint`. Records written before this flag existed have a
+///       `RECORD_KEY_HEADER_BYTES` key length and a `RECORD_VALUE_HEADER_BYTES` value length in LE
+///     - `RECORD_EXPIRES` is set when the record has an expiry
+///     - `RECORD_TYPED` is set when the value isn't a string
+/// - The hash containing `HASH_BYTES` in length
+/// - The time the record expires as a `Timestamp` in LE, only when `RECORD_EXPIRES` is set
+/// - The length of the key
+/// - The bytes containing the key with the length indicated by the record's key header
+/// - The `ValueType` of the value as a byte, only when `RECORD_TYPED` is set
+/// - The length of the value
+/// - The bytes containing the value with the length indicated by the record's value header
+///
+/// Records are always written with varint lengths, the flag is only kept on disk and not in the
+/// record header held in memory. Likewise `RECORD_EXPIRES` and `RECORD_TYPED` are only kept on
+/// disk, in memory the expiry is the fifth component and the value type the last.
+///
+/// The value component holds the bytes as stored in the bucket, so it is the value log pointer
+/// when `RECORD_VALUE_POINTER` is set and the compressed value when `RECORD_COMPRESSED` is set.
+/// The fourth component is the record header.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Record(
+    pub Hash,
+    pub Vec<u8>,
+    pub Vec<u8>,
+    pub RecordHeader,
+    pub Option<Timestamp>,
+    pub ValueType,
+);
+
+impl Record {
+    /// Creates a record with a string value stored inline
+    pub fn new(hash: Hash, key: Vec<u8>, value: Vec<u8>) -> Self {
+        Self(hash, key, value, RECORD_HEADER, None, ValueType::String)
+    }
+
+    /// Whether this is the record of the key, or of an item of the type under the key when
+    /// `item` is set. Items are told apart from keys and by their type, so a key can't clash with
+    /// the key of an item, nor can the key of a member clash with the key of a slot
+    fn is(&self, hash: Hash, key: &[u8], item: Option<ValueType>) -> bool {
+        let matches = match item {
+            Some(item) => self.5 == item,
+            None => !self.5.is_item(),
+        };
+        self.0 == hash && self.1 == key && matches
+    }
+
+    /// The type of the record when it is an item, for looking up the record it replaces, see
+    /// `is`
+    fn item_type(&self) -> Option<ValueType> {
+        self.5.is_item().then_some(self.5)
+    }
+
+    /// Whether the record has expired by `now`, expired records are never returned and are
+    /// removed from their bucket the next time it is touched
+    fn is_expired(&self, now: Timestamp) -> bool {
+        is_expired(self.4, now)
+    }
+
+    /// The bytes the record counts towards the data size, the key and the stored value, which is
+    /// in the value log when the record points there, see `Quota`
+    fn data_len(&self) -> u64 {
+        let value_len = match self.value_pointer() {
+            Some(pointer) => pointer.len as u64,
+            None => self.2.len() as u64,
+        };
+        self.1.len() as u64 + value_len
+    }
+
+    /// Returns the value log pointer if the value of the record is stored in the value log
+    fn value_pointer(&self) -> Option<ValuePointer> {
+        if self.3 & RECORD_VALUE_POINTER == 0 {
+            return None;
+        }
+        ValuePointer::from_bytes(self.2.iter(), ())
+            .ok()
+            .map(|(pointer, _)| pointer)
+    }
+}
+
+/// The data size of the records, see `Record::data_len`
+fn data_len(records: &[Record]) -> u64 {
+    records.iter().map(|x| x.data_len()).sum()
+}
+
+/// The type used to indicate the header of a record, see `Record` for the full layout
+pub(crate) type RecordHeader = u8;
+
+/// The header to indicate that a record is present and not empty space, see `Record` for the full
+/// layout
+const RECORD_HEADER: RecordHeader = 1;
+
+/// The header flag to indicate that the value of the record is a pointer into the value log, see
+/// `Record` for the full layout
+const RECORD_VALUE_POINTER: RecordHeader = 1 << 1;
+
+/// The header flag to indicate that the value of the record is compressed, see `Record` for the
+/// full layout
+const RECORD_COMPRESSED: RecordHeader = 1 << 2;
+
+/// The header flag to indicate that the lengths of the key and value are varints, see `Record`
+/// for the full layout
+const RECORD_VARINT_LENGTHS: RecordHeader = 1 << 3;
+
+/// The header flag to indicate that the record has an expiry, see `Record` for the full layout
+const RECORD_EXPIRES: RecordHeader = 1 << 4;
+
+/// The header flag to indicate that the record is followed by the type of its value, see `Record`
+/// for the full layout
+const RECORD_TYPED: RecordHeader = 1 << 5;
+
+/// The type of the value of a record, written after the key when it isn't a string, see `Record`
+/// for the full layout
+///
+/// A list is stored as a record holding its `ListMeta` under the key of the list, with each item
+/// in a record of its own under `list_item_key`, so a list isn't limited to what fits in a page.
+/// Item records aren't keys, so they are left out when listing or counting the keys
+///
+/// A hash, sorted set or set is stored as a record holding the number of its members under the
+/// key, see `member_count`, with each field of a hash or member of a set in a record of its own
+/// under `member_key`. The record of a member holds its slot followed by its payload, see
+/// `Members`. The slots are numbered from 0 and each has a record under `slot_key` holding the
+/// member in it, so the members can be listed without a record holding all of them. Member and
+/// slot records are items like the items of a list
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ValueType {
+    String = 0,
+    List = 1,
+    ListItem = 2,
+    Hash = 3,
+    SortedSet = 4,
+    Set = 5,
+    Member = 6,
+    Slot = 7,
+}
+
+impl ValueType {
+    fn from_byte(byte: u8) -> Result<Self, ()> {
+        match byte {
+            0 => Ok(Self::String),
+            1 => Ok(Self::List),
+            2 => Ok(Self::ListItem),
+            3 => Ok(Self::Hash),
+            4 => Ok(Self::SortedSet),
+            5 => Ok(Self::Set),
+            6 => Ok(Self::Member),
+            7 => Ok(Self::Slot),
+            _ => Err(()),
+        }
+    }
+
+    /// Whether the record holds an item of a key rather than a key
+    fn is_item(self) -> bool {
+        matches!(self, Self::ListItem | Self::Member | Self::Slot)
+    }
+}
+
+/// The value of the record of a list, the items are stored in records of their own
+///
+/// The items are numbered from `head` up to but not including `tail`. A push to the left takes
+/// the number below `head` and a push to the right takes `tail`, so the items never move
+///
+/// ## Binary layout
+/// - `head` as an i64 in LE
+/// - `tail` as an i64 in LE
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct ListMeta {
+    head: i64,
+    tail: i64,
+}
+
+impl ListMeta {
+    fn len(&self) -> u64 {
+        (self.tail - self.head) as u64
+    }
+
+    /// Reads the list from the value of its record
+    fn of(record: &Record) -> Result<Self, String> {
+        Self::from_bytes(record.2.iter(), ())
+            .map(|(list, _)| list)
+            .map_err(|_| "List record is corrupt".to_string())
+    }
+}
+
+impl IntoBytes for ListMeta {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.head.to_le_bytes().to_vec();
+        bytes.extend(self.tail.to_le_bytes());
+        bytes
+    }
+}
+
+impl<'a, T> ParseFromBytes<T> for ListMeta
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), Self::Error> {
+        let mut read = || {
+            let mut buf = [0; size_of::<i64>()];
+            for byte in buf.iter_mut() {
+                *byte = *bytes.next().ok_or(())?;
+            }
+            Ok(i64::from_le_bytes(buf))
+        };
+        let (head, tail) = (read()?, read()?);
+        if head > tail {
+            return Err(());
+        }
+        Ok((Self { head, tail }, bytes))
+    }
+}
+
+/// The data size of a list holding the items, the record of the list along with the records of
+/// the items, see `Record::data_len`
+fn list_data_len<'a>(key: &[u8], items: impl IntoIterator<Item = &'a Vec<u8>>) -> u64 {
+    let item_key_len = list_item_key(key, 0).len();
+    let items_len: usize = items.into_iter().map(|x| item_key_len + x.len()).sum();
+    (key.len() + LIST_META_BYTES + items_len) as u64
+}
+
+/// The data size of the value under the key, see `Record::data_len`
+fn value_data_len(key: &[u8], value: &Value) -> u64 {
+    match value {
+        Value::String(value) => (key.len() + value.len()) as u64,
+        Value::List(items) => list_data_len(key, items),
+        Value::Hash(fields) => members_data_len(key, fields),
+        Value::SortedSet(members) => members_data_len(key, members),
+        Value::Set(members) => members_data_len(key, members),
+    }
+}
+
+/// The length in bytes of the value of the record of a hash, sorted set or set, see
+/// `member_count`
+const MEMBER_COUNT_BYTES: usize = size_of::<u64>();
+
+/// Reads the number of members from the record of a hash, sorted set or set, which holds it as a
+/// u64 in LE
+fn member_count(record: &Record) -> Result<u64, String> {
+    let bytes = record.2.as_slice().try_into();
+    let bytes = bytes.map_err(|_| "Record of the members is corrupt".to_string())?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Creates the record of the slot of a hash, sorted set or set holding the member in it
+///
+/// The member is stored as it is, it is no longer than a key so it always fits in a page
+fn slot_record(key: &[u8], slot: u64, member: Vec<u8>, expires_at: Option<Timestamp>) -> Record {
+    let slot_key = slot_key(key, slot);
+    let hash = hash_key(&slot_key);
+    Record(
+        hash,
+        slot_key,
+        member,
+        RECORD_HEADER,
+        expires_at,
+        ValueType::Slot,
+    )
+}
+
+/// The data size of the member with the payload in the slot, its record along with the record of
+/// its slot, see `Record::data_len`
+fn member_data_len(key: &[u8], slot: u64, member: &[u8], payload: &[u8]) -> u64 {
+    let member_len = member_key(key, member).len() + varint_len(slot) + payload.len();
+    let slot_len = slot_key(key, slot).len() + member.len();
+    (member_len + slot_len) as u64
+}
+
+/// The data size of a hash, sorted set or set, the record under the key along with the records
+/// of the members, see `Record::data_len`
+fn members_data_len<T: Members>(key: &[u8], value: &T) -> u64 {
+    let members = value.to_members();
+    let members_len: u64 = (0..)
+        .zip(&members)
+        .map(|(slot, (member, payload))| member_data_len(key, slot, member, payload))
+        .sum();
+    (key.len() + MEMBER_COUNT_BYTES) as u64 + members_len
+}
+
+/// A value stored as members in records of their own, see `ValueType`
+///
+/// The record of each member holds a payload along with its slot, which is the value of a field
+/// of a hash, the score of a member of a sorted set, see `score_payload`, and nothing for a
+/// member of a set
+trait Members: Sized {
+    /// The type of the record under the key
+    const VALUE_TYPE: ValueType;
+
+    /// Every member with its payload
+    fn to_members(&self) -> Vec<(Vec<u8>, Vec<u8>)>;
+
+    /// Builds the value from every member with its payload
+    fn from_members(members: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Self, String>;
+}
+
+impl Members for BTreeMap<Vec<u8>, Vec<u8>> {
+    const VALUE_TYPE: ValueType = ValueType::Hash;
+
+    fn to_members(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.clone().into_iter().collect()
+    }
+
+    fn from_members(members: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Self, String> {
+        Ok(members.into_iter().collect())
+    }
+}
+
+impl Members for SortedSet {
+    const VALUE_TYPE: ValueType = ValueType::SortedSet;
+
+    fn to_members(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.iter()
+            .map(|(member, score)| (member.clone(), score_payload(score)))
+            .collect()
+    }
+
+    fn from_members(members: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Self, String> {
+        let mut sorted_set = SortedSet::default();
+        for (member, payload) in members {
+            sorted_set.insert(member, score_of_payload(&payload)?);
+        }
+        Ok(sorted_set)
+    }
+}
+
+/// The payload of the record of a member of a sorted set, its score as an f64 in LE
+fn score_payload(score: Score) -> Vec<u8> {
+    score.value().to_le_bytes().to_vec()
+}
+
+/// Reads the score of a member of a sorted set from the payload of its record, see
+/// `score_payload`
+fn score_of_payload(payload: &[u8]) -> Result<Score, String> {
+    let corrupt = || "Sorted set member is corrupt".to_string();
+    let bytes = payload.try_into().map_err(|_| corrupt())?;
+    Score::new(f64::from_le_bytes(bytes)).ok_or_else(corrupt)
+}
+
+impl Members for BTreeSet<Vec<u8>> {
+    const VALUE_TYPE: ValueType = ValueType::Set;
+
+    fn to_members(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.iter().map(|member| (member.clone(), vec![])).collect()
+    }
+
+    fn from_members(members: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Self, String> {
+        Ok(members.into_iter().map(|(member, _)| member).collect())
+    }
+}
+
+/// The length in bytes of a `ListMeta`
+const LIST_META_BYTES: usize = 2 * size_of::<i64>();
+
+/// The key of the record of an item of the value under the key, see `ValueType`
+///
+/// The key of the value is prefixed with its length so the items of different keys never share
+/// a key
+fn item_key(key: &[u8], suffix: &[u8]) -> Vec<u8> {
+    let mut item_key = vec![];
+    write_varint(&mut item_key, key.len() as u64);
+    item_key.extend(key);
+    item_key.extend(suffix);
+    item_key
+}
+
+/// The key of the record holding the item of the list with the number, see `ListMeta`
+fn list_item_key(key: &[u8], index: i64) -> Vec<u8> {
+    item_key(key, &index.to_be_bytes())
+}
+
+/// The key of the record of the member of a hash, sorted set or set, see `ValueType`
+fn member_key(key: &[u8], member: &[u8]) -> Vec<u8> {
+    item_key(key, member)
+}
+
+/// The key of the record holding the member in the slot of a hash, sorted set or set, see
+/// `ValueType`
+fn slot_key(key: &[u8], slot: u64) -> Vec<u8> {
+    item_key(key, &slot.to_be_bytes())
+}
+
+/// The length of the record header in bytes
+const RECORD_HEADER_BYTES: usize = size_of::<RecordHeader>();
+
+/// The type used for the len of the key component in records written without
+/// `RECORD_VARINT_LENGTHS`, see `Record` for the full layout
+type RecordKeyLength = u16;
+
+/// The len in bytes for the key header in records written without `RECORD_VARINT_LENGTHS`, see
+/// `Record` for the full layout
+const RECORD_KEY_HEADER_BYTES: usize = size_of::<RecordKeyLength>();
+
+/// The type used for the len of the value component in records written without
+/// `RECORD_VARINT_LENGTHS`, see `Record` for the full layout
+type RecordValueLength = u16;
+
+/// The len in bytes for the value header in records written without `RECORD_VARINT_LENGTHS`, see
+/// `Record` for the full layout
+const RECORD_VALUE_HEADER_BYTES: usize = size_of::<RecordValueLength>();
+
+impl IntoBytes for Record {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut result = vec![];
+        let expires = if self.4.is_some() { RECORD_EXPIRES } else { 0 };
+        let typed = if self.5 != ValueType::String {
+            RECORD_TYPED
+        } else {
+            0
+        };
+        result.push(self.3 | RECORD_VARINT_LENGTHS | expires | typed);
+        result.extend(self.0.to_le_bytes());
+        if let Some(expires_at) = self.4 {
+            result.extend(expires_at.to_le_bytes());
+        }
+        write_varint(&mut result, self.1.len() as u64);
+        result.extend(self.1);
+        if typed != 0 {
+            result.push(self.5 as u8);
+        }
+        write_varint(&mut result, self.2.len() as u64);
+        result.extend(self.2);
+        result
+    }
+}
+
+impl ByteLength for Record {
+    fn byte_len(&self) -> usize {
+        RECORD_HEADER_BYTES
+            + HASH_BYTES
+            + self.4.map_or(0, |_| size_of::<Timestamp>())
+            + varint_len(self.1.len() as u64)
+            + self.1.len()
+            + usize::from(self.5 != ValueType::String)
+            + varint_len(self.2.len() as u64)
+            + self.2.len()
+    }
+}
+
+impl<'a, T> ParseFromBytes<T> for Record
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), Self::Error> {
+        let header_bytes: [u8; RECORD_HEADER_BYTES] = take_bytes_from_iterator(&mut bytes);
+        let header = RecordHeader::from_le_bytes(header_bytes);
+        if header & RECORD_HEADER == 0 {
+            return Err(());
+        }
+
+        let hash_bytes: [u8; HASH_BYTES] = take_bytes_from_iterator(&mut bytes);
+        let hash = Hash::from_le_bytes(hash_bytes);
+
+        let expires_at = if header & RECORD_EXPIRES != 0 {
+            let buf: [u8; size_of::<Timestamp>()] = take_bytes_from_iterator(&mut bytes);
+            Some(Timestamp::from_le_bytes(buf))
+        } else {
+            None
+        };
+
+        let varint_lengths = header & RECORD_VARINT_LENGTHS != 0;
+
+        let key_len = if varint_lengths {
+            read_varint(&mut bytes)? as usize
+        } else {
+            let buf: [u8; RECORD_KEY_HEADER_BYTES] = take_bytes_from_iterator(&mut bytes);
+            RecordKeyLength::from_le_bytes(buf) as usize
+        };
+        let key: Vec<u8> = bytes.by_ref().take(key_len).cloned().collect();
+
+        let value_type = if header & RECORD_TYPED != 0 {
+            ValueType::from_byte(*bytes.next().ok_or(())?)?
+        } else {
+            ValueType::String
+        };
+
+        let value_len = if varint_lengths {
+            read_varint(&mut bytes)? as usize
+        } else {
+            let buf: [u8; RECORD_VALUE_HEADER_BYTES] = take_bytes_from_iterator(&mut bytes);
+            RecordValueLength::from_le_bytes(buf) as usize
+        };
+        let value: Vec<u8> = bytes.by_ref().take(value_len).cloned().collect();
+
+        if key.len() != key_len || value.len() != value_len {
+            return Err(());
+        }
+
+        Ok((
+            Record(
+                hash,
+                key,
+                value,
+                header & !(RECORD_VARINT_LENGTHS | RECORD_EXPIRES | RECORD_TYPED),
+                expires_at,
+                value_type,
+            ),
+            bytes,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test_record {
+    use super::*;
+
+    #[test]
+    fn into_and_from_bytes() {
+        let r = Record::new(0b_1110, vec![24, 21, 56, 0], vec![25, 236, 36, 46]);
+        let bytes = r.clone().into_bytes();
+        let (r_, bs) = Record::from_bytes(bytes.iter(), ()).unwrap();
+        assert_eq!(r_, r);
+        assert_eq!(bs.len(), 0);
+    }
+
+    #[test]
+    fn expiry_into_and_from_bytes() {
+        let mut r = Record::new(0b_1110, vec![24, 21, 56, 0], vec![25, 236, 36, 46]);
+        r.4 = Some(1_700_000_000_000);
+        let bytes = r.clone().into_bytes();
+        assert_eq!(bytes.len(), r.byte_len());
+        assert_eq!(
+            bytes[0],
+            RECORD_HEADER | RECORD_VARINT_LENGTHS | RECORD_EXPIRES
+        );
+        let (r_, _) = Record::from_bytes(bytes.iter(), ()).unwrap();
+        assert_eq!(r_, r);
+        assert!(r.is_expired(1_700_000_000_000));
+        assert!(!r.is_expired(1_699_999_999_999));
+    }
+
+    #[test]
+    fn reads_fixed_width_lengths() {
+        let mut bytes = vec![RECORD_HEADER];
+        bytes.extend(7_u64.to_le_bytes());
+        bytes.extend(3_u16.to_le_bytes());
+        bytes.extend(b"key");
+        bytes.extend(300_u16.to_le_bytes());
+        bytes.extend([9; 300]);
+        let (record, _) = Record::from_bytes(bytes.iter(), ()).unwrap();
+        assert_eq!(record, Record::new(7, b"key".to_vec(), vec![9; 300]));
+
+        // Rewriting the record switches it to varint lengths
+        let rewritten = record.into_bytes();
+        assert_eq!(rewritten[0], RECORD_HEADER | RECORD_VARINT_LENGTHS);
+        assert_eq!(rewritten.len(), bytes.len() - 1);
+    }
+
+    #[test]
+    fn value_pointer_into_and_from_bytes() {
+        let pointer = ValuePointer {
+            segment: 2,
+            offset: 100,
+            len: 5000,
+        };
+        let r = Record(
+            0b_1110,
+            vec![24, 21, 56, 0],
+            pointer.into_bytes(),
+            RECORD_HEADER | RECORD_VALUE_POINTER,
+            None,
+            ValueType::String,
+        );
+        let bytes = r.clone().into_bytes();
+        let (r_, _) = Record::from_bytes(bytes.iter(), ()).unwrap();
+        assert_eq!(r_, r);
+        assert_eq!(r_.value_pointer(), Some(pointer));
+        assert_eq!(Record::new(0, vec![], vec![]).value_pointer(), None);
+    }
+}
 
 #[cfg(test)]
 mod test_hash_storage {
     use super::*;
     use crate::test::*;
 
-    async fn get_engine(test_prefix: &str) -> HashStorage {
-        let test_data_prefx = String::from("./test_data");
-        let data_path = format!("{}/{}_data.db", test_data_prefx, test_prefix);
-        let dir_path = format!("{}/{}_dir.db", test_data_prefx, test_prefix);
-        reset_or_create_file(&data_path);
-        reset_or_create_file(&dir_path);
-        HashStorage::new(&dir_path, &data_path).await
+    async fn get_engine(test_prefix: &str) -> HashStorage {
+        let test_data_prefx = String::from("./test_data");
+        let data_path = format!("{}/{}_data.db", test_data_prefx, test_prefix);
+        let dir_path = format!("{}/{}_dir.db", test_data_prefx, test_prefix);
+        reset_or_create_file(&data_path);
+        reset_or_create_file(&dir_path);
+        let _ = std::fs::remove_file(pending_path(Path::new(&data_path)));
+        HashStorage::new(&dir_path, &data_path, HashStorageOptions::default())
+            .await
+            .unwrap()
+    }
+
+    async fn get_engine_without_reset(test_prefix: &str) -> HashStorage {
+        let test_data_prefx = String::from("./test_data");
+        let data_path = format!("{}/{}_data.db", test_data_prefx, test_prefix);
+        let dir_path = format!("{}/{}_dir.db", test_data_prefx, test_prefix);
+        HashStorage::new(&dir_path, &data_path, HashStorageOptions::default())
+            .await
+            .unwrap()
+    }
+
+    async fn get_engine_with_value_log(
+        test_prefix: &str,
+        threshold: usize,
+        segment_bytes: u64,
+    ) -> HashStorage {
+        let test_data_prefx = String::from("./test_data");
+        let data_path = format!("{}/{}_data.db", test_data_prefx, test_prefix);
+        let dir_path = format!("{}/{}_dir.db", test_data_prefx, test_prefix);
+        let values_path = format!("{}/{}_values", test_data_prefx, test_prefix);
+        reset_or_create_file(&data_path);
+        reset_or_create_file(&dir_path);
+        let _ = std::fs::remove_dir_all(&values_path);
+        let options = HashStorageOptions {
+            value_log: Some(ValueLogOptions {
+                directory: values_path,
+                threshold,
+                segment_bytes,
+            }),
+            ..Default::default()
+        };
+        HashStorage::new(&dir_path, &data_path, options)
+            .await
+            .unwrap()
+    }
+
+    async fn get_engine_with_quota(
+        test_prefix: &str,
+        max_data_bytes: u64,
+        policy: EvictionPolicy,
+        reset: bool,
+    ) -> HashStorage {
+        let data_path = format!("./test_data/{}_data.db", test_prefix);
+        let dir_path = format!("./test_data/{}_dir.db", test_prefix);
+        if reset {
+            reset_or_create_file(&data_path);
+            reset_or_create_file(&dir_path);
+        }
+        let options = HashStorageOptions {
+            quota: Some(QuotaOptions {
+                max_data_bytes,
+                policy,
+            }),
+            ..Default::default()
+        };
+        HashStorage::new(&dir_path, &data_path, options)
+            .await
+            .unwrap()
+    }
+
+    /// A printable value which doesn't shrink when compressed
+    fn incompressible_value(len: usize, seed: u64) -> String {
+        let mut state = seed.wrapping_add(0x9E3779B97F4A7C15);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (b'!' + (state % 94) as u8) as char
+            })
+            .collect()
+    }
+
+    fn record_from_size(hash: u64, key: u8, byte: u8, size: usize) -> Record {
+        let value_len =
+            size - RECORD_HEADER_BYTES - HASH_BYTES - varint_len(1) - 1 - varint_len(size as u64);
+        let value = vec![byte; value_len];
+        let record = Record::new(hash, vec![key], value);
+        assert_eq!(record.byte_len(), size);
+        record
+    }
+
+    #[tokio::test]
+    async fn smoke() {
+        let mut engine = get_engine("hash_storage_smoke").await;
+        let cmd = PutCommand("MY_KEY".into(), "MY_VALUE".into(), None);
+        let get_cmd = GetCommand("MY_KEY".into());
+
+        engine.handle_cmd(cmd.clone().into()).await.unwrap();
+        let retrieved = engine.handle_cmd(get_cmd.clone().into()).await.unwrap();
+
+        assert_eq!(retrieved, CommandOutput::Found("MY_VALUE".into()));
+
+        let cmd = PutCommand("MY_KEY".into(), "MY_VALUE2".into(), None);
+        engine.handle_cmd(cmd.clone().into()).await.unwrap();
+        let retrieved = engine.handle_cmd(get_cmd.clone().into()).await.unwrap();
+
+        assert_eq!(retrieved, CommandOutput::Found("MY_VALUE2".into()));
+
+        engine
+            .handle_cmd(DeleteCommand("MY_KEY".into()).into())
+            .await
+            .unwrap();
+
+        let retrieved = engine.handle_cmd(get_cmd.clone().into()).await.unwrap();
+        assert_eq!(retrieved, CommandOutput::NotFound("MY_KEY".into()));
+    }
+
+    #[tokio::test]
+    async fn deletes_only_the_key() {
+        let mut engine = get_engine("hash_storage_deletes_only_the_key").await;
+        // A crowded bucket, every key shares the single bucket
+        for i in 0..50 {
+            let cmd = PutCommand(format!("key{}", i).into(), "v".into(), None);
+            engine.handle_cmd(cmd.into()).await.unwrap();
+        }
+        assert_eq!(engine.bucket_count, 1);
+        let delete = |key: &str| StorageCommand::Delete(DeleteCommand(key.into()));
+        assert_eq!(
+            engine.handle_cmd(delete("key7")).await,
+            Ok(CommandOutput::Deleted)
+        );
+        assert_eq!(
+            engine.handle_cmd(delete("key7")).await,
+            Ok(CommandOutput::NotFound("key7".into()))
+        );
+        let bucket = Bucket::read_from_file(&mut engine.buckets_file, 0).await;
+        assert_eq!(bucket.records.len(), 49);
+        assert_eq!(engine.key_count, 49);
+
+        // Records sharing the hash or the key bytes with the deleted record are kept
+        for (hash, key) in [(7, "a"), (7, "b"), (8, "a")] {
+            engine
+                .put(Record::new(hash, key.into(), "v".into()))
+                .await
+                .unwrap();
+        }
+        assert!(engine.delete_record(7, b"a").await);
+        assert!(!engine.delete_record(7, b"a").await);
+        let bucket = Bucket::read_from_file(&mut engine.buckets_file, 0).await;
+        let mut remaining: Vec<(Hash, Vec<u8>)> = bucket
+            .records
+            .into_iter()
+            .filter(|x| x.0 < 10)
+            .map(|x| (x.0, x.1))
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, vec![(7, b"b".to_vec()), (8, b"a".to_vec())]);
+
+        // An expired key counts as missing
+        let cmd = PutCommand("gone".into(), "v".into(), Some(now_millis() - 1));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(delete("gone")).await,
+            Ok(CommandOutput::NotFound("gone".into()))
+        );
+        assert_eq!(
+            engine.handle_cmd(delete("key8")).await,
+            Ok(CommandOutput::Deleted)
+        );
+        let mdelete = MDeleteCommand(vec!["key9".into(), "key9".into(), "key8".into()]);
+        assert_eq!(
+            engine.handle_cmd(mdelete.into()).await,
+            Ok(CommandOutput::DeletedKeys(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_long_key() {
+        let mut engine = get_engine("hash_storage_rejects_long_key").await;
+        let error = engine
+            .handle_cmd(PutCommand(vec![1; MAX_KEY_BYTES + 1], "value".into(), None).into())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error,
+            format!(
+                "Key is {} bytes, the limit is {} bytes",
+                MAX_KEY_BYTES + 1,
+                MAX_KEY_BYTES
+            )
+        );
+
+        engine
+            .handle_cmd(PutCommand(vec![1; MAX_KEY_BYTES], "value".into(), None).into())
+            .await
+            .unwrap();
+        let retrieved = engine
+            .handle_cmd(GetCommand(vec![1; MAX_KEY_BYTES]).into())
+            .await
+            .unwrap();
+        assert_eq!(retrieved, CommandOutput::Found("value".into()));
+    }
+
+    #[tokio::test]
+    async fn keyspace_visits_every_key_once() {
+        let mut engine = get_engine("hash_storage_keyspace_visits_every_key_once").await;
+        let mut expected = vec![];
+        for i in 0..2500 {
+            let key = format!("key{}", i).into_bytes();
+            let value = format!("value{}", i).into_bytes();
+            engine
+                .handle_cmd(PutCommand(key.clone(), value.clone(), None).into())
+                .await
+                .unwrap();
+            expected.push((key, value));
+        }
+        // Enough splits happened for the directory to hold aliases
+        assert!(engine.directory.len() > engine.bucket_count);
+
+        let mut iter = engine.keyspace();
+        let mut entries = vec![];
+        while let Some((key, value, _)) = iter.next(&mut engine).await.unwrap() {
+            entries.push((key, value));
+        }
+        let mut expected: Vec<_> = expected
+            .into_iter()
+            .map(|(key, value)| (key, Value::String(value)))
+            .collect();
+        entries.sort();
+        expected.sort();
+        assert_eq!(entries, expected);
+    }
+
+    #[tokio::test]
+    async fn keys_and_scan_match_pattern() {
+        let mut engine = get_engine("hash_storage_keys_and_scan_match_pattern").await;
+        for key in ["user:1", "user:2", "order:1"] {
+            engine
+                .handle_cmd(PutCommand(key.into(), format!("{}!", key).into(), None).into())
+                .await
+                .unwrap();
+        }
+
+        let CommandOutput::Keys(mut keys) = engine
+            .handle_cmd(KeysCommand(Some("user:*".into())).into())
+            .await
+            .unwrap()
+        else {
+            panic!("Expected keys");
+        };
+        keys.sort();
+        assert_eq!(keys, vec![b"user:1".to_vec(), b"user:2".to_vec()]);
+
+        let scan = ScanCommand {
+            cursor: 0,
+            pattern: Some("*:1".into()),
+            count: DEFAULT_SCAN_COUNT,
+        };
+        let CommandOutput::Scan(cursor, mut keys) = engine.handle_cmd(scan.into()).await.unwrap()
+        else {
+            panic!("Expected a scan page");
+        };
+        keys.sort();
+        assert_eq!(cursor, 0);
+        assert_eq!(keys, vec![b"order:1".to_vec(), b"user:1".to_vec()]);
+
+        let keys = engine.handle_cmd(KeysCommand(None).into()).await.unwrap();
+        assert!(matches!(keys, CommandOutput::Keys(keys) if keys.len() == 3));
+        let keys = engine
+            .handle_cmd(KeysCommand(Some("none*".into())).into())
+            .await
+            .unwrap();
+        assert_eq!(keys.to_string(), "(empty)");
+    }
+
+    #[tokio::test]
+    async fn scan_survives_splits() {
+        let mut engine = get_engine("hash_storage_scan_survives_splits").await;
+        let mut expected = vec![];
+        for i in 0..500 {
+            let key = format!("key{}", i).into_bytes();
+            engine
+                .handle_cmd(PutCommand(key.clone(), "value".into(), None).into())
+                .await
+                .unwrap();
+            expected.push(key);
+        }
+
+        let global_level = engine.directory.global_level();
+        let mut scanned = vec![];
+        let mut cursor = 0;
+        let mut pages = 0;
+        loop {
+            let scan = ScanCommand {
+                cursor,
+                pattern: None,
+                count: 20,
+            };
+            let CommandOutput::Scan(next, keys) = engine.handle_cmd(scan.into()).await.unwrap()
+            else {
+                panic!("Expected a scan page");
+            };
+            scanned.extend(keys);
+            cursor = next;
+            pages += 1;
+            if cursor == 0 {
+                break;
+            }
+
+            // Split buckets and double the directory part way through the scan
+            if pages > 3 {
+                continue;
+            }
+            for i in 0..600 {
+                let key = format!("new{}:{}", pages, i);
+                engine
+                    .handle_cmd(PutCommand(key.into(), "value".into(), None).into())
+                    .await
+                    .unwrap();
+            }
+        }
+        assert!(engine.directory.global_level() > global_level + 1);
+        assert!(pages > 1);
+
+        // Every key present for the whole scan is returned exactly once
+        let scanned_old: Vec<Vec<u8>> = scanned
+            .iter()
+            .filter(|x| x.starts_with(b"key"))
+            .cloned()
+            .collect();
+        let mut sorted_old = scanned_old.clone();
+        sorted_old.sort();
+        sorted_old.dedup();
+        assert_eq!(sorted_old.len(), scanned_old.len());
+        expected.sort();
+        assert_eq!(sorted_old, expected);
+    }
+
+    #[test]
+    fn next_scan_cursor_walks_reversed_bits() {
+        // With 2 bits the buckets are visited in the order 00, 10, 01, 11
+        assert_eq!(next_scan_cursor(0b00, 2), 0b10);
+        assert_eq!(next_scan_cursor(0b10, 2), 0b01);
+        assert_eq!(next_scan_cursor(0b01, 2), 0b11);
+        assert_eq!(next_scan_cursor(0b11, 2), 0);
+        // A bucket with a lower level skips the cursors of the directory entries aliasing it
+        assert_eq!(next_scan_cursor(0b01, 1), 0);
+        assert_eq!(next_scan_cursor(0, 0), 0);
+    }
+
+    #[tokio::test]
+    async fn backup_holds_database_as_it_started() {
+        let prefix = "hash_storage_backup_holds_database_as_it_started";
+        let backup_path = format!("./test_data/{}_backup", prefix);
+        let _ = std::fs::remove_dir_all(&backup_path);
+        let mut engine = get_engine_with_value_log(prefix, 200, 4096).await;
+
+        let mut expected = vec![];
+        for i in 0..300 {
+            let value = match i % 10 {
+                0 => format!("{:0>300}", i),
+                _ => format!("value{}", i),
+            };
+            let key = format!("key{}", i);
+            engine
+                .handle_cmd(PutCommand(key.clone().into(), value.clone().into(), None).into())
+                .await
+                .unwrap();
+            expected.push((key.into_bytes(), value.into_bytes()));
+        }
+
+        // Skip the copying done after every command, so every change below has to preserve
+        // what it overwrites
+        let output = engine
+            .handle_cmd_inner(BackupCommand(backup_path.clone()).into())
+            .await
+            .unwrap();
+        assert_eq!(output, CommandOutput::Backup);
+        assert!(engine.is_backing_up());
+        assert!(engine
+            .handle_cmd_inner(BackupCommand(backup_path.clone()).into())
+            .await
+            .is_err());
+
+        // Overwrite, delete and split while the backup is running
+        for i in 0..300 {
+            let key = format!("key{}", i);
+            let cmd = match i % 3 {
+                0 => DeleteCommand(key.into()).into(),
+                _ => PutCommand(key.into(), format!("{:1>300}", i).into(), None).into(),
+            };
+            engine.handle_cmd_inner(cmd).await.unwrap();
+        }
+        for i in 0..1000 {
+            let cmd = PutCommand(format!("new{}", i).into(), "value".into(), None);
+            engine.handle_cmd_inner(cmd.into()).await.unwrap();
+        }
+        engine.save_index().await;
+        assert!(engine.is_backing_up());
+        let running = ("backup_running".to_string(), backup_path.clone());
+        assert!(engine.stats().contains(&running));
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+        assert!(!engine.is_backing_up());
+        let finished = ("backup_finished".to_string(), backup_path.clone());
+        assert!(engine.stats().contains(&finished));
+
+        let mut backup = HashStorage::new(
+            &format!("{}/{}_dir.db", backup_path, prefix),
+            &format!("{}/{}_data.db", backup_path, prefix),
+            HashStorageOptions {
+                value_log: Some(ValueLogOptions {
+                    directory: format!("{}/{}_values", backup_path, prefix),
+                    threshold: 200,
+                    segment_bytes: 4096,
+                }),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let mut iter = backup.keyspace();
+        let mut entries = vec![];
+        while let Some((key, value, _)) = iter.next(&mut backup).await.unwrap() {
+            entries.push((key, value));
+        }
+        let mut expected: Vec<_> = expected
+            .into_iter()
+            .map(|(key, value)| (key, Value::String(value)))
+            .collect();
+        entries.sort();
+        expected.sort();
+        assert_eq!(entries, expected);
+
+        // A backup never overwrites another
+        assert!(engine.start_backup(&backup_path).await.is_err());
+    }
+
+    #[test]
+    fn hash_key_matches_string_hash() {
+        let mut hasher = XxHash64::with_seed(0);
+        std::hash::Hash::hash("MY_KEY", &mut hasher);
+        assert_eq!(hash_key(b"MY_KEY"), hasher.finish());
+    }
+
+    #[tokio::test]
+    async fn binary_keys_and_values() {
+        let mut engine = get_engine("hash_storage_binary_keys_and_values").await;
+        let key = vec![0, 159, 146, 150, 255];
+        let value = vec![255, 0, 1, 2, 128];
+
+        engine
+            .handle_cmd(PutCommand(key.clone(), value.clone(), None).into())
+            .await
+            .unwrap();
+        let retrieved = engine
+            .handle_cmd(GetCommand(key.clone()).into())
+            .await
+            .unwrap();
+        assert_eq!(retrieved, CommandOutput::Found(value));
+        assert_eq!(retrieved.to_string(), "x\"ff00010280\"");
+
+        engine
+            .handle_cmd(DeleteCommand(key.clone()).into())
+            .await
+            .unwrap();
+        let retrieved = engine.handle_cmd(GetCommand(key).into()).await.unwrap();
+        assert!(matches!(retrieved, CommandOutput::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_value() {
+        let mut engine = get_engine("hash_storage_rejects_oversized_value").await;
+        let result = engine
+            .handle_cmd(
+                PutCommand(
+                    "MY_KEY".into(),
+                    incompressible_value(DEFAULT_PAGE_BYTES, 0).into(),
+                    None,
+                )
+                .into(),
+            )
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn value_log_separates_large_values() {
+        let mut engine =
+            get_engine_with_value_log("hash_storage_value_log_separates_large_values", 16, 1 << 20)
+                .await;
+        let large = "v".repeat(DEFAULT_PAGE_BYTES * 2);
+        engine
+            .handle_cmd(PutCommand("LARGE".into(), large.clone().into(), None).into())
+            .await
+            .unwrap();
+        engine
+            .handle_cmd(PutCommand("SMALL".into(), "small".into(), None).into())
+            .await
+            .unwrap();
+
+        let bucket = Bucket::read_from_file(&mut engine.buckets_file, 0).await;
+        let large_record = bucket.records.iter().find(|x| x.1 == b"LARGE").unwrap();
+        assert!(large_record.value_pointer().is_some());
+        let small_record = bucket.records.iter().find(|x| x.1 == b"SMALL").unwrap();
+        assert_eq!(small_record.value_pointer(), None);
+
+        let retrieved = engine
+            .handle_cmd(GetCommand("LARGE".into()).into())
+            .await
+            .unwrap();
+        assert_eq!(retrieved, CommandOutput::Found(large.into()));
+        let retrieved = engine
+            .handle_cmd(GetCommand("SMALL".into()).into())
+            .await
+            .unwrap();
+        assert_eq!(retrieved, CommandOutput::Found("small".into()));
+    }
+
+    /// Overwriting the same keys over and over leaves the closed segments mostly garbage, they
+    /// should be collected while the latest values stay readable
+    #[tokio::test]
+    async fn value_log_garbage_collection() {
+        let mut engine =
+            get_engine_with_value_log("hash_storage_value_log_garbage_collection", 16, 1024).await;
+        for i in 0..100 {
+            for key in ["A", "B", "C"] {
+                engine
+                    .handle_cmd(
+                        PutCommand(key.into(), format!("{}{:0>100}", key, i).into(), None).into(),
+                    )
+                    .await
+                    .unwrap();
+            }
+        }
+        // Keep a value which is never overwritten, so it has to be moved out of its segment
+        engine
+            .handle_cmd(PutCommand("D".into(), "d".repeat(100).into(), None).into())
+            .await
+            .unwrap();
+        for i in 0..100 {
+            engine
+                .handle_cmd(PutCommand("A".into(), format!("A{:0>100}", i).into(), None).into())
+                .await
+                .unwrap();
+        }
+
+        let (value_log, _) = engine.value_log.as_ref().unwrap();
+        let head = value_log.head();
+        assert!(head > 10);
+        assert!(value_log.closed_segments().await.len() < 3);
+
+        for (key, value) in [
+            ("A", format!("A{:0>100}", 99)),
+            ("B", format!("B{:0>100}", 99)),
+            ("C", format!("C{:0>100}", 99)),
+            ("D", "d".repeat(100)),
+        ] {
+            let retrieved = engine
+                .handle_cmd(GetCommand(key.into()).into())
+                .await
+                .unwrap();
+            assert_eq!(retrieved, CommandOutput::Found(value.into()));
+        }
+    }
+
+    /// Segments written before the value log was opened have their live bytes counted once
+    /// they are closed, and are collected like any other segment
+    #[tokio::test]
+    async fn value_log_garbage_collection_after_reopen() {
+        let test_prefix = "hash_storage_value_log_garbage_collection_after_reopen";
+        let mut engine = get_engine_with_value_log(test_prefix, 16, 1 << 20).await;
+        for i in 0..100 {
+            let cmd = PutCommand("A".into(), incompressible_value(100, i).into(), None);
+            engine.handle_cmd(cmd.into()).await.unwrap();
+        }
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        let options = HashStorageOptions {
+            value_log: Some(ValueLogOptions {
+                directory: format!("./test_data/{}_values", test_prefix),
+                threshold: 16,
+                segment_bytes: 1024,
+            }),
+            ..Default::default()
+        };
+        let mut engine = HashStorage::new(
+            &format!("./test_data/{}_dir.db", test_prefix),
+            &format!("./test_data/{}_data.db", test_prefix),
+            options,
+        )
+        .await
+        .unwrap();
+        let (value_log, _) = engine.value_log.as_ref().unwrap();
+        assert_eq!(value_log.live_bytes(1), None);
+
+        let cmd = PutCommand("B".into(), incompressible_value(100, 100).into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        let (value_log, _) = engine.value_log.as_ref().unwrap();
+        assert_eq!(value_log.closed_segments().await, Vec::<SegmentId>::new());
+
+        for (key, i) in [("A", 99), ("B", 100)] {
+            let value = incompressible_value(100, i);
+            let retrieved = engine.handle_cmd(GetCommand(key.into()).into()).await;
+            assert_eq!(retrieved, Ok(CommandOutput::Found(value.into())));
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[tokio::test]
+    async fn compresses_values() {
+        let mut engine = get_engine("hash_storage_compresses_values").await;
+        let value = "{\"name\": \"value\"}".repeat(100);
+        engine
+            .handle_cmd(PutCommand("JSON".into(), value.clone().into(), None).into())
+            .await
+            .unwrap();
+
+        let bucket = Bucket::read_from_file(&mut engine.buckets_file, 0).await;
+        let record = bucket.records.iter().find(|x| x.1 == b"JSON").unwrap();
+        assert_ne!(record.3 & RECORD_COMPRESSED, 0);
+        assert!(record.2.len() < value.len());
+
+        let retrieved = engine
+            .handle_cmd(GetCommand("JSON".into()).into())
+            .await
+            .unwrap();
+        assert_eq!(retrieved, CommandOutput::Found(value.into()));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[tokio::test]
+    async fn compresses_values_in_value_log() {
+        let mut engine =
+            get_engine_with_value_log("hash_storage_compresses_values_in_value_log", 16, 1 << 20)
+                .await;
+        let value = "{\"name\": \"value\"}".repeat(1000);
+        engine
+            .handle_cmd(PutCommand("JSON".into(), value.clone().into(), None).into())
+            .await
+            .unwrap();
+
+        let bucket = Bucket::read_from_file(&mut engine.buckets_file, 0).await;
+        let record = bucket.records.iter().find(|x| x.1 == b"JSON").unwrap();
+        assert_ne!(record.3 & RECORD_COMPRESSED, 0);
+        assert!((record.value_pointer().unwrap().len as usize) < value.len());
+
+        let retrieved = engine
+            .handle_cmd(GetCommand("JSON".into()).into())
+            .await
+            .unwrap();
+        assert_eq!(retrieved, CommandOutput::Found(value.into()));
+    }
+
+    /// Records written before compression existed, or without the feature, have no compressed
+    /// flag and are read as is
+    #[tokio::test]
+    async fn reads_uncompressed_records() {
+        let mut engine = get_engine("hash_storage_reads_uncompressed_records").await;
+        let value = "{\"name\": \"value\"}".repeat(100);
+        let key = "JSON".to_string();
+        let bucket = Bucket {
+            bucket_index: 0,
+            level: 0,
+            overflow: vec![],
+            page_bytes: DEFAULT_PAGE_BYTES,
+            records: vec![Record::new(
+                hash_key(key.as_bytes()),
+                key.clone().into_bytes(),
+                value.clone().into_bytes(),
+            )],
+        };
+        bucket.save_to_file(&mut engine.buckets_file).await;
+
+        let retrieved = engine
+            .handle_cmd(GetCommand(key.into()).into())
+            .await
+            .unwrap();
+        assert_eq!(retrieved, CommandOutput::Found(value.into()));
+    }
+
+    /// Simulate a scenario as the following:
+    ///
+    /// Bucket index 0 has a a record of a hash ending with 1010 with 4000 bytes in value.
+    /// Bucket index 0 has is at a local level of 1.
+    /// The global level is at 3.
+    /// We are going to insert a record with a hash of 1110 with 4000 bytes of value, which takes
+    /// two local splits. The put only splits once, leaving both records in bucket 5 with
+    /// overflow page 6, and the second split is left to `split_overflowing`.
+    ///
+    /// 000 -> 0 -> Current record
+    /// 001 -> 1
+    /// 010 -> 0
+    /// 011 -> 2
+    /// 100 -> 0
+    /// 101 -> 3
+    /// 110 -> 0
+    /// 111 -> 4
+    ///
+    ///
+    /// We should expect the following after the second split, with bucket 6 taking over the
+    /// overflow page
+    ///
+    /// 000 -> 0
+    /// 001 -> 1
+    /// 010 -> 5 -> Old record
+    /// 011 -> 2
+    /// 100 -> 0
+    /// 101 -> 3
+    /// 110 -> 6 -> New record
+    /// 111 -> 4
+    ///
+    #[tokio::test]
+    async fn local_split() {
+        let mut engine = get_engine("hash_storage_local_split").await;
+        let old_record = record_from_size(0b_1010, 1, 1, 4000);
+
+        let new_record = record_from_size(0b_1110, 2, 2, 4000);
+
+        let buckets = vec![
+            Bucket {
+                bucket_index: 0,
+                overflow: vec![],
+                page_bytes: DEFAULT_PAGE_BYTES,
+                level: 1,
+                records: vec![old_record],
+            },
+            Bucket {
+                level: 3,
+                overflow: vec![],
+                page_bytes: DEFAULT_PAGE_BYTES,
+                records: vec![],
+                bucket_index: 1,
+            },
+            Bucket {
+                level: 3,
+                overflow: vec![],
+                page_bytes: DEFAULT_PAGE_BYTES,
+                records: vec![],
+                bucket_index: 2,
+            },
+            Bucket {
+                level: 3,
+                overflow: vec![],
+                page_bytes: DEFAULT_PAGE_BYTES,
+                records: vec![],
+                bucket_index: 3,
+            },
+            Bucket {
+                level: 3,
+                overflow: vec![],
+                page_bytes: DEFAULT_PAGE_BYTES,
+                records: vec![],
+                bucket_index: 4,
+            },
+        ];
+        for bucket in &buckets {
+            bucket.save_to_file(&mut engine.buckets_file).await;
+        }
+
+        engine.bucket_count = 5;
+
+        engine
+            .directory
+            .set_entries(vec![0, 1, 0, 2, 0, 3, 0, 4])
+            .await;
+
+        engine.put(new_record).await.unwrap();
+        assert_eq!(engine.bucket_count, 7);
+        assert_eq!(
+            engine.directory.entries().await,
+            vec![0, 1, 5, 2, 0, 3, 5, 4]
+        );
+        let bucket = Bucket::read_from_file(&mut engine.buckets_file, 5).await;
+        assert_eq!(bucket.overflow, vec![6]);
+        assert_eq!(bucket.records.len(), 2);
+
+        engine.split_overflowing().await;
+        assert_eq!(engine.directory.global_level(), 3);
+        assert_eq!(engine.bucket_count, 7);
+        assert_eq!(
+            engine.directory.entries().await,
+            vec![0, 1, 5, 2, 0, 3, 6, 4]
+        );
+
+        let old_record_bucket = Bucket::read_from_file(&mut engine.buckets_file, 5).await;
+        old_record_bucket
+            .records
+            .iter()
+            .find(|x| x.0 == 0b_1010)
+            .unwrap();
+        assert_eq!(old_record_bucket.level, 3);
+
+        let new_record_bucket = Bucket::read_from_file(&mut engine.buckets_file, 6).await;
+        new_record_bucket
+            .records
+            .iter()
+            .find(|x| x.0 == 0b_1110)
+            .unwrap();
+        assert_eq!(new_record_bucket.level, 3);
+    }
+
+    /// Simulate a similar scenario as the local split
+    ///
+    /// We will use 2 records, both 4000 bytes in length. Same as the local split test
+    /// we will have record 1 with a hash of 1010 and the second with 1110.
+    ///
+    /// But instead of having the global level at 3, we have a global level of 1. So inserting
+    /// record number 2 takes two global splits, the put does the first and leaves both records
+    /// in bucket 2 with overflow page 3, and `split_overflowing` does the second.
+    ///
+    /// 0 -> 0 -> Current record
+    /// 1 -> 1 -> Current record
+    ///
+    ///
+    /// We should expect the following after the insert
+    /// 00 -> 0
+    /// 01 -> 1
+    /// 10 -> 2
+    /// 11 -> 1
+    ///
+    /// 000 -> 0
+    /// 001 -> 1
+    /// 010 -> 2 -> Old Record
+    /// 011 -> 1
+    /// 100 -> 0
+    /// 101 -> 1
+    /// 110 -> 3 -> New Record
+    /// 111 -> 1
+    ///
+    #[tokio::test]
+    async fn global_split() {
+        let mut engine = get_engine("hash_storage_global_split").await;
+        let old_record = record_from_size(0b_1010, 1, 1, 4000);
+
+        let new_record = record_from_size(0b_1110, 2, 2, 4000);
+
+        let buckets = vec![
+            Bucket {
+                bucket_index: 0,
+                overflow: vec![],
+                page_bytes: DEFAULT_PAGE_BYTES,
+                level: 1,
+                records: vec![old_record],
+            },
+            Bucket {
+                level: 1,
+                overflow: vec![],
+                page_bytes: DEFAULT_PAGE_BYTES,
+                records: vec![],
+                bucket_index: 1,
+            },
+        ];
+        for bucket in &buckets {
+            bucket.save_to_file(&mut engine.buckets_file).await;
+        }
+        engine.bucket_count = 2;
+        engine.directory.set_entries(vec![0, 1]).await;
+
+        engine.put(new_record).await.unwrap();
+        assert_eq!(engine.directory.global_level(), 2);
+        assert_eq!(engine.bucket_count, 4);
+        assert_eq!(engine.directory.entries().await, vec![0, 1, 2, 1]);
+        let bucket = Bucket::read_from_file(&mut engine.buckets_file, 2).await;
+        assert_eq!(bucket.overflow, vec![3]);
+        assert_eq!(bucket.records.len(), 2);
+
+        engine.split_overflowing().await;
+        assert_eq!(engine.directory.global_level(), 3);
+        assert_eq!(engine.bucket_count, 4);
+        assert_eq!(
+            engine.directory.entries().await,
+            vec![0, 1, 2, 1, 0, 1, 3, 1]
+        );
+
+        let old_record_bucket = Bucket::read_from_file(&mut engine.buckets_file, 2).await;
+        old_record_bucket
+            .records
+            .iter()
+            .find(|x| x.0 == 0b_1010)
+            .unwrap();
+        assert_eq!(old_record_bucket.level, 3);
+
+        let new_record_bucket = Bucket::read_from_file(&mut engine.buckets_file, 3).await;
+        new_record_bucket
+            .records
+            .iter()
+            .find(|x| x.0 == 0b_1110)
+            .unwrap();
+        assert_eq!(new_record_bucket.level, 3);
+    }
+
+    /// Two records whose hashes only differ in bit 7 take 8 splits to be parted. The put only
+    /// does the first, the rest are done one at a time by `split_overflowing` and the records
+    /// can be found all along
+    #[tokio::test]
+    async fn splits_are_spread_across_commands() {
+        let mut engine = get_engine("hash_storage_splits_are_spread_across_commands").await;
+        let records = [
+            record_from_size(0, 1, 1, 4000),
+            record_from_size(1 << 7, 2, 2, 4000),
+        ];
+        for record in records.clone() {
+            engine.put(record).await.unwrap();
+        }
+        assert_eq!(engine.directory.global_level(), 1);
+
+        let mut splits = 0;
+        while !engine.overflowing.is_empty() {
+            for record in &records {
+                let found = engine.find_record(record.0, &record.1).await;
+                assert_eq!(found.as_ref(), Some(record));
+            }
+            engine.split_overflowing().await;
+            splits += 1;
+        }
+        assert_eq!(splits, 7);
+        assert_eq!(engine.directory.global_level(), 8);
+        for record in &records {
+            let bucket_index = engine
+                .directory
+                .get(engine.hash_to_remainder(record.0))
+                .await;
+            let bucket = Bucket::read_from_file(&mut engine.buckets_file, bucket_index).await;
+            assert_eq!(bucket.records, vec![record.clone()]);
+            assert!(bucket.overflow.is_empty());
+        }
+    }
+
+    /// Records with the same hash can't be parted by a split, so they stay in one bucket with
+    /// overflow pages
+    #[tokio::test]
+    async fn same_hashes_share_overflow_pages() {
+        let mut engine = get_engine("hash_storage_same_hashes_share_overflow_pages").await;
+        let records: Vec<_> = (1..=3).map(|x| record_from_size(5, x, x, 3000)).collect();
+        for record in records.clone() {
+            engine.put(record).await.unwrap();
+            engine.split_overflowing().await;
+        }
+        assert_eq!(engine.directory.global_level(), 0);
+        let bucket = Bucket::read_from_file(&mut engine.buckets_file, 0).await;
+        assert_eq!(bucket.overflow, vec![1, 2]);
+        assert_eq!(bucket.records, records);
+
+        // The overflow pages are kept for the bucket once it shrinks
+        engine.remove_record(5, &[2], None).await.unwrap();
+        let bucket = Bucket::read_from_file(&mut engine.buckets_file, 0).await;
+        assert_eq!(bucket.overflow, vec![1, 2]);
+        assert_eq!(bucket.records, vec![records[0].clone(), records[2].clone()]);
+        assert_eq!(engine.count_keys().await, 2);
+    }
+
+    /// Grow the directory past a single directory page, then reload the engine without calling
+    /// exit. The splits should have already persisted the directory and the bucket count.
+    #[tokio::test]
+    async fn splits_persist_index() {
+        let mut engine = get_engine("hash_storage_splits_persist_index").await;
+        for i in 0..1500 {
+            engine
+                .handle_cmd(
+                    PutCommand(
+                        format!("key{}", i).into(),
+                        incompressible_value(1000, i).into(),
+                        None,
+                    )
+                    .into(),
+                )
+                .await
+                .unwrap();
+        }
+        assert!(engine.directory.global_level() > 9);
+
+        let mut engine_reloaded =
+            get_engine_without_reset("hash_storage_splits_persist_index").await;
+        assert_eq!(
+            engine_reloaded.directory.global_level(),
+            engine.directory.global_level()
+        );
+        assert_eq!(engine_reloaded.bucket_count, engine.bucket_count);
+        for i in 0..1500 {
+            let retrieved = engine_reloaded
+                .handle_cmd(GetCommand(format!("key{}", i).into()).into())
+                .await
+                .unwrap();
+            assert_eq!(
+                retrieved,
+                CommandOutput::Found(incompressible_value(1000, i).into())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_hidden_and_removed() {
+        let mut engine = get_engine("hash_storage_expired_keys_are_hidden_and_removed").await;
+        let now = now_millis();
+        for (key, expires_at) in [("gone", Some(now - 1)), ("later", Some(now + 60_000))] {
+            let cmd = PutCommand(key.into(), "v".into(), expires_at);
+            engine.handle_cmd(cmd.into()).await.unwrap();
+        }
+        engine
+            .handle_cmd(PutCommand("kept".into(), "v".into(), None).into())
+            .await
+            .unwrap();
+
+        let get = |key: &str| StorageCommand::Get(GetCommand(key.into()));
+        let ttl = |key: &str| StorageCommand::Ttl(TtlCommand(key.into()));
+        assert_eq!(
+            engine.handle_cmd(get("gone")).await,
+            Ok(CommandOutput::NotFound("gone".into()))
+        );
+        assert_eq!(
+            engine.handle_cmd(ttl("later")).await,
+            Ok(CommandOutput::Ttl(Some(60)))
+        );
+        assert_eq!(
+            engine.handle_cmd(ttl("kept")).await,
+            Ok(CommandOutput::Ttl(None))
+        );
+        let mut keys = engine.keys(None).await;
+        keys.sort();
+        assert_eq!(keys, vec![b"kept".to_vec(), b"later".to_vec()]);
+        // The get removed the expired record from its bucket
+        let bucket = Bucket::read_from_file(&mut engine.buckets_file, 0).await;
+        assert_eq!(bucket.records.len(), 2);
+
+        // Expiring and persisting keys
+        let expire = |key: &str, expires_at| ExpireCommand(key.into(), expires_at).into();
+        assert_eq!(
+            engine.handle_cmd(expire("kept", Some(now - 1))).await,
+            Ok(CommandOutput::Expire)
+        );
+        assert_eq!(
+            engine.handle_cmd(get("kept")).await,
+            Ok(CommandOutput::NotFound("kept".into()))
+        );
+        assert_eq!(
+            engine.handle_cmd(expire("later", None)).await,
+            Ok(CommandOutput::Persist)
+        );
+        assert_eq!(
+            engine.handle_cmd(ttl("later")).await,
+            Ok(CommandOutput::Ttl(None))
+        );
+        assert_eq!(
+            engine.handle_cmd(expire("missing", None)).await,
+            Ok(CommandOutput::NotFound("missing".into()))
+        );
+        // A put without an expiry makes the key persist
+        let cmd = PutCommand("later".into(), "v".into(), Some(now + 1000));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        let cmd = PutCommand("later".into(), "v2".into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(ttl("later")).await,
+            Ok(CommandOutput::Ttl(None))
+        );
+    }
+
+    #[tokio::test]
+    async fn increments_counters() {
+        let test_prefix = "hash_storage_increments_counters";
+        let mut engine = get_engine(test_prefix).await;
+        let incr = |key: &str, by| StorageCommand::Incr(IncrCommand(key.into(), by));
+        assert_eq!(
+            engine.handle_cmd(incr("count", 1)).await,
+            Ok(CommandOutput::Integer(1))
+        );
+        assert_eq!(
+            engine.handle_cmd(incr("count", -5)).await,
+            Ok(CommandOutput::Integer(-4))
+        );
+        assert_eq!(
+            engine.handle_cmd(GetCommand("count".into()).into()).await,
+            Ok(CommandOutput::Found(b"-4".to_vec()))
+        );
+
+        // The expiry of the key is kept
+        let expires_at = now_millis() + 60_000;
+        let cmd = PutCommand("session".into(), "10".into(), Some(expires_at));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(incr("session", 1)).await,
+            Ok(CommandOutput::Integer(11))
+        );
+        let record = engine.find_record(hash_key(b"session"), b"session").await;
+        assert_eq!(record.unwrap().4, Some(expires_at));
+
+        // Values which aren't integers are left alone
+        let cmd = PutCommand("name".into(), "bob".into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(incr("name", 1)).await,
+            Err(IncrError::NotAnInteger.to_string())
+        );
+        let cmd = PutCommand("max".into(), i64::MAX.to_string().into_bytes(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.incr(IncrCommand("max".into(), 1)).await,
+            Err(IncrError::Overflow)
+        );
+        let cmd = ListPushCommand("list".into(), ListSide::Left, vec!["1".into()]);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.incr(IncrCommand("list".into(), 1)).await,
+            Err(IncrError::Storage(WRONG_TYPE.into()))
+        );
+        assert_eq!(
+            engine.handle_cmd(GetCommand("name".into()).into()).await,
+            Ok(CommandOutput::Found(b"bob".to_vec()))
+        );
+    }
+
+    #[tokio::test]
+    async fn conditional_updates() {
+        let test_prefix = "hash_storage_conditional_updates";
+        let mut engine = get_engine(test_prefix).await;
+        let put_if = |value: &str, condition| {
+            let cmd = PutCommand("key".into(), value.into(), None);
+            StorageCommand::PutIf(PutIfCommand(cmd, condition))
+        };
+        let get = || StorageCommand::Get(GetCommand("key".into()));
+        assert_eq!(
+            engine.handle_cmd(put_if("1", PutCondition::Present)).await,
+            Ok(CommandOutput::NotPut)
+        );
+        assert_eq!(
+            engine.handle_cmd(put_if("1", PutCondition::Absent)).await,
+            Ok(CommandOutput::Put)
+        );
+        assert_eq!(
+            engine.handle_cmd(put_if("2", PutCondition::Absent)).await,
+            Ok(CommandOutput::NotPut)
+        );
+        assert_eq!(
+            engine.handle_cmd(put_if("2", PutCondition::Present)).await,
+            Ok(CommandOutput::Put)
+        );
+        assert_eq!(
+            engine.handle_cmd(get()).await,
+            Ok(CommandOutput::Found(b"2".to_vec()))
+        );
+
+        // Compare and swap keeps the expiry
+        let expires_at = now_millis() + 60_000;
+        let expire = ExpireCommand("key".into(), Some(expires_at));
+        engine.handle_cmd(expire.into()).await.unwrap();
+        let cas = |expected: &str, value: &str| {
+            CasCommand("key".into(), expected.into(), value.into()).into()
+        };
+        assert_eq!(
+            engine.handle_cmd(cas("1", "3")).await,
+            Ok(CommandOutput::NotSwapped)
+        );
+        assert_eq!(
+            engine.handle_cmd(cas("2", "3")).await,
+            Ok(CommandOutput::Swapped)
+        );
+        let record = engine.find_record(hash_key(b"key"), b"key").await;
+        assert_eq!(record.unwrap().4, Some(expires_at));
+
+        // Get and set clears the expiry like a put
+        let getset = |value: &str| GetSetCommand("key".into(), value.into()).into();
+        assert_eq!(
+            engine.handle_cmd(getset("4")).await,
+            Ok(CommandOutput::Replaced(Some(b"3".to_vec())))
+        );
+        let record = engine.find_record(hash_key(b"key"), b"key").await;
+        assert_eq!(record.unwrap().4, None);
+
+        let getdel = || GetDelCommand("key".into()).into();
+        assert_eq!(
+            engine.handle_cmd(getdel()).await,
+            Ok(CommandOutput::Taken(b"4".to_vec()))
+        );
+        assert_eq!(
+            engine.handle_cmd(getdel()).await,
+            Ok(CommandOutput::NotFound(b"key".to_vec()))
+        );
+        assert_eq!(
+            engine.handle_cmd(cas("4", "5")).await,
+            Ok(CommandOutput::NotSwapped)
+        );
+        assert_eq!(
+            engine.handle_cmd(getset("5")).await,
+            Ok(CommandOutput::Replaced(None))
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_keys() {
+        let test_prefix = "hash_storage_counts_keys";
+        let mut engine = get_engine(test_prefix).await;
+        // Enough keys to split buckets
+        for i in 0..200 {
+            let cmd = PutCommand(format!("key{}", i).into(), vec![1; 100], None);
+            engine.handle_cmd(cmd.into()).await.unwrap();
+        }
+        let cmd = PutCommand("key0".into(), "replaced".into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        for i in 0..10 {
+            let cmd = DeleteCommand(format!("key{}", i).into());
+            engine.handle_cmd(cmd.into()).await.unwrap();
+        }
+        let cmd = DeleteCommand("missing".into());
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        let cmd = PutCommand("gone".into(), "v".into(), Some(now_millis() - 1));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(191))
+        );
+        // The expired key is removed once its bucket is read
+        let cmd = StorageCommand::Exists(ExistsCommand("gone".into()));
+        assert_eq!(
+            engine.handle_cmd(cmd).await,
+            Ok(CommandOutput::Exists(false))
+        );
+        assert_eq!(engine.key_count, 190);
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(190))
+        );
+        let cmd = StorageCommand::Exists(ExistsCommand("key10".into()));
+        assert_eq!(
+            engine.handle_cmd(cmd).await,
+            Ok(CommandOutput::Exists(true))
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_keys_without_recorded_count() {
+        let test_prefix = "hash_storage_counts_keys_without_recorded_count";
+        let mut engine = get_engine(test_prefix).await;
+        for i in 0..100 {
+            let cmd = PutCommand(format!("key{}", i).into(), vec![1; 100], None);
+            engine.handle_cmd(cmd.into()).await.unwrap();
+        }
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        // Rewrite the buckets file as it was before the key count was recorded
+        let data_path = format!("./test_data/{}_data.db", test_prefix);
+        let mut bytes = std::fs::read(&data_path).unwrap();
+        let fields = HEADER_BYTES..HEADER_BYTES + KEY_COUNT_BYTES + PAGE_BYTES_FIELD_BYTES;
+        bytes.drain(fields);
+        bytes[HEADER_BYTES - 1] &= 0x3f;
+        std::fs::write(&data_path, bytes).unwrap();
+
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert!(!engine.buckets_file.has_key_count);
+        assert_eq!(engine.key_count, 100);
+        let cmd = PutCommand("new".into(), "v".into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(GetCommand("key50".into()).into()).await,
+            Ok(CommandOutput::Found(vec![1; 100]))
+        );
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(101))
+        );
+    }
+
+    #[tokio::test]
+    async fn renames_and_copies() {
+        let test_prefix = "hash_storage_renames_and_copies";
+        let mut engine = get_engine(test_prefix).await;
+        // Long enough values for the keys to spread across buckets
+        let value = |i| incompressible_value(100, i).into_bytes();
+        for i in 0..100 {
+            let cmd = PutCommand(format!("key{}", i).into(), value(i), None);
+            engine.handle_cmd(cmd.into()).await.unwrap();
+        }
+        let expires_at = now_millis() + 60_000;
+        let cmd = ExpireCommand("key1".into(), Some(expires_at));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        let rename = |from: &str, to: &str| RenameCommand(from.into(), to.into()).into();
+        let copy = |from: &str, to: &str| CopyCommand(from.into(), to.into()).into();
+        let get = |key: &str| StorageCommand::Get(GetCommand(key.into()));
+
+        assert_ne!(
+            engine
+                .directory
+                .get(engine.hash_to_remainder(hash_key(b"key1")))
+                .await,
+            engine
+                .directory
+                .get(engine.hash_to_remainder(hash_key(b"renamed")))
+                .await
+        );
+        assert_eq!(
+            engine.handle_cmd(rename("key1", "renamed")).await,
+            Ok(CommandOutput::Renamed)
+        );
+        assert_eq!(
+            engine.handle_cmd(get("key1")).await,
+            Ok(CommandOutput::NotFound("key1".into()))
+        );
+        assert_eq!(
+            engine.handle_cmd(get("renamed")).await,
+            Ok(CommandOutput::Found(value(1)))
+        );
+        let record = engine.find_record(hash_key(b"renamed"), b"renamed").await;
+        assert_eq!(record.unwrap().4, Some(expires_at));
+
+        // The new key is replaced
+        assert_eq!(
+            engine.handle_cmd(rename("key2", "key3")).await,
+            Ok(CommandOutput::Renamed)
+        );
+        assert_eq!(
+            engine.handle_cmd(get("key3")).await,
+            Ok(CommandOutput::Found(value(2)))
+        );
+        assert_eq!(
+            engine.handle_cmd(rename("key3", "key3")).await,
+            Ok(CommandOutput::Renamed)
+        );
+        assert_eq!(
+            engine.handle_cmd(rename("missing", "key3")).await,
+            Ok(CommandOutput::NotFound("missing".into()))
+        );
+
+        assert_eq!(
+            engine.handle_cmd(copy("key4", "copied")).await,
+            Ok(CommandOutput::Copied)
+        );
+        assert_eq!(
+            engine.handle_cmd(get("key4")).await,
+            Ok(CommandOutput::Found(value(4)))
+        );
+        assert_eq!(
+            engine.handle_cmd(get("copied")).await,
+            Ok(CommandOutput::Found(value(4)))
+        );
+        assert_eq!(
+            engine.handle_cmd(copy("missing", "copied")).await,
+            Ok(CommandOutput::NotFound("missing".into()))
+        );
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(100))
+        );
+    }
+
+    #[tokio::test]
+    async fn renames_values_in_value_log() {
+        let test_prefix = "hash_storage_renames_values_in_value_log";
+        let mut engine = get_engine_with_value_log(test_prefix, 64, 1024).await;
+        let value = incompressible_value(500, 1).into_bytes();
+        let cmd = PutCommand("old".into(), value.clone(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        let cmd = RenameCommand("old".into(), "new".into());
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        // Fill and close a few segments so the old value is garbage collected
+        for i in 0..10 {
+            let cmd = PutCommand("other".into(), incompressible_value(500, i).into(), None);
+            engine.handle_cmd(cmd.into()).await.unwrap();
+        }
+        assert_eq!(
+            engine.handle_cmd(GetCommand("new".into()).into()).await,
+            Ok(CommandOutput::Found(value))
+        );
+    }
+
+    #[tokio::test]
+    async fn flushes_every_key() {
+        let test_prefix = "hash_storage_flushes_every_key";
+        let mut engine = get_engine(test_prefix).await;
+        for i in 0..200 {
+            let cmd = PutCommand(format!("key{}", i).into(), vec![1; 100], None);
+            engine.handle_cmd(cmd.into()).await.unwrap();
+        }
+        assert!(engine.bucket_count > 1);
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::FlushDb).await,
+            Ok(CommandOutput::FlushDb)
+        );
+        assert!(engine.is_empty().await);
+        assert_eq!((engine.bucket_count, engine.directory.len()), (1, 1));
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(0))
+        );
+        let cmd = PutCommand("after".into(), "v".into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert_eq!(engine.keys(None).await, vec![b"after".to_vec()]);
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(1))
+        );
+        let temp = flush_temp_path(Path::new(&format!("./test_data/{}_data.db", test_prefix)));
+        assert!(!temp.exists());
+    }
+
+    #[tokio::test]
+    async fn finishes_a_flush_cut_short() {
+        let test_prefix = "hash_storage_finishes_a_flush_cut_short";
+        let data_path = format!("./test_data/{}_data.db", test_prefix);
+        let dir_path = format!("./test_data/{}_dir.db", test_prefix);
+        let mut engine = get_engine("hash_storage_finishes_a_flush_cut_short_empty").await;
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+        let empty = engine.paths.clone();
+        let mut engine = get_engine(test_prefix).await;
+        for i in 0..200 {
+            let cmd = PutCommand(format!("key{}", i).into(), vec![1; 100], None);
+            engine.handle_cmd(cmd.into()).await.unwrap();
+        }
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+        drop(engine);
+
+        // Crash after the directory was replaced but before the buckets were
+        std::fs::copy(&empty.0, &dir_path).unwrap();
+        std::fs::copy(&empty.1, flush_temp_path(Path::new(&data_path))).unwrap();
+        let pending = pending_path(Path::new(&data_path));
+        write_pending(&pending, Mutation::FlushDb, None).await;
+
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert!(engine.is_empty().await);
+        assert_eq!((engine.bucket_count, engine.directory.len()), (1, 1));
+        assert!(!pending.exists());
+        assert!(!flush_temp_path(Path::new(&data_path)).exists());
+
+        // The empty files of a flush which never recorded itself are thrown away
+        let cmd = PutCommand("kept".into(), "v".into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+        drop(engine);
+        std::fs::copy(&empty.1, flush_temp_path(Path::new(&data_path))).unwrap();
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert_eq!(engine.keys(None).await, vec![b"kept".to_vec()]);
+        assert!(!flush_temp_path(Path::new(&data_path)).exists());
+    }
+
+    #[tokio::test]
+    async fn finishes_a_rename_cut_short() {
+        let test_prefix = "hash_storage_finishes_a_rename_cut_short";
+        let mut engine = get_engine(test_prefix).await;
+        let expires_at = now_millis() + 60_000;
+        let cmd = PutCommand("old".into(), "value".into(), Some(expires_at));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        // Crash after the new key was written but before the old one was deleted
+        let rename = RenameCommand("old".into(), "new".into());
+        engine.start_pending(Mutation::Rename(rename)).await;
+        let cmd = PutCommand("new".into(), "value".into(), Some(expires_at));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+        drop(engine);
+
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert_eq!(engine.keys(None).await, vec![b"new".to_vec()]);
+        assert_eq!(
+            engine.get_value(b"new").await,
+            Ok(Some((Value::String(b"value".to_vec()), Some(expires_at))))
+        );
+        assert!(!pending_path(&engine.paths.1).exists());
+    }
+
+    /// Counts every record in the buckets, including the items of lists
+    async fn record_count(engine: &mut HashStorage) -> usize {
+        let mut count = 0;
+        for bucket_index in 0..engine.bucket_count {
+            let bucket = Bucket::read_from_file(&mut engine.buckets_file, bucket_index).await;
+            count += bucket.records.len();
+        }
+        count
+    }
+
+    /// Whether the record of the member of the hash, sorted set or set under the key is stored
+    async fn member_is_stored(engine: &mut HashStorage, key: &[u8], member: &[u8]) -> bool {
+        let member_key = member_key(key, member);
+        let hash = hash_key(&member_key);
+        engine
+            .find(hash, &member_key, Some(ValueType::Member))
+            .await
+            .is_some()
+    }
+
+    #[tokio::test]
+    async fn lists() {
+        let test_prefix = "hash_storage_lists";
+        let mut engine = get_engine(test_prefix).await;
+        let item = |i| incompressible_value(100, i).into_bytes();
+        let push = |side, values: Vec<Vec<u8>>| ListPushCommand("list".into(), side, values).into();
+        let pop = |side| StorageCommand::ListPop(ListPopCommand("list".into(), side));
+        let range =
+            |start, stop| StorageCommand::ListRange(ListRangeCommand("list".into(), start, stop));
+        let len = || StorageCommand::ListLen(ListLenCommand("list".into()));
+
+        // Far more than a page of items
+        let values: Vec<_> = (0..100).map(item).collect();
+        assert_eq!(
+            engine.handle_cmd(push(ListSide::Right, values)).await,
+            Ok(CommandOutput::Length(100))
+        );
+        assert_eq!(
+            engine
+                .handle_cmd(push(ListSide::Left, vec![item(100), item(101)]))
+                .await,
+            Ok(CommandOutput::Length(102))
+        );
+        assert!(engine.bucket_count > 1);
+        let mut expected: Vec<_> = (0..100).map(item).collect();
+        expected.insert(0, item(100));
+        expected.insert(0, item(101));
+        assert_eq!(
+            engine.handle_cmd(range(0, -1)).await,
+            Ok(CommandOutput::Items(expected.clone()))
+        );
+        assert_eq!(
+            engine.handle_cmd(range(-3, 1000)).await,
+            Ok(CommandOutput::Items(expected[99..].to_vec()))
+        );
+        assert_eq!(
+            engine.handle_cmd(range(5, 2)).await,
+            Ok(CommandOutput::Items(vec![]))
+        );
+        assert_eq!(
+            engine.handle_cmd(pop(ListSide::Left)).await,
+            Ok(CommandOutput::Found(item(101)))
+        );
+        assert_eq!(
+            engine.handle_cmd(pop(ListSide::Right)).await,
+            Ok(CommandOutput::Found(item(99)))
+        );
+        assert_eq!(
+            engine.handle_cmd(len()).await,
+            Ok(CommandOutput::Length(100))
+        );
+
+        // The items are neither keys nor visible to other commands
+        let cmd = PutCommand("string".into(), "v".into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(2))
+        );
+        let mut keys = engine.keys(None).await;
+        keys.sort();
+        assert_eq!(keys, vec![b"list".to_vec(), b"string".to_vec()]);
+        assert_eq!(
+            engine.handle_cmd(GetCommand("list".into()).into()).await,
+            Err(WRONG_TYPE.to_string())
+        );
+        let cmd = ListPushCommand("string".into(), ListSide::Left, vec![item(0)]);
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Err(WRONG_TYPE.to_string())
+        );
+        let cmd = ListLenCommand("string".into());
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::ListLen(cmd)).await,
+            Err(WRONG_TYPE.to_string())
+        );
+
+        // Popping the last item deletes the key
+        for _ in 0..100 {
+            engine.handle_cmd(pop(ListSide::Left)).await.unwrap();
+        }
+        assert_eq!(
+            engine.handle_cmd(pop(ListSide::Left)).await,
+            Ok(CommandOutput::NotFound("list".into()))
+        );
+        assert_eq!(engine.handle_cmd(len()).await, Ok(CommandOutput::Length(0)));
+        assert_eq!(record_count(&mut engine).await, 1);
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_keep_items_with_key() {
+        let test_prefix = "hash_storage_lists_keep_items_with_key";
+        let mut engine = get_engine(test_prefix).await;
+        let items: Vec<_> = (0..50)
+            .map(|i| incompressible_value(100, i).into_bytes())
+            .collect();
+        let push = |key: &str| ListPushCommand(key.into(), ListSide::Right, items.clone()).into();
+        let range = |key: &str| StorageCommand::ListRange(ListRangeCommand(key.into(), 0, -1));
+
+        // Deleting or replacing a list removes its items
+        engine.handle_cmd(push("list")).await.unwrap();
+        assert_eq!(record_count(&mut engine).await, 51);
+        assert_eq!(
+            engine.handle_cmd(DeleteCommand("list".into()).into()).await,
+            Ok(CommandOutput::Deleted)
+        );
+        assert_eq!(record_count(&mut engine).await, 0);
+        engine.handle_cmd(push("list")).await.unwrap();
+        let cmd = PutCommand("list".into(), "v".into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(record_count(&mut engine).await, 1);
+        engine
+            .handle_cmd(DeleteCommand("list".into()).into())
+            .await
+            .unwrap();
+
+        // Renamed and copied lists bring their items along
+        engine.handle_cmd(push("list")).await.unwrap();
+        let cmd = RenameCommand("list".into(), "renamed".into());
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        let cmd = CopyCommand("renamed".into(), "copied".into());
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(record_count(&mut engine).await, 102);
+        assert_eq!(
+            engine.handle_cmd(range("list")).await,
+            Ok(CommandOutput::Items(vec![]))
+        );
+        assert_eq!(
+            engine.handle_cmd(range("renamed")).await,
+            Ok(CommandOutput::Items(items.clone()))
+        );
+        assert_eq!(
+            engine.handle_cmd(range("copied")).await,
+            Ok(CommandOutput::Items(items.clone()))
+        );
+
+        // The items expire along with the list
+        let cmd = ExpireCommand("copied".into(), Some(now_millis() - 1));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(range("copied")).await,
+            Ok(CommandOutput::Items(vec![]))
+        );
+        let hash = hash_key(&list_item_key(b"copied", 0));
+        assert!(engine
+            .find(
+                hash,
+                &list_item_key(b"copied", 0),
+                Some(ValueType::ListItem)
+            )
+            .await
+            .is_none());
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn hashes() {
+        let test_prefix = "hash_storage_hashes";
+        let mut engine = get_engine(test_prefix).await;
+        let set = |key: &str, fields: &[(&str, &str)]| {
+            let fields = fields.iter().map(|(f, v)| ((*f).into(), (*v).into()));
+            HashSetCommand(key.into(), fields.collect()).into()
+        };
+        let get = |key: &str, field: &str| HashGetCommand(key.into(), field.into()).into();
+        let delete = |fields: &[&str]| {
+            let fields = fields.iter().map(|x| (*x).into()).collect();
+            HashDeleteCommand("user".into(), fields).into()
+        };
+        let get_all = || HashGetAllCommand("user".into()).into();
+        let fields = |fields: &[(&str, &str)]| {
+            let fields = fields.iter().map(|(f, v)| ((*f).into(), (*v).into()));
+            CommandOutput::Fields(fields.collect())
+        };
+
+        assert_eq!(
+            engine
+                .handle_cmd(set("user", &[("name", "Ann"), ("age", "41")]))
+                .await,
+            Ok(CommandOutput::Count(2))
+        );
+        let expires_at = now_millis() + 60_000;
+        let cmd = ExpireCommand("user".into(), Some(expires_at));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine
+                .handle_cmd(set("user", &[("age", "42"), ("city", "Oslo")]))
+                .await,
+            Ok(CommandOutput::Count(1))
+        );
+        assert_eq!(
+            engine.handle_cmd(get("user", "age")).await,
+            Ok(CommandOutput::Found(b"42".to_vec()))
+        );
+        assert_eq!(
+            engine.handle_cmd(get("user", "email")).await,
+            Ok(CommandOutput::FieldNotFound(b"email".to_vec()))
+        );
+        assert_eq!(
+            engine.handle_cmd(get("missing", "age")).await,
+            Ok(CommandOutput::NotFound(b"missing".to_vec()))
+        );
+        // The fields are in order and setting them keeps the expiry of the key
+        assert_eq!(
+            engine.handle_cmd(get_all()).await,
+            Ok(fields(&[("age", "42"), ("city", "Oslo"), ("name", "Ann")]))
+        );
+        assert_eq!(
+            engine
+                .handle_cmd(HashKeysCommand("user".into()).into())
+                .await,
+            Ok(CommandOutput::Items(vec![
+                b"age".to_vec(),
+                b"city".to_vec(),
+                b"name".to_vec()
+            ]))
+        );
+        let record = engine.find_record(hash_key(b"user"), b"user").await;
+        assert_eq!(record.unwrap().4, Some(expires_at));
+
+        // Other types are refused
+        let cmd = PutCommand("string".into(), "v".into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(set("string", &[("a", "1")])).await,
+            Err(WRONG_TYPE.to_string())
+        );
+        assert_eq!(
+            engine.handle_cmd(GetCommand("user".into()).into()).await,
+            Err(WRONG_TYPE.to_string())
+        );
+        let cmd = ListLenCommand("user".into());
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Err(WRONG_TYPE.to_string())
+        );
+
+        // Renamed and copied hashes keep their fields
+        let cmd = CopyCommand("user".into(), "copied".into());
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        let cmd = RenameCommand("copied".into(), "renamed".into());
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(get("renamed", "city")).await,
+            Ok(CommandOutput::Found(b"Oslo".to_vec()))
+        );
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(3))
+        );
+
+        // The key is deleted along with the last field
+        assert_eq!(
+            engine.handle_cmd(delete(&["age", "email"])).await,
+            Ok(CommandOutput::Count(1))
+        );
+        assert_eq!(
+            engine.handle_cmd(get_all()).await,
+            Ok(fields(&[("city", "Oslo"), ("name", "Ann")]))
+        );
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert_eq!(
+            engine.handle_cmd(get_all()).await,
+            Ok(fields(&[("city", "Oslo"), ("name", "Ann")]))
+        );
+        assert_eq!(
+            engine.handle_cmd(delete(&["city", "name"])).await,
+            Ok(CommandOutput::Count(2))
+        );
+        assert_eq!(engine.handle_cmd(get_all()).await, Ok(fields(&[])));
+        assert_eq!(
+            engine.handle_cmd(delete(&["city"])).await,
+            Ok(CommandOutput::Count(0))
+        );
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn hashes_keep_fields_in_records() {
+        let test_prefix = "hash_storage_hashes_keep_fields_in_records";
+        let mut engine = get_engine(test_prefix).await;
+        let field = |i: u64| format!("field{}", i).into_bytes();
+        let get_all = |key: &str| HashGetAllCommand(key.into()).into();
+        let all = |fields: &BTreeMap<Vec<u8>, Vec<u8>>| {
+            CommandOutput::Fields(fields.clone().into_iter().collect())
+        };
+
+        // The fields add up to far more than fits in a page
+        let mut fields: BTreeMap<_, _> = (0..100)
+            .map(|i| (field(i), incompressible_value(100, i).into_bytes()))
+            .collect();
+        let cmd = HashSetCommand("user".into(), fields.clone().into_iter().collect());
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(CommandOutput::Count(100))
+        );
+        // The record of the key, and a record for each field and for its slot
+        assert_eq!(record_count(&mut engine).await, 201);
+
+        // Removing fields from the middle keeps the rest
+        let removed: Vec<_> = (10..60).map(field).collect();
+        for x in &removed {
+            fields.remove(x);
+        }
+        let cmd = HashDeleteCommand("user".into(), removed);
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(CommandOutput::Count(50))
+        );
+        assert_eq!(record_count(&mut engine).await, 101);
+        let cmd = HashGetCommand("user".into(), field(99));
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(CommandOutput::Found(fields[&field(99)].clone()))
+        );
+        assert_eq!(engine.handle_cmd(get_all("user")).await, Ok(all(&fields)));
+
+        // A field which can't fit in a page is refused before any field is set
+        let large = incompressible_value(DEFAULT_PAGE_BYTES, 0).into_bytes();
+        let cmd = HashSetCommand("user".into(), vec![(field(0), vec![]), (field(1), large)]);
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Err("Member is too large to store".to_string())
+        );
+        assert_eq!(engine.handle_cmd(get_all("user")).await, Ok(all(&fields)));
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert_eq!(engine.handle_cmd(get_all("user")).await, Ok(all(&fields)));
+
+        // Copied fields expire along with the copy
+        let cmd = CopyCommand("user".into(), "copied".into());
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(engine.handle_cmd(get_all("copied")).await, Ok(all(&fields)));
+        let cmd = ExpireCommand("copied".into(), Some(now_millis() - 1));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(get_all("copied")).await,
+            Ok(all(&BTreeMap::new()))
+        );
+        assert!(!member_is_stored(&mut engine, b"copied", &field(0)).await);
+
+        // Replacing the hash removes its fields
+        assert!(member_is_stored(&mut engine, b"user", &field(0)).await);
+        let cmd = PutCommand("user".into(), "v".into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert!(!member_is_stored(&mut engine, b"user", &field(0)).await);
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn sorted_sets() {
+        let test_prefix = "hash_storage_sorted_sets";
+        let mut engine = get_engine(test_prefix).await;
+        let score = |x| Score::new(x).unwrap();
+        let add = |members: &[(f64, &str)]| {
+            let members = members.iter().map(|(s, m)| (score(*s), (*m).into()));
+            SortedSetAddCommand("board".into(), members.collect()).into()
+        };
+        let get_score =
+            |key: &str, member: &str| SortedSetScoreCommand(key.into(), member.into()).into();
+        let remove = |members: &[&str]| {
+            let members = members.iter().map(|x| (*x).into()).collect();
+            SortedSetRemoveCommand("board".into(), members).into()
+        };
+        let range = || SortedSetRangeCommand("board".into(), 0, -1).into();
+        let members = |members: &[(&str, f64)]| {
+            let members = members.iter().map(|(m, s)| ((*m).into(), score(*s)));
+            CommandOutput::Members(members.collect())
+        };
+
+        assert_eq!(
+            engine.handle_cmd(add(&[(3.0, "ann"), (1.5, "bob")])).await,
+            Ok(CommandOutput::Count(2))
+        );
+        let expires_at = now_millis() + 60_000;
+        let cmd = ExpireCommand("board".into(), Some(expires_at));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(add(&[(-1.0, "ann"), (2.0, "cid")])).await,
+            Ok(CommandOutput::Count(1))
+        );
+        assert_eq!(
+            engine.handle_cmd(get_score("board", "ann")).await,
+            Ok(CommandOutput::Score(score(-1.0)))
+        );
+        assert_eq!(
+            engine.handle_cmd(get_score("board", "dan")).await,
+            Ok(CommandOutput::MemberNotFound(b"dan".to_vec()))
+        );
+        assert_eq!(
+            engine.handle_cmd(get_score("missing", "ann")).await,
+            Ok(CommandOutput::NotFound(b"missing".to_vec()))
+        );
+        // The members are ordered by score and adding them keeps the expiry of the key
+        assert_eq!(
+            engine.handle_cmd(range()).await,
+            Ok(members(&[("ann", -1.0), ("bob", 1.5), ("cid", 2.0)]))
+        );
+        let cmd = SortedSetRangeByScoreCommand("board".into(), 0.0, f64::INFINITY);
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(members(&[("bob", 1.5), ("cid", 2.0)]))
+        );
+        let record = engine.find_record(hash_key(b"board"), b"board").await;
+        assert_eq!(record.unwrap().4, Some(expires_at));
+
+        // Other types are refused
+        let cmd = HashGetAllCommand("board".into());
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Err(WRONG_TYPE.to_string())
+        );
+        let cmd = HashSetCommand("user".into(), vec![("a".into(), "1".into())]);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(get_score("user", "a")).await,
+            Err(WRONG_TYPE.to_string())
+        );
+
+        // Copied sorted sets keep their members
+        let cmd = CopyCommand("board".into(), "copied".into());
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(get_score("copied", "bob")).await,
+            Ok(CommandOutput::Score(score(1.5)))
+        );
+
+        // The key is deleted along with the last member
+        assert_eq!(
+            engine.handle_cmd(remove(&["bob", "dan"])).await,
+            Ok(CommandOutput::Count(1))
+        );
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert_eq!(
+            engine.handle_cmd(range()).await,
+            Ok(members(&[("ann", -1.0), ("cid", 2.0)]))
+        );
+        assert_eq!(
+            engine.handle_cmd(remove(&["ann", "cid"])).await,
+            Ok(CommandOutput::Count(2))
+        );
+        assert_eq!(engine.handle_cmd(range()).await, Ok(members(&[])));
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn sorted_sets_keep_members_in_records() {
+        let test_prefix = "hash_storage_sorted_sets_keep_members_in_records";
+        let mut engine = get_engine(test_prefix).await;
+        let member = |i: u64| incompressible_value(100, i).into_bytes();
+        let score = |i: u64| Score::new(i as f64 / 2.0).unwrap();
+        let range = |key: &str| SortedSetRangeCommand(key.into(), 0, -1).into();
+        let members = |members: &SortedSet| CommandOutput::Members(members.range(0, -1));
+
+        // The members add up to far more than fits in a page
+        let mut board = SortedSet::default();
+        for i in 0..100 {
+            board.insert(member(i), score(i));
+        }
+        let added = (0..100).map(|i| (score(i), member(i))).collect();
+        let cmd = SortedSetAddCommand("board".into(), added);
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(CommandOutput::Count(100))
+        );
+        assert_eq!(record_count(&mut engine).await, 201);
+
+        // Moving and removing members from the middle keeps the order
+        let cmd = SortedSetAddCommand("board".into(), vec![(score(0), member(70))]);
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(CommandOutput::Count(0))
+        );
+        board.insert(member(70), score(0));
+        let removed: Vec<_> = (10..60).map(member).collect();
+        for x in &removed {
+            board.remove(x);
+        }
+        let cmd = SortedSetRemoveCommand("board".into(), removed);
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(CommandOutput::Count(50))
+        );
+        assert_eq!(record_count(&mut engine).await, 101);
+        assert_eq!(engine.handle_cmd(range("board")).await, Ok(members(&board)));
+        let cmd = SortedSetScoreCommand("board".into(), member(70));
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(CommandOutput::Score(score(0)))
+        );
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert_eq!(engine.handle_cmd(range("board")).await, Ok(members(&board)));
+
+        // Renamed members expire along with the key
+        let cmd = RenameCommand("board".into(), "renamed".into());
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(range("renamed")).await,
+            Ok(members(&board))
+        );
+        assert!(!member_is_stored(&mut engine, b"board", &member(0)).await);
+        let cmd = ExpireCommand("renamed".into(), Some(now_millis() - 1));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(range("renamed")).await,
+            Ok(members(&SortedSet::default()))
+        );
+        assert!(!member_is_stored(&mut engine, b"renamed", &member(0)).await);
+    }
+
+    #[tokio::test]
+    async fn sets() {
+        let test_prefix = "hash_storage_sets";
+        let mut engine = get_engine(test_prefix).await;
+        let bytes = |members: &[&str]| -> Vec<Vec<u8>> {
+            members.iter().map(|x| x.as_bytes().to_vec()).collect()
+        };
+        let add = |key: &str, members: &[&str]| SetAddCommand(key.into(), bytes(members)).into();
+        let remove = |members: &[&str]| SetRemoveCommand("tags".into(), bytes(members)).into();
+        let is_member =
+            |key: &str, member: &str| SetIsMemberCommand(key.into(), member.into()).into();
+        let members = || SetMembersCommand("tags".into()).into();
+        let combine = |operation, keys: &[&str]| SetCombineCommand(operation, bytes(keys)).into();
+        let items = |members: &[&str]| CommandOutput::Items(bytes(members));
+
+        assert_eq!(
+            engine
+                .handle_cmd(add("tags", &["red", "blue", "red"]))
+                .await,
+            Ok(CommandOutput::Count(2))
+        );
+        let expires_at = now_millis() + 60_000;
+        let cmd = ExpireCommand("tags".into(), Some(expires_at));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(add("tags", &["blue", "green"])).await,
+            Ok(CommandOutput::Count(1))
+        );
+        assert_eq!(
+            engine.handle_cmd(is_member("tags", "red")).await,
+            Ok(CommandOutput::IsMember(true))
+        );
+        assert_eq!(
+            engine.handle_cmd(is_member("tags", "pink")).await,
+            Ok(CommandOutput::IsMember(false))
+        );
+        assert_eq!(
+            engine.handle_cmd(is_member("missing", "red")).await,
+            Ok(CommandOutput::IsMember(false))
+        );
+        // The members are in order and adding them keeps the expiry of the key
+        assert_eq!(
+            engine.handle_cmd(members()).await,
+            Ok(items(&["blue", "green", "red"]))
+        );
+        assert_eq!(
+            engine
+                .handle_cmd(SetCardCommand("tags".into()).into())
+                .await,
+            Ok(CommandOutput::Length(3))
+        );
+        let record = engine.find_record(hash_key(b"tags"), b"tags").await;
+        assert_eq!(record.unwrap().4, Some(expires_at));
+
+        // Missing keys count as empty sets
+        engine
+            .handle_cmd(add("other", &["green", "pink"]))
+            .await
+            .unwrap();
+        let outputs = [
+            (
+                SetOperation::Intersection,
+                &["tags", "other"][..],
+                &["green"][..],
+            ),
+            (SetOperation::Intersection, &["tags", "missing"], &[]),
+            (
+                SetOperation::Union,
+                &["tags", "other", "missing"],
+                &["blue", "green", "pink", "red"],
+            ),
+            (
+                SetOperation::Difference,
+                &["tags", "other"],
+                &["blue", "red"],
+            ),
+            (SetOperation::Difference, &["missing", "tags"], &[]),
+        ];
+        for (operation, keys, members) in outputs {
+            assert_eq!(
+                engine.handle_cmd(combine(operation, keys)).await,
+                Ok(items(members))
+            );
+        }
+
+        // Other types are refused
+        let cmd = PutCommand("string".into(), "v".into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(add("string", &["a"])).await,
+            Err(WRONG_TYPE.to_string())
+        );
+        assert_eq!(
+            engine
+                .handle_cmd(combine(SetOperation::Union, &["tags", "string"]))
+                .await,
+            Err(WRONG_TYPE.to_string())
+        );
+        assert_eq!(
+            engine.handle_cmd(GetCommand("tags".into()).into()).await,
+            Err(WRONG_TYPE.to_string())
+        );
+
+        // Copied sets keep their members
+        let cmd = CopyCommand("tags".into(), "copied".into());
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(is_member("copied", "green")).await,
+            Ok(CommandOutput::IsMember(true))
+        );
+
+        // The key is deleted along with the last member
+        assert_eq!(
+            engine.handle_cmd(remove(&["blue", "pink"])).await,
+            Ok(CommandOutput::Count(1))
+        );
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert_eq!(
+            engine.handle_cmd(members()).await,
+            Ok(items(&["green", "red"]))
+        );
+        assert_eq!(
+            engine.handle_cmd(remove(&["green", "red"])).await,
+            Ok(CommandOutput::Count(2))
+        );
+        assert_eq!(engine.handle_cmd(members()).await, Ok(items(&[])));
+        assert_eq!(
+            engine.handle_cmd(StorageCommand::DbSize).await,
+            Ok(CommandOutput::DbSize(3))
+        );
+    }
+
+    #[tokio::test]
+    async fn sets_keep_members_in_records() {
+        let test_prefix = "hash_storage_sets_keep_members_in_records";
+        let mut engine = get_engine(test_prefix).await;
+        let member = |i: u64| incompressible_value(100, i).into_bytes();
+        let card = |key: &str| SetCardCommand(key.into()).into();
+        let items =
+            |members: &BTreeSet<Vec<u8>>| CommandOutput::Items(members.iter().cloned().collect());
+
+        // The members add up to far more than fits in a page
+        let mut tags: BTreeSet<_> = (0..100).map(member).collect();
+        let cmd = SetAddCommand("tags".into(), tags.iter().cloned().collect());
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(CommandOutput::Count(100))
+        );
+        assert_eq!(record_count(&mut engine).await, 201);
+
+        // Removing members from the middle keeps the rest
+        let removed: Vec<_> = (10..60).map(member).collect();
+        for x in &removed {
+            tags.remove(x);
+        }
+        let cmd = SetRemoveCommand("tags".into(), removed);
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(CommandOutput::Count(50))
+        );
+        assert_eq!(record_count(&mut engine).await, 101);
+        assert_eq!(
+            engine.handle_cmd(card("tags")).await,
+            Ok(CommandOutput::Length(50))
+        );
+        let cmd = SetIsMemberCommand("tags".into(), member(99));
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(CommandOutput::IsMember(true))
+        );
+        let cmd = SetIsMemberCommand("tags".into(), member(30));
+        assert_eq!(
+            engine.handle_cmd(cmd.into()).await,
+            Ok(CommandOutput::IsMember(false))
+        );
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        let cmd = SetMembersCommand("tags".into());
+        assert_eq!(engine.handle_cmd(cmd.into()).await, Ok(items(&tags)));
+
+        // Set algebra reads the members of each key
+        let others: BTreeSet<_> = (50..150).map(member).collect();
+        let cmd = SetAddCommand("others".into(), others.iter().cloned().collect());
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        let cmd = SetCombineCommand(
+            SetOperation::Intersection,
+            vec!["tags".into(), "others".into()],
+        );
+        let both = tags.intersection(&others).cloned().collect();
+        assert_eq!(engine.handle_cmd(cmd.into()).await, Ok(items(&both)));
+
+        // The members expire along with the key
+        let cmd = ExpireCommand("tags".into(), Some(now_millis() - 1));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        assert_eq!(
+            engine.handle_cmd(card("tags")).await,
+            Ok(CommandOutput::Length(0))
+        );
+        assert!(!member_is_stored(&mut engine, b"tags", &member(0)).await);
     }
 
-    async fn get_engine_without_reset(test_prefix: &str) -> HashStorage {
-        let test_data_prefx = String::from("./test_data");
-        let data_path = format!("{}/{}_data.db", test_data_prefx, test_prefix);
-        let dir_path = format!("{}/{}_dir.db", test_data_prefx, test_prefix);
-        HashStorage::new(&dir_path, &data_path).await
-    }
+    #[tokio::test]
+    async fn expiry_survives_restart() {
+        let test_prefix = "hash_storage_expiry_survives_restart";
+        let mut engine = get_engine(test_prefix).await;
+        let expires_at = now_millis() + 120_000;
+        let cmd = PutCommand("session".into(), "token".into(), Some(expires_at));
+        engine.handle_cmd(cmd.into()).await.unwrap();
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
 
-    fn record_from_size(hash: u64, key: u8, byte: u8, size: usize) -> Record {
-        let value_len = size
-            - RECORD_HEADER_BYTES
-            - HASH_BYTES
-            - RECORD_VALUE_HEADER_BYTES
-            - RECORD_KEY_HEADER_BYTES
-            - 1;
-        let value = vec![byte; value_len];
-        Record(hash, vec![key], value)
+        let mut engine = get_engine_without_reset(test_prefix).await;
+        assert_eq!(
+            engine
+                .find_record(hash_key(b"session"), b"session")
+                .await
+                .unwrap()
+                .4,
+            Some(expires_at)
+        );
     }
 
     #[tokio::test]
-    async fn smoke() {
-        let mut engine = get_engine("hash_storage_smoke").await;
-        let cmd = PutCommand("MY_KEY".into(), "MY_VALUE".into());
-        let get_cmd = GetCommand("MY_KEY".into());
+    async fn quota_rejects_writes() {
+        let test_prefix = "hash_storage_quota_rejects_writes";
+        let mut engine =
+            get_engine_with_quota(test_prefix, 100, EvictionPolicy::Reject, true).await;
+        let put = |key: &str, len, seed| {
+            PutCommand(key.into(), incompressible_value(len, seed).into(), None).into()
+        };
+        engine.handle_cmd(put("a", 50, 1)).await.unwrap();
+        assert!(engine
+            .handle_cmd(put("b", 50, 2))
+            .await
+            .unwrap_err()
+            .starts_with("Out of space"));
+        // Replacing a value only needs room for the difference
+        engine.handle_cmd(put("a", 90, 3)).await.unwrap();
+        engine.handle_cmd(put("b", 8, 4)).await.unwrap();
+        assert!(engine.handle_cmd(put("c", 200, 5)).await.is_err());
+        engine
+            .handle_cmd(DeleteCommand("a".into()).into())
+            .await
+            .unwrap();
+        engine.handle_cmd(put("c", 50, 6)).await.unwrap();
 
-        engine.handle_cmd(cmd.clone().into()).await.unwrap();
-        let retrieved = engine.handle_cmd(get_cmd.clone().into()).await.unwrap();
+        let quota = engine.quota.as_ref().unwrap();
+        assert_eq!((quota.data_bytes, quota.rejected_writes), (60, 2));
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
 
-        assert_eq!(retrieved, CommandOutput::Found("MY_VALUE".into()));
+        // The data size is counted again when the storage opens
+        let engine = get_engine_with_quota(test_prefix, 100, EvictionPolicy::Reject, false).await;
+        assert_eq!(engine.quota.as_ref().unwrap().data_bytes, 60);
+    }
 
-        let cmd = PutCommand("MY_KEY".into(), "MY_VALUE2".into());
-        engine.handle_cmd(cmd.clone().into()).await.unwrap();
-        let retrieved = engine.handle_cmd(get_cmd.clone().into()).await.unwrap();
+    #[tokio::test]
+    async fn batches() {
+        let test_prefix = "hash_storage_batches";
+        let mut engine = get_engine(test_prefix).await;
+        let put = |key: &str, value: &str| PutCommand(key.into(), value.into(), None);
+        let mset = MSetCommand(vec![put("a", "1"), put("b", "2"), put("a", "3")]);
+        assert_eq!(engine.handle_cmd(mset.into()).await, Ok(CommandOutput::Put));
+        let mget = || MGetCommand(vec!["a".into(), "missing".into(), "b".into()]).into();
+        assert_eq!(
+            engine.handle_cmd(mget()).await,
+            Ok(CommandOutput::Values(vec![
+                Some(b"3".to_vec()),
+                None,
+                Some(b"2".to_vec())
+            ]))
+        );
+        let mdelete = MDeleteCommand(vec!["a".into(), "missing".into()]);
+        assert_eq!(
+            engine.handle_cmd(mdelete.into()).await,
+            Ok(CommandOutput::DeletedKeys(1))
+        );
+        assert_eq!(
+            engine.handle_cmd(mget()).await,
+            Ok(CommandOutput::Values(vec![None, None, Some(b"2".to_vec())]))
+        );
 
-        assert_eq!(retrieved, CommandOutput::Found("MY_VALUE2".into()));
+        // Nothing is put when one of the values is rejected
+        let long_key = "k".repeat(MAX_KEY_BYTES + 1);
+        let mset = MSetCommand(vec![put("c", "1"), put(&long_key, "2")]);
+        assert!(engine.handle_cmd(mset.into()).await.is_err());
+        assert_eq!(
+            engine.handle_cmd(GetCommand("c".into()).into()).await,
+            Ok(CommandOutput::NotFound(b"c".to_vec()))
+        );
+    }
+
+    #[tokio::test]
+    async fn quota_rejects_whole_batches() {
+        let test_prefix = "hash_storage_quota_rejects_whole_batches";
+        let mut engine =
+            get_engine_with_quota(test_prefix, 100, EvictionPolicy::Reject, true).await;
+        let put = |key: &str, len, seed| {
+            PutCommand(key.into(), incompressible_value(len, seed).into(), None)
+        };
+        let mset = MSetCommand(vec![put("a", 40, 1), put("b", 40, 2), put("c", 40, 3)]);
+        assert!(engine.handle_cmd(mset.into()).await.is_err());
+        assert!(engine.is_empty().await);
+        assert_eq!(engine.quota.as_ref().unwrap().data_bytes, 0);
+
+        let mset = MSetCommand(vec![put("a", 40, 1), put("b", 40, 2)]);
+        engine.handle_cmd(mset.into()).await.unwrap();
+        // Replaced values only need room for the difference
+        let mset = MSetCommand(vec![put("a", 10, 1), put("b", 10, 2), put("c", 60, 3)]);
+        engine.handle_cmd(mset.into()).await.unwrap();
+        assert_eq!(engine.quota.as_ref().unwrap().data_bytes, 83);
+    }
 
+    #[tokio::test]
+    async fn quota_evicts_least_recently_used() {
+        let test_prefix = "hash_storage_quota_evicts_least_recently_used";
+        let mut engine = get_engine_with_quota(test_prefix, 303, EvictionPolicy::Lru, true).await;
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            let cmd = PutCommand(key.into(), incompressible_value(100, i as u64).into(), None);
+            engine.handle_cmd(cmd.into()).await.unwrap();
+        }
         engine
-            .handle_cmd(DeleteCommand("MY_KEY".into()).into())
+            .handle_cmd(GetCommand("a".into()).into())
             .await
             .unwrap();
+        let cmd = PutCommand("d".into(), incompressible_value(100, 3).into(), None);
+        engine.handle_cmd(cmd.into()).await.unwrap();
+
+        let mut keys = engine.keys(None).await;
+        keys.sort();
+        assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+        let stats = engine.stats();
+        assert!(stats.contains(&("evicted_keys".into(), "1".into())));
+        assert!(stats.contains(&("data_bytes".into(), "303".into())));
+    }
 
-        let retrieved = engine.handle_cmd(get_cmd.clone().into()).await.unwrap();
-        assert_eq!(retrieved, CommandOutput::NotFound("MY_KEY".into()));
+    #[tokio::test]
+    async fn quota_evicts_random_keys() {
+        let test_prefix = "hash_storage_quota_evicts_random_keys";
+        let mut engine =
+            get_engine_with_quota(test_prefix, 2000, EvictionPolicy::Random, true).await;
+        for i in 0..200 {
+            let key = format!("key{}", i);
+            let value = incompressible_value(96, i);
+            engine
+                .handle_cmd(PutCommand(key.into(), value.into(), None).into())
+                .await
+                .unwrap();
+        }
+        let quota = engine.quota.as_ref().unwrap();
+        assert!(quota.data_bytes <= 2000);
+        assert!(quota.evicted_keys >= 180);
+        assert_eq!(
+            engine.keys(None).await.len() as u64,
+            200 - engine.quota.as_ref().unwrap().evicted_keys
+        );
+        assert_eq!(
+            engine.count_data_bytes().await,
+            engine.quota.as_ref().unwrap().data_bytes
+        );
     }
 
-    /// Simulate a scenario as the following:
-    ///
-    /// Bucket index 0 has a a record of a hash ending with 1010 with 4000 bytes in value.
-    /// Bucket index 0 has is at a local level of 1.
-    /// The global level is at 3.
-    /// We are going to insert a record with a hash of 1110 with 4000 bytes of value, forcing
-    /// it to recursively local split twice.
-    ///
-    /// 000 -> 0 -> Current record
-    /// 001 -> 1
-    /// 010 -> 0
-    /// 011 -> 2
-    /// 100 -> 0
-    /// 101 -> 3
-    /// 110 -> 0
-    /// 111 -> 4
-    ///
-    ///
-    /// We should expect the following after the insert
-    ///
-    /// 000 -> 0
-    /// 001 -> 1
-    /// 010 -> 5 -> Old record
-    /// 011 -> 2
-    /// 100 -> 0
-    /// 101 -> 3
-    /// 110 -> 6 -> New record
-    /// 111 -> 4
-    ///
     #[tokio::test]
-    async fn local_split() {
-        let mut engine = get_engine("hash_storage_local_split").await;
-        let old_record = record_from_size(0b_1010, 1, 1, 4000);
+    async fn exit_save_load() {
+        let mut engine = get_engine("hash_storage_exit_save_load").await;
 
-        let new_record = record_from_size(0b_1110, 2, 2, 4000);
+        engine
+            .directory
+            .set_entries(vec![1, 5, 6, 7, 2, 4, 7, 8])
+            .await;
+        engine.bucket_count = 8;
 
-        let mut buckets = vec![
-            Bucket {
-                bucket_index: 0,
-                remaining_byte_space: 0,
-                level: 1,
-                records: vec![old_record],
-            },
-            Bucket {
-                level: 3,
-                remaining_byte_space: 0,
-                records: vec![],
-                bucket_index: 1,
-            },
-            Bucket {
-                level: 3,
-                remaining_byte_space: 0,
-                records: vec![],
-                bucket_index: 2,
-            },
-            Bucket {
-                level: 3,
-                remaining_byte_space: 0,
-                records: vec![],
-                bucket_index: 3,
-            },
-            Bucket {
-                level: 3,
-                remaining_byte_space: 0,
-                records: vec![],
-                bucket_index: 4,
-            },
-        ];
-        for bucket in &mut buckets {
-            bucket.update_remaining_byte_count();
-            bucket.save_to_file(&mut engine.buckets_file).await;
-        }
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
 
-        engine.bucket_count = 5;
-        engine.global_level = 3;
+        let mut engine_reloaded = get_engine_without_reset("hash_storage_exit_save_load").await;
 
-        engine.bucket_lookup = vec![0, 1, 0, 2, 0, 3, 0, 4];
+        assert_eq!(
+            engine_reloaded.directory.entries().await,
+            engine.directory.entries().await
+        );
+        assert_eq!(
+            engine_reloaded.directory.global_level(),
+            engine.directory.global_level()
+        );
+        assert_eq!(engine_reloaded.bucket_count, engine.bucket_count);
+    }
 
-        engine.put(new_record).await.unwrap();
+    #[tokio::test]
+    async fn encrypted_files() {
+        let test_prefix = "hash_storage_encrypted_files";
+        let data_path = format!("./test_data/{}_data.db", test_prefix);
+        let dir_path = format!("./test_data/{}_dir.db", test_prefix);
+        reset_or_create_file(&data_path);
+        reset_or_create_file(&dir_path);
+        let options = |byte: &str| HashStorageOptions {
+            encryption_key: Some(EncryptionKey::from_hex(&byte.repeat(32)).unwrap()),
+            ..Default::default()
+        };
 
-        assert_eq!(engine.global_level, 3);
-        assert_eq!(engine.bucket_count, 7);
-        assert_eq!(engine.bucket_lookup, vec![0, 1, 5, 2, 0, 3, 6, 4]);
+        let mut engine = HashStorage::new(&dir_path, &data_path, options("01"))
+            .await
+            .unwrap();
+        for i in 0..200 {
+            engine
+                .handle_cmd(
+                    PutCommand(
+                        format!("KEY_{}", i).into(),
+                        format!("SECRET_VALUE_{}", i).into(),
+                        None,
+                    )
+                    .into(),
+                )
+                .await
+                .unwrap();
+        }
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+        assert!(engine.directory.global_level() > 0);
 
-        let old_record_bucket = Bucket::read_from_file(&mut engine.buckets_file, 5).await;
-        old_record_bucket
-            .records
-            .iter()
-            .find(|x| x.0 == 0b_1010)
+        let raw = std::fs::read(&data_path).unwrap();
+        assert!(!raw.windows(12).any(|x| x == b"SECRET_VALUE"));
+
+        let mut reloaded = HashStorage::new(&dir_path, &data_path, options("01"))
+            .await
             .unwrap();
-        assert_eq!(old_record_bucket.level, 3);
+        for i in 0..200 {
+            assert_eq!(
+                reloaded
+                    .handle_cmd(GetCommand(format!("KEY_{}", i).into()).into())
+                    .await
+                    .unwrap(),
+                CommandOutput::Found(format!("SECRET_VALUE_{}", i).into())
+            );
+        }
 
-        let new_record_bucket = Bucket::read_from_file(&mut engine.buckets_file, 6).await;
-        new_record_bucket
-            .records
-            .iter()
-            .find(|x| x.0 == 0b_1110)
+        let error = HashStorage::new(&dir_path, &data_path, options("02"))
+            .await
+            .err()
             .unwrap();
-        assert_eq!(new_record_bucket.level, 3);
+        assert!(error.contains("encryption key is wrong"));
+        let error = HashStorage::new(&dir_path, &data_path, HashStorageOptions::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(error.contains("no encryption key was given"));
+
+        // The files emptied by a FLUSHDB are encrypted as well
+        reloaded.handle_cmd(StorageCommand::FlushDb).await.unwrap();
+        assert!(HashStorage::new(&dir_path, &data_path, options("02"))
+            .await
+            .is_err());
+        assert!(HashStorage::new(&dir_path, &data_path, options("01"))
+            .await
+            .is_ok());
+
+        let with_value_log = HashStorageOptions {
+            value_log: Some(ValueLogOptions {
+                directory: format!("./test_data/{}_values", test_prefix),
+                threshold: 16,
+                segment_bytes: 1024,
+            }),
+            ..options("01")
+        };
+        assert!(HashStorage::new(&dir_path, &data_path, with_value_log)
+            .await
+            .is_err());
     }
 
-    /// Simulate a similar scenario as the local split
-    ///
-    /// We will use 2 records, both 4000 bytes in length. Same as the local split test
-    /// we will have record 1 with a hash of 1010 and the second with 1110.
-    ///
-    /// But instead of having the global level at 3, we have a global level of 1. So inserting both
-    /// record number 2 should cause a global split twice.
-    ///
-    /// 0 -> 0 -> Current record
-    /// 1 -> 1 -> Current record
-    ///
-    ///
-    /// We should expect the following after the insert
-    /// 00 -> 0
-    /// 01 -> 1
-    /// 10 -> 2
-    /// 11 -> 1
-    ///
-    /// 000 -> 0
-    /// 001 -> 1
-    /// 010 -> 2 -> Old Record
-    /// 011 -> 1
-    /// 100 -> 0
-    /// 101 -> 1
-    /// 110 -> 3 -> New Record
-    /// 111 -> 1
-    ///
     #[tokio::test]
-    async fn global_split() {
-        let mut engine = get_engine("hash_storage_global_split").await;
-        let old_record = record_from_size(0b_1010, 1, 1, 4000);
+    async fn page_size_is_recorded() {
+        let test_prefix = "hash_storage_page_size_is_recorded";
+        let data_path = format!("./test_data/{}_data.db", test_prefix);
+        let dir_path = format!("./test_data/{}_dir.db", test_prefix);
+        reset_or_create_file(&data_path);
+        reset_or_create_file(&dir_path);
+        let options = |page_bytes| HashStorageOptions {
+            page_bytes,
+            ..Default::default()
+        };
 
-        let new_record = record_from_size(0b_1110, 2, 2, 4000);
+        let mut engine = HashStorage::new(&dir_path, &data_path, options(Some(16 * 1024)))
+            .await
+            .unwrap();
+        let value = incompressible_value(10_000, 0);
+        engine
+            .handle_cmd(PutCommand("LARGE".into(), value.clone().into(), None).into())
+            .await
+            .unwrap();
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
 
-        let mut buckets = vec![
-            Bucket {
-                bucket_index: 0,
-                remaining_byte_space: 0,
-                level: 1,
-                records: vec![old_record],
-            },
-            Bucket {
-                level: 1,
-                remaining_byte_space: 0,
-                records: vec![],
-                bucket_index: 1,
-            },
-        ];
-        for bucket in &mut buckets {
-            bucket.update_remaining_byte_count();
-            bucket.save_to_file(&mut engine.buckets_file).await;
+        // The recorded page size wins over the one asked for
+        let mut reloaded = HashStorage::new(&dir_path, &data_path, options(Some(4096)))
+            .await
+            .unwrap();
+        assert_eq!(reloaded.buckets_file.page_bytes, 16 * 1024);
+        let retrieved = reloaded
+            .handle_cmd(GetCommand("LARGE".into()).into())
+            .await
+            .unwrap();
+        assert_eq!(retrieved, CommandOutput::Found(value.into()));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_page_size() {
+        let test_prefix = "hash_storage_rejects_invalid_page_size";
+        let data_path = format!("./test_data/{}_data.db", test_prefix);
+        let dir_path = format!("./test_data/{}_dir.db", test_prefix);
+        for page_bytes in [5000, 2048, 128 * 1024] {
+            reset_or_create_file(&data_path);
+            reset_or_create_file(&dir_path);
+            let options = HashStorageOptions {
+                page_bytes: Some(page_bytes),
+                ..Default::default()
+            };
+            assert!(HashStorage::new(&dir_path, &data_path, options)
+                .await
+                .is_err());
         }
-        engine.bucket_count = 2;
-        engine.global_level = 1;
-        engine.bucket_lookup = vec![0, 1];
+    }
 
-        engine.put(new_record).await.unwrap();
+    #[tokio::test]
+    async fn reads_page_size_from_the_header_byte() {
+        let test_prefix = "hash_storage_reads_page_size_from_the_header_byte";
+        let data_path = format!("./test_data/{}_data.db", test_prefix);
+        let dir_path = format!("./test_data/{}_dir.db", test_prefix);
+        reset_or_create_file(&data_path);
+        reset_or_create_file(&dir_path);
+        let options = |page_bytes| HashStorageOptions {
+            page_bytes,
+            ..Default::default()
+        };
+        let mut engine = HashStorage::new(&dir_path, &data_path, options(Some(16 * 1024)))
+            .await
+            .unwrap();
+        let value = incompressible_value(10_000, 0);
+        engine
+            .handle_cmd(PutCommand("LARGE".into(), value.clone().into(), None).into())
+            .await
+            .unwrap();
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
 
-        assert_eq!(engine.global_level, 3);
-        assert_eq!(engine.bucket_count, 4);
-        assert_eq!(engine.bucket_lookup, vec![0, 1, 2, 1, 0, 1, 3, 1]);
+        // Files written before the page size had a field of its own keep log2 of it in the top
+        // byte of the header
+        let mut data = std::fs::read(&data_path).unwrap();
+        let field = HEADER_BYTES + KEY_COUNT_BYTES;
+        data.drain(field..field + PAGE_BYTES_FIELD_BYTES);
+        data[HEADER_BYTES - 1] = 0x80 | 14;
+        std::fs::write(&data_path, data).unwrap();
 
-        let old_record_bucket = Bucket::read_from_file(&mut engine.buckets_file, 2).await;
-        old_record_bucket
-            .records
-            .iter()
-            .find(|x| x.0 == 0b_1010)
+        let mut reloaded = HashStorage::new(&dir_path, &data_path, options(Some(4096)))
+            .await
             .unwrap();
-        assert_eq!(old_record_bucket.level, 3);
-
-        let new_record_bucket = Bucket::read_from_file(&mut engine.buckets_file, 3).await;
-        new_record_bucket
-            .records
-            .iter()
-            .find(|x| x.0 == 0b_1110)
+        assert!(!reloaded.buckets_file.has_page_bytes);
+        assert_eq!(reloaded.buckets_file.page_bytes, 16 * 1024);
+        let retrieved = reloaded
+            .handle_cmd(GetCommand("LARGE".into()).into())
+            .await
             .unwrap();
-        assert_eq!(new_record_bucket.level, 3);
+        assert_eq!(retrieved, CommandOutput::Found(value.into()));
     }
 
     #[tokio::test]
-    async fn exit_save_load() {
-        let mut engine = get_engine("hash_storage_exit_save_load").await;
-
-        engine.bucket_lookup = vec![1, 5, 6, 7, 2, 4, 7, 8];
-        engine.global_level = 3;
-        engine.bucket_count = 8;
-
+    async fn reads_buckets_file_without_page_size() {
+        let test_prefix = "hash_storage_reads_buckets_file_without_page_size";
+        let mut engine = get_engine(test_prefix).await;
+        engine
+            .handle_cmd(PutCommand("KEY".into(), "VALUE".into(), None).into())
+            .await
+            .unwrap();
         engine.handle_cmd(StorageCommand::Flush).await.unwrap();
 
-        let engine_reloaded = get_engine_without_reset("hash_storage_exit_save_load").await;
-
-        assert_eq!(engine_reloaded.bucket_lookup, engine.bucket_lookup);
-        assert_eq!(engine_reloaded.global_level, engine.global_level);
-        assert_eq!(engine_reloaded.bucket_count, engine.bucket_count);
+        // Files written before the page size was recorded only hold the bucket count
+        let data_path = format!("./test_data/{}_data.db", test_prefix);
+        let mut data = std::fs::read(&data_path).unwrap();
+        data.drain(HEADER_BYTES..HEADER_BYTES + KEY_COUNT_BYTES + PAGE_BYTES_FIELD_BYTES);
+        data[..HEADER_BYTES].copy_from_slice(&1_u64.to_le_bytes());
+        std::fs::write(&data_path, data).unwrap();
+
+        let mut reloaded = get_engine_without_reset(test_prefix).await;
+        assert_eq!(reloaded.buckets_file.page_bytes, DEFAULT_PAGE_BYTES);
+        let retrieved = reloaded
+            .handle_cmd(GetCommand("KEY".into()).into())
+            .await
+            .unwrap();
+        assert_eq!(retrieved, CommandOutput::Found("VALUE".into()));
     }
 }
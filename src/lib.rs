@@ -5,7 +5,6 @@
 #[cfg(test)]
 mod test;
 
-mod consts;
 mod server;
 mod command;
 mod repl;
@@ -13,10 +12,26 @@ mod setup;
 mod execute;
 mod parse;
 mod stdin;
+mod directory;
 mod hash_storage;
+mod linear_hash_storage;
+mod backup;
+mod bench;
 mod bytes;
+mod compression;
+mod dump;
+mod encryption;
+mod glob;
+mod mutation_log;
+mod quota;
+mod recovery;
+mod sorted_set;
 mod wal;
+mod value_log;
 
 pub use repl::*;
 pub use stdin::*;
 pub use server::*;
+pub use dump::{run_dump, run_restore};
+pub use recovery::run_recover;
+pub use bench::run_bench;
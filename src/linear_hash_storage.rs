@@ -0,0 +1,791 @@
+use crate::bytes::{ByteLength, IntoBytes, ParseFromBytes};
+use crate::command::*;
+use crate::hash_storage::{hash_key, take_bytes_from_iterator, Hash, Record, DEFAULT_PAGE_BYTES};
+use std::io::SeekFrom;
+use std::mem::size_of;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Number of bytes in a page, linear hashing always uses the default page size
+const PAGE_BYTES: usize = DEFAULT_PAGE_BYTES;
+
+/// The type used to represent the level of the hash table, the table has between 2^level and
+/// 2^(level + 1) primary buckets
+type LinearLevel = u8;
+
+/// The length in bytes of `LinearLevel`
+const LINEAR_LEVEL_BYTES: usize = size_of::<LinearLevel>();
+
+/// Type used for indexing and counting both primary buckets and overflow pages
+type PageIndexType = usize;
+
+/// The length in bytes of `PageIndexType`
+const PAGE_INDEX_TYPE_BYTES: usize = size_of::<PageIndexType>();
+
+/// The length of the page header in bytes, this is the pointer to the next overflow page
+const PAGE_HEADER_BYTES: usize = PAGE_INDEX_TYPE_BYTES;
+
+/// The value of the next pointer in the page header when there are no more overflow pages
+const NO_NEXT_PAGE: PageIndexType = 0;
+
+/// Where a page lives, primary buckets and overflow pages are kept in separate files so the
+/// address of a primary bucket can be derived from the hash alone
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PageLocation {
+    /// The nth primary bucket in the buckets file, 0 indexed
+    Primary(PageIndexType),
+
+    /// The nth page in the overflow file, 0 indexed
+    Overflow(PageIndexType),
+}
+
+/// A page in a bucket chain, either the primary bucket or one of its overflow pages
+///
+/// ## Binary layout
+///
+/// - First `PAGE_HEADER_BYTES` is the index of the next overflow page in the chain plus one, a
+///   value of `NO_NEXT_PAGE` means this is the last page in the chain
+/// - Rest are records
+///
+#[derive(PartialEq, Debug, Clone)]
+struct LinearPage {
+    /// Where the page is stored
+    location: PageLocation,
+
+    /// The next overflow page in the chain
+    next: Option<PageIndexType>,
+
+    /// The number of bytes remaining available in the page
+    remaining_byte_space: usize,
+
+    /// The records contained in the page
+    records: Vec<Record>,
+}
+
+impl<'a, T> ParseFromBytes<T> for LinearPage
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+
+    type Metadata = PageLocation;
+
+    fn from_bytes(mut bytes: T, location: Self::Metadata) -> Result<(Self, T), Self::Error> {
+        let page: [u8; PAGE_BYTES] = take_bytes_from_iterator(&mut bytes);
+        let mut page = page.iter().peekable();
+
+        let next_bytes: [u8; PAGE_HEADER_BYTES] = take_bytes_from_iterator(&mut page);
+        let next = match PageIndexType::from_le_bytes(next_bytes) {
+            NO_NEXT_PAGE => None,
+            n => Some(n - 1),
+        };
+        let mut records = vec![];
+
+        while let Some(x) = page.peek() {
+            if **x == 0 {
+                page.next();
+                continue;
+            }
+            let (record, rest_page) = Record::from_bytes(page, ())?;
+            records.push(record);
+            page = rest_page;
+        }
+
+        let mut linear_page = LinearPage {
+            location,
+            next,
+            records,
+            remaining_byte_space: 0,
+        };
+
+        linear_page.update_remaining_byte_count();
+
+        Ok((linear_page, bytes))
+    }
+}
+
+impl LinearPage {
+    fn empty(location: PageLocation) -> Self {
+        Self {
+            location,
+            next: None,
+            remaining_byte_space: PAGE_BYTES - PAGE_HEADER_BYTES,
+            records: vec![],
+        }
+    }
+
+    fn update_remaining_byte_count(&mut self) {
+        let records_byte_len: usize = self.records.iter().map(|r| r.byte_len()).sum();
+        self.remaining_byte_space = PAGE_BYTES - PAGE_HEADER_BYTES - records_byte_len
+    }
+
+    async fn read_from_file(file: &mut File, location: PageLocation) -> Self {
+        let index = match location {
+            PageLocation::Primary(index) | PageLocation::Overflow(index) => index,
+        };
+        file.seek(SeekFrom::Start((index * PAGE_BYTES) as u64))
+            .await
+            .unwrap();
+        let mut buf = [0; PAGE_BYTES];
+        file.read_exact(&mut buf).await.unwrap();
+        let (page, _) = Self::from_bytes(buf.iter(), location).unwrap();
+        page
+    }
+
+    async fn save_to_file(&self, file: &mut File) {
+        let index = match self.location {
+            PageLocation::Primary(index) | PageLocation::Overflow(index) => index,
+        };
+        file.seek(SeekFrom::Start((index * PAGE_BYTES) as u64))
+            .await
+            .unwrap();
+        let mut buf = [0_u8; PAGE_BYTES];
+        let next = self.next.map_or(NO_NEXT_PAGE, |n| n + 1);
+        buf[..PAGE_HEADER_BYTES].copy_from_slice(&next.to_le_bytes());
+        let mut ptr = PAGE_HEADER_BYTES;
+        for record in self.records.iter().cloned() {
+            let bytes = record.into_bytes();
+            let length = bytes.len();
+            buf[ptr..ptr + length].copy_from_slice(&bytes);
+            ptr += length;
+        }
+
+        file.write_all(&buf).await.unwrap();
+    }
+}
+
+/// Hash storage engine utilising linear hashing
+///
+/// Unlike `HashStorage` there is no directory, the primary bucket of a hash is derived from the
+/// level and the split pointer. Buckets grow one at a time in a round robin order, and records
+/// which don't fit in their primary bucket spill into a chain of overflow pages until the bucket
+/// is split.
+pub struct LinearHashStorage {
+    /// The file containing the state of the hash table
+    ///
+    /// # File layout
+    /// - First `LINEAR_LEVEL_BYTES` is the level in LE
+    /// - Next `PAGE_INDEX_TYPE_BYTES` is the split pointer in LE
+    /// - Next `PAGE_INDEX_TYPE_BYTES` is the number of overflow pages in LE
+    /// - Next `PAGE_INDEX_TYPE_BYTES` is the number of free overflow pages in LE
+    /// - Followed by the free overflow page indices, each `PAGE_INDEX_TYPE_BYTES` in LE
+    ///
+    /// The entire file is loaded and saved all at once
+    meta_file: File,
+
+    /// The file containing the primary buckets, page n is the nth bucket
+    buckets_file: File,
+
+    /// The file containing the overflow pages of every bucket chain
+    overflow_file: File,
+
+    /// The level of the hash table
+    level: LinearLevel,
+
+    /// The next bucket to be split
+    ///
+    /// Buckets before the split pointer have already been split in this round and are addressed
+    /// with `level + 1` bits of the hash
+    split_pointer: PageIndexType,
+
+    /// The number of overflow pages in the overflow file
+    overflow_count: PageIndexType,
+
+    /// Overflow pages which were released by a split and can be reused
+    free_overflow: Vec<PageIndexType>,
+}
+
+/// Loads the meta file of the hash table
+///
+/// # Returns
+/// - A tuple containing the level, split pointer, overflow page count and free overflow pages
+async fn load_meta_file(
+    meta_file: &mut File,
+) -> (
+    LinearLevel,
+    PageIndexType,
+    PageIndexType,
+    Vec<PageIndexType>,
+) {
+    if meta_file.metadata().await.unwrap().len() == 0 {
+        return (0, 0, 0, vec![]);
+    }
+
+    meta_file.seek(SeekFrom::Start(0)).await.unwrap();
+    let mut buf = vec![];
+    meta_file.read_to_end(&mut buf).await.unwrap();
+    let mut bytes = buf.iter();
+
+    let level = LinearLevel::from_le_bytes(take_bytes_from_iterator(&mut bytes));
+    let split_pointer = PageIndexType::from_le_bytes(take_bytes_from_iterator(&mut bytes));
+    let overflow_count = PageIndexType::from_le_bytes(take_bytes_from_iterator(&mut bytes));
+    let free_count = PageIndexType::from_le_bytes(take_bytes_from_iterator(&mut bytes));
+    let free_overflow = (0..free_count)
+        .map(|_| PageIndexType::from_le_bytes(take_bytes_from_iterator(&mut bytes)))
+        .collect();
+
+    (level, split_pointer, overflow_count, free_overflow)
+}
+
+impl LinearHashStorage {
+    /// Creates a represenation of the storage engine by specifying its files
+    ///
+    /// If the specified files do not exist, they will be created.
+    ///
+    /// # Arguments
+    /// * `meta_file` - The file containing the level, split pointer and overflow bookkeeping
+    /// * `buckets_file` - The file containing the primary buckets
+    /// * `overflow_file` - The file containing the overflow pages
+    ///
+    /// # Returns
+    /// A new instance of the index
+    pub async fn new(meta_file: &str, buckets_file: &str, overflow_file: &str) -> Self {
+        let open = |path: &str| -> File {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(path)
+                .unwrap()
+                .into()
+        };
+
+        let mut meta_file = open(meta_file);
+        let mut buckets_file = open(buckets_file);
+        let overflow_file = open(overflow_file);
+
+        let (level, split_pointer, overflow_count, free_overflow) =
+            load_meta_file(&mut meta_file).await;
+
+        if buckets_file.metadata().await.unwrap().len() == 0 {
+            // setup the file by pushing an empty bucket to it
+            LinearPage::empty(PageLocation::Primary(0))
+                .save_to_file(&mut buckets_file)
+                .await;
+        }
+
+        Self {
+            meta_file,
+            buckets_file,
+            overflow_file,
+            level,
+            split_pointer,
+            overflow_count,
+            free_overflow,
+        }
+    }
+
+    pub async fn handle_cmd(&mut self, cmd: StorageCommand) -> Result<CommandOutput, String> {
+        match cmd {
+            StorageCommand::Put(PutCommand(_, _, Some(_)))
+            | StorageCommand::Expire(_)
+            | StorageCommand::Ttl(_) => {
+                Err("Expiry is not supported by the linear hash storage".to_string())
+            }
+            StorageCommand::Put(cmd) => {
+                validate_key(&cmd.0)?;
+                validate_value(&cmd.1)?;
+                self.put(Record::new(hash_key(&cmd.0), cmd.0, cmd.1))
+                    .await
+                    .map_err(|_| "Key and value are too large to store".to_string())?;
+                Ok(CommandOutput::Put)
+            }
+            StorageCommand::Delete(cmd) => {
+                if self.delete(cmd.clone()).await.unwrap() {
+                    Ok(CommandOutput::Deleted)
+                } else {
+                    Ok(CommandOutput::NotFound(cmd.0))
+                }
+            }
+            StorageCommand::Get(cmd) => {
+                if let Some(value) = self.get(hash_key(&cmd.0), &cmd.0).await.unwrap() {
+                    Ok(CommandOutput::Found(value))
+                } else {
+                    Ok(CommandOutput::NotFound(cmd.0))
+                }
+            }
+            StorageCommand::Incr(cmd) => {
+                let hash = hash_key(&cmd.0);
+                let value = self.get(hash, &cmd.0).await.unwrap();
+                let value = increment(value.as_deref(), cmd.1)?;
+                let record = Record::new(hash, cmd.0, value.to_string().into_bytes());
+                self.put(record)
+                    .await
+                    .map_err(|_| "Key and value are too large to store".to_string())?;
+                Ok(CommandOutput::Integer(value))
+            }
+            StorageCommand::PutIf(_)
+            | StorageCommand::Cas(_)
+            | StorageCommand::GetDel(_)
+            | StorageCommand::GetSet(_) => {
+                Err("Conditional updates are not supported by the linear hash storage".to_string())
+            }
+            StorageCommand::MGet(_) | StorageCommand::MSet(_) | StorageCommand::MDelete(_) => {
+                Err("Batches are not supported by the linear hash storage".to_string())
+            }
+            StorageCommand::Exists(cmd) => {
+                let value = self.get(hash_key(&cmd.0), &cmd.0).await.unwrap();
+                Ok(CommandOutput::Exists(value.is_some()))
+            }
+            StorageCommand::Rename(_)
+            | StorageCommand::Copy(_)
+            | StorageCommand::DbSize
+            | StorageCommand::FlushDb => {
+                Err("Key management is not supported by the linear hash storage".to_string())
+            }
+            StorageCommand::ListPush(_)
+            | StorageCommand::ListPop(_)
+            | StorageCommand::ListRange(_)
+            | StorageCommand::ListLen(_) => {
+                Err("Lists are not supported by the linear hash storage".to_string())
+            }
+            StorageCommand::HashSet(_)
+            | StorageCommand::HashGet(_)
+            | StorageCommand::HashDelete(_)
+            | StorageCommand::HashGetAll(_)
+            | StorageCommand::HashKeys(_) => {
+                Err("Hashes are not supported by the linear hash storage".to_string())
+            }
+            StorageCommand::SortedSetAdd(_)
+            | StorageCommand::SortedSetScore(_)
+            | StorageCommand::SortedSetRange(_)
+            | StorageCommand::SortedSetRangeByScore(_)
+            | StorageCommand::SortedSetRemove(_) => {
+                Err("Sorted sets are not supported by the linear hash storage".to_string())
+            }
+            StorageCommand::SetAdd(_)
+            | StorageCommand::SetRemove(_)
+            | StorageCommand::SetIsMember(_)
+            | StorageCommand::SetMembers(_)
+            | StorageCommand::SetCard(_)
+            | StorageCommand::SetCombine(_) => {
+                Err("Sets are not supported by the linear hash storage".to_string())
+            }
+            StorageCommand::Keys(_) | StorageCommand::Scan(_) => {
+                Err("Listing keys is not supported by the linear hash storage".to_string())
+            }
+            StorageCommand::Backup(_) => {
+                Err("Backups are not supported by the linear hash storage".to_string())
+            }
+            StorageCommand::Stats => Ok(CommandOutput::Stats(self.stats())),
+            StorageCommand::Flush => {
+                self.exit().await;
+                Ok(CommandOutput::Exit)
+            }
+        }
+    }
+
+    async fn exit(&mut self) {
+        let mut buf = vec![];
+        buf.extend(self.level.to_le_bytes());
+        buf.extend(self.split_pointer.to_le_bytes());
+        buf.extend(self.overflow_count.to_le_bytes());
+        buf.extend(self.free_overflow.len().to_le_bytes());
+        for index in &self.free_overflow {
+            buf.extend(index.to_le_bytes());
+        }
+        self.meta_file.set_len(0).await.unwrap();
+        self.meta_file.seek(SeekFrom::Start(0)).await.unwrap();
+        self.meta_file.write_all(&buf).await.unwrap();
+
+        self.meta_file.sync_all().await.unwrap();
+        self.buckets_file.sync_all().await.unwrap();
+        self.overflow_file.sync_all().await.unwrap();
+    }
+
+    /// The number of primary buckets in the hash table
+    fn bucket_count(&self) -> PageIndexType {
+        2_usize.pow(self.level.into()) + self.split_pointer
+    }
+
+    /// Returns the primary bucket which the hash belongs to
+    fn hash_to_bucket(&self, hash: Hash) -> PageIndexType {
+        let bucket = hash % 2_u64.pow(self.level.into());
+        if (bucket as PageIndexType) < self.split_pointer {
+            (hash % 2_u64.pow(u32::from(self.level) + 1)) as PageIndexType
+        } else {
+            bucket as PageIndexType
+        }
+    }
+
+    /// The stats of the storage as name value pairs, named like the stats of `HashStorage`
+    ///
+    /// There is no directory, the meta file held in memory takes its place in `directory_bytes`
+    fn stats(&self) -> Vec<(String, String)> {
+        let meta_bytes =
+            LINEAR_LEVEL_BYTES + (3 + self.free_overflow.len()) * PAGE_INDEX_TYPE_BYTES;
+        [
+            ("buckets", self.bucket_count()),
+            ("overflow_pages", self.overflow_count),
+            ("free_overflow_pages", self.free_overflow.len()),
+            ("level", self.level.into()),
+            ("split_pointer", self.split_pointer),
+            ("directory_bytes", meta_bytes),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+    }
+
+    async fn read_page(&mut self, location: PageLocation) -> LinearPage {
+        match location {
+            PageLocation::Primary(_) => {
+                LinearPage::read_from_file(&mut self.buckets_file, location).await
+            }
+            PageLocation::Overflow(_) => {
+                LinearPage::read_from_file(&mut self.overflow_file, location).await
+            }
+        }
+    }
+
+    async fn save_page(&mut self, page: &LinearPage) {
+        match page.location {
+            PageLocation::Primary(_) => page.save_to_file(&mut self.buckets_file).await,
+            PageLocation::Overflow(_) => page.save_to_file(&mut self.overflow_file).await,
+        }
+    }
+
+    /// Reads the primary bucket and all of its overflow pages
+    async fn read_chain(&mut self, bucket_index: PageIndexType) -> Vec<LinearPage> {
+        let mut chain = vec![self.read_page(PageLocation::Primary(bucket_index)).await];
+        while let Some(next) = chain.last().unwrap().next {
+            chain.push(self.read_page(PageLocation::Overflow(next)).await);
+        }
+        chain
+    }
+
+    /// Takes an overflow page from the free list or appends a new one to the overflow file
+    fn allocate_overflow(&mut self) -> PageIndexType {
+        if let Some(index) = self.free_overflow.pop() {
+            return index;
+        }
+        let index = self.overflow_count;
+        self.overflow_count += 1;
+        index
+    }
+
+    async fn put(&mut self, record: Record) -> Result<(), ()> {
+        if record.byte_len() > PAGE_BYTES - PAGE_HEADER_BYTES {
+            return Err(());
+        }
+
+        let bucket_index = self.hash_to_bucket(record.0);
+        let mut chain = self.read_chain(bucket_index).await;
+
+        // Update in place if the new value fits, otherwise remove the existing record so it can
+        // be placed in whichever page has room
+        for page in chain.iter_mut() {
+            if let Some(position) = page
+                .records
+                .iter()
+                .position(|x| x.0 == record.0 && x.1 == record.1)
+            {
+                let existing_len = page.records[position].byte_len();
+                if existing_len + page.remaining_byte_space >= record.byte_len() {
+                    page.records[position] = record;
+                    page.update_remaining_byte_count();
+                    self.save_page(page).await;
+                    return Ok(());
+                }
+                page.records.remove(position);
+                page.update_remaining_byte_count();
+                self.save_page(page).await;
+                break;
+            }
+        }
+
+        if let Some(page) = chain
+            .iter_mut()
+            .find(|x| x.remaining_byte_space >= record.byte_len())
+        {
+            page.records.push(record);
+            page.update_remaining_byte_count();
+            self.save_page(page).await;
+            return Ok(());
+        }
+
+        // No room anywhere in the chain, grow it with an overflow page and then split the next
+        // bucket in the round
+        let overflow_index = self.allocate_overflow();
+        let mut overflow = LinearPage::empty(PageLocation::Overflow(overflow_index));
+        overflow.records.push(record);
+        overflow.update_remaining_byte_count();
+        self.save_page(&overflow).await;
+
+        let last = chain.last_mut().unwrap();
+        last.next = Some(overflow_index);
+        self.save_page(last).await;
+
+        self.split().await;
+        Ok(())
+    }
+
+    /// Splits the bucket at the split pointer into itself and a new bucket at the end of the
+    /// buckets file, then advances the split pointer
+    async fn split(&mut self) {
+        let bucket_index = self.split_pointer;
+        let new_bucket_index = self.bucket_count();
+
+        let chain = self.read_chain(bucket_index).await;
+        let next_modulo = 2_u64.pow(u32::from(self.level) + 1);
+
+        let (original, new): (Vec<Record>, Vec<Record>) = chain
+            .iter()
+            .flat_map(|page| page.records.iter().cloned())
+            .partition(|x| (x.0 % next_modulo) as PageIndexType == bucket_index);
+
+        // Release the overflow pages of the old chain, they are handed back out as needed when
+        // the two buckets are written
+        for page in &chain {
+            if let PageLocation::Overflow(index) = page.location {
+                self.free_overflow.push(index);
+            }
+        }
+
+        self.write_chain(bucket_index, original).await;
+        self.write_chain(new_bucket_index, new).await;
+
+        self.split_pointer += 1;
+        if self.split_pointer == 2_usize.pow(self.level.into()) {
+            self.level += 1;
+            self.split_pointer = 0;
+        }
+    }
+
+    /// Writes the records into the primary bucket, using overflow pages as needed
+    async fn write_chain(&mut self, bucket_index: PageIndexType, records: Vec<Record>) {
+        let mut pages = vec![LinearPage::empty(PageLocation::Primary(bucket_index))];
+        for record in records {
+            let page = pages.last_mut().unwrap();
+            if page.remaining_byte_space < record.byte_len() {
+                let overflow_index = self.allocate_overflow();
+                page.next = Some(overflow_index);
+                pages.push(LinearPage::empty(PageLocation::Overflow(overflow_index)));
+            }
+            let page = pages.last_mut().unwrap();
+            page.records.push(record);
+            page.update_remaining_byte_count();
+        }
+        for page in &pages {
+            self.save_page(page).await;
+        }
+    }
+
+    async fn get(&mut self, hash: Hash, key: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+        let bucket_index = self.hash_to_bucket(hash);
+        let chain = self.read_chain(bucket_index).await;
+
+        Ok(chain
+            .into_iter()
+            .flat_map(|page| page.records)
+            .find(|x| x.0 == hash && x.1 == key)
+            .map(|r| r.2))
+    }
+
+    /// Deletes the key
+    ///
+    /// # Returns
+    /// - Whether the key was present
+    pub async fn delete(&mut self, cmd: DeleteCommand) -> Result<bool, ()> {
+        let hash = hash_key(&cmd.0);
+        let bucket_index = self.hash_to_bucket(hash);
+        let mut chain = self.read_chain(bucket_index).await;
+
+        for page in chain.iter_mut() {
+            if let Some(position) = page
+                .records
+                .iter()
+                .position(|x| x.0 == hash && x.1 == cmd.0)
+            {
+                page.records.remove(position);
+                page.update_remaining_byte_count();
+                self.save_page(page).await;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod test_linear_page {
+    use super::*;
+    use crate::test::*;
+
+    #[tokio::test]
+    async fn to_and_from_file() {
+        let mut page = LinearPage {
+            location: PageLocation::Overflow(1),
+            next: Some(0),
+            remaining_byte_space: 0,
+            records: vec![
+                Record::new(0b_1110, vec![1], vec![25, 236, 36, 46]),
+                Record::new(0b_0010, vec![2], vec![26, 236, 36, 46]),
+            ],
+        };
+        page.update_remaining_byte_count();
+
+        let mut file = reset_or_create_file("./test_data/test_linear_page_to_and_from_file").into();
+        page.save_to_file(&mut file).await;
+
+        let page_ = LinearPage::read_from_file(&mut file, PageLocation::Overflow(1)).await;
+        assert_eq!(page_, page);
+    }
+}
+
+#[cfg(test)]
+mod test_linear_hash_storage {
+    use super::*;
+    use crate::test::*;
+
+    fn paths(test_prefix: &str) -> (String, String, String) {
+        let test_data_prefx = String::from("./test_data");
+        (
+            format!("{}/{}_meta.db", test_data_prefx, test_prefix),
+            format!("{}/{}_data.db", test_data_prefx, test_prefix),
+            format!("{}/{}_overflow.db", test_data_prefx, test_prefix),
+        )
+    }
+
+    async fn get_engine(test_prefix: &str) -> LinearHashStorage {
+        let (meta_path, data_path, overflow_path) = paths(test_prefix);
+        reset_or_create_file(&meta_path);
+        reset_or_create_file(&data_path);
+        reset_or_create_file(&overflow_path);
+        LinearHashStorage::new(&meta_path, &data_path, &overflow_path).await
+    }
+
+    async fn get_engine_without_reset(test_prefix: &str) -> LinearHashStorage {
+        let (meta_path, data_path, overflow_path) = paths(test_prefix);
+        LinearHashStorage::new(&meta_path, &data_path, &overflow_path).await
+    }
+
+    fn record_with_value_len(hash: u64, key: u8, value_len: usize) -> Record {
+        Record::new(hash, vec![key], vec![key; value_len])
+    }
+
+    #[tokio::test]
+    async fn smoke() {
+        let mut engine = get_engine("linear_hash_storage_smoke").await;
+        let cmd = PutCommand("MY_KEY".into(), "MY_VALUE".into(), None);
+        let get_cmd = GetCommand("MY_KEY".into());
+
+        engine.handle_cmd(cmd.clone().into()).await.unwrap();
+        let retrieved = engine.handle_cmd(get_cmd.clone().into()).await.unwrap();
+
+        assert_eq!(retrieved, CommandOutput::Found("MY_VALUE".into()));
+
+        let cmd = PutCommand("MY_KEY".into(), "MY_VALUE2".into(), None);
+        engine.handle_cmd(cmd.clone().into()).await.unwrap();
+        let retrieved = engine.handle_cmd(get_cmd.clone().into()).await.unwrap();
+
+        assert_eq!(retrieved, CommandOutput::Found("MY_VALUE2".into()));
+
+        engine
+            .handle_cmd(DeleteCommand("MY_KEY".into()).into())
+            .await
+            .unwrap();
+
+        let retrieved = engine.handle_cmd(get_cmd.clone().into()).await.unwrap();
+        assert_eq!(retrieved, CommandOutput::NotFound("MY_KEY".into()));
+    }
+
+    /// Two records which don't fit in a single page collide in bucket 0 while there is only
+    /// 1 bucket. The second record spills into an overflow page, which triggers the split of
+    /// bucket 0 into bucket 0 (hash ending in 0) and bucket 1 (hash ending in 1).
+    #[tokio::test]
+    async fn overflow_then_split() {
+        let mut engine = get_engine("linear_hash_storage_overflow_then_split").await;
+        let even = record_with_value_len(0b_1010, 1, 3000);
+        let odd = record_with_value_len(0b_1011, 2, 3000);
+
+        engine.put(even.clone()).await.unwrap();
+        assert_eq!(engine.bucket_count(), 1);
+
+        engine.put(odd.clone()).await.unwrap();
+        assert_eq!(engine.level, 1);
+        assert_eq!(engine.split_pointer, 0);
+        assert_eq!(engine.bucket_count(), 2);
+        assert_eq!(engine.free_overflow, vec![0]);
+
+        let bucket_0 = engine.read_chain(0).await;
+        assert_eq!(bucket_0.len(), 1);
+        assert_eq!(bucket_0[0].records, vec![even.clone()]);
+
+        let bucket_1 = engine.read_chain(1).await;
+        assert_eq!(bucket_1.len(), 1);
+        assert_eq!(bucket_1[0].records, vec![odd.clone()]);
+
+        assert_eq!(engine.get(even.0, &even.1).await.unwrap(), Some(even.2));
+        assert_eq!(engine.get(odd.0, &odd.1).await.unwrap(), Some(odd.2));
+    }
+
+    /// Records which still collide after the split stay in an overflow chain, the split pointer
+    /// moves on regardless.
+    #[tokio::test]
+    async fn overflow_chain_survives_split() {
+        let mut engine = get_engine("linear_hash_storage_overflow_chain").await;
+        let records = vec![
+            record_with_value_len(0b_0000, 1, 3000),
+            record_with_value_len(0b_0100, 2, 3000),
+            record_with_value_len(0b_1000, 3, 3000),
+        ];
+
+        for record in &records {
+            engine.put(record.clone()).await.unwrap();
+        }
+
+        // Level 0 -> 1 on the first overflow, then bucket 0 overflows again and the split
+        // pointer walks over it
+        assert_eq!(engine.level, 1);
+        assert_eq!(engine.split_pointer, 1);
+        assert_eq!(engine.bucket_count(), 3);
+        assert_eq!(engine.read_chain(0).await.len(), 3);
+
+        for record in &records {
+            assert_eq!(
+                engine.get(record.0, &record.1).await.unwrap(),
+                Some(record.2.clone())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_record() {
+        let mut engine = get_engine("linear_hash_storage_rejects_oversized_record").await;
+        let record = record_with_value_len(0, 1, PAGE_BYTES);
+        assert_eq!(engine.put(record).await, Err(()));
+    }
+
+    #[tokio::test]
+    async fn exit_save_load() {
+        let mut engine = get_engine("linear_hash_storage_exit_save_load").await;
+        for i in 0..50_u8 {
+            engine
+                .handle_cmd(
+                    PutCommand(format!("key{}", i).into(), "v".repeat(400).into(), None).into(),
+                )
+                .await
+                .unwrap();
+        }
+
+        engine.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        let mut engine_reloaded =
+            get_engine_without_reset("linear_hash_storage_exit_save_load").await;
+
+        assert_eq!(engine_reloaded.level, engine.level);
+        assert_eq!(engine_reloaded.split_pointer, engine.split_pointer);
+        assert_eq!(engine_reloaded.overflow_count, engine.overflow_count);
+        assert_eq!(engine_reloaded.free_overflow, engine.free_overflow);
+
+        for i in 0..50_u8 {
+            let retrieved = engine_reloaded
+                .handle_cmd(GetCommand(format!("key{}", i).into()).into())
+                .await
+                .unwrap();
+            assert_eq!(retrieved, CommandOutput::Found("v".repeat(400).into()));
+        }
+    }
+}
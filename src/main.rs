@@ -4,6 +4,10 @@ enum Mode {
     Repl,
     Stdin,
     Server,
+    Dump(Option<String>),
+    Restore(Option<String>),
+    Recover(Vec<String>),
+    Bench(Vec<String>),
 }
 
 #[tokio::main]
@@ -15,6 +19,10 @@ async fn main() {
         match arg as &str {
             "--repl" => mode = Mode::Repl,
             "--stdin" => mode = Mode::Stdin,
+            "--dump" => mode = Mode::Dump(args.get(2).cloned()),
+            "--restore" => mode = Mode::Restore(args.get(2).cloned()),
+            "--recover" => mode = Mode::Recover(args[2..].to_vec()),
+            "--bench" => mode = Mode::Bench(args[2..].to_vec()),
             _ => {}
         }
     }
@@ -23,5 +31,16 @@ async fn main() {
         Mode::Repl => run_repl().await,
         Mode::Stdin => process_from_stdin().await,
         Mode::Server => run_server().await,
+        Mode::Dump(path) => exit_on_error(run_dump(path.as_deref()).await),
+        Mode::Restore(path) => exit_on_error(run_restore(path.as_deref()).await),
+        Mode::Recover(args) => exit_on_error(run_recover(&args).await),
+        Mode::Bench(args) => exit_on_error(run_bench(&args).await),
+    }
+}
+
+fn exit_on_error(result: Result<(), String>) {
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,482 @@
+use crate::bytes::{ByteLength, IntoBytes, ParseFromBytes};
+use crate::command::{now_millis, Mutation, Timestamp};
+use crate::encryption::{BlockFile, EncryptionKey};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+
+/// Log sequence number, numbers the mutations written to the mutation log in order starting
+/// from 1
+pub(crate) type Lsn = u64;
+
+/// The name of the file in a backup holding the LSN of the first mutation the backup leaves out
+pub(crate) const BACKUP_LSN_FILE_NAME: &str = "backup_lsn";
+
+/// The file extension of segment files
+const SEGMENT_EXTENSION: &str = "mlog";
+
+/// Default size in bytes after which the mutation log moves on to a new segment
+pub const DEFAULT_MUTATION_LOG_SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The length in bytes of the LSN and timestamp in front of every entry
+const LOG_ENTRY_HEADER_BYTES: usize = size_of::<Lsn>() + size_of::<Timestamp>();
+
+/// The type used for the length of an entry in an encrypted segment
+type EntryLength = u32;
+
+/// The length in bytes of the length in front of every entry of an encrypted segment
+const ENTRY_LENGTH_BYTES: usize = size_of::<EntryLength>();
+
+/// Options for the mutation log, see `MutationLog`
+#[derive(Debug, Clone)]
+pub struct MutationLogOptions {
+    /// The directory containing the segments being written
+    pub directory: String,
+
+    /// The directory closed segments are moved to, when `None` they stay in `directory`
+    pub archive_directory: Option<String>,
+
+    /// Size in bytes after which the mutation log moves on to a new segment
+    pub segment_bytes: u64,
+}
+
+/// A mutation written to the mutation log
+///
+/// ## Binary layout
+/// - The LSN in LE
+/// - The time the mutation was written as a `Timestamp` in LE
+/// - The mutation, see `Mutation`
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LogEntry {
+    pub lsn: Lsn,
+    pub timestamp: Timestamp,
+    pub mutation: Mutation,
+}
+
+/// Reads the entries at the start of the bytes, stopping at the first one which is cut short
+///
+/// # Returns
+/// - The entries along with the number of bytes they take up
+fn parse_entries(bytes: &[u8]) -> (Vec<LogEntry>, usize) {
+    let mut entries = vec![];
+    let mut offset = 0;
+    while bytes.len() - offset >= LOG_ENTRY_HEADER_BYTES {
+        let header = &bytes[offset..offset + LOG_ENTRY_HEADER_BYTES];
+        let lsn = Lsn::from_le_bytes(header[..size_of::<Lsn>()].try_into().unwrap());
+        let timestamp = Timestamp::from_le_bytes(header[size_of::<Lsn>()..].try_into().unwrap());
+        let rest = &bytes[offset + LOG_ENTRY_HEADER_BYTES..];
+        let Ok((mutation, _)) = Mutation::from_bytes(rest.iter(), ()) else {
+            break;
+        };
+        offset += LOG_ENTRY_HEADER_BYTES + mutation.byte_len();
+        entries.push(LogEntry {
+            lsn,
+            timestamp,
+            mutation,
+        });
+    }
+    (entries, offset)
+}
+
+/// Reads the entries of the segment, stopping at the first one which is cut short
+///
+/// A plaintext segment holds the entries one after the other. In an encrypted segment every
+/// entry is a block of its own, with its length in a block in front of it, see `BlockFile`
+///
+/// # Returns
+/// - The entries along with the number of bytes they take up
+async fn read_segment(file: &mut BlockFile) -> (Vec<LogEntry>, u64) {
+    if !file.is_encrypted() {
+        let len = file.len().await as usize;
+        let bytes = file.read_block(0, len).await.unwrap().unwrap_or_default();
+        let (entries, len) = parse_entries(&bytes);
+        return (entries, len as u64);
+    }
+
+    let mut entries = vec![];
+    let mut position = 0;
+    loop {
+        let Ok(Some(len)) = file.read_block(position, ENTRY_LENGTH_BYTES).await else {
+            break;
+        };
+        let len = EntryLength::from_le_bytes(len.try_into().unwrap()) as usize;
+        let entry_position = position + file.block_len(ENTRY_LENGTH_BYTES) as u64;
+        let Ok(Some(bytes)) = file.read_block(entry_position, len).await else {
+            break;
+        };
+        let (mut parsed, parsed_len) = parse_entries(&bytes);
+        if parsed.len() != 1 || parsed_len != len {
+            break;
+        }
+        entries.append(&mut parsed);
+        position = entry_position + file.block_len(len) as u64;
+    }
+    (entries, position)
+}
+
+/// Opens the segment file, creating it if it does not exist, see `BlockFile::open`
+async fn open_segment(path: &Path, key: Option<&EncryptionKey>) -> Result<BlockFile, String> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    BlockFile::open(file.into(), key)
+        .await
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))
+}
+
+/// The name of the segment whose first entry has the LSN
+fn segment_name(first_lsn: Lsn) -> String {
+    format!("{:020}.{}", first_lsn, SEGMENT_EXTENSION)
+}
+
+/// Returns the first LSNs of the segment files in the directory, in order
+async fn list_segments(directory: &Path) -> Vec<Lsn> {
+    let mut segments = vec![];
+    let Ok(mut entries) = tokio::fs::read_dir(directory).await else {
+        return segments;
+    };
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        let path = entry.path();
+        if path.extension().and_then(|x| x.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(lsn) = path
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.parse::<Lsn>().ok())
+        {
+            segments.push(lsn);
+        }
+    }
+    segments.sort();
+    segments
+}
+
+/// Reads every entry of the segments in the directory, in LSN order, decrypting them with the
+/// key when they are encrypted
+pub(crate) async fn read_segments(
+    directory: &Path,
+    key: Option<&EncryptionKey>,
+) -> Result<Vec<LogEntry>, String> {
+    if !tokio::fs::try_exists(directory).await.unwrap_or(false) {
+        return Err(format!("{} does not exist", directory.display()));
+    }
+    let mut entries = vec![];
+    for segment in list_segments(directory).await {
+        let path = directory.join(segment_name(segment));
+        let len = tokio::fs::metadata(&path)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+            .len();
+        // An empty segment has nothing to read, and opening it with a key would write to it
+        if len == 0 {
+            continue;
+        }
+        let mut file = open_segment(&path, key).await?;
+        entries.extend(read_segment(&mut file).await.0);
+    }
+    Ok(entries)
+}
+
+/// Makes the mutation log in the directory carry on from the LSN
+///
+/// # Returns
+/// - An error if the log in the directory has already written past the LSN
+pub(crate) async fn start_log_at(
+    directory: &Path,
+    lsn: Lsn,
+    key: Option<&EncryptionKey>,
+) -> Result<(), String> {
+    let entries = read_segments(directory, key).await.unwrap_or_default();
+    if entries.last().is_some_and(|x| x.lsn >= lsn) {
+        return Err(format!(
+            "The mutation log in {} is already past LSN {}",
+            directory.display(),
+            lsn
+        ));
+    }
+    tokio::fs::create_dir_all(directory)
+        .await
+        .map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+    File::create(directory.join(segment_name(lsn)))
+        .await
+        .map_err(|e| format!("Failed to create the mutation log: {}", e))?;
+    Ok(())
+}
+
+/// Durable log of every mutation applied to the storage, used to recover the storage to a point
+/// in time on top of a backup, see `recovery::recover`
+///
+/// The log is split into segment files named `<first LSN>.mlog` inside a directory. Mutations
+/// are appended to the segment with the highest LSN, the head, and every append is synced
+/// before the mutation is applied. Once the head grows past the segment size it is closed and a
+/// new head is started. Closed segments are moved to the archive directory when there is one.
+///
+/// When the storage is encrypted the segments are encrypted with the same key, so the archive
+/// only ever holds encrypted segments, see `read_segment`.
+pub(crate) struct MutationLog {
+    /// The directory containing the segment files
+    directory: PathBuf,
+
+    /// The directory closed segments are moved to
+    archive: Option<PathBuf>,
+
+    /// Size in bytes after which a new head segment is started
+    segment_bytes: u64,
+
+    /// The LSN the head segment starts at, which names it
+    head: Lsn,
+
+    /// The head segment file
+    head_file: BlockFile,
+
+    /// The key segments are encrypted with
+    key: Option<EncryptionKey>,
+
+    /// The length in bytes of the head segment
+    head_len: u64,
+
+    /// The LSN of the next mutation appended
+    next_lsn: Lsn,
+}
+
+impl MutationLog {
+    /// Opens the mutation log, creating the directories if they do not exist
+    ///
+    /// An entry at the end of the head segment which was cut short by a crash is dropped.
+    ///
+    /// # Returns
+    /// - An error if the head segment is encrypted and the key is wrong or missing
+    pub async fn open(
+        options: &MutationLogOptions,
+        key: Option<&EncryptionKey>,
+    ) -> Result<Self, String> {
+        let directory = PathBuf::from(&options.directory);
+        tokio::fs::create_dir_all(&directory).await.unwrap();
+        let archive = options.archive_directory.as_ref().map(PathBuf::from);
+        if let Some(archive) = &archive {
+            tokio::fs::create_dir_all(archive).await.unwrap();
+        }
+
+        let head = list_segments(&directory).await.last().copied().unwrap_or(1);
+        let mut head_file = open_segment(&directory.join(segment_name(head)), key).await?;
+        let (entries, head_len) = read_segment(&mut head_file).await;
+        head_file.set_len(head_len).await;
+
+        Ok(Self {
+            directory,
+            archive,
+            segment_bytes: options.segment_bytes,
+            head,
+            head_file,
+            key: key.cloned(),
+            head_len,
+            next_lsn: entries.last().map_or(head, |x| x.lsn + 1),
+        })
+    }
+
+    /// The LSN of the next mutation appended
+    pub fn next_lsn(&self) -> Lsn {
+        self.next_lsn
+    }
+
+    /// Appends the mutation to the head segment and syncs it, starting a new head if the
+    /// current one is full
+    pub async fn append(&mut self, mutation: &Mutation) -> Lsn {
+        let lsn = self.next_lsn;
+        let mut buf = Vec::with_capacity(LOG_ENTRY_HEADER_BYTES + mutation.byte_len());
+        buf.extend(lsn.to_le_bytes());
+        buf.extend(now_millis().to_le_bytes());
+        buf.extend(mutation.clone().into_bytes());
+
+        let entry_len = match self.head_file.is_encrypted() {
+            true => {
+                self.head_file.block_len(ENTRY_LENGTH_BYTES) + self.head_file.block_len(buf.len())
+            }
+            false => buf.len(),
+        } as u64;
+        if self.head_len > 0 && self.head_len + entry_len > self.segment_bytes {
+            self.rotate().await;
+        }
+        let mut position = self.head_len;
+        if self.head_file.is_encrypted() {
+            let len: EntryLength = buf
+                .len()
+                .try_into()
+                .expect("Mutations are smaller than 4 GiB");
+            self.head_file
+                .write_block(position, &len.to_le_bytes())
+                .await;
+            position += self.head_file.block_len(ENTRY_LENGTH_BYTES) as u64;
+        }
+        self.head_file.write_block(position, &buf).await;
+        self.head_file.sync_all().await;
+        self.head_len += entry_len;
+        self.next_lsn += 1;
+        lsn
+    }
+
+    /// Closes the head segment, moving it to the archive, and starts a new one
+    async fn rotate(&mut self) {
+        let closed = self.head;
+        self.head = self.next_lsn;
+        self.head_len = 0;
+        let path = self.directory.join(segment_name(self.head));
+        File::create(&path).await.unwrap();
+        self.head_file = open_segment(&path, self.key.as_ref()).await.unwrap();
+
+        if self.archive.is_some() {
+            self.archive_segment(closed).await;
+            tokio::fs::remove_file(self.directory.join(segment_name(closed)))
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Copies the head segment as it is now to the archive, it is copied again once it is
+    /// closed
+    pub async fn archive_head(&mut self) {
+        if self.archive.is_some() {
+            self.archive_segment(self.head).await;
+        }
+    }
+
+    /// Copies the segment to the archive
+    async fn archive_segment(&self, segment: Lsn) {
+        let archive = self.archive.as_ref().unwrap();
+        let name = segment_name(segment);
+        // Written under another name first so the archive never holds part of a segment
+        let temp = archive.join(format!("{}.tmp", name));
+        tokio::fs::copy(self.directory.join(&name), &temp)
+            .await
+            .unwrap();
+        File::open(&temp).await.unwrap().sync_all().await.unwrap();
+        tokio::fs::rename(&temp, archive.join(&name)).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_mutation_log {
+    use super::*;
+    use crate::command::{DeleteCommand, PutCommand};
+    use crate::encryption::ENCRYPTION_KEY_BYTES;
+
+    fn options(name: &str, segment_bytes: u64) -> MutationLogOptions {
+        let directory = format!("./test_data/{}", name);
+        let archive = format!("./test_data/{}_archive", name);
+        let _ = std::fs::remove_dir_all(&directory);
+        let _ = std::fs::remove_dir_all(&archive);
+        MutationLogOptions {
+            directory,
+            archive_directory: Some(archive),
+            segment_bytes,
+        }
+    }
+
+    fn put(key: &str) -> Mutation {
+        Mutation::Put(PutCommand(key.into(), vec![1; 40], None))
+    }
+
+    #[tokio::test]
+    async fn append_and_reopen() {
+        let options = options("test_mutation_log_append_and_reopen", 1024);
+        let mut log = MutationLog::open(&options, None).await.unwrap();
+        assert_eq!(log.append(&put("a")).await, 1);
+        assert_eq!(log.append(&put("b")).await, 2);
+
+        // Cut the last entry short like a crash part way through a write would
+        let head = PathBuf::from(&options.directory).join(segment_name(1));
+        let len = std::fs::metadata(&head).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&head)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut log = MutationLog::open(&options, None).await.unwrap();
+        assert_eq!(log.next_lsn(), 2);
+        let delete = Mutation::Delete(DeleteCommand("a".into()));
+        assert_eq!(log.append(&delete).await, 2);
+
+        let entries = read_segments(Path::new(&options.directory), None)
+            .await
+            .unwrap();
+        let mutations: Vec<Mutation> = entries.into_iter().map(|x| x.mutation).collect();
+        assert_eq!(mutations, vec![put("a"), delete]);
+    }
+
+    #[tokio::test]
+    async fn encrypted_segments() {
+        let options = options("test_mutation_log_encrypted_segments", 300);
+        let key = EncryptionKey::from_bytes(&[3; ENCRYPTION_KEY_BYTES]).unwrap();
+        let mut log = MutationLog::open(&options, Some(&key)).await.unwrap();
+        for key_name in ["secret_a", "secret_b", "secret_c"] {
+            log.append(&put(key_name)).await;
+        }
+        log.archive_head().await;
+
+        let archive = PathBuf::from(options.archive_directory.as_ref().unwrap());
+        assert!(list_segments(&archive).await.len() > 1);
+        for directory in [Path::new(&options.directory), &archive] {
+            for segment in list_segments(directory).await {
+                let raw = std::fs::read(directory.join(segment_name(segment))).unwrap();
+                assert!(!raw.windows(6).any(|x| x == b"secret"));
+            }
+        }
+
+        let entries = read_segments(&archive, Some(&key)).await.unwrap();
+        let mutations: Vec<Mutation> = entries.into_iter().map(|x| x.mutation).collect();
+        assert_eq!(
+            mutations,
+            vec![put("secret_a"), put("secret_b"), put("secret_c")]
+        );
+        assert!(read_segments(&archive, None).await.is_err());
+
+        // Cut the last entry short like a crash part way through a write would
+        let head = list_segments(Path::new(&options.directory)).await[0];
+        let head = PathBuf::from(&options.directory).join(segment_name(head));
+        let len = std::fs::metadata(&head).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&head)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        assert!(MutationLog::open(&options, None).await.is_err());
+        let mut log = MutationLog::open(&options, Some(&key)).await.unwrap();
+        assert_eq!(log.append(&put("d")).await, 3);
+    }
+
+    #[tokio::test]
+    async fn archives_closed_segments() {
+        let options = options("test_mutation_log_archives_closed_segments", 100);
+        let mut log = MutationLog::open(&options, None).await.unwrap();
+        for key in ["a", "b", "c"] {
+            log.append(&put(key)).await;
+        }
+        let directory = Path::new(&options.directory);
+        let archive = PathBuf::from(options.archive_directory.as_ref().unwrap());
+        assert_eq!(list_segments(directory).await, vec![3]);
+        assert_eq!(list_segments(&archive).await, vec![1, 2]);
+
+        log.archive_head().await;
+        let entries = read_segments(&archive, None).await.unwrap();
+        let lsns: Vec<Lsn> = entries.iter().map(|x| x.lsn).collect();
+        assert_eq!(lsns, vec![1, 2, 3]);
+        assert!(entries.windows(2).all(|x| x[0].timestamp <= x[1].timestamp));
+
+        // Carries on from the head left in the directory
+        let mut log = MutationLog::open(&options, None).await.unwrap();
+        assert_eq!(log.append(&put("d")).await, 4);
+
+        assert!(start_log_at(directory, 4, None).await.is_err());
+        start_log_at(directory, 10, None).await.unwrap();
+        let mut log = MutationLog::open(&options, None).await.unwrap();
+        assert_eq!(log.append(&put("e")).await, 10);
+    }
+}
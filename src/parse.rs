@@ -1,4 +1,17 @@
-use crate::command::{DeleteCommand, GetCommand, PutCommand, UserCommand};
+use crate::bytes::decode_hex;
+use crate::command::{
+    now_millis, validate_key, validate_value, BackupCommand, DeleteCommand, ExpireCommand, GetCommand,
+    IncrCommand, KeysCommand, PutCommand, PutCondition, PutIfCommand, CasCommand, GetDelCommand,
+    GetSetCommand, MDeleteCommand, MGetCommand, MSetCommand, ExistsCommand, RenameCommand,
+    CopyCommand, ListPushCommand, ListPopCommand, ListRangeCommand, ListLenCommand, ListSide,
+    HashSetCommand, HashGetCommand, HashDeleteCommand, HashGetAllCommand, HashKeysCommand,
+    SortedSetAddCommand, SortedSetScoreCommand, SortedSetRangeCommand,
+    SortedSetRangeByScoreCommand, SortedSetRemoveCommand, SetAddCommand, SetRemoveCommand,
+    SetIsMemberCommand, SetMembersCommand, SetCardCommand, SetCombineCommand, SetOperation,
+    ScanCommand, Timestamp, TtlCommand, UserCommand, DEFAULT_SCAN_COUNT,
+};
+use crate::sorted_set::Score;
+use base64::prelude::*;
 
 pub fn parse_command(input: String) -> Result<UserCommand, String> {
     let tokens = Lexer::new(input).lex()?;
@@ -10,6 +23,51 @@ enum Keyword {
     Get,
     Put,
     Delete,
+    Keys,
+    Scan,
+    Backup,
+    Expire,
+    Ttl,
+    Persist,
+    Incr,
+    Decr,
+    IncrBy,
+    Cas,
+    GetDel,
+    GetSet,
+    MGet,
+    MSet,
+    MDelete,
+    Exists,
+    Rename,
+    Copy,
+    DbSize,
+    FlushDb,
+    LPush,
+    RPush,
+    LPop,
+    RPop,
+    LRange,
+    LLen,
+    HSet,
+    HGet,
+    HDel,
+    HGetAll,
+    HKeys,
+    ZAdd,
+    ZScore,
+    ZRange,
+    ZRangeByScore,
+    ZRem,
+    SAdd,
+    SRem,
+    SIsMember,
+    SMembers,
+    SCard,
+    SInter,
+    SUnion,
+    SDiff,
+    Stats,
     Exit,
     Begin,
     Rollback,
@@ -21,6 +79,8 @@ enum Token {
     Keyword(Keyword),
     Ident(String),
     Literal(String),
+    /// A literal written as `x"<hex>"` or `b64"<base64>"`
+    Bytes(Vec<u8>),
 }
 
 #[derive(Debug)]
@@ -45,7 +105,7 @@ impl Lexer {
         while let Some(c) = self.input.get(self.pos) {
             if c.is_whitespace() {
                 self.pos += 1;
-            } else if c.is_alphanumeric() {
+            } else if c.is_alphanumeric() || c == &'-' {
                 self.lex_alphanumeric()?;
             } else if c == &'"' {
                 self.lex_literal()?;
@@ -58,8 +118,13 @@ impl Lexer {
 
     fn lex_alphanumeric(&mut self) -> Result<(), String> {
         while let Some(c) = self.input.get(self.pos) {
+            if c == &'"' && matches!(self.buffer.as_str(), "x" | "X" | "b64" | "B64") {
+                return self.lex_bytes_literal();
+            }
             self.pos += 1;
-            if c.is_alphanumeric() {
+            // A leading minus sign allows negative numbers such as `INCRBY key -5`, and dots
+            // allow decimals such as `ZADD key 1.5 member`
+            if c.is_alphanumeric() || c == &'.' || (c == &'-' && self.buffer.is_empty()) {
                 self.buffer.push(*c);
             } else if c.is_whitespace() {
                 break;
@@ -78,6 +143,141 @@ impl Lexer {
             "DELETE" | "delete" => {
                 self.tokens.push(Token::Keyword(Keyword::Delete));
             }
+            "KEYS" | "keys" => {
+                self.tokens.push(Token::Keyword(Keyword::Keys));
+            }
+            "SCAN" | "scan" => {
+                self.tokens.push(Token::Keyword(Keyword::Scan));
+            }
+            "BACKUP" | "backup" => {
+                self.tokens.push(Token::Keyword(Keyword::Backup));
+            }
+            "EXPIRE" | "expire" => {
+                self.tokens.push(Token::Keyword(Keyword::Expire));
+            }
+            "TTL" | "ttl" => {
+                self.tokens.push(Token::Keyword(Keyword::Ttl));
+            }
+            "PERSIST" | "persist" => {
+                self.tokens.push(Token::Keyword(Keyword::Persist));
+            }
+            "INCR" | "incr" => {
+                self.tokens.push(Token::Keyword(Keyword::Incr));
+            }
+            "DECR" | "decr" => {
+                self.tokens.push(Token::Keyword(Keyword::Decr));
+            }
+            "INCRBY" | "incrby" => {
+                self.tokens.push(Token::Keyword(Keyword::IncrBy));
+            }
+            "CAS" | "cas" => {
+                self.tokens.push(Token::Keyword(Keyword::Cas));
+            }
+            "GETDEL" | "getdel" => {
+                self.tokens.push(Token::Keyword(Keyword::GetDel));
+            }
+            "GETSET" | "getset" => {
+                self.tokens.push(Token::Keyword(Keyword::GetSet));
+            }
+            "MGET" | "mget" => {
+                self.tokens.push(Token::Keyword(Keyword::MGet));
+            }
+            "MSET" | "mset" => {
+                self.tokens.push(Token::Keyword(Keyword::MSet));
+            }
+            "MDELETE" | "mdelete" => {
+                self.tokens.push(Token::Keyword(Keyword::MDelete));
+            }
+            "EXISTS" | "exists" => {
+                self.tokens.push(Token::Keyword(Keyword::Exists));
+            }
+            "RENAME" | "rename" => {
+                self.tokens.push(Token::Keyword(Keyword::Rename));
+            }
+            "COPY" | "copy" => {
+                self.tokens.push(Token::Keyword(Keyword::Copy));
+            }
+            "DBSIZE" | "dbsize" => {
+                self.tokens.push(Token::Keyword(Keyword::DbSize));
+            }
+            "FLUSHDB" | "flushdb" => {
+                self.tokens.push(Token::Keyword(Keyword::FlushDb));
+            }
+            "LPUSH" | "lpush" => {
+                self.tokens.push(Token::Keyword(Keyword::LPush));
+            }
+            "RPUSH" | "rpush" => {
+                self.tokens.push(Token::Keyword(Keyword::RPush));
+            }
+            "LPOP" | "lpop" => {
+                self.tokens.push(Token::Keyword(Keyword::LPop));
+            }
+            "RPOP" | "rpop" => {
+                self.tokens.push(Token::Keyword(Keyword::RPop));
+            }
+            "LRANGE" | "lrange" => {
+                self.tokens.push(Token::Keyword(Keyword::LRange));
+            }
+            "LLEN" | "llen" => {
+                self.tokens.push(Token::Keyword(Keyword::LLen));
+            }
+            "HSET" | "hset" => {
+                self.tokens.push(Token::Keyword(Keyword::HSet));
+            }
+            "HGET" | "hget" => {
+                self.tokens.push(Token::Keyword(Keyword::HGet));
+            }
+            "HDEL" | "hdel" => {
+                self.tokens.push(Token::Keyword(Keyword::HDel));
+            }
+            "HGETALL" | "hgetall" => {
+                self.tokens.push(Token::Keyword(Keyword::HGetAll));
+            }
+            "HKEYS" | "hkeys" => {
+                self.tokens.push(Token::Keyword(Keyword::HKeys));
+            }
+            "ZADD" | "zadd" => {
+                self.tokens.push(Token::Keyword(Keyword::ZAdd));
+            }
+            "ZSCORE" | "zscore" => {
+                self.tokens.push(Token::Keyword(Keyword::ZScore));
+            }
+            "ZRANGE" | "zrange" => {
+                self.tokens.push(Token::Keyword(Keyword::ZRange));
+            }
+            "ZRANGEBYSCORE" | "zrangebyscore" => {
+                self.tokens.push(Token::Keyword(Keyword::ZRangeByScore));
+            }
+            "ZREM" | "zrem" => {
+                self.tokens.push(Token::Keyword(Keyword::ZRem));
+            }
+            "SADD" | "sadd" => {
+                self.tokens.push(Token::Keyword(Keyword::SAdd));
+            }
+            "SREM" | "srem" => {
+                self.tokens.push(Token::Keyword(Keyword::SRem));
+            }
+            "SISMEMBER" | "sismember" => {
+                self.tokens.push(Token::Keyword(Keyword::SIsMember));
+            }
+            "SMEMBERS" | "smembers" => {
+                self.tokens.push(Token::Keyword(Keyword::SMembers));
+            }
+            "SCARD" | "scard" => {
+                self.tokens.push(Token::Keyword(Keyword::SCard));
+            }
+            "SINTER" | "sinter" => {
+                self.tokens.push(Token::Keyword(Keyword::SInter));
+            }
+            "SUNION" | "sunion" => {
+                self.tokens.push(Token::Keyword(Keyword::SUnion));
+            }
+            "SDIFF" | "sdiff" => {
+                self.tokens.push(Token::Keyword(Keyword::SDiff));
+            }
+            "STATS" | "stats" => {
+                self.tokens.push(Token::Keyword(Keyword::Stats));
+            }
             "EXIT" | "exit" => {
                 self.tokens.push(Token::Keyword(Keyword::Exit));
             }
@@ -138,6 +338,23 @@ impl Lexer {
 
         Err(format!("Unexpected end of input, {}", self.buffer))
     }
+
+    /// Lexes a literal prefixed with `x` for hex or `b64` for base64 into the bytes it holds
+    fn lex_bytes_literal(&mut self) -> Result<(), String> {
+        let prefix = std::mem::take(&mut self.buffer);
+        self.lex_literal()?;
+        let Some(Token::Literal(literal)) = self.tokens.pop() else {
+            unreachable!("lex_literal always pushes a literal");
+        };
+        let bytes = match prefix.as_str() {
+            "x" | "X" => decode_hex(&literal)?,
+            _ => BASE64_STANDARD
+                .decode(&literal)
+                .map_err(|e| format!("Invalid base64: {}", e))?,
+        };
+        self.tokens.push(Token::Bytes(bytes));
+        Ok(())
+    }
 }
 
 fn parse_tokens(mut tokens: impl Iterator<Item = Token>) -> Result<UserCommand, String> {
@@ -151,40 +368,191 @@ fn parse_tokens(mut tokens: impl Iterator<Item = Token>) -> Result<UserCommand,
             Keyword::Get => process_get_keyword(&mut tokens),
             Keyword::Put => process_put_keyword(&mut tokens),
             Keyword::Delete => process_delete_keyword(&mut tokens),
+            Keyword::Keys => process_keys_keyword(&mut tokens),
+            Keyword::Scan => process_scan_keyword(&mut tokens),
+            Keyword::Backup => process_backup_keyword(&mut tokens),
+            Keyword::Expire => process_expire_keyword(&mut tokens),
+            Keyword::Ttl => process_ttl_keyword(&mut tokens),
+            Keyword::Persist => process_persist_keyword(&mut tokens),
+            Keyword::Incr => process_incr_keyword(&mut tokens, "INCR", Some(1)),
+            Keyword::Decr => process_incr_keyword(&mut tokens, "DECR", Some(-1)),
+            Keyword::IncrBy => process_incr_keyword(&mut tokens, "INCRBY", None),
+            Keyword::Cas => process_cas_keyword(&mut tokens),
+            Keyword::GetDel => process_getdel_keyword(&mut tokens),
+            Keyword::GetSet => process_getset_keyword(&mut tokens),
+            Keyword::MGet => Ok(UserCommand::MGet(MGetCommand(parse_identifiers(
+                &mut tokens,
+                "MGET",
+            )?))),
+            Keyword::MSet => process_mset_keyword(&mut tokens),
+            Keyword::MDelete => Ok(UserCommand::MDelete(MDeleteCommand(parse_identifiers(
+                &mut tokens,
+                "MDELETE",
+            )?))),
+            Keyword::Exists => process_exists_keyword(&mut tokens),
+            Keyword::Rename => {
+                let (from, to) = parse_key_pair(&mut tokens, "RENAME")?;
+                Ok(UserCommand::Rename(RenameCommand(from, to)))
+            }
+            Keyword::Copy => {
+                let (from, to) = parse_key_pair(&mut tokens, "COPY")?;
+                Ok(UserCommand::Copy(CopyCommand(from, to)))
+            }
+            Keyword::DbSize => Ok(UserCommand::DbSize),
+            Keyword::FlushDb => Ok(UserCommand::FlushDb),
+            Keyword::LPush => process_push_keyword(&mut tokens, "LPUSH", ListSide::Left),
+            Keyword::RPush => process_push_keyword(&mut tokens, "RPUSH", ListSide::Right),
+            Keyword::LPop => process_pop_keyword(&mut tokens, "LPOP", ListSide::Left),
+            Keyword::RPop => process_pop_keyword(&mut tokens, "RPOP", ListSide::Right),
+            Keyword::LRange => process_lrange_keyword(&mut tokens),
+            Keyword::LLen => process_llen_keyword(&mut tokens),
+            Keyword::HSet => process_hset_keyword(&mut tokens),
+            Keyword::HGet => process_hget_keyword(&mut tokens),
+            Keyword::HDel => process_hdel_keyword(&mut tokens),
+            Keyword::HGetAll => {
+                let ident = parse_single_identifier(&mut tokens, "HGETALL")?;
+                Ok(UserCommand::HashGetAll(HashGetAllCommand(ident)))
+            }
+            Keyword::HKeys => {
+                let ident = parse_single_identifier(&mut tokens, "HKEYS")?;
+                Ok(UserCommand::HashKeys(HashKeysCommand(ident)))
+            }
+            Keyword::ZAdd => process_zadd_keyword(&mut tokens),
+            Keyword::ZScore => process_zscore_keyword(&mut tokens),
+            Keyword::ZRange => process_zrange_keyword(&mut tokens),
+            Keyword::ZRangeByScore => process_zrangebyscore_keyword(&mut tokens),
+            Keyword::ZRem => process_zrem_keyword(&mut tokens),
+            Keyword::SAdd => {
+                let ident = parse_identifier(&mut tokens, "SADD")?;
+                let members = parse_identifiers(&mut tokens, "identifier")?;
+                Ok(UserCommand::SetAdd(SetAddCommand(ident, members)))
+            }
+            Keyword::SRem => {
+                let ident = parse_identifier(&mut tokens, "SREM")?;
+                let members = parse_identifiers(&mut tokens, "identifier")?;
+                Ok(UserCommand::SetRemove(SetRemoveCommand(ident, members)))
+            }
+            Keyword::SIsMember => process_sismember_keyword(&mut tokens),
+            Keyword::SMembers => {
+                let ident = parse_single_identifier(&mut tokens, "SMEMBERS")?;
+                Ok(UserCommand::SetMembers(SetMembersCommand(ident)))
+            }
+            Keyword::SCard => {
+                let ident = parse_single_identifier(&mut tokens, "SCARD")?;
+                Ok(UserCommand::SetCard(SetCardCommand(ident)))
+            }
+            Keyword::SInter => Ok(UserCommand::SetCombine(SetCombineCommand(
+                SetOperation::Intersection,
+                parse_identifiers(&mut tokens, "SINTER")?,
+            ))),
+            Keyword::SUnion => Ok(UserCommand::SetCombine(SetCombineCommand(
+                SetOperation::Union,
+                parse_identifiers(&mut tokens, "SUNION")?,
+            ))),
+            Keyword::SDiff => Ok(UserCommand::SetCombine(SetCombineCommand(
+                SetOperation::Difference,
+                parse_identifiers(&mut tokens, "SDIFF")?,
+            ))),
+            Keyword::Stats => Ok(UserCommand::Stats),
             Keyword::Rollback => Ok(UserCommand::Rollback),
             Keyword::Commit => Ok(UserCommand::Commit),
             Keyword::Begin => Ok(UserCommand::Begin),
             Keyword::Exit => Ok(UserCommand::Exit),
         },
-        _ => Err("Expected keyword GET, PUT or DELETE".into()),
+        Token::Ident(ident) => Err(format!("Unknown command: {}", ident)),
+        _ => Err("Expected a command".into()),
     }
 }
 
+/// Parses a key, which is either an identifier or a hex or base64 literal
 fn parse_identifier(
     tokens: &mut impl Iterator<Item = Token>,
     keyword: &str,
-) -> Result<String, String> {
-    match tokens.next() {
-        Some(Token::Ident(ident)) => Ok(ident),
-        _ => Err(format!("Expected identifier after {}", keyword)),
+) -> Result<Vec<u8>, String> {
+    let key = match tokens.next() {
+        Some(Token::Ident(ident)) => ident.into_bytes(),
+        Some(Token::Bytes(bytes)) => bytes,
+        _ => return Err(format!("Expected identifier after {}", keyword)),
+    };
+    validate_key(&key)?;
+    Ok(key)
+}
+
+/// Parses one or more keys up to the end of the input
+fn parse_identifiers(
+    tokens: &mut impl Iterator<Item = Token>,
+    keyword: &str,
+) -> Result<Vec<Vec<u8>>, String> {
+    let mut tokens = tokens.peekable();
+    let mut keys = vec![parse_identifier(&mut tokens, keyword)?];
+    while tokens.peek().is_some() {
+        keys.push(parse_identifier(&mut tokens, "identifier")?);
     }
+    Ok(keys)
+}
+
+/// Parses a value, which is a string, hex or base64 literal
+fn parse_value(tokens: &mut impl Iterator<Item = Token>, after: &str) -> Result<Vec<u8>, String> {
+    let value = match tokens.next() {
+        Some(Token::Literal(literal)) => literal.into_bytes(),
+        Some(Token::Bytes(bytes)) => bytes,
+        _ => return Err(format!("Expected literal after {}", after)),
+    };
+    validate_value(&value)?;
+    Ok(value)
 }
 
+/// Parses the value of a put followed by the options `EX seconds` and one of `NX` or `XX`, in any
+/// order
 fn process_put_keyword_with_key(
-    ident: String,
+    ident: Vec<u8>,
     tokens: &mut impl Iterator<Item = Token>,
 ) -> Result<UserCommand, String> {
-    match tokens.next() {
-        Some(Token::Literal(literal)) => {
-            if tokens.next().is_some() {
-                return Err("Unexpected token after literal".to_string());
+    let value = parse_value(tokens, "identifier")?;
+    let mut expires_at = None;
+    let mut condition = None;
+    while let Some(token) = tokens.next() {
+        let Token::Ident(option) = token else {
+            return Err("Unexpected token after literal".to_string());
+        };
+        match option.as_str() {
+            "EX" | "ex" if expires_at.is_none() => {
+                let seconds = parse_seconds(tokens, "EX")?;
+                if seconds == 0 {
+                    return Err("Invalid expire time: 0".to_string());
+                }
+                expires_at = Some(self::expires_at(seconds));
             }
-            Ok(UserCommand::Put(PutCommand(ident, literal)))
+            "NX" | "nx" if condition.is_none() => condition = Some(PutCondition::Absent),
+            "XX" | "xx" if condition.is_none() => condition = Some(PutCondition::Present),
+            _ => return Err(format!("Unexpected option: {}", option)),
         }
-        _ => Err("Expected literal after identifier".to_string()),
+    }
+    let cmd = PutCommand(ident, value, expires_at);
+    match condition {
+        Some(condition) => Ok(UserCommand::PutIf(PutIfCommand(cmd, condition))),
+        None => Ok(UserCommand::Put(cmd)),
     }
 }
 
+/// Parses a number of seconds
+fn parse_seconds(tokens: &mut impl Iterator<Item = Token>, keyword: &str) -> Result<u64, String> {
+    match tokens.next() {
+        Some(Token::Ident(seconds)) => seconds
+            .parse()
+            .map_err(|_| format!("Invalid expire time: {}", seconds)),
+        _ => Err(format!("Expected seconds after {}", keyword)),
+    }
+}
+
+/// The time a key expires at when it expires in the number of seconds from now
+///
+/// The expiry is fixed when the command is parsed, so a command waiting in a transaction expires
+/// the key counting from when it was sent
+fn expires_at(seconds: u64) -> Timestamp {
+    now_millis().saturating_add(seconds.saturating_mul(1000))
+}
+
 fn process_put_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
     let ident = parse_identifier(tokens, "PUT")?;
     process_put_keyword_with_key(ident, tokens)
@@ -205,3 +573,829 @@ fn process_delete_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<Us
     }
     Ok(UserCommand::Delete(DeleteCommand(ident)))
 }
+
+/// Parses a glob pattern, which is an identifier or any literal
+fn parse_pattern(tokens: &mut impl Iterator<Item = Token>) -> Result<Vec<u8>, String> {
+    match tokens.next() {
+        Some(Token::Ident(ident)) => Ok(ident.into_bytes()),
+        Some(Token::Literal(literal)) => Ok(literal.into_bytes()),
+        Some(Token::Bytes(bytes)) => Ok(bytes),
+        _ => Err("Expected pattern".to_string()),
+    }
+}
+
+fn process_keys_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let mut tokens = tokens.peekable();
+    let pattern = match tokens.peek() {
+        Some(_) => Some(parse_pattern(&mut tokens)?),
+        None => None,
+    };
+    if tokens.next().is_some() {
+        return Err("Unexpected token after pattern".to_string());
+    }
+    Ok(UserCommand::Keys(KeysCommand(pattern)))
+}
+
+/// Parses `EXPIRE key seconds`, a key given 0 seconds expires straight away
+fn process_expire_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "EXPIRE")?;
+    let seconds = parse_seconds(tokens, "identifier")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after expire time".to_string());
+    }
+    Ok(UserCommand::Expire(ExpireCommand(ident, Some(expires_at(seconds)))))
+}
+
+fn process_persist_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "PERSIST")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after identifier".to_string());
+    }
+    Ok(UserCommand::Expire(ExpireCommand(ident, None)))
+}
+
+fn process_ttl_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "TTL")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after identifier".to_string());
+    }
+    Ok(UserCommand::Ttl(TtlCommand(ident)))
+}
+
+/// Parses `INCR key`, `DECR key` and `INCRBY key n`, the amount is read from the input if `by`
+/// is None
+fn process_incr_keyword(
+    tokens: &mut impl Iterator<Item = Token>,
+    keyword: &str,
+    by: Option<i64>,
+) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, keyword)?;
+    let by = match by {
+        Some(by) => by,
+        None => match tokens.next() {
+            Some(Token::Ident(by)) => by
+                .parse()
+                .map_err(|_| format!("Invalid increment: {}", by))?,
+            _ => return Err(format!("Expected increment after {}", keyword)),
+        },
+    };
+    if tokens.next().is_some() {
+        return Err("Unexpected token after identifier".to_string());
+    }
+    Ok(UserCommand::Incr(IncrCommand(ident, by)))
+}
+
+/// Parses `MSET k1 "v1" k2 "v2" ...` with at least one pair
+fn process_mset_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let mut tokens = tokens.peekable();
+    let mut puts = vec![];
+    let mut after = "MSET";
+    loop {
+        let ident = parse_identifier(&mut tokens, after)?;
+        let value = parse_value(&mut tokens, "identifier")?;
+        puts.push(PutCommand(ident, value, None));
+        if tokens.peek().is_none() {
+            return Ok(UserCommand::MSet(MSetCommand(puts)));
+        }
+        after = "literal";
+    }
+}
+
+fn process_exists_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "EXISTS")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after identifier".to_string());
+    }
+    Ok(UserCommand::Exists(ExistsCommand(ident)))
+}
+
+/// Parses the source and destination keys of `RENAME` and `COPY`
+fn parse_key_pair(
+    tokens: &mut impl Iterator<Item = Token>,
+    keyword: &str,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let from = parse_identifier(tokens, keyword)?;
+    let to = parse_identifier(tokens, "identifier")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after identifier".to_string());
+    }
+    Ok((from, to))
+}
+
+/// Parses `LPUSH key "v1" "v2" ...` and `RPUSH` with at least one value
+fn process_push_keyword(
+    tokens: &mut impl Iterator<Item = Token>,
+    keyword: &str,
+    side: ListSide,
+) -> Result<UserCommand, String> {
+    let mut tokens = tokens.peekable();
+    let ident = parse_identifier(&mut tokens, keyword)?;
+    let mut values = vec![parse_value(&mut tokens, "identifier")?];
+    while tokens.peek().is_some() {
+        values.push(parse_value(&mut tokens, "literal")?);
+    }
+    Ok(UserCommand::ListPush(ListPushCommand(ident, side, values)))
+}
+
+/// Parses `LPOP key` and `RPOP key`
+fn process_pop_keyword(
+    tokens: &mut impl Iterator<Item = Token>,
+    keyword: &str,
+    side: ListSide,
+) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, keyword)?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after identifier".to_string());
+    }
+    Ok(UserCommand::ListPop(ListPopCommand(ident, side)))
+}
+
+/// Parses an index into a list, which can be negative to count back from the tail
+fn parse_index(tokens: &mut impl Iterator<Item = Token>, after: &str) -> Result<i64, String> {
+    match tokens.next() {
+        Some(Token::Ident(index)) => index
+            .parse()
+            .map_err(|_| format!("Invalid index: {}", index)),
+        _ => Err(format!("Expected index after {}", after)),
+    }
+}
+
+/// Parses `LRANGE key start stop`
+fn process_lrange_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "LRANGE")?;
+    let start = parse_index(tokens, "identifier")?;
+    let stop = parse_index(tokens, "start")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after stop".to_string());
+    }
+    Ok(UserCommand::ListRange(ListRangeCommand(ident, start, stop)))
+}
+
+fn process_llen_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "LLEN")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after identifier".to_string());
+    }
+    Ok(UserCommand::ListLen(ListLenCommand(ident)))
+}
+
+/// Parses a key which ends the input
+fn parse_single_identifier(
+    tokens: &mut impl Iterator<Item = Token>,
+    keyword: &str,
+) -> Result<Vec<u8>, String> {
+    let ident = parse_identifier(tokens, keyword)?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after identifier".to_string());
+    }
+    Ok(ident)
+}
+
+/// Parses `HSET key field "value" field2 "value2" ...` with at least one field
+fn process_hset_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let mut tokens = tokens.peekable();
+    let ident = parse_identifier(&mut tokens, "HSET")?;
+    let mut fields = vec![];
+    let mut after = "identifier";
+    loop {
+        let field = parse_identifier(&mut tokens, after)?;
+        let value = parse_value(&mut tokens, "field")?;
+        fields.push((field, value));
+        if tokens.peek().is_none() {
+            return Ok(UserCommand::HashSet(HashSetCommand(ident, fields)));
+        }
+        after = "literal";
+    }
+}
+
+/// Parses `HGET key field`
+fn process_hget_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "HGET")?;
+    let field = parse_identifier(tokens, "identifier")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after field".to_string());
+    }
+    Ok(UserCommand::HashGet(HashGetCommand(ident, field)))
+}
+
+/// Parses `HDEL key field field2 ...` with at least one field
+fn process_hdel_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "HDEL")?;
+    let fields = parse_identifiers(tokens, "identifier")?;
+    Ok(UserCommand::HashDelete(HashDeleteCommand(ident, fields)))
+}
+
+/// Parses the score of a member of a sorted set, which has to be a finite number
+fn parse_score(tokens: &mut impl Iterator<Item = Token>, after: &str) -> Result<Score, String> {
+    match tokens.next() {
+        Some(Token::Ident(score)) => score
+            .parse()
+            .ok()
+            .and_then(Score::new)
+            .ok_or_else(|| format!("Invalid score: {}", score)),
+        _ => Err(format!("Expected score after {}", after)),
+    }
+}
+
+/// Parses a bound of a range of scores, which can also be `inf` or `-inf`
+fn parse_score_bound(tokens: &mut impl Iterator<Item = Token>, after: &str) -> Result<f64, String> {
+    match tokens.next() {
+        Some(Token::Ident(bound)) => bound
+            .parse::<f64>()
+            .ok()
+            .filter(|x| !x.is_nan())
+            .ok_or_else(|| format!("Invalid score: {}", bound)),
+        _ => Err(format!("Expected score after {}", after)),
+    }
+}
+
+/// Parses `ZADD key score member score2 member2 ...` with at least one member
+fn process_zadd_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let mut tokens = tokens.peekable();
+    let ident = parse_identifier(&mut tokens, "ZADD")?;
+    let mut members = vec![];
+    let mut after = "identifier";
+    loop {
+        let score = parse_score(&mut tokens, after)?;
+        let member = parse_identifier(&mut tokens, "score")?;
+        members.push((score, member));
+        if tokens.peek().is_none() {
+            return Ok(UserCommand::SortedSetAdd(SortedSetAddCommand(ident, members)));
+        }
+        after = "member";
+    }
+}
+
+/// Parses `ZSCORE key member`
+fn process_zscore_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "ZSCORE")?;
+    let member = parse_identifier(tokens, "identifier")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after member".to_string());
+    }
+    Ok(UserCommand::SortedSetScore(SortedSetScoreCommand(ident, member)))
+}
+
+/// Parses `ZRANGE key start stop`
+fn process_zrange_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "ZRANGE")?;
+    let start = parse_index(tokens, "identifier")?;
+    let stop = parse_index(tokens, "start")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after stop".to_string());
+    }
+    Ok(UserCommand::SortedSetRange(SortedSetRangeCommand(ident, start, stop)))
+}
+
+/// Parses `ZRANGEBYSCORE key min max`
+fn process_zrangebyscore_keyword(
+    tokens: &mut impl Iterator<Item = Token>,
+) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "ZRANGEBYSCORE")?;
+    let min = parse_score_bound(tokens, "identifier")?;
+    let max = parse_score_bound(tokens, "min")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after max".to_string());
+    }
+    Ok(UserCommand::SortedSetRangeByScore(SortedSetRangeByScoreCommand(
+        ident, min, max,
+    )))
+}
+
+/// Parses `ZREM key member member2 ...` with at least one member
+fn process_zrem_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "ZREM")?;
+    let members = parse_identifiers(tokens, "identifier")?;
+    Ok(UserCommand::SortedSetRemove(SortedSetRemoveCommand(ident, members)))
+}
+
+/// Parses `SISMEMBER key member`
+fn process_sismember_keyword(
+    tokens: &mut impl Iterator<Item = Token>,
+) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "SISMEMBER")?;
+    let member = parse_identifier(tokens, "identifier")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after member".to_string());
+    }
+    Ok(UserCommand::SetIsMember(SetIsMemberCommand(ident, member)))
+}
+
+/// Parses `CAS key "expected" "new"`
+fn process_cas_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "CAS")?;
+    let expected = parse_value(tokens, "identifier")?;
+    let value = parse_value(tokens, "expected value")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after literal".to_string());
+    }
+    Ok(UserCommand::Cas(CasCommand(ident, expected, value)))
+}
+
+fn process_getdel_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "GETDEL")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after identifier".to_string());
+    }
+    Ok(UserCommand::GetDel(GetDelCommand(ident)))
+}
+
+fn process_getset_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let ident = parse_identifier(tokens, "GETSET")?;
+    let value = parse_value(tokens, "identifier")?;
+    if tokens.next().is_some() {
+        return Err("Unexpected token after literal".to_string());
+    }
+    Ok(UserCommand::GetSet(GetSetCommand(ident, value)))
+}
+
+fn process_backup_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let path = match tokens.next() {
+        Some(Token::Ident(ident)) => ident,
+        Some(Token::Literal(literal)) => literal,
+        _ => return Err("Expected path after BACKUP".to_string()),
+    };
+    if tokens.next().is_some() {
+        return Err("Unexpected token after path".to_string());
+    }
+    Ok(UserCommand::Backup(BackupCommand(path)))
+}
+
+/// Parses `SCAN cursor [MATCH pattern] [COUNT count]`
+fn process_scan_keyword(tokens: &mut impl Iterator<Item = Token>) -> Result<UserCommand, String> {
+    let cursor = match tokens.next() {
+        Some(Token::Ident(ident)) => ident
+            .parse()
+            .map_err(|_| format!("Invalid cursor: {}", ident))?,
+        _ => return Err("Expected cursor after SCAN".to_string()),
+    };
+    let mut cmd = ScanCommand {
+        cursor,
+        pattern: None,
+        count: DEFAULT_SCAN_COUNT,
+    };
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Ident(option) if matches!(option.as_str(), "MATCH" | "match") => {
+                cmd.pattern = Some(parse_pattern(tokens)?);
+            }
+            Token::Ident(option) if matches!(option.as_str(), "COUNT" | "count") => {
+                cmd.count = match tokens.next() {
+                    Some(Token::Ident(count)) => count
+                        .parse()
+                        .ok()
+                        .filter(|x| *x > 0)
+                        .ok_or_else(|| format!("Invalid count: {}", count))?,
+                    _ => return Err("Expected count after COUNT".to_string()),
+                };
+            }
+            _ => return Err("Expected MATCH or COUNT".to_string()),
+        }
+    }
+    Ok(UserCommand::Scan(cmd))
+}
+
+#[cfg(test)]
+mod test_parse {
+    use super::*;
+    use crate::command::MAX_KEY_BYTES;
+
+    #[test]
+    fn parse_unknown_command() {
+        assert_eq!(
+            parse_command("FETCH key".into()),
+            Err("Unknown command: FETCH".into())
+        );
+        assert_eq!(
+            parse_command("\"key\"".into()),
+            Err("Expected a command".into())
+        );
+    }
+
+    #[test]
+    fn parse_string_literal() {
+        assert_eq!(
+            parse_command("PUT key \"value\"".into()),
+            Ok(UserCommand::Put(PutCommand(b"key".to_vec(), b"value".to_vec(), None)))
+        );
+    }
+
+    #[test]
+    fn parse_bytes_literals() {
+        assert_eq!(
+            parse_command("PUT x\"00ff\" b64\"AAEC/w==\"".into()),
+            Ok(UserCommand::Put(PutCommand(vec![0, 255], vec![0, 1, 2, 255], None)))
+        );
+        assert_eq!(
+            parse_command("GET X\"00FF\"".into()),
+            Ok(UserCommand::Get(GetCommand(vec![0, 255])))
+        );
+        assert_eq!(
+            parse_command("DELETE B64\"AP8=\"".into()),
+            Ok(UserCommand::Delete(DeleteCommand(vec![0, 255])))
+        );
+        assert!(parse_command("PUT key x\"0\"".into()).is_err());
+        assert!(parse_command("PUT key b64\"!\"".into()).is_err());
+        assert!(parse_command("PUT key y\"00\"".into()).is_err());
+    }
+
+    #[test]
+    fn rejects_long_keys() {
+        let key = "k".repeat(MAX_KEY_BYTES);
+        assert!(parse_command(format!("GET {}", key)).is_ok());
+        assert_eq!(
+            parse_command(format!("GET {}k", key)),
+            Err(format!(
+                "Key is {} bytes, the limit is {} bytes",
+                MAX_KEY_BYTES + 1,
+                MAX_KEY_BYTES
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_patterns() {
+        assert_eq!(
+            parse_command("KEYS".into()),
+            Ok(UserCommand::Keys(KeysCommand(None)))
+        );
+        assert_eq!(
+            parse_command("keys \"user:*\"".into()),
+            Ok(UserCommand::Keys(KeysCommand(Some(b"user:*".to_vec()))))
+        );
+        assert!(parse_command("KEYS \"a\" \"b\"".into()).is_err());
+    }
+
+    #[test]
+    fn parse_expiry() {
+        let before = now_millis();
+        let Ok(UserCommand::Put(PutCommand(key, value, Some(expires_at)))) =
+            parse_command("PUT key \"value\" EX 60".into())
+        else {
+            panic!("Expected a put with an expiry");
+        };
+        assert_eq!((key, value), (b"key".to_vec(), b"value".to_vec()));
+        assert!(expires_at >= before + 60_000 && expires_at <= now_millis() + 60_000);
+
+        let Ok(UserCommand::Expire(ExpireCommand(key, Some(expires_at)))) =
+            parse_command("expire key 10".into())
+        else {
+            panic!("Expected an expire");
+        };
+        assert_eq!(key, b"key".to_vec());
+        assert!(expires_at >= before + 10_000);
+
+        assert_eq!(
+            parse_command("PERSIST key".into()),
+            Ok(UserCommand::Expire(ExpireCommand(b"key".to_vec(), None)))
+        );
+        assert_eq!(
+            parse_command("TTL key".into()),
+            Ok(UserCommand::Ttl(TtlCommand(b"key".to_vec())))
+        );
+        assert!(parse_command("PUT key \"value\" EX 0".into()).is_err());
+        assert!(parse_command("PUT key \"value\" EX".into()).is_err());
+        assert!(parse_command("PUT key \"value\" EX 10 EX 10".into()).is_err());
+        assert!(parse_command("PUT key \"value\" PX 10".into()).is_err());
+        assert!(parse_command("EXPIRE key -1".into()).is_err());
+        assert!(parse_command("EXPIRE key".into()).is_err());
+        assert!(parse_command("TTL".into()).is_err());
+    }
+
+    #[test]
+    fn parse_counters() {
+        assert_eq!(
+            parse_command("INCR key".into()),
+            Ok(UserCommand::Incr(IncrCommand(b"key".to_vec(), 1)))
+        );
+        assert_eq!(
+            parse_command("decr key".into()),
+            Ok(UserCommand::Incr(IncrCommand(b"key".to_vec(), -1)))
+        );
+        assert_eq!(
+            parse_command("INCRBY key -5".into()),
+            Ok(UserCommand::Incr(IncrCommand(b"key".to_vec(), -5)))
+        );
+        assert_eq!(
+            parse_command("INCRBY key 10".into()),
+            Ok(UserCommand::Incr(IncrCommand(b"key".to_vec(), 10)))
+        );
+        assert!(parse_command("INCR".into()).is_err());
+        assert!(parse_command("INCR key 1".into()).is_err());
+        assert!(parse_command("INCRBY key".into()).is_err());
+        assert!(parse_command("INCRBY key 1-".into()).is_err());
+        assert!(parse_command("INCRBY key 1.5".into()).is_err());
+        assert!(parse_command("INCRBY key 99999999999999999999".into()).is_err());
+    }
+
+    #[test]
+    fn parse_conditional_puts() {
+        let put = |expires_at| PutCommand(b"key".to_vec(), b"v".to_vec(), expires_at);
+        assert_eq!(
+            parse_command("PUT key \"v\" NX".into()),
+            Ok(UserCommand::PutIf(PutIfCommand(put(None), PutCondition::Absent)))
+        );
+        assert_eq!(
+            parse_command("put key \"v\" xx".into()),
+            Ok(UserCommand::PutIf(PutIfCommand(put(None), PutCondition::Present)))
+        );
+        let Ok(UserCommand::PutIf(PutIfCommand(PutCommand(_, _, Some(_)), PutCondition::Absent))) =
+            parse_command("PUT key \"v\" NX EX 10".into())
+        else {
+            panic!("Expected a conditional put with an expiry");
+        };
+        assert!(parse_command("PUT key \"v\" EX 10 XX".into()).is_ok());
+        assert!(parse_command("PUT key \"v\" NX XX".into()).is_err());
+        assert!(parse_command("PUT key \"v\" NX NX".into()).is_err());
+        assert!(parse_command("PUT key \"v\" NX \"w\"".into()).is_err());
+
+        assert_eq!(
+            parse_command("CAS key \"old\" x\"00\"".into()),
+            Ok(UserCommand::Cas(CasCommand(
+                b"key".to_vec(),
+                b"old".to_vec(),
+                vec![0]
+            )))
+        );
+        assert!(parse_command("CAS key \"old\"".into()).is_err());
+        assert_eq!(
+            parse_command("GETDEL key".into()),
+            Ok(UserCommand::GetDel(GetDelCommand(b"key".to_vec())))
+        );
+        assert_eq!(
+            parse_command("getset key \"v\"".into()),
+            Ok(UserCommand::GetSet(GetSetCommand(b"key".to_vec(), b"v".to_vec())))
+        );
+        assert!(parse_command("GETSET key".into()).is_err());
+        assert!(parse_command("GETDEL key key".into()).is_err());
+    }
+
+    #[test]
+    fn parse_batches() {
+        assert_eq!(
+            parse_command("MGET a x\"62\" c".into()),
+            Ok(UserCommand::MGet(MGetCommand(vec![
+                b"a".to_vec(),
+                b"b".to_vec(),
+                b"c".to_vec()
+            ])))
+        );
+        assert_eq!(
+            parse_command("MDELETE a".into()),
+            Ok(UserCommand::MDelete(MDeleteCommand(vec![b"a".to_vec()])))
+        );
+        assert_eq!(
+            parse_command("MSET a \"1\" b \"2\"".into()),
+            Ok(UserCommand::MSet(MSetCommand(vec![
+                PutCommand(b"a".to_vec(), b"1".to_vec(), None),
+                PutCommand(b"b".to_vec(), b"2".to_vec(), None),
+            ])))
+        );
+        assert!(parse_command("MGET".into()).is_err());
+        assert!(parse_command("MGET a \"b\"".into()).is_err());
+        assert!(parse_command("MDELETE".into()).is_err());
+        assert!(parse_command("MSET".into()).is_err());
+        assert!(parse_command("MSET a".into()).is_err());
+        assert!(parse_command("MSET a \"1\" b".into()).is_err());
+        assert!(parse_command("MSET a \"1\" \"2\"".into()).is_err());
+    }
+
+    #[test]
+    fn parse_key_management() {
+        assert_eq!(
+            parse_command("EXISTS key".into()),
+            Ok(UserCommand::Exists(ExistsCommand(b"key".to_vec())))
+        );
+        assert_eq!(
+            parse_command("RENAME a b".into()),
+            Ok(UserCommand::Rename(RenameCommand(b"a".to_vec(), b"b".to_vec())))
+        );
+        assert_eq!(
+            parse_command("copy a x\"62\"".into()),
+            Ok(UserCommand::Copy(CopyCommand(b"a".to_vec(), b"b".to_vec())))
+        );
+        assert_eq!(parse_command("DBSIZE".into()), Ok(UserCommand::DbSize));
+        assert_eq!(parse_command("flushdb".into()), Ok(UserCommand::FlushDb));
+        assert!(parse_command("EXISTS".into()).is_err());
+        assert!(parse_command("RENAME a".into()).is_err());
+        assert!(parse_command("COPY a b c".into()).is_err());
+        assert!(parse_command("RENAME a \"b\"".into()).is_err());
+    }
+
+    #[test]
+    fn parse_lists() {
+        assert_eq!(
+            parse_command("LPUSH list \"a\" x\"62\"".into()),
+            Ok(UserCommand::ListPush(ListPushCommand(
+                b"list".to_vec(),
+                ListSide::Left,
+                vec![b"a".to_vec(), b"b".to_vec()],
+            )))
+        );
+        assert_eq!(
+            parse_command("rpush list \"a\"".into()),
+            Ok(UserCommand::ListPush(ListPushCommand(
+                b"list".to_vec(),
+                ListSide::Right,
+                vec![b"a".to_vec()],
+            )))
+        );
+        assert_eq!(
+            parse_command("LPOP list".into()),
+            Ok(UserCommand::ListPop(ListPopCommand(b"list".to_vec(), ListSide::Left)))
+        );
+        assert_eq!(
+            parse_command("RPOP list".into()),
+            Ok(UserCommand::ListPop(ListPopCommand(b"list".to_vec(), ListSide::Right)))
+        );
+        assert_eq!(
+            parse_command("LRANGE list 0 -1".into()),
+            Ok(UserCommand::ListRange(ListRangeCommand(b"list".to_vec(), 0, -1)))
+        );
+        assert_eq!(
+            parse_command("llen list".into()),
+            Ok(UserCommand::ListLen(ListLenCommand(b"list".to_vec())))
+        );
+        assert!(parse_command("LPUSH list".into()).is_err());
+        assert!(parse_command("LPUSH list a".into()).is_err());
+        assert!(parse_command("LPOP list \"a\"".into()).is_err());
+        assert!(parse_command("LRANGE list 0".into()).is_err());
+        assert!(parse_command("LRANGE list 0 end".into()).is_err());
+        assert!(parse_command("LRANGE list 0 1 2".into()).is_err());
+        assert!(parse_command("LLEN".into()).is_err());
+    }
+
+    #[test]
+    fn parse_hashes() {
+        assert_eq!(
+            parse_command("HSET user name \"Ann\" age x\"3432\"".into()),
+            Ok(UserCommand::HashSet(HashSetCommand(
+                b"user".to_vec(),
+                vec![
+                    (b"name".to_vec(), b"Ann".to_vec()),
+                    (b"age".to_vec(), b"42".to_vec()),
+                ],
+            )))
+        );
+        assert_eq!(
+            parse_command("hget user name".into()),
+            Ok(UserCommand::HashGet(HashGetCommand(
+                b"user".to_vec(),
+                b"name".to_vec()
+            )))
+        );
+        assert_eq!(
+            parse_command("HDEL user name age".into()),
+            Ok(UserCommand::HashDelete(HashDeleteCommand(
+                b"user".to_vec(),
+                vec![b"name".to_vec(), b"age".to_vec()],
+            )))
+        );
+        assert_eq!(
+            parse_command("HGETALL user".into()),
+            Ok(UserCommand::HashGetAll(HashGetAllCommand(b"user".to_vec())))
+        );
+        assert_eq!(
+            parse_command("HKEYS user".into()),
+            Ok(UserCommand::HashKeys(HashKeysCommand(b"user".to_vec())))
+        );
+        assert!(parse_command("HSET user".into()).is_err());
+        assert!(parse_command("HSET user name".into()).is_err());
+        assert!(parse_command("HSET user name \"Ann\" age".into()).is_err());
+        assert!(parse_command("HGET user".into()).is_err());
+        assert!(parse_command("HGET user name age".into()).is_err());
+        assert!(parse_command("HDEL user".into()).is_err());
+        assert!(parse_command("HKEYS user name".into()).is_err());
+    }
+
+    #[test]
+    fn parse_sorted_sets() {
+        let score = |x| Score::new(x).unwrap();
+        assert_eq!(
+            parse_command("ZADD board 1.5 ann -2 bob".into()),
+            Ok(UserCommand::SortedSetAdd(SortedSetAddCommand(
+                b"board".to_vec(),
+                vec![(score(1.5), b"ann".to_vec()), (score(-2.0), b"bob".to_vec())],
+            )))
+        );
+        assert_eq!(
+            parse_command("zscore board ann".into()),
+            Ok(UserCommand::SortedSetScore(SortedSetScoreCommand(
+                b"board".to_vec(),
+                b"ann".to_vec()
+            )))
+        );
+        assert_eq!(
+            parse_command("ZRANGE board 0 -1".into()),
+            Ok(UserCommand::SortedSetRange(SortedSetRangeCommand(
+                b"board".to_vec(),
+                0,
+                -1
+            )))
+        );
+        assert_eq!(
+            parse_command("ZRANGEBYSCORE board -inf 2.5".into()),
+            Ok(UserCommand::SortedSetRangeByScore(
+                SortedSetRangeByScoreCommand(b"board".to_vec(), f64::NEG_INFINITY, 2.5)
+            ))
+        );
+        assert_eq!(
+            parse_command("ZREM board ann bob".into()),
+            Ok(UserCommand::SortedSetRemove(SortedSetRemoveCommand(
+                b"board".to_vec(),
+                vec![b"ann".to_vec(), b"bob".to_vec()],
+            )))
+        );
+        assert!(parse_command("ZADD board ann".into()).is_err());
+        assert!(parse_command("ZADD board 1".into()).is_err());
+        assert!(parse_command("ZADD board inf ann".into()).is_err());
+        assert!(parse_command("ZADD board nan ann".into()).is_err());
+        assert!(parse_command("ZADD board 1 ann 2".into()).is_err());
+        assert!(parse_command("ZRANGEBYSCORE board nan 1".into()).is_err());
+        assert!(parse_command("ZRANGEBYSCORE board 1".into()).is_err());
+        assert!(parse_command("ZRANGE board 0 1.5".into()).is_err());
+        assert!(parse_command("ZREM board".into()).is_err());
+    }
+
+    #[test]
+    fn parse_sets() {
+        assert_eq!(
+            parse_command("SADD tags red x\"00ff\"".into()),
+            Ok(UserCommand::SetAdd(SetAddCommand(
+                b"tags".to_vec(),
+                vec![b"red".to_vec(), vec![0, 255]],
+            )))
+        );
+        assert_eq!(
+            parse_command("srem tags red blue".into()),
+            Ok(UserCommand::SetRemove(SetRemoveCommand(
+                b"tags".to_vec(),
+                vec![b"red".to_vec(), b"blue".to_vec()],
+            )))
+        );
+        assert_eq!(
+            parse_command("SISMEMBER tags red".into()),
+            Ok(UserCommand::SetIsMember(SetIsMemberCommand(
+                b"tags".to_vec(),
+                b"red".to_vec()
+            )))
+        );
+        assert_eq!(
+            parse_command("SMEMBERS tags".into()),
+            Ok(UserCommand::SetMembers(SetMembersCommand(b"tags".to_vec())))
+        );
+        assert_eq!(
+            parse_command("SCARD tags".into()),
+            Ok(UserCommand::SetCard(SetCardCommand(b"tags".to_vec())))
+        );
+        for (input, operation) in [
+            ("SINTER a b", SetOperation::Intersection),
+            ("SUNION a b", SetOperation::Union),
+            ("sdiff a b", SetOperation::Difference),
+        ] {
+            assert_eq!(
+                parse_command(input.into()),
+                Ok(UserCommand::SetCombine(SetCombineCommand(
+                    operation,
+                    vec![b"a".to_vec(), b"b".to_vec()],
+                )))
+            );
+        }
+        assert!(parse_command("SADD tags".into()).is_err());
+        assert!(parse_command("SISMEMBER tags".into()).is_err());
+        assert!(parse_command("SISMEMBER tags a b".into()).is_err());
+        assert!(parse_command("SMEMBERS tags other".into()).is_err());
+        assert!(parse_command("SINTER".into()).is_err());
+    }
+
+    #[test]
+    fn parse_stats() {
+        assert_eq!(parse_command("STATS".into()), Ok(UserCommand::Stats));
+        assert_eq!(parse_command("stats".into()), Ok(UserCommand::Stats));
+    }
+
+    #[test]
+    fn parse_scan() {
+        assert_eq!(
+            parse_command("SCAN 0".into()),
+            Ok(UserCommand::Scan(ScanCommand {
+                cursor: 0,
+                pattern: None,
+                count: DEFAULT_SCAN_COUNT,
+            }))
+        );
+        assert_eq!(
+            parse_command("scan 12 count 100 match \"user:*\"".into()),
+            Ok(UserCommand::Scan(ScanCommand {
+                cursor: 12,
+                pattern: Some(b"user:*".to_vec()),
+                count: 100,
+            }))
+        );
+        assert!(parse_command("SCAN".into()).is_err());
+        assert!(parse_command("SCAN abc".into()).is_err());
+        assert!(parse_command("SCAN 0 COUNT 0".into()).is_err());
+        assert!(parse_command("SCAN 0 MATCH".into()).is_err());
+        assert!(parse_command("SCAN 0 LIMIT 5".into()).is_err());
+    }
+}
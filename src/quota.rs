@@ -0,0 +1,193 @@
+use crate::command::now_millis;
+use crate::hash_storage::Hash;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// The number of buckets sampled for a key to evict, see `HashStorage::evict_one`
+pub(crate) const EVICTION_SAMPLE_BUCKETS: usize = 5;
+
+/// What happens to a put which would take the data size past the quota
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionPolicy {
+    /// The put fails with an out of space error
+    Reject,
+
+    /// Keys are evicted until the put fits, the least recently used of a sample of keys first
+    Lru,
+
+    /// Keys are evicted until the put fits, picked at random
+    Random,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "lru" => Ok(Self::Lru),
+            "random" => Ok(Self::Random),
+            _ => Err(format!(
+                "Unknown eviction policy {}, expected reject, lru or random",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for EvictionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reject => write!(f, "reject"),
+            Self::Lru => write!(f, "lru"),
+            Self::Random => write!(f, "random"),
+        }
+    }
+}
+
+/// Options for bounding the size of the data, see `Quota`
+#[derive(Debug, Clone)]
+pub struct QuotaOptions {
+    /// The most bytes of keys and stored values the storage holds
+    pub max_data_bytes: u64,
+
+    pub policy: EvictionPolicy,
+}
+
+/// Keeps the data size of a `HashStorage` under a maximum
+///
+/// The data size counts the bytes of every key and of its value as stored, so after compression
+/// and in the value log for large values. It is counted when the storage opens and kept up to
+/// date as records are written and removed.
+///
+/// Keys are only used in memory for the approximate LRU, so keys which haven't been used since
+/// the storage opened are evicted first.
+pub(crate) struct Quota {
+    pub options: QuotaOptions,
+
+    /// The current data size in bytes
+    pub data_bytes: u64,
+
+    /// The number of keys evicted since the storage opened
+    pub evicted_keys: u64,
+
+    /// The number of puts rejected for being over the quota since the storage opened
+    pub rejected_writes: u64,
+
+    /// The value of `clock` when each key was last used
+    last_used: HashMap<Hash, u64>,
+
+    /// Counts up on every use of a key
+    clock: u64,
+
+    /// The state of the xorshift generator picking the keys to evict at random
+    random_state: u64,
+}
+
+impl Quota {
+    pub fn new(options: QuotaOptions, data_bytes: u64) -> Self {
+        Self {
+            options,
+            data_bytes,
+            evicted_keys: 0,
+            rejected_writes: 0,
+            last_used: HashMap::new(),
+            clock: 0,
+            // The state must never be 0
+            random_state: now_millis() | 1,
+        }
+    }
+
+    /// Updates the data size after records of `added` bytes were written and records of
+    /// `removed` bytes were removed
+    pub fn update(&mut self, added: u64, removed: u64) {
+        self.data_bytes = (self.data_bytes + added).saturating_sub(removed);
+    }
+
+    /// Forgets every key and the data size once every key is deleted
+    pub fn clear(&mut self) {
+        self.data_bytes = 0;
+        self.last_used.clear();
+    }
+
+    /// Marks the key as used now
+    pub fn touch(&mut self, hash: Hash) {
+        self.clock += 1;
+        self.last_used.insert(hash, self.clock);
+    }
+
+    /// Forgets the key once it is removed
+    pub fn forget(&mut self, hash: Hash) {
+        self.last_used.remove(&hash);
+    }
+
+    /// When the key was last used, 0 if it hasn't been used since the storage opened
+    pub fn last_used(&self, hash: Hash) -> u64 {
+        self.last_used.get(&hash).copied().unwrap_or(0)
+    }
+
+    /// Returns a pseudo random number, see xorshift64
+    pub fn random(&mut self) -> u64 {
+        let mut x = self.random_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.random_state = x;
+        x
+    }
+
+    /// The stats of the quota as name value pairs
+    pub fn stats(&self) -> Vec<(String, String)> {
+        vec![
+            ("data_bytes".into(), self.data_bytes.to_string()),
+            (
+                "max_data_bytes".into(),
+                self.options.max_data_bytes.to_string(),
+            ),
+            ("eviction_policy".into(), self.options.policy.to_string()),
+            ("evicted_keys".into(), self.evicted_keys.to_string()),
+            ("rejected_writes".into(), self.rejected_writes.to_string()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod test_quota {
+    use super::*;
+
+    #[test]
+    fn parse_policies() {
+        for policy in [
+            EvictionPolicy::Reject,
+            EvictionPolicy::Lru,
+            EvictionPolicy::Random,
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("LRU".parse::<EvictionPolicy>().is_err());
+    }
+
+    #[test]
+    fn tracks_use_and_size() {
+        let options = QuotaOptions {
+            max_data_bytes: 100,
+            policy: EvictionPolicy::Lru,
+        };
+        let mut quota = Quota::new(options, 10);
+        quota.update(20, 5);
+        assert_eq!(quota.data_bytes, 25);
+        quota.update(0, 100);
+        assert_eq!(quota.data_bytes, 0);
+
+        quota.touch(1);
+        quota.touch(2);
+        quota.touch(1);
+        assert!(quota.last_used(1) > quota.last_used(2));
+        quota.forget(1);
+        assert_eq!(quota.last_used(1), 0);
+
+        let randoms: Vec<u64> = (0..10).map(|_| quota.random()).collect();
+        assert!(randoms.windows(2).all(|x| x[0] != x[1]));
+    }
+}
@@ -0,0 +1,389 @@
+use crate::command::*;
+use crate::hash_storage::{HashStorage, HashStorageOptions};
+use crate::mutation_log::{read_segments, start_log_at, LogEntry, Lsn, BACKUP_LSN_FILE_NAME};
+use crate::setup::{
+    hash_storage_options, DEFAULT_HASH_DB_FILE, DEFAULT_HASH_DIRECTORY_FILE,
+    DEFAULT_MUTATION_LOG_DIRECTORY,
+};
+use std::path::Path;
+
+/// How far a recovery replays the archived mutations
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RecoveryTarget {
+    /// Replay every archived mutation
+    Latest,
+
+    /// Replay the mutations up to and including the LSN
+    Lsn(Lsn),
+
+    /// Replay the mutations written at or before the timestamp
+    Time(Timestamp),
+}
+
+impl RecoveryTarget {
+    /// Whether the entry is replayed
+    fn includes(&self, entry: &LogEntry) -> bool {
+        match self {
+            RecoveryTarget::Latest => true,
+            RecoveryTarget::Lsn(lsn) => entry.lsn <= *lsn,
+            RecoveryTarget::Time(timestamp) => entry.timestamp <= *timestamp,
+        }
+    }
+}
+
+/// The point a recovery got to
+#[derive(Debug, PartialEq)]
+pub(crate) struct Recovered {
+    /// The number of archived mutations replayed
+    pub replayed: usize,
+
+    /// The last mutation replayed, `None` when the backup is recovered as it is
+    pub last: Option<(Lsn, Timestamp)>,
+}
+
+/// Files the database is recovered into, which must not exist yet
+pub(crate) struct RecoveryDestination<'a> {
+    pub directory_file: &'a str,
+    pub buckets_file: &'a str,
+
+    /// The mutation log carries on from after the last archived mutation in this directory
+    pub mutation_log_directory: &'a Path,
+
+    /// The options the database is opened with, the value log is recovered into its directory
+    pub options: HashStorageOptions,
+}
+
+/// Recovers a database from a backup taken by `HashStorage::start_backup` by replaying the
+/// mutations in the archive written since the backup started, up to the target
+///
+/// The mutation log of the recovered database starts after the last archived mutation, even one
+/// which wasn't replayed, so its mutations never reuse an LSN of the archive.
+///
+/// # Returns
+/// - An error if the destination files already exist, the backup wasn't taken with the
+///   mutation log turned on or the archive is missing mutations
+pub(crate) async fn recover(
+    backup: &Path,
+    archive: &Path,
+    target: RecoveryTarget,
+    destination: RecoveryDestination<'_>,
+) -> Result<Recovered, String> {
+    for path in [destination.directory_file, destination.buckets_file] {
+        if Path::new(path).exists() {
+            return Err(format!("Refusing to recover over the existing {}", path));
+        }
+    }
+
+    let lsn_path = backup.join(BACKUP_LSN_FILE_NAME);
+    let backup_lsn: Lsn = tokio::fs::read_to_string(&lsn_path)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", lsn_path.display(), e))?
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid LSN in {}: {}", lsn_path.display(), e))?;
+
+    let key = destination.options.encryption_key.clone();
+    let entries: Vec<LogEntry> = read_segments(archive, key.as_ref())
+        .await?
+        .into_iter()
+        .filter(|x| x.lsn >= backup_lsn)
+        .collect();
+    // Mutations are only ever left out at the end of the archive, a gap means segments are lost
+    let mut expected = backup_lsn;
+    for entry in &entries {
+        if entry.lsn != expected {
+            return Err(format!(
+                "The archive is missing the mutation at LSN {}",
+                expected
+            ));
+        }
+        expected += 1;
+    }
+
+    copy_file(backup, destination.directory_file).await?;
+    copy_file(backup, destination.buckets_file).await?;
+    if let Some(value_log) = &destination.options.value_log {
+        copy_directory(backup, &value_log.directory).await?;
+    }
+
+    // Evictions were logged as deletes, so the replay doesn't evict anything itself
+    let options = HashStorageOptions {
+        mutation_log: None,
+        quota: None,
+        ..destination.options
+    };
+    let mut storage = HashStorage::new(
+        destination.directory_file,
+        destination.buckets_file,
+        options,
+    )
+    .await?;
+    let mut recovered = Recovered {
+        replayed: 0,
+        last: None,
+    };
+    for entry in entries.iter().take_while(|x| target.includes(x)) {
+        let cmd = match entry.mutation.clone() {
+            Mutation::Put(cmd) => cmd.into(),
+            Mutation::Delete(cmd) => cmd.into(),
+            Mutation::Expire(cmd) => cmd.into(),
+            Mutation::Rename(cmd) => cmd.into(),
+            Mutation::FlushDb => StorageCommand::FlushDb,
+            Mutation::ListPush(cmd) => cmd.into(),
+            Mutation::ListPop(cmd) => cmd.into(),
+            Mutation::Copy(cmd) => cmd.into(),
+            Mutation::HashSet(cmd) => cmd.into(),
+            Mutation::HashDelete(cmd) => cmd.into(),
+            Mutation::SortedSetAdd(cmd) => cmd.into(),
+            Mutation::SortedSetRemove(cmd) => cmd.into(),
+            Mutation::SetAdd(cmd) => cmd.into(),
+            Mutation::SetRemove(cmd) => cmd.into(),
+            Mutation::Incr(cmd) => cmd.into(),
+            Mutation::PutIf(cmd) => cmd.into(),
+            Mutation::Cas(cmd) => cmd.into(),
+        };
+        // A mutation which failed when it was logged fails the same way again
+        let _ = storage.handle_cmd(cmd).await;
+        recovered.replayed += 1;
+        recovered.last = Some((entry.lsn, entry.timestamp));
+    }
+    storage.handle_cmd(StorageCommand::Flush).await?;
+
+    start_log_at(destination.mutation_log_directory, expected, key.as_ref()).await?;
+    Ok(recovered)
+}
+
+/// Copies the file in the backup named like the destination to the destination
+async fn copy_file(backup: &Path, destination: &str) -> Result<(), String> {
+    let name = Path::new(destination)
+        .file_name()
+        .ok_or_else(|| format!("{} is not a file", destination))?;
+    let source = backup.join(name);
+    tokio::fs::copy(&source, destination)
+        .await
+        .map_err(|e| format!("Failed to copy {}: {}", source.display(), e))?;
+    Ok(())
+}
+
+/// Copies the files of the directory in the backup named like the destination, if the backup
+/// has one, into the destination
+async fn copy_directory(backup: &Path, destination: &str) -> Result<(), String> {
+    let Some(name) = Path::new(destination).file_name() else {
+        return Err(format!("{} is not a directory", destination));
+    };
+    let Ok(mut entries) = tokio::fs::read_dir(backup.join(name)).await else {
+        return Ok(());
+    };
+    tokio::fs::create_dir_all(destination)
+        .await
+        .map_err(|e| format!("Failed to create {}: {}", destination, e))?;
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        let target = Path::new(destination).join(entry.file_name());
+        tokio::fs::copy(entry.path(), &target)
+            .await
+            .map_err(|e| format!("Failed to copy {}: {}", entry.path().display(), e))?;
+    }
+    Ok(())
+}
+
+/// Reads the arguments of `--recover <backup> <archive> [--until-lsn <lsn> | --until-time <ms>]`
+fn parse_args(args: &[String]) -> Result<(&str, &str, RecoveryTarget), String> {
+    let usage = "Usage: --recover <backup> <archive> [--until-lsn <lsn> | --until-time <ms>]";
+    let (backup, archive) = match args {
+        [backup, archive, ..] => (backup.as_str(), archive.as_str()),
+        _ => return Err(usage.into()),
+    };
+    let target = match &args[2..] {
+        [] => RecoveryTarget::Latest,
+        [flag, value] if flag == "--until-lsn" => RecoveryTarget::Lsn(
+            value
+                .parse()
+                .map_err(|_| format!("Invalid LSN: {}", value))?,
+        ),
+        [flag, value] if flag == "--until-time" => RecoveryTarget::Time(
+            value
+                .parse()
+                .map_err(|_| format!("Invalid timestamp: {}", value))?,
+        ),
+        _ => return Err(usage.into()),
+    };
+    Ok((backup, archive, target))
+}
+
+/// Recovers the database from a backup and an archive of the mutation log, see `recover`
+pub async fn run_recover(args: &[String]) -> Result<(), String> {
+    let (backup, archive, target) = parse_args(args)?;
+    let destination = RecoveryDestination {
+        directory_file: DEFAULT_HASH_DIRECTORY_FILE,
+        buckets_file: DEFAULT_HASH_DB_FILE,
+        mutation_log_directory: Path::new(DEFAULT_MUTATION_LOG_DIRECTORY),
+        options: hash_storage_options(),
+    };
+    let recovered = recover(Path::new(backup), Path::new(archive), target, destination).await?;
+    match recovered.last {
+        Some((lsn, timestamp)) => println!(
+            "Replayed {} mutations, recovered to LSN {} written at {} ms",
+            recovered.replayed, lsn, timestamp
+        ),
+        None => println!("Recovered the backup, no mutations were replayed"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_recovery {
+    use super::*;
+    use crate::mutation_log::MutationLogOptions;
+
+    async fn get(storage: &mut HashStorage, key: &str) -> CommandOutput {
+        storage
+            .handle_cmd(GetCommand(key.into()).into())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn recovers_to_before_mutations() {
+        let source = "./test_data/test_recovery_recovers_to_before_mutations_source";
+        let backup = "./test_data/test_recovery_recovers_to_before_mutations_backup";
+        let archive = "./test_data/test_recovery_recovers_to_before_mutations_archive";
+        let target = "./test_data/test_recovery_recovers_to_before_mutations_target";
+        for path in [source, backup, archive, target] {
+            let _ = std::fs::remove_dir_all(path);
+            std::fs::create_dir_all(path).unwrap();
+        }
+        let _ = std::fs::remove_dir(backup);
+        let log_options = |directory: String, archive: Option<&str>| MutationLogOptions {
+            directory,
+            archive_directory: archive.map(String::from),
+            segment_bytes: 512,
+        };
+
+        let options = HashStorageOptions {
+            mutation_log: Some(log_options(format!("{}/hash_log", source), Some(archive))),
+            ..Default::default()
+        };
+        let mut storage = HashStorage::new(
+            &format!("{}/hash_dir.db", source),
+            &format!("{}/hash_data.db", source),
+            options,
+        )
+        .await
+        .unwrap();
+        for i in 0..20 {
+            let cmd = PutCommand(format!("key{}", i).into(), "before".into(), None);
+            storage.handle_cmd(cmd.into()).await.unwrap();
+        }
+        let cmd = BackupCommand(backup.into());
+        storage.handle_cmd(cmd.into()).await.unwrap();
+        for i in 0..20 {
+            let cmd = PutCommand(format!("key{}", i).into(), "after".into(), None);
+            storage.handle_cmd(cmd.into()).await.unwrap();
+        }
+        // LSN 40 is the last put, then every key is deleted by mistake
+        for i in 0..20 {
+            let cmd = DeleteCommand(format!("key{}", i).into());
+            storage.handle_cmd(cmd.into()).await.unwrap();
+        }
+        storage.handle_cmd(StorageCommand::Flush).await.unwrap();
+
+        let directory_file = format!("{}/hash_dir.db", target);
+        let buckets_file = format!("{}/hash_data.db", target);
+        let log_directory = format!("{}/hash_log", target);
+        let destination = || RecoveryDestination {
+            directory_file: &directory_file,
+            buckets_file: &buckets_file,
+            mutation_log_directory: Path::new(&log_directory),
+            options: HashStorageOptions::default(),
+        };
+        let recovered = recover(
+            Path::new(backup),
+            Path::new(archive),
+            RecoveryTarget::Lsn(40),
+            destination(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(recovered.replayed, 20);
+        assert_eq!(recovered.last.map(|x| x.0), Some(40));
+
+        // Refuses to recover over the recovered database
+        let error = recover(
+            Path::new(backup),
+            Path::new(archive),
+            RecoveryTarget::Latest,
+            destination(),
+        )
+        .await;
+        assert!(error.is_err());
+
+        let options = HashStorageOptions {
+            mutation_log: Some(log_options(log_directory.clone(), None)),
+            ..Default::default()
+        };
+        let mut storage = HashStorage::new(&directory_file, &buckets_file, options)
+            .await
+            .unwrap();
+        for i in 0..20 {
+            assert_eq!(
+                get(&mut storage, &format!("key{}", i)).await,
+                CommandOutput::Found("after".into())
+            );
+        }
+        // New mutations carry on after the last archived one
+        let cmd = PutCommand("new".into(), "1".into(), None);
+        storage.handle_cmd(cmd.into()).await.unwrap();
+        let entries = read_segments(Path::new(&log_directory), None)
+            .await
+            .unwrap();
+        let lsns: Vec<Lsn> = entries.iter().map(|x| x.lsn).collect();
+        assert_eq!(lsns, vec![61]);
+    }
+
+    #[tokio::test]
+    async fn refuses_backup_without_lsn() {
+        let backup = "./test_data/test_recovery_refuses_backup_without_lsn_backup";
+        let archive = "./test_data/test_recovery_refuses_backup_without_lsn_archive";
+        let target = "./test_data/test_recovery_refuses_backup_without_lsn_target";
+        for path in [backup, archive, target] {
+            let _ = std::fs::remove_dir_all(path);
+            std::fs::create_dir_all(path).unwrap();
+        }
+        let destination = RecoveryDestination {
+            directory_file: &format!("{}/hash_dir.db", target),
+            buckets_file: &format!("{}/hash_data.db", target),
+            mutation_log_directory: &Path::new(target).join("hash_log"),
+            options: HashStorageOptions::default(),
+        };
+
+        // Taken without the mutation log
+        let error = recover(
+            Path::new(backup),
+            Path::new(archive),
+            RecoveryTarget::Latest,
+            destination,
+        )
+        .await;
+        assert!(error.unwrap_err().contains(BACKUP_LSN_FILE_NAME));
+    }
+
+    #[test]
+    fn parses_args() {
+        let args = |x: &str| x.split(' ').map(String::from).collect::<Vec<_>>();
+        assert_eq!(
+            parse_args(&args("backup archive")),
+            Ok(("backup", "archive", RecoveryTarget::Latest))
+        );
+        assert_eq!(
+            parse_args(&args("backup archive --until-lsn 12")),
+            Ok(("backup", "archive", RecoveryTarget::Lsn(12)))
+        );
+        assert_eq!(
+            parse_args(&args("backup archive --until-time 1700000000000")),
+            Ok(("backup", "archive", RecoveryTarget::Time(1700000000000)))
+        );
+        assert!(parse_args(&args("backup")).is_err());
+        assert!(parse_args(&args("backup archive --until-lsn x")).is_err());
+        assert!(parse_args(&args("backup archive --until 1")).is_err());
+    }
+}
@@ -40,10 +40,7 @@ async fn inner_loop(
             // TODO: Handle reciever error
             println!("Received ctrl-c");
             let output = execute_command(storage, wal,UserCommand::Exit, None).await.unwrap();
-            match output {
-                CommandOutput::Exit => return true,
-                _ => return false
-            }
+            matches!(output, CommandOutput::Exit)
         }
         input = reader.next_line() => {
             if let Some(input) = input.unwrap() {
@@ -60,7 +57,7 @@ async fn inner_loop(
                     println!("{}", output);
                 }
             }
-            return true
+            true
         }
     }
 }
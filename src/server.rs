@@ -1,3 +1,4 @@
+use crate::backup::BACKUP_STEP_CHUNKS;
 use crate::command::*;
 use crate::execute::*;
 
@@ -10,7 +11,7 @@ use tokio::{
         unix::{signal, SignalKind},
     },
     spawn,
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::mpsc::{channel, error::TryRecvError, Receiver, Sender},
     sync::oneshot::{channel as one_channel, Receiver as OneReceiver, Sender as OneSender},
 };
 
@@ -86,18 +87,31 @@ pub struct SendLine {
 async fn receive_line_single_thread(r: &mut Receiver<SendLine>, ctrlc: &mut OneReceiver<()>) {
     let (mut storage, mut wal) = setup_db().await;
     loop {
-        select! {
-            _ = &mut *ctrlc => {
-                break;
+        let msg = if storage.is_backing_up() {
+            // Carry on with the running backup whenever no commands are waiting
+            match r.try_recv() {
+                Err(TryRecvError::Empty) => {
+                    if ctrlc.try_recv().is_ok() {
+                        break;
+                    }
+                    storage.continue_backup(BACKUP_STEP_CHUNKS).await;
+                    continue;
+                }
+                msg => msg.ok(),
             }
-            msg = r.recv() => {
-                if let Some(SendLine {line, cb, transaction_id:t_id}) = msg {
-                   let output = execute_user_input(&mut storage, &mut wal, &line, t_id.as_deref()).await;
-                   cb.send(output).unwrap();
-                } else {
+        } else {
+            select! {
+                _ = &mut *ctrlc => {
                     break;
                 }
+                msg = r.recv() => msg,
             }
+        };
+        if let Some(SendLine {line, cb, transaction_id:t_id}) = msg {
+           let output = execute_user_input(&mut storage, &mut wal, &line, t_id.as_deref()).await;
+           cb.send(output).unwrap();
+        } else {
+            break;
         }
     }
     execute_command(&mut storage, &mut wal, UserCommand::Exit, None)
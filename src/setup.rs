@@ -1,12 +1,114 @@
+use crate::encryption::EncryptionKey;
 use crate::hash_storage::*;
+use crate::mutation_log::{MutationLogOptions, DEFAULT_MUTATION_LOG_SEGMENT_BYTES};
+use crate::quota::{EvictionPolicy, QuotaOptions};
+use crate::value_log::DEFAULT_VALUE_LOG_SEGMENT_BYTES;
 use crate::wal::*;
 
 const DEFAULT_DB_FILE: &str = "data.db";
 
-const DEFAULT_HASH_DB_FILE: &str = "hash_data.db";
-const DEFAULT_HASH_DIRECTORY_FILE: &str = "hash_dir.db";
+pub(crate) const DEFAULT_HASH_DB_FILE: &str = "hash_data.db";
+pub(crate) const DEFAULT_HASH_DIRECTORY_FILE: &str = "hash_dir.db";
+pub(crate) const DEFAULT_VALUE_LOG_DIRECTORY: &str = "hash_values";
+pub(crate) const DEFAULT_MUTATION_LOG_DIRECTORY: &str = "hash_log";
+
+/// Environment variable which turns on the value log, values larger than this many bytes are
+/// stored in the value log instead of inline in the buckets
+const VALUE_LOG_THRESHOLD_ENV: &str = "SILLY_RUSTY_KV_VALUE_LOG_THRESHOLD";
+
+/// Environment variable holding the directory closed mutation log segments are archived to,
+/// setting it turns on the mutation log
+const MUTATION_LOG_ARCHIVE_ENV: &str = "SILLY_RUSTY_KV_LOG_ARCHIVE";
+
+/// Environment variable holding the most bytes of keys and values the database holds, see
+/// `Quota`
+const MAX_DATA_BYTES_ENV: &str = "SILLY_RUSTY_KV_MAX_DATA_BYTES";
+
+/// Environment variable holding what happens to puts over `MAX_DATA_BYTES_ENV`, `reject` unless
+/// it is set, see `EvictionPolicy`
+const EVICTION_POLICY_ENV: &str = "SILLY_RUSTY_KV_EVICTION_POLICY";
+
+/// Environment variable holding the page size in bytes a new database is created with, see
+/// `HashStorageOptions::page_bytes`
+const PAGE_BYTES_ENV: &str = "SILLY_RUSTY_KV_PAGE_BYTES";
+
+/// Environment variable holding the key, written as hex, which the database files are encrypted
+/// with
+const ENCRYPTION_KEY_ENV: &str = "SILLY_RUSTY_KV_ENCRYPTION_KEY";
+
+/// Environment variable holding the path to a file containing the encryption key, used when
+/// `ENCRYPTION_KEY_ENV` isn't set
+const ENCRYPTION_KEY_FILE_ENV: &str = "SILLY_RUSTY_KV_ENCRYPTION_KEY_FILE";
+
+/// Reads the encryption key from the environment
+///
+/// # Returns
+/// - `None` if encryption isn't turned on
+fn encryption_key() -> Result<Option<EncryptionKey>, String> {
+    if let Ok(hex) = std::env::var(ENCRYPTION_KEY_ENV) {
+        return EncryptionKey::from_hex(&hex)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", ENCRYPTION_KEY_ENV, e));
+    }
+    if let Ok(path) = std::env::var(ENCRYPTION_KEY_FILE_ENV) {
+        return EncryptionKey::read_key_file(&path)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", ENCRYPTION_KEY_FILE_ENV, e));
+    }
+    Ok(None)
+}
+
+/// Reads the options the hash storage is opened with from the environment
+pub(crate) fn hash_storage_options() -> HashStorageOptions {
+    let value_log = std::env::var(VALUE_LOG_THRESHOLD_ENV)
+        .ok()
+        .map(|threshold| ValueLogOptions {
+            directory: DEFAULT_VALUE_LOG_DIRECTORY.into(),
+            threshold: threshold
+                .parse()
+                .unwrap_or_else(|_| panic!("{} must be a number", VALUE_LOG_THRESHOLD_ENV)),
+            segment_bytes: DEFAULT_VALUE_LOG_SEGMENT_BYTES,
+        });
+    let page_bytes = std::env::var(PAGE_BYTES_ENV).ok().map(|page_bytes| {
+        page_bytes
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number", PAGE_BYTES_ENV))
+    });
+    let mutation_log =
+        std::env::var(MUTATION_LOG_ARCHIVE_ENV)
+            .ok()
+            .map(|archive| MutationLogOptions {
+                directory: DEFAULT_MUTATION_LOG_DIRECTORY.into(),
+                archive_directory: Some(archive),
+                segment_bytes: DEFAULT_MUTATION_LOG_SEGMENT_BYTES,
+            });
+    let quota = std::env::var(MAX_DATA_BYTES_ENV)
+        .ok()
+        .map(|max_data_bytes| QuotaOptions {
+            max_data_bytes: max_data_bytes
+                .parse()
+                .unwrap_or_else(|_| panic!("{} must be a number", MAX_DATA_BYTES_ENV)),
+            policy: std::env::var(EVICTION_POLICY_ENV)
+                .map_or(Ok(EvictionPolicy::Reject), |x| x.parse())
+                .unwrap_or_else(|e| panic!("{}: {}", EVICTION_POLICY_ENV, e)),
+        });
+    let encryption_key = encryption_key().unwrap_or_else(|e| panic!("{}", e));
+    HashStorageOptions {
+        value_log,
+        encryption_key,
+        page_bytes,
+        mutation_log,
+        quota,
+    }
+}
 
 pub async fn setup_db() -> (HashStorage, Wal) {
-    let hash_storage = HashStorage::new(DEFAULT_HASH_DIRECTORY_FILE, DEFAULT_HASH_DB_FILE).await;
+    let hash_storage = HashStorage::new(
+        DEFAULT_HASH_DIRECTORY_FILE,
+        DEFAULT_HASH_DB_FILE,
+        hash_storage_options(),
+    )
+    .await
+    .unwrap_or_else(|e| panic!("Failed to open the database: {}", e));
     (hash_storage, Wal::new())
 }
@@ -0,0 +1,208 @@
+use crate::command::list_range;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+/// The score of a member of a sorted set, a finite number
+///
+/// Scores are ordered by `f64::total_cmp` so they can be kept in a `BTreeSet`. Every score is made
+/// with `Score::new`, which refuses NaN and the infinities and turns -0 into 0, so the order
+/// matches the order of the numbers
+#[derive(Debug, Clone, Copy)]
+pub struct Score(f64);
+
+impl Score {
+    pub fn new(score: f64) -> Option<Self> {
+        if !score.is_finite() {
+            return None;
+        }
+        // -0 + 0 is 0
+        Some(Self(score + 0.0))
+    }
+
+    pub fn value(self) -> f64 {
+        self.0
+    }
+}
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl Display for Score {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The members of a sorted set with their scores, ordered by score and then by member
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SortedSet {
+    /// Every member after its score, in order
+    ordered: BTreeSet<(Score, Vec<u8>)>,
+
+    /// The score of every member
+    scores: BTreeMap<Vec<u8>, Score>,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<Score> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds the member with the score, or moves it to the score when it is already a member
+    ///
+    /// # Returns
+    /// - Whether the member is new
+    pub fn insert(&mut self, member: Vec<u8>, score: Score) -> bool {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&(previous, member.clone()));
+        }
+        self.ordered.insert((score, member));
+        previous.is_none()
+    }
+
+    /// Removes the member
+    ///
+    /// # Returns
+    /// - Whether it was a member
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        let Some((member, score)) = self.scores.remove_entry(member) else {
+            return false;
+        };
+        self.ordered.remove(&(score, member));
+        true
+    }
+
+    /// The members with their scores in order
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, Score)> {
+        self.ordered.iter().map(|(score, member)| (member, *score))
+    }
+
+    /// The members with their scores from the start to the stop position inclusive, positions
+    /// are resolved like the indices of a list, see `list_range`
+    pub fn range(&self, start: i64, stop: i64) -> Vec<(Vec<u8>, Score)> {
+        let range = list_range(self.len() as u64, start, stop);
+        self.iter()
+            .skip(range.start as usize)
+            .take((range.end - range.start) as usize)
+            .map(|(member, score)| (member.clone(), score))
+            .collect()
+    }
+
+    /// The members with their scores from `min` to `max` inclusive, in order. The bounds can be
+    /// infinite
+    pub fn range_by_score(&self, min: f64, max: f64) -> Vec<(Vec<u8>, Score)> {
+        // The empty member comes first among the members with the same score
+        self.ordered
+            .range((Score(min), vec![])..)
+            .take_while(|(score, _)| score.0 <= max)
+            .map(|(score, member)| (member.clone(), *score))
+            .collect()
+    }
+}
+
+impl FromIterator<(Vec<u8>, Score)> for SortedSet {
+    fn from_iter<T: IntoIterator<Item = (Vec<u8>, Score)>>(iter: T) -> Self {
+        let mut set = Self::default();
+        for (member, score) in iter {
+            set.insert(member, score);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod test_sorted_set {
+    use super::*;
+
+    fn score(score: f64) -> Score {
+        Score::new(score).unwrap()
+    }
+
+    fn members(members: &[(&str, f64)]) -> Vec<(Vec<u8>, Score)> {
+        members
+            .iter()
+            .map(|(member, x)| (member.as_bytes().to_vec(), score(*x)))
+            .collect()
+    }
+
+    #[test]
+    fn orders_members_by_score() {
+        let mut set = SortedSet::default();
+        assert!(set.insert(b"b".to_vec(), score(2.0)));
+        assert!(set.insert(b"a".to_vec(), score(2.0)));
+        assert!(set.insert(b"c".to_vec(), score(-1.5)));
+        assert!(!set.insert(b"b".to_vec(), score(0.0)));
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.score(b"b"), Some(score(0.0)));
+        assert_eq!(
+            set.range(0, -1),
+            members(&[("c", -1.5), ("b", 0.0), ("a", 2.0)])
+        );
+        assert_eq!(set.range(-2, 100), members(&[("b", 0.0), ("a", 2.0)]));
+        assert_eq!(set.range(2, 1), vec![]);
+
+        assert!(set.remove(b"b"));
+        assert!(!set.remove(b"b"));
+        assert_eq!(set.score(b"b"), None);
+        assert_eq!(set.range(0, -1), members(&[("c", -1.5), ("a", 2.0)]));
+        assert_eq!(set.iter().count(), 2);
+    }
+
+    #[test]
+    fn ranges_by_score() {
+        let set: SortedSet = members(&[("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.5)])
+            .into_iter()
+            .collect();
+        assert_eq!(
+            set.range_by_score(2.0, 3.5),
+            members(&[("b", 2.0), ("c", 2.0), ("d", 3.5)])
+        );
+        assert_eq!(
+            set.range_by_score(f64::NEG_INFINITY, 1.5),
+            members(&[("a", 1.0)])
+        );
+        assert_eq!(
+            set.range_by_score(3.0, f64::INFINITY),
+            members(&[("d", 3.5)])
+        );
+        assert_eq!(set.range_by_score(3.0, 2.0), vec![]);
+        assert_eq!(set.range_by_score(f64::INFINITY, f64::INFINITY), vec![]);
+    }
+
+    #[test]
+    fn scores_are_finite() {
+        assert_eq!(Score::new(f64::NAN), None);
+        assert_eq!(Score::new(f64::INFINITY), None);
+        assert_eq!(Score::new(-0.0), Some(score(0.0)));
+        assert!(score(-1.0) < score(0.5));
+        assert_eq!(score(1.0).to_string(), "1");
+        assert_eq!(score(-2.5).to_string(), "-2.5");
+    }
+}
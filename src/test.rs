@@ -1,5 +1,9 @@
 use std::fs::{File, OpenOptions};
+use std::path::Path;
 pub fn reset_or_create_file(name: &str) -> File {
+    if let Some(parent) = Path::new(name).parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
     OpenOptions::new()
         .create(true)
         .read(true)
@@ -0,0 +1,457 @@
+use crate::bytes::{read_varint, varint_len, write_varint, ByteLength, IntoBytes, ParseFromBytes};
+use crate::command::MAX_VALUE_BYTES;
+use crate::hash_storage::{take_bytes_from_iterator, Hash, HASH_BYTES};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Identifier of a segment file in the value log, segments are created in increasing order
+pub(crate) type SegmentId = u32;
+
+/// The type used for the position of a value in a segment
+type SegmentOffset = u32;
+
+/// The type used for the length of a value in a `ValuePointer`
+type ValueLength = u32;
+
+// The length in a pointer fits every value which can be stored
+const _: () = assert!(MAX_VALUE_BYTES <= ValueLength::MAX as usize);
+
+/// The file extension of segment files
+const SEGMENT_EXTENSION: &str = "vlog";
+
+/// Default size in bytes after which the value log moves on to a new segment
+pub const DEFAULT_VALUE_LOG_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Points at a value stored in the value log
+///
+/// ## Binary layout
+/// - The segment id in LE
+/// - The offset of the value in the segment in LE
+/// - The length of the value in LE
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ValuePointer {
+    pub segment: SegmentId,
+    pub offset: SegmentOffset,
+    pub len: ValueLength,
+}
+
+/// The length in bytes of a `ValuePointer`
+pub(crate) const VALUE_POINTER_BYTES: usize =
+    size_of::<SegmentId>() + size_of::<SegmentOffset>() + size_of::<ValueLength>();
+
+impl IntoBytes for ValuePointer {
+    fn into_bytes(self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(VALUE_POINTER_BYTES);
+        result.extend(self.segment.to_le_bytes());
+        result.extend(self.offset.to_le_bytes());
+        result.extend(self.len.to_le_bytes());
+        result
+    }
+}
+
+impl<'a, T> ParseFromBytes<T> for ValuePointer
+where
+    T: Iterator<Item = &'a u8>,
+{
+    type Error = ();
+    type Metadata = ();
+
+    fn from_bytes(mut bytes: T, metadata: ()) -> Result<(Self, T), Self::Error> {
+        let segment = SegmentId::from_le_bytes(take_bytes_from_iterator(&mut bytes));
+        let offset = SegmentOffset::from_le_bytes(take_bytes_from_iterator(&mut bytes));
+        let len = ValueLength::from_le_bytes(take_bytes_from_iterator(&mut bytes));
+        Ok((
+            ValuePointer {
+                segment,
+                offset,
+                len,
+            },
+            bytes,
+        ))
+    }
+}
+
+/// An entry of a segment without its value, used when walking a segment for garbage collection
+///
+/// ## Binary layout
+/// - The hash of the key, `HASH_BYTES` in LE
+/// - The length of the key as a varint, see `bytes::write_varint`
+/// - The key
+/// - The length of the value as a varint
+/// - The value
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ValueLogEntry {
+    pub hash: Hash,
+    pub key: Vec<u8>,
+    pub pointer: ValuePointer,
+}
+
+impl ByteLength for ValueLogEntry {
+    fn byte_len(&self) -> usize {
+        entry_byte_len(self.key.len(), self.pointer.len as usize)
+    }
+}
+
+/// The length in bytes of an entry in a segment
+pub(crate) fn entry_byte_len(key_len: usize, value_len: usize) -> usize {
+    HASH_BYTES + varint_len(key_len as u64) + key_len + varint_len(value_len as u64) + value_len
+}
+
+/// Returns the ids of the segment files in the directory
+async fn list_segments(directory: &Path) -> Vec<SegmentId> {
+    let mut segments = vec![];
+    let mut entries = tokio::fs::read_dir(directory).await.unwrap();
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        let path = entry.path();
+        if path.extension().and_then(|x| x.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(id) = path
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.parse::<SegmentId>().ok())
+        {
+            segments.push(id);
+        }
+    }
+    segments
+}
+
+/// Append only log holding the values which are too large to be stored inline in a bucket
+///
+/// The log is split into segment files named `<segment id>.vlog` inside a directory. Values are
+/// only ever appended to the segment with the highest id, the head. Once the head grows past the
+/// segment size a new head is started, and older segments become candidates for garbage
+/// collection, see `HashStorage::collect_value_log_garbage`.
+pub(crate) struct ValueLog {
+    /// The directory containing the segment files
+    directory: PathBuf,
+
+    /// Size in bytes after which a new head segment is started
+    segment_bytes: u64,
+
+    /// The segment which values are appended to
+    head: SegmentId,
+
+    /// The length in bytes of the head segment
+    head_len: u64,
+
+    /// Open segment files
+    files: HashMap<SegmentId, File>,
+
+    /// The length in bytes of the entries of each segment which are still referenced, kept up to
+    /// date by `append` and `release`
+    ///
+    /// Segments written before the log was opened are missing until they are counted with
+    /// `set_live_bytes`
+    live_bytes: HashMap<SegmentId, u64>,
+}
+
+impl ValueLog {
+    /// Opens the value log in the directory, creating the directory if it does not exist
+    ///
+    /// # Returns
+    /// - An error if the segment size is too large for the offsets of a `ValuePointer`
+    pub async fn open(directory: &str, segment_bytes: u64) -> Result<Self, String> {
+        // An entry only starts past the start of a segment when it ends within the segment size,
+        // so every offset is below the segment size
+        if segment_bytes > SegmentOffset::MAX.into() {
+            return Err(format!(
+                "Value log segment size must be at most {} bytes, got {}",
+                SegmentOffset::MAX,
+                segment_bytes
+            ));
+        }
+        let directory = PathBuf::from(directory);
+        tokio::fs::create_dir_all(&directory).await.unwrap();
+
+        let head = list_segments(&directory)
+            .await
+            .into_iter()
+            .max()
+            .unwrap_or(1);
+
+        let mut value_log = Self {
+            directory,
+            segment_bytes,
+            head,
+            head_len: 0,
+            files: HashMap::new(),
+            live_bytes: HashMap::new(),
+        };
+        value_log.head_len = value_log.segment_len(head).await;
+        if value_log.head_len == 0 {
+            value_log.live_bytes.insert(head, 0);
+        }
+        Ok(value_log)
+    }
+
+    fn segment_path(&self, segment: SegmentId) -> PathBuf {
+        self.directory
+            .join(format!("{:08}.{}", segment, SEGMENT_EXTENSION))
+    }
+
+    /// Returns the open segment file, opening or creating it if needed
+    async fn file(&mut self, segment: SegmentId) -> &mut File {
+        if !self.files.contains_key(&segment) {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(self.segment_path(segment))
+                .unwrap();
+            self.files.insert(segment, file.into());
+        }
+        self.files.get_mut(&segment).unwrap()
+    }
+
+    /// The directory containing the segment files
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// The path and length in bytes of every segment file
+    pub async fn segment_files(&self) -> Vec<(PathBuf, u64)> {
+        let mut files = vec![];
+        for segment in list_segments(&self.directory).await {
+            let path = self.segment_path(segment);
+            let len = tokio::fs::metadata(&path).await.unwrap().len();
+            files.push((path, len));
+        }
+        files
+    }
+
+    /// The segment which values are appended to
+    pub fn head(&self) -> SegmentId {
+        self.head
+    }
+
+    /// Segments which are no longer appended to, oldest first
+    pub async fn closed_segments(&self) -> Vec<SegmentId> {
+        let mut segments: Vec<SegmentId> = list_segments(&self.directory)
+            .await
+            .into_iter()
+            .filter(|x| *x < self.head)
+            .collect();
+        segments.sort();
+        segments
+    }
+
+    /// The length in bytes of the segment file
+    pub async fn segment_len(&mut self, segment: SegmentId) -> u64 {
+        self.file(segment).await.metadata().await.unwrap().len()
+    }
+
+    /// The length in bytes of the entries of the segment which are still referenced, `None` if
+    /// the segment hasn't been counted since the log was opened
+    pub fn live_bytes(&self, segment: SegmentId) -> Option<u64> {
+        self.live_bytes.get(&segment).copied()
+    }
+
+    /// Records the length in bytes of the entries of the segment which are still referenced
+    pub fn set_live_bytes(&mut self, segment: SegmentId, live_bytes: u64) {
+        self.live_bytes.insert(segment, live_bytes);
+    }
+
+    /// Marks the entry the pointer points at as no longer referenced by the key
+    pub fn release(&mut self, pointer: ValuePointer, key_len: usize) {
+        if let Some(live_bytes) = self.live_bytes.get_mut(&pointer.segment) {
+            let entry_len = entry_byte_len(key_len, pointer.len as usize) as u64;
+            *live_bytes = live_bytes.saturating_sub(entry_len);
+        }
+    }
+
+    /// Marks every entry of every segment as no longer referenced
+    pub async fn release_all(&mut self) {
+        for segment in list_segments(&self.directory).await {
+            self.live_bytes.insert(segment, 0);
+        }
+    }
+
+    /// Appends the value to the head segment, starting a new head if the current one is full
+    pub async fn append(&mut self, hash: Hash, key: &[u8], value: &[u8]) -> ValuePointer {
+        let entry_len = entry_byte_len(key.len(), value.len()) as u64;
+        if self.head_len > 0 && self.head_len + entry_len > self.segment_bytes {
+            self.head += 1;
+            self.head_len = 0;
+            self.live_bytes.insert(self.head, 0);
+        }
+
+        let mut buf = Vec::with_capacity(entry_len as usize);
+        buf.extend(hash.to_le_bytes());
+        write_varint(&mut buf, key.len() as u64);
+        buf.extend(key);
+        write_varint(&mut buf, value.len() as u64);
+        let offset = self.head_len as usize + buf.len();
+        buf.extend(value);
+
+        let head = self.head;
+        let head_len = self.head_len;
+        let file = self.file(head).await;
+        file.seek(SeekFrom::Start(head_len)).await.unwrap();
+        file.write_all(&buf).await.unwrap();
+        self.head_len += entry_len;
+        if let Some(live_bytes) = self.live_bytes.get_mut(&head) {
+            *live_bytes += entry_len;
+        }
+
+        ValuePointer {
+            segment: head,
+            offset: offset
+                .try_into()
+                .expect("Offsets fit as the segment size is checked on open"),
+            len: value.len() as ValueLength,
+        }
+    }
+
+    /// Reads the value which the pointer points at
+    pub async fn read(&mut self, pointer: ValuePointer) -> Vec<u8> {
+        let file = self.file(pointer.segment).await;
+        file.seek(SeekFrom::Start(pointer.offset.into()))
+            .await
+            .unwrap();
+        let mut buf = vec![0; pointer.len as usize];
+        file.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    /// Reads every entry of a segment along with the length of the segment in bytes
+    pub async fn scan(&mut self, segment: SegmentId) -> (Vec<ValueLogEntry>, usize) {
+        let file = self.file(segment).await;
+        file.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut buf = vec![];
+        file.read_to_end(&mut buf).await.unwrap();
+
+        let mut entries = vec![];
+        let mut offset = 0;
+        while offset < buf.len() {
+            let mut bytes = buf[offset..].iter();
+            let hash = Hash::from_le_bytes(take_bytes_from_iterator(&mut bytes));
+            let key_len = read_varint(&mut bytes).unwrap() as usize;
+            let key: Vec<u8> = bytes.by_ref().take(key_len).cloned().collect();
+            let value_len = read_varint(&mut bytes).unwrap() as usize;
+            let value_offset = offset + entry_byte_len(key.len(), value_len) - value_len;
+            let entry = ValueLogEntry {
+                hash,
+                pointer: ValuePointer {
+                    segment,
+                    offset: value_offset
+                        .try_into()
+                        .expect("Offsets fit as the segment size is checked on open"),
+                    len: value_len as ValueLength,
+                },
+                key,
+            };
+            offset += entry.byte_len();
+            entries.push(entry);
+        }
+        (entries, buf.len())
+    }
+
+    /// Deletes a segment once none of its values are referenced anymore
+    pub async fn remove_segment(&mut self, segment: SegmentId) {
+        self.files.remove(&segment);
+        self.live_bytes.remove(&segment);
+        tokio::fs::remove_file(self.segment_path(segment))
+            .await
+            .unwrap();
+    }
+
+    pub async fn sync_all(&mut self) {
+        for file in self.files.values_mut() {
+            file.sync_all().await.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_value_log {
+    use super::*;
+
+    async fn get_value_log(name: &str, segment_bytes: u64) -> ValueLog {
+        let path = format!("./test_data/{}", name);
+        let _ = std::fs::remove_dir_all(&path);
+        ValueLog::open(&path, segment_bytes).await.unwrap()
+    }
+
+    #[test]
+    fn pointer_into_and_from_bytes() {
+        let pointer = ValuePointer {
+            segment: 3,
+            offset: 1234,
+            len: 99,
+        };
+        let bytes = pointer.into_bytes();
+        assert_eq!(bytes.len(), VALUE_POINTER_BYTES);
+        let (pointer_, _) = ValuePointer::from_bytes(bytes.iter(), ()).unwrap();
+        assert_eq!(pointer_, pointer);
+    }
+
+    #[tokio::test]
+    async fn append_read_and_scan() {
+        let mut value_log = get_value_log("test_value_log_append_read_and_scan", 1024).await;
+        let first = value_log.append(1, b"a", &[1; 10]).await;
+        let second = value_log.append(2, b"bb", &[2; 20]).await;
+
+        assert_eq!(value_log.read(first).await, vec![1; 10]);
+        assert_eq!(value_log.read(second).await, vec![2; 20]);
+
+        let (entries, len) = value_log.scan(1).await;
+        assert_eq!(
+            entries,
+            vec![
+                ValueLogEntry {
+                    hash: 1,
+                    key: b"a".to_vec(),
+                    pointer: first
+                },
+                ValueLogEntry {
+                    hash: 2,
+                    key: b"bb".to_vec(),
+                    pointer: second
+                },
+            ]
+        );
+        assert_eq!(len, entries.iter().map(|x| x.byte_len()).sum::<usize>());
+        // Short keys and values take a single byte for each length
+        assert_eq!(len, HASH_BYTES * 2 + 1 + 1 + 1 + 10 + 1 + 2 + 1 + 20);
+    }
+
+    #[tokio::test]
+    async fn rotates_segments() {
+        let name = "test_value_log_rotates_segments";
+        let mut value_log = get_value_log(name, 100).await;
+        let first = value_log.append(1, b"a", &[1; 80]).await;
+        let second = value_log.append(2, b"b", &[2; 80]).await;
+
+        assert_eq!(first.segment, 1);
+        assert_eq!(second.segment, 2);
+        assert_eq!(value_log.head(), 2);
+
+        let entry_len = entry_byte_len(1, 80) as u64;
+        assert_eq!(value_log.live_bytes(1), Some(entry_len));
+        value_log.release(first, 1);
+        assert_eq!(value_log.live_bytes(1), Some(0));
+        assert_eq!(value_log.live_bytes(2), Some(entry_len));
+        assert_eq!(value_log.closed_segments().await, vec![1]);
+
+        value_log.remove_segment(1).await;
+        assert_eq!(value_log.closed_segments().await, Vec::<SegmentId>::new());
+
+        let mut reopened = ValueLog::open(&format!("./test_data/{}", name), 100)
+            .await
+            .unwrap();
+        assert_eq!(reopened.head(), 2);
+        assert_eq!(reopened.read(second).await, vec![2; 80]);
+        // Segments written before opening haven't been counted yet
+        assert_eq!(reopened.live_bytes(2), None);
+        assert!(ValueLog::open(&format!("./test_data/{}", name), 1 << 32)
+            .await
+            .is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::command::*;
-use std::collections::HashMap;
+use crate::sorted_set::SortedSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use uuid::Uuid;
 
 pub struct Wal {
@@ -23,25 +24,59 @@ impl Wal {
         self.data.remove(key)
     }
 
-    pub fn get(&self, key: &str, cmd: &GetCommand) -> Option<Option<String>> {
-        if let Some(ms) = self.data.get(key) {
-            for m in ms {
-                match m {
-                    Mutation::Put(PutCommand(ck, cv)) => {
-                        if ck == &cmd.0 {
-                            return Some(Some(cv.to_string()));
-                        }
-                    }
-                    Mutation::Delete(DeleteCommand(ck)) => {
-                        if ck == &cmd.0 {
-                            return Some(None);
-                        }
-                    }
+    /// The value of the key and the time it expires at as seen from inside the transaction
+    ///
+    /// The mutations of the transaction are applied in order to `committed`, the entry of the key
+    /// in the storage. An expired value counts as missing.
+    ///
+    /// An increment which fails is skipped on purpose. The commit fails with nothing written
+    /// instead, see `check_increments`, and until then the transaction sees the rest of its
+    /// mutations
+    pub fn view(
+        &self,
+        key: &str,
+        ident: &[u8],
+        committed: Option<(Value, Option<Timestamp>)>,
+    ) -> Option<(Value, Option<Timestamp>)> {
+        let Some(ms) = self.data.get(key) else {
+            return committed;
+        };
+        apply_mutations(ms, ident, committed).0
+    }
+
+    /// The keys the transaction increments, unless there is no such transaction
+    pub fn incremented_keys(&self, key: &str) -> Option<Vec<Vec<u8>>> {
+        let mut keys = vec![];
+        for m in self.data.get(key)? {
+            if let Mutation::Incr(IncrCommand(ck, _)) = m {
+                if !keys.contains(ck) {
+                    keys.push(ck.clone());
                 }
             }
-            None
-        } else {
-            None
+        }
+        Some(keys)
+    }
+
+    /// Checks that every increment of the key in the transaction applies to `committed`, the
+    /// entry of the key in the storage
+    ///
+    /// An increment passes the check when it is buffered, but another client can change the key
+    /// before the commit, so the commit checks them again before writing anything
+    ///
+    /// # Returns
+    /// - The error of the first increment which fails
+    pub fn check_increments(
+        &self,
+        key: &str,
+        ident: &[u8],
+        committed: Option<(Value, Option<Timestamp>)>,
+    ) -> Result<(), IncrError> {
+        let Some(ms) = self.data.get(key) else {
+            return Ok(());
+        };
+        match apply_mutations(ms, ident, committed).1 {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
     }
 
@@ -51,3 +86,276 @@ impl Wal {
         Ok(())
     }
 }
+
+/// Applies the mutations in order to `committed`, the entry of the key in the storage, see
+/// `Wal::view`
+///
+/// # Returns
+/// - The entry of the key, with the error of the first increment of the key which failed
+fn apply_mutations(
+    ms: &[Mutation],
+    ident: &[u8],
+    committed: Option<(Value, Option<Timestamp>)>,
+) -> (Option<(Value, Option<Timestamp>)>, Option<IncrError>) {
+    let mut failed = None;
+    let mut entry = committed;
+    for m in ms {
+        match m {
+            Mutation::Put(PutCommand(ck, cv, expires_at)) if ck == ident => {
+                entry = Some((Value::String(cv.clone()), *expires_at));
+            }
+            Mutation::ListPush(ListPushCommand(ck, side, values)) if ck == ident => {
+                let (items, _) = entry.get_or_insert((Value::List(VecDeque::new()), None));
+                // A push onto a string is refused before it is buffered
+                if let Value::List(items) = items {
+                    for value in values {
+                        match side {
+                            ListSide::Left => items.push_front(value.clone()),
+                            ListSide::Right => items.push_back(value.clone()),
+                        }
+                    }
+                }
+            }
+            Mutation::ListPop(ListPopCommand(ck, side)) if ck == ident => {
+                if let Some((Value::List(items), _)) = &mut entry {
+                    match side {
+                        ListSide::Left => items.pop_front(),
+                        ListSide::Right => items.pop_back(),
+                    };
+                    if items.is_empty() {
+                        entry = None;
+                    }
+                }
+            }
+            Mutation::HashSet(HashSetCommand(ck, pairs)) if ck == ident => {
+                let (fields, _) = entry.get_or_insert((Value::Hash(BTreeMap::new()), None));
+                // Likewise a field set on another type is refused before it is buffered
+                if let Value::Hash(fields) = fields {
+                    fields.extend(pairs.iter().cloned());
+                }
+            }
+            Mutation::HashDelete(HashDeleteCommand(ck, removed)) if ck == ident => {
+                if let Some((Value::Hash(fields), _)) = &mut entry {
+                    for field in removed {
+                        fields.remove(field);
+                    }
+                    if fields.is_empty() {
+                        entry = None;
+                    }
+                }
+            }
+            Mutation::SortedSetAdd(SortedSetAddCommand(ck, added)) if ck == ident => {
+                let (members, _) =
+                    entry.get_or_insert((Value::SortedSet(SortedSet::default()), None));
+                if let Value::SortedSet(members) = members {
+                    for (score, member) in added {
+                        members.insert(member.clone(), *score);
+                    }
+                }
+            }
+            Mutation::SortedSetRemove(SortedSetRemoveCommand(ck, removed)) if ck == ident => {
+                if let Some((Value::SortedSet(members), _)) = &mut entry {
+                    for member in removed {
+                        members.remove(member);
+                    }
+                    if members.is_empty() {
+                        entry = None;
+                    }
+                }
+            }
+            Mutation::SetAdd(SetAddCommand(ck, added)) if ck == ident => {
+                let (members, _) = entry.get_or_insert((Value::Set(BTreeSet::new()), None));
+                if let Value::Set(members) = members {
+                    members.extend(added.iter().cloned());
+                }
+            }
+            Mutation::SetRemove(SetRemoveCommand(ck, removed)) if ck == ident => {
+                if let Some((Value::Set(members), _)) = &mut entry {
+                    for member in removed {
+                        members.remove(member);
+                    }
+                    if members.is_empty() {
+                        entry = None;
+                    }
+                }
+            }
+            Mutation::Incr(IncrCommand(ck, by)) if ck == ident => {
+                let (current, expires_at) = match &entry {
+                    Some((Value::String(value), expires_at)) => (Some(value), *expires_at),
+                    // An increment of another type is refused before it is buffered, so another
+                    // client changed the type since
+                    Some(_) => {
+                        failed.get_or_insert(IncrError::Storage(WRONG_TYPE.into()));
+                        continue;
+                    }
+                    None => (None, None),
+                };
+                match increment(current.map(|x| x.as_slice()), *by) {
+                    Ok(value) => {
+                        entry = Some((Value::String(value.to_string().into()), expires_at));
+                    }
+                    Err(e) => {
+                        failed.get_or_insert(e);
+                    }
+                }
+            }
+            Mutation::PutIf(PutIfCommand(PutCommand(ck, cv, expires_at), condition))
+                if ck == ident && condition.holds(entry.is_some()) =>
+            {
+                entry = Some((Value::String(cv.clone()), *expires_at));
+            }
+            Mutation::Cas(CasCommand(ck, expected, cv)) if ck == ident => {
+                if let Some((Value::String(value), _)) = &mut entry {
+                    if value == expected {
+                        *value = cv.clone();
+                    }
+                }
+            }
+            Mutation::Delete(DeleteCommand(ck)) if ck == ident => entry = None,
+            Mutation::Expire(ExpireCommand(ck, expires_at)) if ck == ident => {
+                if let Some(entry) = &mut entry {
+                    entry.1 = *expires_at;
+                }
+            }
+            // Transactions buffer a rename as writes of the new key and a delete, see
+            // `execute_command`, so only the key it removes matters
+            Mutation::Rename(RenameCommand(ck, _)) if ck == ident => entry = None,
+            Mutation::FlushDb => entry = None,
+            _ => {}
+        }
+    }
+    let entry = entry.filter(|x| !is_expired(x.1, now_millis()));
+    (entry, failed)
+}
+
+#[cfg(test)]
+mod test_wal {
+    use super::*;
+
+    #[test]
+    fn view_applies_mutations_in_order() {
+        let mut wal = Wal::new();
+        let id = wal.begin();
+        let string = |value: &str| Value::String(value.into());
+        let committed = Some((string("1"), None));
+        assert_eq!(wal.view(&id, b"key", committed.clone()), committed);
+        assert_eq!(wal.view("unknown", b"key", committed.clone()), committed);
+
+        let put = |value: &str| Mutation::Put(PutCommand(b"key".to_vec(), value.into(), None));
+        wal.mutate(&id, put("2")).unwrap();
+        wal.mutate(&id, Mutation::Delete(DeleteCommand(b"key".to_vec())))
+            .unwrap();
+        assert_eq!(wal.view(&id, b"key", committed.clone()), None);
+        // The latest put wins rather than the first
+        wal.mutate(&id, put("3")).unwrap();
+        assert_eq!(
+            wal.view(&id, b"key", committed.clone()),
+            Some((string("3"), None))
+        );
+        let expires_at = now_millis() + 60_000;
+        let expire = ExpireCommand(b"key".to_vec(), Some(expires_at));
+        wal.mutate(&id, Mutation::Expire(expire)).unwrap();
+        assert_eq!(
+            wal.view(&id, b"key", committed.clone()),
+            Some((string("3"), Some(expires_at)))
+        );
+        let expire = ExpireCommand(b"key".to_vec(), Some(now_millis() - 1));
+        wal.mutate(&id, Mutation::Expire(expire)).unwrap();
+        assert_eq!(wal.view(&id, b"key", committed.clone()), None);
+        assert_eq!(wal.view(&id, b"other", None), None);
+    }
+
+    #[test]
+    fn increments_are_checked_against_the_committed_value() {
+        let mut wal = Wal::new();
+        let id = wal.begin();
+        let string = |value: &str| Some((Value::String(value.into()), None));
+        wal.mutate(&id, Mutation::Incr(IncrCommand(b"key".to_vec(), 5)))
+            .unwrap();
+        assert_eq!(wal.incremented_keys(&id), Some(vec![b"key".to_vec()]));
+        assert_eq!(wal.incremented_keys("unknown"), None);
+
+        assert_eq!(wal.view(&id, b"key", string("1")), string("6"));
+        assert_eq!(wal.check_increments(&id, b"key", string("1")), Ok(()));
+        assert_eq!(wal.check_increments(&id, b"key", None), Ok(()));
+
+        // The view skips an increment which fails, the check fails the commit instead
+        assert_eq!(wal.view(&id, b"key", string("x")), string("x"));
+        assert_eq!(
+            wal.check_increments(&id, b"key", string("x")),
+            Err(IncrError::NotAnInteger)
+        );
+        let list = Some((Value::List(VecDeque::from([b"a".to_vec()])), None));
+        assert_eq!(
+            wal.check_increments(&id, b"key", list),
+            Err(IncrError::Storage(WRONG_TYPE.into()))
+        );
+        assert_eq!(
+            wal.check_increments(&id, b"key", string(&i64::MAX.to_string())),
+            Err(IncrError::Overflow)
+        );
+    }
+
+    #[test]
+    fn view_applies_list_mutations() {
+        let mut wal = Wal::new();
+        let id = wal.begin();
+        let list = |items: &[&str]| {
+            Some((
+                Value::List(items.iter().map(|x| (*x).into()).collect()),
+                None,
+            ))
+        };
+        let push = |side, values: &[&str]| {
+            let values = values.iter().map(|x| (*x).into()).collect();
+            Mutation::ListPush(ListPushCommand(b"key".to_vec(), side, values))
+        };
+        let pop = |side| Mutation::ListPop(ListPopCommand(b"key".to_vec(), side));
+
+        wal.mutate(&id, push(ListSide::Left, &["b", "a"])).unwrap();
+        assert_eq!(wal.view(&id, b"key", None), list(&["a", "b"]));
+        assert_eq!(wal.view(&id, b"key", list(&["c"])), list(&["a", "b", "c"]));
+
+        wal.mutate(&id, push(ListSide::Right, &["c"])).unwrap();
+        wal.mutate(&id, pop(ListSide::Left)).unwrap();
+        assert_eq!(wal.view(&id, b"key", None), list(&["b", "c"]));
+
+        // The list is gone along with its last item
+        wal.mutate(&id, pop(ListSide::Right)).unwrap();
+        wal.mutate(&id, pop(ListSide::Right)).unwrap();
+        assert_eq!(wal.view(&id, b"key", None), None);
+        assert_eq!(wal.view(&id, b"key", list(&["x"])), list(&["b"]));
+    }
+
+    #[test]
+    fn view_applies_hash_mutations() {
+        let mut wal = Wal::new();
+        let id = wal.begin();
+        let hash = |fields: &[(&str, &str)]| {
+            let fields = fields.iter().map(|(f, v)| ((*f).into(), (*v).into()));
+            Some((Value::Hash(fields.collect()), None))
+        };
+        let set = |fields: &[(&str, &str)]| {
+            let fields = fields.iter().map(|(f, v)| ((*f).into(), (*v).into()));
+            Mutation::HashSet(HashSetCommand(b"key".to_vec(), fields.collect()))
+        };
+        let delete = |fields: &[&str]| {
+            let fields = fields.iter().map(|x| (*x).into()).collect();
+            Mutation::HashDelete(HashDeleteCommand(b"key".to_vec(), fields))
+        };
+
+        wal.mutate(&id, set(&[("a", "1"), ("b", "2")])).unwrap();
+        assert_eq!(wal.view(&id, b"key", None), hash(&[("a", "1"), ("b", "2")]));
+        wal.mutate(&id, set(&[("a", "3")])).unwrap();
+        assert_eq!(
+            wal.view(&id, b"key", hash(&[("c", "4")])),
+            hash(&[("a", "3"), ("b", "2"), ("c", "4")])
+        );
+
+        // The hash is gone along with its last field
+        wal.mutate(&id, delete(&["a", "missing"])).unwrap();
+        assert_eq!(wal.view(&id, b"key", None), hash(&[("b", "2")]));
+        wal.mutate(&id, delete(&["b"])).unwrap();
+        assert_eq!(wal.view(&id, b"key", None), None);
+    }
+}